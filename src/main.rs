@@ -1,26 +1,492 @@
+// conversions between `Scalar` and a fixed float type cast to the same type in one of the two
+// precisions
+#![allow(clippy::unnecessary_cast)]
+
 extern crate core;
 
-use clap::Parser;
-use rand::Rng;
+use clap::{Parser, ValueEnum};
+use num_traits::Float;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use speedy2d::color::Color;
-use speedy2d::window::{WindowHandler, WindowHelper, WindowStartupInfo};
+use speedy2d::dimen::{UVec2, Vec2};
+use speedy2d::shape::Rectangle;
+use speedy2d::window::{
+    KeyScancode, ModifiersState, MouseButton, VirtualKeyCode, WindowHandler, WindowHelper,
+    WindowStartupInfo,
+};
 use speedy2d::{Graphics2D, Window};
-use std::time::Instant;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::BufWriter;
+#[cfg(feature = "net")]
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{field, info_span};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
 
-use crate::util::particle::Particle;
-use crate::util::particle_quad_tree::{ParticleQuadTree, QuadtreeVisitor};
+use crate::util::boundary::{Boundary, Bounds};
+use crate::util::config_file::{ConfigWatcher, Setting};
+use crate::util::console::{Command, Console};
+use crate::util::emitter::Emitter;
+use crate::util::fingerprint::fingerprint;
+use crate::util::integrator::{
+    BlockIntegrator, EulerIntegrator, ForceEvaluator, Integrator, LeapfrogIntegrator,
+    Rk4Integrator, VerletIntegrator,
+};
+use crate::util::morton_tree::MortonTree;
+use crate::util::neighbor_list::NeighborList;
+use crate::util::obstacle::Obstacle;
+use crate::util::particle::{create_particle, Particle};
+use crate::util::particle_quad_tree::{
+    EnergyReport, ForceParameters, ForceTree, ParticleQuadTree, Repulsion,
+};
+use crate::util::profiler::{ProfilerHud, StepReport, StepReportLayer};
+use crate::util::scenario::{two_galaxies, Disc, Gravity, Plummer, Ring};
+use crate::util::snapshot::{load_snapshot, Projection};
+use crate::util::species::SpeciesConfig;
+use crate::util::sph::SphParameters;
+use crate::util::spring::{remap_springs, Spring};
+use crate::util::state_file::{load_state, save_state, StateConfig};
+#[cfg(feature = "net")]
+use crate::util::status_server::{Diagnostics, StatusServer};
+use crate::util::units::Units;
+use crate::util::velocity_distribution::VelocityDistribution;
+use crate::util::vtk::VtkExporter;
 use util::vector2d::Vector2D;
 
 pub mod util;
 
+/// The floating point type of the simulation, `f64` with the `f64` feature. Drawing stays in
+/// `f32` screen coordinates.
+#[cfg(not(feature = "f64"))]
+type Scalar = f32;
+#[cfg(feature = "f64")]
+type Scalar = f64;
+#[cfg(not(feature = "f64"))]
+use std::f32::consts;
+#[cfg(feature = "f64")]
+use std::f64::consts;
+
 struct Universe<T> {
     particles: Vec<Particle<T>>,
+    integrator: Box<dyn Integrator<T>>,
+    quadtree: ParticleQuadTree<T>, // rebuilt every step, kept to reuse its nodes
+    scratch_quadtree: ParticleQuadTree<T>, // for forces at positions moved during a step
+    tree_backend: TreeBackend,     // which tree evaluates the forces between particles
+    morton_tree: MortonTree<T>,    // only built with the Morton backend
+    scratch_morton_tree: MortonTree<T>,
     grav_const: T,
+    force_exponent: T,    // gravity falls off with 1/r^n
+    solid_interior: bool, // overlapping particles attract like uniform discs
+    softening: T,
+    theta: T,
+    coulomb_const: T,
+    repulsion: Option<Repulsion<T>>,
+    damping: T,
+    uniform_field: Vector2D<T>,
+    swirl_strength: T, // tangential acceleration around the swirl center, adjusted with ; and '
+    swirl_center: Vector2D<T>,
+    frame_rotation: T, // angular velocity of the reference frame, adjusted with , and .
+    frame_center: Vector2D<T>,
+    attractors: Vec<Particle<T>>,
+    obstacles: Vec<Obstacle<T>>,
+    springs: Vec<Spring<T>>,
+    emitters: Vec<Emitter>,
+    max_particles: Option<usize>, // emitters stop once the universe holds this many particles
+    lifetime: Option<T>,          // particles are removed once they are this old
+    ages: Vec<T>,                 // only tracked with a lifetime
+    fade_out: bool,               // particles fade out during their last second
+    sph: Option<SphParameters>,   // fluid mode, replaces gravity between particles
+    collisions: bool,
+    restitution: T, // fraction of the normal velocity kept in collisions and wall bounces
+    friction: T,    // fraction of the tangential velocity removed in collisions
+    merge_on_collision: bool,
+    boundary: Boundary,
+    culled: usize, // particles removed by the kill boundary so far
+    max_acceleration: Option<T>,
+    max_speed: Option<T>,
+    clamped: usize,       // particles whose acceleration was clamped in the last step
+    speed_limited: usize, // particles slowed down to the maximum speed in the last step
+    substeps: u32,        // integration steps per physics step
+    rebuild_every: u32,   // substeps between rebuilds of the force tree
+    rebuild_policy: RebuildPolicy<T>, // skips rebuilds while the particles barely moved
+    neighbor_list: NeighborList<T>, // pairs within reach of the repulsion and the fluid
+    time_scale: T,        // simulated time per real time, adjusted with [ and ]
+    energy_every: Option<u64>, // print the energy before every n-th step
+    last_energy: Option<(u64, EnergyReport<T>)>, // the latest energy and the step it was taken at
+    fission_threshold: Option<T>, // heavier particles split in two
+    species: Vec<SpeciesConfig>, // empty if all particles are of the same kind
+    forces: Vec<Vector2D<T>>, // applied from outside during the next step, empty if there are none
+    pinned: Vec<bool>,    // kept in place by the integration, empty if no particle is pinned
+    temperatures: Vec<T>, // only tracked in the temperature color mode
+    cooling_time: T,      // temperatures decay by a factor of e over this time
     num_particles: u32,
+    num_steps: u64,
+    sim_time: T,
+}
+
+impl<T> Universe<T> {
+    /// Removes the particles at the given ascending indices, keeping the order of the others.
+    fn remove_particles(&mut self, indices: &[usize]) {
+        remove_indices(&mut self.particles, indices);
+        if !self.ages.is_empty() {
+            remove_indices(&mut self.ages, indices);
+        }
+        remap_springs(&mut self.springs, indices);
+        if !self.forces.is_empty() {
+            remove_indices(&mut self.forces, indices);
+        }
+        if !self.pinned.is_empty() {
+            remove_indices(&mut self.pinned, indices);
+        }
+        if !self.temperatures.is_empty() {
+            remove_indices(&mut self.temperatures, indices);
+        }
+        self.integrator.reset();
+        self.rebuild_policy.invalidate();
+        self.neighbor_list.invalidate();
+    }
+}
+
+impl Universe<Scalar> {
+    /// Replaces the force trees with empty ones whose leaves hold up to `capacity` particles.
+    fn set_leaf_capacity(&mut self, capacity: usize) {
+        self.quadtree = empty_quadtree(capacity);
+        self.scratch_quadtree = empty_quadtree(capacity);
+        self.morton_tree = MortonTree::new(capacity);
+        self.scratch_morton_tree = MortonTree::new(capacity);
+        self.rebuild_policy.invalidate();
+    }
+
+    /// The distance within which the repulsion and the fluid act, `None` if neither is on.
+    fn short_range_cutoff(&self) -> Option<Scalar> {
+        let repulsion = self.repulsion.map(|repulsion| repulsion.cutoff);
+        let sph = self.sph.map(|sph| sph.smoothing_length);
+        match (repulsion, sph) {
+            (Some(repulsion), Some(sph)) => Some(repulsion.max(sph)),
+            (repulsion, sph) => repulsion.or(sph),
+        }
+    }
+
+    /// A hash of the exact positions and velocities of all particles, equal for two runs only if
+    /// their states are bit-identical.
+    fn state_hash(&self) -> u64 {
+        fingerprint(&self.particles)
+    }
+
+    /// Advances the particles by one physics step of `elapsed_s`: forces, integration, boundaries
+    /// and collisions. `cursor_attractor` acts during this step only, `density` gives the radius
+    /// of merged particles.
+    fn step(
+        &mut self,
+        elapsed_s: Scalar,
+        cursor_attractor: Option<Particle<Scalar>>,
+        density: Option<Scalar>,
+    ) {
+        if self.particles.is_empty() {
+            return;
+        }
+
+        // rebuild the trees in place of the ones from the last step. The Morton tree only
+        // evaluates forces, collisions and energy reports still need the quadtree.
+        // Their queries skip nodes by their bounds, so they need a tree of the current positions,
+        // while the forces can reuse a tree until the particles moved too far
+        let energy_due = self
+            .energy_every
+            .is_some_and(|every| self.num_steps.is_multiple_of(every));
+        let morton = self.tree_backend == TreeBackend::Morton;
+        let needs_current_quadtree = self.collisions || self.merge_on_collision;
+        let rebuild = needs_current_quadtree || self.rebuild_policy.needs_rebuild(&self.particles);
+        let tree_span = info_span!("tree_build").entered();
+        if (!morton && rebuild) || (morton && (needs_current_quadtree || energy_due)) {
+            self.quadtree.rebuild(&self.particles);
+        }
+        if morton && rebuild {
+            self.morton_tree.rebuild(&self.particles);
+        }
+        if rebuild {
+            let smallest_leaf = match self.tree_backend {
+                TreeBackend::Pointer => self.quadtree.smallest_leaf(),
+                TreeBackend::Morton => self.morton_tree.smallest_leaf(),
+            };
+            self.rebuild_policy
+                .record_build(&self.particles, smallest_leaf);
+        }
+        tree_span.exit();
+
+        // the repulsion and the fluid only act within a short distance, they take the pairs from
+        // the neighbor lists, which are checked before every substep
+        let cutoff = self.short_range_cutoff();
+        if let Some(cutoff) = cutoff {
+            let _span = info_span!("neighbor_lists").entered();
+            self.neighbor_list.update(&self.particles, cutoff);
+        }
+        let pressure = match &self.sph {
+            Some(sph) => sph.pressure_accelerations(&self.neighbor_list, &self.particles),
+            None => Vec::new(),
+        };
+        let mut attractors = self.attractors.clone();
+        attractors.extend(cursor_attractor);
+        let species_drag: Vec<Scalar> = self.species.iter().map(|s| s.drag).collect();
+        let force_params = ForceParameters {
+            grav_const: if self.sph.is_some() {
+                0.0
+            } else {
+                self.grav_const
+            },
+            force_exponent: self.force_exponent,
+            solid_interior: self.solid_interior,
+            softening: self.softening,
+            theta: self.theta,
+            coulomb_const: self.coulomb_const,
+            repulsion: self.repulsion,
+            neighbors: None,
+            damping: self.damping,
+            species_drag: &species_drag,
+            uniform_field: self.uniform_field,
+            swirl_strength: self.swirl_strength,
+            swirl_center: self.swirl_center,
+            frame_rotation: self.frame_rotation,
+            frame_center: self.frame_center,
+            attractors: &attractors,
+            obstacles: &self.obstacles,
+            springs: &self.springs,
+            extra_accelerations: &pressure,
+            external_forces: &self.forces,
+            pinned: &self.pinned,
+            period: self.boundary.period(),
+            max_acceleration: self.max_acceleration,
+        };
+        // measured before the step, while the tree still matches the positions
+        if energy_due {
+            let energy = self.quadtree.energy(&self.particles, &force_params);
+            println!(
+                "step {}: kinetic {:.4e}, potential {:.4e}, total {:.4e}",
+                self.num_steps, energy.kinetic, energy.potential, energy.total
+            );
+            self.last_energy = Some((self.num_steps, energy));
+        }
+        // between rebuilds the substeps reuse the tree, whose summaries lag behind a little.
+        // Faster time scales take more substeps, so their length and the accuracy stay the same
+        let time_scale = self.time_scale.max(1.0);
+        let substeps = (self.substeps as Scalar * time_scale).ceil() as u32;
+        let substep = elapsed_s / substeps as Scalar;
+        self.clamped = 0;
+        self.speed_limited = 0;
+        for i in 0..substeps {
+            if i > 0
+                && i % self.rebuild_every == 0
+                && (needs_current_quadtree || self.rebuild_policy.needs_rebuild(&self.particles))
+            {
+                let tree_span = info_span!("tree_build").entered();
+                if !morton || needs_current_quadtree {
+                    self.quadtree.rebuild(&self.particles);
+                }
+                let smallest_leaf = if morton {
+                    self.morton_tree.rebuild(&self.particles);
+                    self.morton_tree.smallest_leaf()
+                } else {
+                    self.quadtree.smallest_leaf()
+                };
+                self.rebuild_policy
+                    .record_build(&self.particles, smallest_leaf);
+                tree_span.exit();
+            }
+            if let Some(cutoff) = cutoff.filter(|_| i > 0) {
+                let _span = info_span!("neighbor_lists").entered();
+                self.neighbor_list.update(&self.particles, cutoff);
+            }
+            let params = ForceParameters {
+                neighbors: cutoff.map(|_| &self.neighbor_list),
+                ..force_params
+            };
+            let (tree, scratch): (&dyn ForceTree<Scalar>, &mut dyn ForceTree<Scalar>) =
+                match self.tree_backend {
+                    TreeBackend::Pointer => (&self.quadtree, &mut self.scratch_quadtree),
+                    TreeBackend::Morton => (&self.morton_tree, &mut self.scratch_morton_tree),
+                };
+            let forces = BarnesHut {
+                tree,
+                scratch: RefCell::new(scratch),
+                params: &params,
+            };
+            let clamped = self.integrator.step(&mut self.particles, &forces, substep);
+            self.clamped = self.clamped.max(clamped);
+            if let Some(max_speed) = self.max_speed {
+                let limited = limit_speeds(&mut self.particles, max_speed);
+                self.speed_limited = self.speed_limited.max(limited);
+            }
+        }
+        self.forces.clear();
+        if let Some(sph) = &self.sph {
+            sph.confine(&mut self.particles);
+        }
+        self.boundary.apply(&mut self.particles, self.restitution);
+        if self.collisions {
+            self.quadtree.resolve_collisions(
+                &mut self.particles,
+                elapsed_s,
+                self.restitution,
+                self.friction,
+                &mut self.temperatures,
+            );
+        }
+        if self.merge_on_collision {
+            let merging: Vec<bool> = self.species.iter().map(|s| s.merges).collect();
+            let absorbed =
+                self.quadtree
+                    .merge_collisions(&mut self.particles, elapsed_s, density, &merging);
+            self.remove_particles(&absorbed);
+        }
+        self.num_steps += 1;
+        self.sim_time += elapsed_s;
+    }
+
+    /// Ages all particles by one step and removes those that outlived the lifetime.
+    /// Particles added since the last call start at age zero.
+    fn expire_particles(&mut self, elapsed_s: Scalar) {
+        let Some(lifetime) = self.lifetime else {
+            return;
+        };
+        self.ages.resize(self.particles.len(), 0.0);
+        for age in &mut self.ages {
+            *age += elapsed_s;
+        }
+        let expired: Vec<usize> = (0..self.ages.len())
+            .filter(|&i| self.ages[i] >= lifetime)
+            .collect();
+        if !expired.is_empty() {
+            self.remove_particles(&expired);
+        }
+    }
+
+    /// Adds a force that acts on the particle at `index` during the next step only.
+    fn apply_force(&mut self, index: usize, force: Vector2D<Scalar>) {
+        self.forces
+            .resize(self.particles.len(), Vector2D::default());
+        self.forces[index] = &self.forces[index] + force;
+    }
+
+    /// Changes the momentum of the particle at `index` right away.
+    fn apply_impulse(&mut self, index: usize, impulse: Vector2D<Scalar>) {
+        let particle = &mut self.particles[index];
+        particle.velocity = &particle.velocity + impulse * (1.0 / particle.mass);
+        // limited right away, so the next drift does not carry it off at the full speed
+        if let Some(max_speed) = self.max_speed {
+            limit_speeds(std::slice::from_mut(particle), max_speed);
+        }
+    }
+
+    /// Pins the particle at `index` in place, or releases it if it was pinned.
+    fn toggle_pin(&mut self, index: usize) {
+        self.pinned.resize(self.particles.len(), false);
+        self.pinned[index] = !self.pinned[index];
+    }
+
+    /// Lets all temperatures decay exponentially for one step.
+    /// Particles added since the last call start cold.
+    fn cool_particles(&mut self, elapsed_s: Scalar) {
+        self.temperatures.resize(self.particles.len(), 0.0);
+        let decay = (-elapsed_s / self.cooling_time).exp();
+        for temperature in &mut self.temperatures {
+            *temperature *= decay;
+        }
+    }
+
+    /// Negates all velocities, so the simulation runs backwards from here.
+    /// Dissipative effects like drag and inelastic collisions still act forwards.
+    fn reverse_time(&mut self) {
+        for particle in &mut self.particles {
+            particle.velocity = particle.velocity * -1.0;
+        }
+    }
+
+    /// Splits every particle heavier than the fission threshold into two halves that fly apart
+    /// in a random direction, conserving mass, charge and momentum. The halves keep the total
+    /// area, or follow the density if there is one. Returns the number of splits.
+    fn split_heavy_particles<R: Rng>(&mut self, density: Option<Scalar>, rng: &mut R) -> usize {
+        let Some(threshold) = self.fission_threshold else {
+            return 0;
+        };
+        let heavy: Vec<usize> = (0..self.particles.len())
+            .filter(|&i| self.particles[i].mass > threshold)
+            .collect();
+        for &i in &heavy {
+            let parent = self.particles[i];
+            let mut child = parent;
+            child.mass = parent.mass / 2.0;
+            child.charge = parent.charge / 2.0;
+            child.radius = match density {
+                Some(density) => Particle::radius_for_mass(child.mass, density),
+                None => parent.radius / consts::SQRT_2,
+            };
+            // place the halves just out of contact and let them drift apart by a radius per
+            // second, so they are not merged again right away
+            let direction = Vector2D::from_polar(1.0, rng.gen_range(0.0..consts::TAU));
+            let offset = direction * (child.radius * 1.01);
+            let kick = direction * child.radius;
+            let mut first = child;
+            first.position = &parent.position + offset;
+            first.velocity = &parent.velocity + kick;
+            child.position = parent.position - offset;
+            child.velocity = parent.velocity - kick;
+            self.particles[i] = first;
+            self.particles.push(child);
+        }
+        if !heavy.is_empty() {
+            self.integrator.reset();
+        }
+        heavy.len()
+    }
+
+    /// Removes the particles that left the kill boundary.
+    fn cull_escaped(&mut self) {
+        let escaped = self.boundary.escaped(&self.particles);
+        if !escaped.is_empty() {
+            self.culled += escaped.len();
+            self.remove_particles(&escaped);
+        }
+    }
+}
+
+/// Removes the items at the given ascending indices, keeping the order of the others.
+fn remove_indices<E>(items: &mut Vec<E>, indices: &[usize]) {
+    let mut removed = indices.iter().peekable();
+    let mut index = 0;
+    items.retain(|_| {
+        let keep = removed.peek() != Some(&&index);
+        if !keep {
+            removed.next();
+        }
+        index += 1;
+        keep
+    });
 }
 
+/// The keys of the window, listed after the options in --help
+const KEY_HELP: &str = "Keys:
+  [ / ]   halve / double the time scale
+  ; / '   weaken / strengthen the swirl
+  , / .   rotate the reference frame slower / faster
+  T       reverse time
+  S       save the state to --save-state
+  F5      show the frame profiler
+
+Mouse:
+  left button           attract the particles to the cursor
+  Shift + left button   push them away
+  Ctrl + left button    pin or release the particle under the cursor";
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = KEY_HELP)]
 struct Args {
     #[arg(long, default_value_t = 1600)]
     window_width: u32,
@@ -30,154 +496,3656 @@ struct Args {
 
     #[arg(long, default_value_t = 1000)]
     num_particles: u32,
-}
 
-fn main() {
-    let args = Args::parse();
+    #[arg(long, allow_negative_numbers = true)]
+    grav_const: Option<Scalar>,
 
-    // initialize window in which the universe is drawn
-    let window =
-        Window::new_centered("Particles", (args.window_width, args.window_height)).unwrap();
-    window.run_loop(UniverseWindowHandler {
-        // create a new universe handler with an empty universe
-        universe: Universe {
-            particles: Vec::new(),
-            grav_const: 10.0,
-            num_particles: args.num_particles,
-        },
-        last_tick: Instant::now(),
-    })
+    #[arg(long)]
+    repulsive: bool,
+
+    #[arg(long, default_value_t = 0.5)]
+    softening: Scalar,
+
+    #[arg(long, default_value_t = 1.0)]
+    force_exponent: Scalar,
+
+    #[arg(long)]
+    solid_interior: bool,
+
+    #[arg(long, default_value_t = 0.7)]
+    theta: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    coulomb_const: Scalar,
+
+    #[arg(long)]
+    random_charges: bool,
+
+    #[arg(long, default_value_t = 1.0)]
+    mass_min: Scalar,
+
+    #[arg(long, default_value_t = 1.0)]
+    mass_max: Scalar,
+
+    #[arg(long)]
+    radius_by_mass: bool,
+
+    #[arg(long, conflicts_with = "radius_by_mass")]
+    density: Option<Scalar>,
+
+    #[arg(long)]
+    repulsion: Option<Scalar>,
+
+    #[arg(long, default_value_t = 4)]
+    repulsion_exponent: u32,
+
+    #[arg(long, default_value_t = 5.0)]
+    repulsion_cutoff: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    damping: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    gravity_x: Scalar,
+
+    #[arg(long)]
+    gravity_y: Option<Scalar>,
+
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    swirl_strength: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    swirl_x: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    swirl_y: Scalar,
+
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    frame_rotation: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    frame_x: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    frame_y: Scalar,
+
+    #[arg(long, value_enum, default_value_t = Scenario::Default)]
+    scenario: Scenario,
+
+    #[arg(long, default_value_t = 0.5)]
+    world_scale: Scalar,
+
+    #[arg(long)]
+    spawn_width: Option<Scalar>,
+
+    #[arg(long)]
+    spawn_height: Option<Scalar>,
+
+    #[arg(long, default_value_t = 10.0)]
+    disc_inner_radius: Scalar,
+
+    #[arg(long, default_value_t = 150.0)]
+    disc_outer_radius: Scalar,
+
+    #[arg(long, default_value_t = 5000.0)]
+    disc_mass: Scalar,
+
+    #[arg(long)]
+    pin_center: bool,
+
+    #[arg(long, default_value_t = 100.0)]
+    ring_radius: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    ring_jitter: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    ring_speed: Scalar,
+
+    #[arg(long, default_value_t = 50.0)]
+    plummer_radius: Scalar,
+
+    #[arg(long, default_value_t = 5000.0)]
+    plummer_mass: Scalar,
+
+    #[arg(long, default_value_t = 300.0)]
+    galaxy_separation: Scalar,
+
+    #[arg(long, default_value_t = 2000.0)]
+    galaxy_mass: Scalar,
+
+    #[arg(long, default_value_t = 0.5)]
+    galaxy_split: Scalar,
+
+    #[arg(long, value_enum, default_value_t = SimulationMode::Gravity)]
+    mode: SimulationMode,
+
+    #[arg(long, default_value_t = 4.0)]
+    sph_smoothing_length: Scalar,
+
+    #[arg(long, default_value_t = 200.0)]
+    sph_stiffness: Scalar,
+
+    #[arg(long, default_value_t = 0.25)]
+    sph_rest_density: Scalar,
+
+    #[arg(long)]
+    central_mass: Option<Scalar>,
+
+    #[arg(long, default_value_t = 250.0)]
+    central_x: Scalar,
+
+    #[arg(long, default_value_t = 50.0)]
+    central_y: Scalar,
+
+    #[arg(long, default_value_t = 1000.0)]
+    cursor_mass: Scalar,
+
+    #[arg(long = "emitter")]
+    emitters: Vec<Emitter>,
+
+    #[arg(long)]
+    max_particles: Option<usize>,
+
+    #[arg(long)]
+    lifetime: Option<Scalar>,
+
+    #[arg(long, requires = "lifetime")]
+    fade_out: bool,
+
+    #[arg(long = "obstacle")]
+    obstacles: Vec<Obstacle<Scalar>>,
+
+    #[arg(long)]
+    spring_grid: Option<u32>,
+
+    #[arg(long)]
+    collisions: bool,
+
+    #[arg(long, default_value_t = 1.0)]
+    restitution: Scalar,
+
+    #[arg(long, default_value_t = 0.0)]
+    friction: Scalar,
+
+    #[arg(long, conflicts_with = "collisions")]
+    merge_on_collision: bool,
+
+    #[arg(long, value_delimiter = ',')]
+    species: Vec<SpeciesConfig>,
+
+    #[arg(long, value_enum, default_value_t = BoundaryMode::Open)]
+    boundary: BoundaryMode,
+
+    #[arg(long)]
+    bounds: Option<Bounds>,
+
+    #[arg(long)]
+    kill_radius: Option<Scalar>,
+
+    #[arg(long)]
+    max_acceleration: Option<Scalar>,
+
+    #[arg(long)]
+    max_speed: Option<Scalar>,
+
+    #[arg(long)]
+    fission_threshold: Option<Scalar>,
+
+    #[arg(long)]
+    reverse: bool,
+
+    #[arg(long, value_enum, default_value_t = ColorMode::Species)]
+    color_mode: ColorMode,
+
+    #[arg(long, default_value_t = 2.0)]
+    cooling_time: Scalar,
+
+    #[arg(long, default_value_t = 10.0)]
+    hot_temperature: Scalar,
+
+    #[arg(long, value_enum, default_value_t = IntegrationScheme::Euler)]
+    integrator: IntegrationScheme,
+
+    // the Euler integration writes to a copy of the particles that replaces them after the step
+    #[arg(long)]
+    double_buffer: bool,
+
+    #[arg(long, default_value_t = 6)]
+    block_levels: u32,
+
+    #[arg(long, default_value_t = 0.1)]
+    block_accuracy: Scalar,
+
+    #[arg(long, value_enum)]
+    units: Option<UnitSystem>,
+
+    #[arg(long, value_enum, default_value_t = VelocityDist::Zero)]
+    velocity_dist: VelocityDist,
+
+    #[arg(long, default_value_t = 1.0)]
+    temperature: Scalar,
+
+    #[arg(long)]
+    remove_drift: bool,
+
+    #[arg(long)]
+    load_snapshot: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = Projection::Xy)]
+    projection: Projection,
+
+    #[arg(long, conflicts_with = "load_snapshot")]
+    load_state: Option<PathBuf>,
+
+    #[arg(long)]
+    save_state: Option<PathBuf>,
+
+    #[arg(long)]
+    seed: Option<u64>,
+
+    // uses a fixed seed if none is given and prints a fingerprint of the state every
+    // `fingerprint_every` steps. Everything runs on one thread, so not even the order of the
+    // parallel work can differ between runs.
+    // The step length must not depend on the frame time, so the variable timestep is excluded.
+    // Fingerprints only match between builds of the same precision, see the f64 feature, and on
+    // the same platform, since powf and ln come from its math library
+    #[arg(long, conflicts_with = "variable_step")]
+    reproducible: bool,
+
+    #[arg(long, default_value_t = 100)]
+    fingerprint_every: u64,
+
+    #[arg(long)]
+    energy_every: Option<u64>,
+
+    #[arg(long, default_value_t = 60.0)]
+    physics_hz: Scalar,
+
+    #[arg(long)]
+    variable_step: bool,
+
+    #[arg(long, default_value_t = 1)]
+    substeps: u32,
+
+    #[arg(long, default_value_t = 1)]
+    rebuild_every: u32,
+
+    // reuses the force tree until some particle moved farther than this fraction of the smallest
+    // leaf since it was built, instead of rebuilding it every time
+    #[arg(long)]
+    rebuild_threshold: Option<Scalar>,
+
+    // lists the neighbors for the repulsion and the fluid this much farther than their cutoff, and
+    // reuses the lists until some particle moved half of it. Zero rebuilds them in every step
+    #[arg(long, default_value_t = 0.0)]
+    neighbor_skin: Scalar,
+
+    #[arg(long, default_value = "auto")]
+    leaf_capacity: LeafCapacity,
+
+    #[arg(long, value_enum, default_value_t = TreeBackend::Pointer)]
+    tree: TreeBackend,
+
+    #[arg(long, default_value_t = 1.0)]
+    time_scale: Scalar,
+
+    #[arg(long, default_value_t = 0)]
+    warmup_ticks: u64,
+
+    #[arg(long)]
+    export_vtk: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 1)]
+    export_every: u64,
+
+    // writes the quadtree after the warm-up steps and exits, see ParticleQuadTree::dump
+    #[arg(long)]
+    dump_tree: Option<PathBuf>,
+
+    #[arg(long)]
+    console: bool,
+
+    // reads settings from a file of `key = value` lines, they replace the command line values
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    // reads the config file again when it changes and applies the settings that can change while
+    // the simulation runs
+    #[arg(long, requires = "config")]
+    watch_config: bool,
+
+    #[arg(long, default_value_t = LevelFilter::WARN)]
+    log_level: LevelFilter,
+
+    #[arg(long)]
+    log_json: Option<PathBuf>,
+
+    #[cfg(feature = "net")]
+    #[arg(long)]
+    status_port: Option<u16>,
+
+    // only local clients can reach the status server unless another address is given
+    #[cfg(feature = "net")]
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    status_bind: IpAddr,
 }
 
-struct UniverseWindowHandler {
-    universe: Universe<f32>,
-    last_tick: Instant,
+/// Physical unit systems that can be used instead of arbitrary simulation units
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum UnitSystem {
+    Si,
+    Solar,
 }
 
-impl WindowHandler for UniverseWindowHandler {
-    fn on_start(&mut self, _helper: &mut WindowHelper<()>, _info: WindowStartupInfo) {
-        // initialize particles
-        let mut rng = rand::thread_rng();
-        (0..self.universe.num_particles).for_each(|_| {
-            // non-uniform distribution for a more interesting simulation
-            let x = rng.gen_range(0.0..500.0);
-            let y = rng.gen_range(0.0..100.0);
-            self.universe.particles.push(create_particle(x, y));
-        });
+impl UnitSystem {
+    fn units(self) -> Units {
+        match self {
+            UnitSystem::Si => Units::SI,
+            UnitSystem::Solar => Units::SOLAR,
+        }
     }
+}
 
-    fn on_draw(&mut self, helper: &mut WindowHelper, graphics: &mut Graphics2D) {
-        // do calculations
-        self.last_tick = Instant::now();
+/// Initial conditions for runs that do not load particles
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+enum Scenario {
+    /// Particles scattered in a 500 x 100 box
+    Default,
+    /// Particles scattered in a region with the aspect ratio of the window
+    Uniform,
+    /// A rotating disc around a heavy central particle
+    Disc,
+    /// Particles on a circle, optionally moving along it
+    Ring,
+    /// A cluster close to equilibrium, following a 2D Plummer profile
+    Plummer,
+    /// Two rotating discs on a collision course
+    TwoGalaxies,
+}
 
-        // draw graphics
-        graphics.clear_screen(Color::BLACK);
+/// Which tree evaluates the forces between particles
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+enum TreeBackend {
+    /// A quadtree of boxed nodes, split while particles are partitioned into them
+    Pointer,
+    /// A linear quadtree in one array, built by sorting the particles along a Z-order curve.
+    /// Faster to build for many particles.
+    Morton,
+}
 
-        // find bounds of the universe
-        let mut min_x = self.universe.particles[0].position.x;
-        let mut max_x = min_x;
-        let mut min_y = self.universe.particles[0].position.y;
-        let mut max_y = min_y;
-        let num_particles = self.universe.particles.len();
-        (1..num_particles).for_each(|i| {
-            let x = self.universe.particles[i].position.x;
-            let y = self.universe.particles[i].position.y;
-            if x < min_x {
-                min_x = x;
-            }
-            if x > max_x {
-                max_x = x;
-            }
-            if y < min_y {
-                min_y = y;
-            }
-            if y > max_y {
-                max_y = y;
-            }
-        });
-        let width = max_x - min_x;
-        let height = max_y - min_y;
-        let center_x = (min_x + max_x) / 2.0;
-        let center_y = (min_y + max_y) / 2.0;
+/// How the particles are advanced by one step
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+enum IntegrationScheme {
+    /// Semi-implicit Euler: kick by the full step, then drift
+    Euler,
+    /// Kick-drift-kick leapfrog, evaluates the forces twice per step but conserves energy far
+    /// better on orbits
+    Leapfrog,
+    /// Velocity Verlet, the same update as leapfrog but reusing the accelerations from the end of
+    /// the previous step, so the forces are evaluated once per step
+    Verlet,
+    /// Classical fourth order Runge-Kutta, builds a tree and evaluates the forces four times per
+    /// step. Very accurate, but only recommended for small numbers of particles
+    Rk4,
+    /// Leapfrog with individual power of two timesteps chosen from the accelerations, only the
+    /// particles whose step ends are evaluated. Saves time on centrally concentrated clusters
+    Block,
+}
 
-        // create the temporary quadtree
-        let mut quadtree = ParticleQuadTree::new(Vector2D { x: center_x, y: center_y }, width, height, 100);
-        let num_particles = self.universe.particles.len();
-        (0..num_particles).for_each(|i| {
-            quadtree.insert(&self.universe.particles, i);
-        });
+impl IntegrationScheme {
+    fn create(self, args: &Args) -> Box<dyn Integrator<Scalar>> {
+        match self {
+            IntegrationScheme::Euler => Box::new(EulerIntegrator::new(args.double_buffer)),
+            IntegrationScheme::Leapfrog => Box::<LeapfrogIntegrator<Scalar>>::default(),
+            IntegrationScheme::Verlet => Box::<VerletIntegrator<Scalar>>::default(),
+            IntegrationScheme::Rk4 => Box::<Rk4Integrator<Scalar>>::default(),
+            IntegrationScheme::Block => {
+                Box::new(BlockIntegrator::new(args.block_levels, args.block_accuracy))
+            }
+        }
+    }
+}
 
-        quadtree.tick(
-            &mut self.universe.particles,
-            self.universe.grav_const,
-            1.0 / 30.0,
-        );
+/// How particles are colored
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+enum ColorMode {
+    /// The color of the particle's species, or white
+    Species,
+    /// From blue to white to red as collisions heat the particle up
+    Temperature,
+}
 
-        let mut tree_visitor = WindowHandlerTreeVisitor {
-            graphics,
-            universe: &self.universe,
-            univ_width: width,
-            univ_height: height,
-            univ_center: Vector2D { x: center_x, y: center_y },
-            screen_width: helper.get_size_pixels().x as f32,
-            screen_height: helper.get_size_pixels().y as f32,
-        };
-        quadtree.visit(&mut tree_visitor);
+/// How many elements a leaf of the force tree holds before it splits
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum LeafCapacity {
+    /// Chosen from the number of particles
+    Auto,
+    Fixed(usize),
+}
 
-        // Request that we draw another frame once this one has finished
-        helper.request_redraw();
+impl LeafCapacity {
+    /// The capacity for a simulation of about `num_particles` particles. Building the tree and
+    /// evaluating the forces is fastest with 8 to 32 elements per leaf, larger trees prefer the
+    /// larger leaves.
+    fn resolve(self, num_particles: usize) -> usize {
+        match self {
+            LeafCapacity::Fixed(capacity) => capacity,
+            LeafCapacity::Auto if num_particles < 1_000 => 8,
+            LeafCapacity::Auto if num_particles < 20_000 => 16,
+            LeafCapacity::Auto => 32,
+        }
     }
 }
 
-/// Creates a random particle.
-fn create_particle(x: f32, y: f32) -> Particle<f32> {
-    let mass: f32 = 1.0;
-    Particle {
-        position: Vector2D { x, y },
-        velocity: Vector2D { x: 0.0, y: 0.0 },
-        radius: 1.0,
-        mass,
+/// Parses a leaf capacity given as `auto` or a positive number.
+impl FromStr for LeafCapacity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(LeafCapacity::Auto);
+        }
+        match s.parse() {
+            Ok(capacity) if capacity > 0 => Ok(LeafCapacity::Fixed(capacity)),
+            _ => Err(format!(
+                "expected auto or a positive number for the leaf capacity, got: {}",
+                s
+            )),
+        }
     }
 }
 
-struct WindowHandlerTreeVisitor<'a, T> {
-    graphics: &'a mut Graphics2D,
-    universe: &'a Universe<T>,
-    univ_width: f32,
-    univ_height: f32,
-    univ_center: Vector2D<f32>,
-    screen_width: f32,
-    screen_height: f32,
+/// What happens to particles at the edge of the world
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+enum BoundaryMode {
+    Open,
+    /// Walls that particles bounce off
+    Reflect,
+    /// A periodic world where opposite edges are connected
+    Wrap,
+    /// Particles far from the center of mass are removed
+    Kill,
 }
 
-impl QuadtreeVisitor<f32> for WindowHandlerTreeVisitor<'_, f32> {
-    fn visit_node(&mut self, _tree: &ParticleQuadTree<f32>) {
-        // nop
-    }
+/// What kind of forces act between particles
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+enum SimulationMode {
+    Gravity,
+    /// Smoothed particle hydrodynamics, a fluid in a tank
+    Sph,
+}
 
-    fn visit_leaf_node(&mut self, _tree: &ParticleQuadTree<f32>, _element_indices: &Vec<usize>) {
-        // nop
-    }
+/// Distributions for the initial particle velocities
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum VelocityDist {
+    Zero,
+    Maxwell,
+}
 
-    fn visit_element(&mut self, element_index: usize) {
-        let element = self.universe.particles.get(element_index).unwrap();
-        let screen_pos = self.local_to_screen(element.position);
-        self.graphics
-            .draw_circle((screen_pos.x, screen_pos.y), element.radius, Color::WHITE);
+impl Args {
+    /// Replaces the command line value with one from the config file
+    fn apply_setting(&mut self, setting: &Setting) -> Result<(), String> {
+        match setting {
+            Setting::GravConst(grav_const) => self.grav_const = Some(*grav_const),
+            Setting::Damping(damping) => self.damping = *damping,
+            Setting::Theta(theta) => self.theta = *theta,
+            Setting::Softening(softening) => self.softening = *softening,
+            Setting::TimeScale(time_scale) => self.time_scale = *time_scale,
+            Setting::SwirlStrength(strength) => self.swirl_strength = *strength,
+            Setting::HotTemperature(temperature) => self.hot_temperature = *temperature,
+            Setting::NumParticles(count) => self.num_particles = *count,
+            Setting::Seed(seed) => self.seed = Some(*seed),
+            Setting::Scenario(name) => self.scenario = Scenario::from_str(name, true)?,
+        }
+        Ok(())
     }
 }
 
-impl WindowHandlerTreeVisitor<'_, f32> {
-    fn local_to_screen(&self, p: Vector2D<f32>) -> Vector2D<f32> {
-        let factor;
-        if self.univ_width > self.univ_height {
-            factor = self.univ_width;
-        } else {
-            factor = self.univ_height;
+fn main() {
+    let mut args = Args::parse();
+    let config_file = args.config.as_deref().map(|path| {
+        ConfigWatcher::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        })
+    });
+    for setting in config_file.iter().flat_map(ConfigWatcher::settings) {
+        if let Err(e) = args.apply_setting(setting) {
+            eprintln!("invalid config: {}", e);
+            std::process::exit(1)
         }
-        Vector2D {
-            x: (p.x - self.univ_center.x) / factor * self.screen_width + self.screen_width / 2.0,
-            y: (p.y - self.univ_center.y) / factor * self.screen_height + self.screen_height / 2.0,
+    }
+    let step_report = init_logging(args.log_level, args.log_json.as_deref());
+    let velocity_distribution = match args.velocity_dist {
+        VelocityDist::Zero => VelocityDistribution::Zero,
+        VelocityDist::Maxwell => VelocityDistribution::Maxwell {
+            temperature: args.temperature,
+            remove_drift: args.remove_drift,
+        },
+    };
+    if args.mass_min <= 0.0 || args.mass_min > args.mass_max {
+        eprintln!(
+            "invalid mass range {}..{}: masses must be positive and --mass-min at most --mass-max",
+            args.mass_min, args.mass_max
+        );
+        std::process::exit(1)
+    }
+    if args.density.is_some_and(|density| density <= 0.0) {
+        eprintln!("invalid density: must be positive");
+        std::process::exit(1)
+    }
+    if args.disc_inner_radius < 0.0 || args.disc_inner_radius > args.disc_outer_radius {
+        eprintln!(
+            "invalid disc radii {}..{}: radii must not be negative and the inner radius at most the outer one",
+            args.disc_inner_radius, args.disc_outer_radius
+        );
+        std::process::exit(1)
+    }
+    if args.ring_radius < 0.0 || args.ring_jitter < 0.0 {
+        eprintln!("invalid ring: radius and jitter must not be negative");
+        std::process::exit(1)
+    }
+    if !args.species.is_empty()
+        && (args.species.len() > 256 || args.species.iter().all(|s| s.fraction <= 0.0))
+    {
+        eprintln!("invalid species: at most 256 species, with at least one positive fraction");
+        std::process::exit(1)
+    }
+    for (i, species) in args.species.iter().enumerate() {
+        if args.species[..i]
+            .iter()
+            .any(|other| other.name == species.name)
+        {
+            eprintln!("species {} is listed more than once", species.name);
+            std::process::exit(1)
         }
     }
+    if !(0.0..=1.0).contains(&args.restitution) {
+        eprintln!(
+            "invalid restitution {}: must be between 0 and 1",
+            args.restitution
+        );
+        std::process::exit(1)
+    }
+    if !(0.0..=1.0).contains(&args.friction) {
+        eprintln!(
+            "invalid friction {}: must be between 0 and 1",
+            args.friction
+        );
+        std::process::exit(1)
+    }
+    if args.cooling_time <= 0.0 || args.hot_temperature <= 0.0 {
+        eprintln!(
+            "invalid temperature settings: cooling time {} and hot temperature {} must be positive",
+            args.cooling_time, args.hot_temperature
+        );
+        std::process::exit(1)
+    }
+    if args.max_acceleration.is_some_and(|max| max <= 0.0) {
+        eprintln!("invalid maximum acceleration: must be positive");
+        std::process::exit(1)
+    }
+    if args.max_speed.is_some_and(|max| max <= 0.0) {
+        eprintln!("invalid maximum speed: must be positive");
+        std::process::exit(1)
+    }
+    if !(args.neighbor_skin >= 0.0 && args.neighbor_skin.is_finite()) {
+        eprintln!("invalid neighbor skin: must not be negative");
+        std::process::exit(1)
+    }
+    if args
+        .fission_threshold
+        .is_some_and(|threshold| threshold <= 0.0)
+    {
+        eprintln!("invalid fission threshold: must be positive");
+        std::process::exit(1)
+    }
+    if !(args.time_scale >= 0.0 && args.time_scale <= MAX_TIME_SCALE) {
+        eprintln!(
+            "invalid time scale {}: must be between 0 and {}",
+            args.time_scale, MAX_TIME_SCALE
+        );
+        std::process::exit(1)
+    }
+    if args.double_buffer && args.integrator != IntegrationScheme::Euler {
+        eprintln!("--double-buffer only applies to the euler integrator");
+        std::process::exit(1)
+    }
+    if args.block_levels > MAX_BLOCK_LEVELS
+        || !(args.block_accuracy > 0.0 && args.block_accuracy.is_finite())
+    {
+        eprintln!(
+            "invalid block timesteps: at most {} levels and a positive accuracy",
+            MAX_BLOCK_LEVELS
+        );
+        std::process::exit(1)
+    }
+    if !(args.physics_hz > 0.0 && args.physics_hz.is_finite()) {
+        eprintln!("invalid physics rate {}: must be positive", args.physics_hz);
+        std::process::exit(1)
+    }
+    if !(args.force_exponent > 0.0 && args.force_exponent.is_finite()) {
+        eprintln!(
+            "invalid force exponent {}: must be positive",
+            args.force_exponent
+        );
+        std::process::exit(1)
+    }
+    if args.plummer_radius <= 0.0 || args.plummer_mass <= 0.0 {
+        eprintln!("invalid plummer cluster: radius and mass must be positive");
+        std::process::exit(1)
+    }
+    // the uniform scenario fills a region shaped like the window instead of the default stripe
+    let (spawn_width, spawn_height) = match args.scenario {
+        Scenario::Uniform => (
+            args.window_width as Scalar * args.world_scale,
+            args.window_height as Scalar * args.world_scale,
+        ),
+        _ => (500.0, 100.0),
+    };
+    let spawn_width = args.spawn_width.unwrap_or(spawn_width);
+    let spawn_height = args.spawn_height.unwrap_or(spawn_height);
+    if spawn_width <= 0.0 || spawn_height <= 0.0 {
+        eprintln!(
+            "invalid spawn region {}x{}: width and height must be positive",
+            spawn_width, spawn_height
+        );
+        std::process::exit(1)
+    }
+    let spawn_settings = SpawnSettings {
+        width: spawn_width,
+        height: spawn_height,
+        velocity_distribution,
+        random_charges: args.random_charges,
+        mass_min: args.mass_min,
+        mass_max: args.mass_max,
+        radius_by_mass: args.radius_by_mass,
+        density: args.density,
+    };
+    let mut particles = match &args.load_snapshot {
+        Some(path) => load_snapshot(path, args.projection).unwrap_or_else(|e| {
+            eprintln!("failed to load snapshot {}: {}", path.display(), e);
+            std::process::exit(1)
+        }),
+        None => Vec::new(),
+    };
+    #[cfg(feature = "parallel")]
+    if args.reproducible {
+        // the force evaluation and the tree build use the global pool
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build_global()
+            .expect("the global thread pool is only configured here");
+    }
+    let seed = match (args.seed, args.reproducible) {
+        (Some(seed), _) => seed,
+        (None, true) => 0,
+        (None, false) => rand::random(),
+    };
+    let mut grav_const = args.grav_const.unwrap_or_else(|| {
+        args.units
+            .map_or(10.0, |units| units.units().grav_const() as Scalar)
+    });
+    let mut num_steps = 0;
+    let mut sim_time = 0.0;
+    if let Some(path) = &args.load_state {
+        let (config, loaded) = load_state(path).unwrap_or_else(|e| {
+            eprintln!("failed to load state {}: {}", path.display(), e);
+            std::process::exit(1)
+        });
+        particles = loaded;
+        if args.grav_const.is_none() {
+            grav_const = config.grav_const;
+        }
+        num_steps = config.num_steps;
+        sim_time = config.sim_time;
+    }
+    if args.repulsive {
+        grav_const = -grav_const.abs();
+    }
+    let mut springs = Vec::new();
+    if let Some(size) = args.spring_grid {
+        spawn_spring_grid(&mut particles, &mut springs, size);
+    }
+    let sph = (args.mode == SimulationMode::Sph).then_some(SphParameters {
+        smoothing_length: args.sph_smoothing_length,
+        stiffness: args.sph_stiffness,
+        rest_density: args.sph_rest_density,
+        tank_min: Vector2D { x: 0.0, y: 0.0 },
+        tank_max: Vector2D { x: 200.0, y: 100.0 },
+    });
+    // the fluid needs something pulling it down, unless told otherwise
+    let default_gravity_y = if sph.is_some() { 20.0 } else { 0.0 };
+    if let Some(sph) = &sph {
+        if particles.is_empty() {
+            spawn_dam_break(&mut particles, sph);
+        }
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut pinned = Vec::new();
+    if particles.is_empty() {
+        let center = spawn_scenario(&mut particles, &args, grav_const, &mut rng);
+        if let (true, Some(center)) = (args.pin_center, center) {
+            pinned.resize(center + 1, false);
+            pinned[center] = true;
+        }
+    }
+    // without explicit bounds, the walls enclose the initial particles
+    let bounds = args
+        .bounds
+        .or_else(|| Bounds::enclosing(&particles))
+        .unwrap_or(Bounds {
+            min: Vector2D { x: 0.0, y: 0.0 },
+            max: Vector2D {
+                x: spawn_width,
+                y: spawn_height,
+            },
+        });
+    let boundary = match args.boundary {
+        BoundaryMode::Open => Boundary::Open,
+        BoundaryMode::Reflect => Boundary::Reflect(bounds),
+        BoundaryMode::Wrap => Boundary::Wrap(bounds),
+        BoundaryMode::Kill => match args.kill_radius {
+            Some(radius) if radius > 0.0 => Boundary::Kill { radius },
+            _ => {
+                eprintln!("--boundary kill needs a positive --kill-radius");
+                std::process::exit(1)
+            }
+        },
+    };
+
+    // particles scattered in on_start are not there yet
+    let leaf_capacity = args
+        .leaf_capacity
+        .resolve(particles.len().max(args.num_particles as usize));
+
+    let vtk_exporter = args.export_vtk.as_ref().map(|directory| {
+        VtkExporter::new(directory.clone(), args.export_every).unwrap_or_else(|e| {
+            eprintln!(
+                "failed to create export directory {}: {}",
+                directory.display(),
+                e
+            );
+            std::process::exit(1)
+        })
+    });
+    #[cfg(feature = "net")]
+    let status_server = args.status_port.map(|port| {
+        StatusServer::start(args.status_bind, port).unwrap_or_else(|e| {
+            eprintln!(
+                "failed to start the status server on {}:{}: {}",
+                args.status_bind, port, e
+            );
+            std::process::exit(1)
+        })
+    });
+
+    // initialize window in which the universe is drawn
+    let window =
+        Window::new_centered("Particles", (args.window_width, args.window_height)).unwrap();
+    window.run_loop(UniverseWindowHandler {
+        // create a new universe handler with an empty universe
+        universe: Universe {
+            particles,
+            integrator: args.integrator.create(&args),
+            quadtree: empty_quadtree(leaf_capacity),
+            scratch_quadtree: empty_quadtree(leaf_capacity),
+            tree_backend: args.tree,
+            morton_tree: MortonTree::new(leaf_capacity),
+            scratch_morton_tree: MortonTree::new(leaf_capacity),
+            grav_const,
+            force_exponent: args.force_exponent,
+            solid_interior: args.solid_interior,
+            softening: args.softening,
+            theta: args.theta,
+            coulomb_const: args.coulomb_const,
+            repulsion: args.repulsion.map(|strength| Repulsion {
+                strength,
+                exponent: args.repulsion_exponent,
+                cutoff: args.repulsion_cutoff,
+            }),
+            damping: args.damping,
+            uniform_field: Vector2D {
+                x: args.gravity_x,
+                y: args.gravity_y.unwrap_or(default_gravity_y),
+            },
+            swirl_strength: args.swirl_strength,
+            swirl_center: Vector2D {
+                x: args.swirl_x,
+                y: args.swirl_y,
+            },
+            frame_rotation: args.frame_rotation,
+            frame_center: Vector2D {
+                x: args.frame_x,
+                y: args.frame_y,
+            },
+            attractors: args
+                .central_mass
+                .map(|mass| Particle {
+                    position: Vector2D {
+                        x: args.central_x,
+                        y: args.central_y,
+                    },
+                    velocity: Vector2D { x: 0.0, y: 0.0 },
+                    radius: 6.0,
+                    mass,
+                    charge: 0.0,
+                    species: 0,
+                })
+                .into_iter()
+                .collect(),
+            obstacles: args.obstacles,
+            springs,
+            emitters: args.emitters,
+            max_particles: args.max_particles,
+            lifetime: args.lifetime,
+            ages: Vec::new(),
+            fade_out: args.fade_out,
+            sph,
+            collisions: args.collisions,
+            restitution: args.restitution,
+            friction: args.friction,
+            merge_on_collision: args.merge_on_collision,
+            boundary,
+            culled: 0,
+            max_acceleration: args.max_acceleration,
+            max_speed: args.max_speed,
+            clamped: 0,
+            speed_limited: 0,
+            substeps: args.substeps.max(1),
+            rebuild_every: args.rebuild_every.max(1),
+            rebuild_policy: RebuildPolicy::new(args.rebuild_threshold),
+            neighbor_list: NeighborList::new(args.neighbor_skin),
+            time_scale: args.time_scale,
+            energy_every: args.energy_every.map(|every| every.max(1)),
+            last_energy: None,
+            fission_threshold: args.fission_threshold,
+            species: args.species,
+            forces: Vec::new(),
+            pinned,
+            temperatures: Vec::new(),
+            cooling_time: args.cooling_time,
+            num_particles: args.num_particles,
+            num_steps,
+            sim_time,
+        },
+        last_tick: Instant::now(),
+        physics_hz: args.physics_hz,
+        accumulator: 0.0,
+        variable_step: args.variable_step,
+        warmup_ticks: args.warmup_ticks,
+        rng,
+        spawn_settings,
+        paused: false,
+        console: args.console.then(Console::start),
+        config: config_file.filter(|_| args.watch_config),
+        render_instances: Vec::new(),
+        step_report,
+        profiler: ProfilerHud::new(),
+        camera: None,
+        cursor: Cursor::default(),
+        shown_count: 0,
+        shown_time_scale: 1.0,
+        cursor_mass: args.cursor_mass,
+        save_path: args.save_state,
+        reverse_on_start: args.reverse,
+        color_mode: args.color_mode,
+        hot_temperature: args.hot_temperature,
+        fingerprint_every: args.reproducible.then_some(args.fingerprint_every.max(1)),
+        vtk_exporter,
+        dump_tree_path: args.dump_tree,
+        #[cfg(feature = "net")]
+        status_server,
+    })
+}
+
+/// Logs to stderr and, if a path is given, writes every event and closed span as JSON to a file.
+/// Returns the timings of the last frame, which are collected regardless of the log level.
+fn init_logging(level: LevelFilter, json_path: Option<&Path>) -> Arc<Mutex<StepReport>> {
+    let (report_layer, step_report) = StepReportLayer::new();
+    let json_layer = json_path.map(|path| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("failed to create log file {}: {}", path.display(), e);
+            std::process::exit(1)
+        });
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(Mutex::new(file))
+    });
+    tracing_subscriber::registry()
+        .with(report_layer)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(level),
+        )
+        .with(json_layer.with_filter(level))
+        .init();
+    step_report
+}
+
+struct UniverseWindowHandler {
+    universe: Universe<Scalar>,
+    last_tick: Instant,
+    physics_hz: Scalar,  // physics steps per second of real time
+    accumulator: Scalar, // real time in seconds not yet simulated
+    variable_step: bool, // one step per frame over the measured frame time instead
+    warmup_ticks: u64,   // physics steps run before the first frame
+    rng: StdRng,
+    spawn_settings: SpawnSettings,
+    paused: bool,
+    console: Option<Console>,
+    config: Option<ConfigWatcher>, // watched for changes with --watch-config
+    render_instances: Vec<RenderInstance>, // reused across frames to avoid reallocation
+    step_report: Arc<Mutex<StepReport>>, // timings of the previous frame
+    profiler: ProfilerHud,
+    camera: Option<Camera>, // smoothed view of the universe, follows the particle bounds
+    cursor: Cursor,
+    shown_count: usize, // particle count in the window title
+    shown_time_scale: Scalar,
+    cursor_mass: Scalar,
+    save_path: Option<PathBuf>,
+    reverse_on_start: bool,
+    color_mode: ColorMode,
+    hot_temperature: Scalar, // drawn fully red in the temperature color mode
+    fingerprint_every: Option<u64>,
+    vtk_exporter: Option<VtkExporter>,
+    dump_tree_path: Option<PathBuf>, // the tree is written there after the warm-up, then it exits
+    #[cfg(feature = "net")]
+    status_server: Option<StatusServer>,
+}
+
+impl WindowHandler for UniverseWindowHandler {
+    fn on_start(&mut self, helper: &mut WindowHelper<()>, _info: WindowStartupInfo) {
+        // initialize particles, unless they were loaded from a snapshot
+        if self.universe.particles.is_empty() {
+            spawn_particles(
+                &mut self.universe.particles,
+                self.universe.num_particles,
+                &self.spawn_settings,
+                &self.universe.species,
+                &mut self.rng,
+            );
+        }
+        if self.warmup_ticks > 0 {
+            self.warm_up(helper.get_size_pixels());
+        }
+        if self.reverse_on_start {
+            self.universe.reverse_time();
+        }
+        if let Some(path) = self.dump_tree_path.take() {
+            self.dump_tree(&path);
+            std::process::exit(0)
+        }
+    }
+
+    fn on_draw(&mut self, helper: &mut WindowHelper, graphics: &mut Graphics2D) {
+        // apply console commands between steps
+        let commands: Vec<Command> = match &self.console {
+            Some(console) => console.drain().collect(),
+            None => Vec::new(),
+        };
+        for command in commands {
+            if self.apply_command(command).is_break() {
+                helper.terminate_loop();
+            }
+        }
+        self.reload_config();
+
+        self.profiler.push(*self.step_report.lock().unwrap());
+        let _frame_span = info_span!("frame", particles = self.universe.particles.len()).entered();
+        self.advance(helper.get_size_pixels(), Instant::now());
+
+        // draw graphics
+        graphics.clear_screen(Color::BLACK);
+        if self.universe.particles.is_empty() {
+            // nothing to simulate or draw until an emitter or the console adds particles
+            helper.request_redraw();
+            return;
+        }
+
+        // find bounds of the universe
+        let bounds_span = info_span!("bounds").entered();
+        let mut min_x = self.universe.particles[0].position.x;
+        let mut max_x = min_x;
+        let mut min_y = self.universe.particles[0].position.y;
+        let mut max_y = min_y;
+        let num_particles = self.universe.particles.len();
+        (1..num_particles).for_each(|i| {
+            let x = self.universe.particles[i].position.x;
+            let y = self.universe.particles[i].position.y;
+            if x < min_x {
+                min_x = x;
+            }
+            if x > max_x {
+                max_x = x;
+            }
+            if y < min_y {
+                min_y = y;
+            }
+            if y > max_y {
+                max_y = y;
+            }
+        });
+        let width = max_x - min_x;
+        let height = max_y - min_y;
+        let center_x = (min_x + max_x) / 2.0;
+        let center_y = (min_y + max_y) / 2.0;
+        bounds_span.exit();
+
+        if self.shown_count != self.universe.particles.len()
+            || self.shown_time_scale != self.universe.time_scale
+        {
+            self.shown_count = self.universe.particles.len();
+            self.shown_time_scale = self.universe.time_scale;
+            let mut title = format!("Particles ({}", self.shown_count);
+            if self.universe.culled > 0 {
+                title += &format!(", {} culled", self.universe.culled);
+            }
+            if self.shown_time_scale != 1.0 {
+                title += &format!(", {}x speed", self.shown_time_scale);
+            }
+            helper.set_title(title + ")");
+        }
+
+        // map the visible particles to screen space in parallel, then submit them serially
+        let render_prep_span = info_span!("render_prep", instances = field::Empty).entered();
+        // a periodic world is always shown as a whole, since particles jump between its edges
+        let target = match self.universe.boundary {
+            Boundary::Wrap(bounds) => Camera {
+                center: (&bounds.min + bounds.max) * 0.5,
+                width: bounds.size().x,
+                height: bounds.size().y,
+            },
+            _ => Camera {
+                center: Vector2D {
+                    x: center_x,
+                    y: center_y,
+                },
+                width,
+                height,
+            },
+        };
+        let camera = *self
+            .camera
+            .get_or_insert(target)
+            .approach(&target, CAMERA_SMOOTHING);
+        let transform = camera.screen_transform(helper.get_size_pixels());
+        let fade = match self.universe.lifetime {
+            Some(lifetime) if self.universe.fade_out => Some((&self.universe.ages[..], lifetime)),
+            _ => None,
+        };
+        let heat = (self.color_mode == ColorMode::Temperature)
+            .then_some((&self.universe.temperatures[..], self.hot_temperature));
+        prepare_render_instances(
+            &self.universe.particles,
+            &self.universe.species,
+            fade,
+            heat,
+            &transform,
+            &mut self.render_instances,
+        );
+        render_prep_span.record("instances", self.render_instances.len());
+        render_prep_span.exit();
+        let draw_span = info_span!("draw").entered();
+        for instance in &self.render_instances {
+            graphics.draw_circle(
+                (instance.position.x, instance.position.y),
+                instance.radius,
+                instance.color,
+            );
+        }
+        for attractor in &self.universe.attractors {
+            let position = transform.local_to_screen(attractor.position);
+            let radius = attractor.radius as f32;
+            if transform.is_visible(position, radius) {
+                graphics.draw_circle((position.x, position.y), radius, Color::RED);
+            }
+        }
+        for obstacle in &self.universe.obstacles {
+            draw_obstacle(obstacle, &transform, graphics);
+        }
+        if let Some(bounds) = self.universe.boundary.bounds() {
+            draw_bounds(&bounds, &transform, graphics);
+        }
+        draw_span.exit();
+
+        if self.profiler.visible {
+            draw_profiler(&self.profiler, graphics);
+        }
+
+        // Request that we draw another frame once this one has finished
+        helper.request_redraw();
+    }
+
+    fn on_mouse_move(&mut self, _helper: &mut WindowHelper<()>, position: Vec2) {
+        self.cursor.position = Some(Vector2D {
+            x: position.x,
+            y: position.y,
+        });
+    }
+
+    fn on_mouse_button_down(&mut self, helper: &mut WindowHelper<()>, button: MouseButton) {
+        if button != MouseButton::Left {
+            return;
+        }
+        if !self.cursor.pin {
+            self.cursor.pressed = true;
+            return;
+        }
+        if let (Some(position), Some(camera)) = (self.cursor.position, &self.camera) {
+            let transform = camera.screen_transform(helper.get_size_pixels());
+            let nearest = (0..self.universe.particles.len())
+                .map(|i| {
+                    let on_screen = transform.local_to_screen(self.universe.particles[i].position);
+                    (i, (on_screen - position).length_sq())
+                })
+                .filter(|(_, distance_sq)| *distance_sq <= PIN_PICK_DISTANCE * PIN_PICK_DISTANCE)
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            if let Some((index, _)) = nearest {
+                self.universe.toggle_pin(index);
+            }
+        }
+    }
+
+    fn on_mouse_button_up(&mut self, _helper: &mut WindowHelper<()>, button: MouseButton) {
+        if button == MouseButton::Left {
+            self.cursor.pressed = false;
+        }
+    }
+
+    fn on_keyboard_modifiers_changed(
+        &mut self,
+        _helper: &mut WindowHelper<()>,
+        state: ModifiersState,
+    ) {
+        self.cursor.repel = state.shift();
+        self.cursor.pin = state.ctrl();
+    }
+
+    fn on_key_down(
+        &mut self,
+        _helper: &mut WindowHelper<()>,
+        virtual_key_code: Option<VirtualKeyCode>,
+        _scancode: KeyScancode,
+    ) {
+        match virtual_key_code {
+            Some(VirtualKeyCode::F5) => self.profiler.visible = !self.profiler.visible,
+            Some(VirtualKeyCode::T) => self.universe.reverse_time(),
+            Some(VirtualKeyCode::Semicolon) => self.universe.swirl_strength -= SWIRL_STEP,
+            Some(VirtualKeyCode::Apostrophe) => self.universe.swirl_strength += SWIRL_STEP,
+            Some(VirtualKeyCode::Comma) => self.universe.frame_rotation -= FRAME_ROTATION_STEP,
+            Some(VirtualKeyCode::Period) => self.universe.frame_rotation += FRAME_ROTATION_STEP,
+            Some(VirtualKeyCode::LBracket) => {
+                self.universe.time_scale =
+                    (self.universe.time_scale / 2.0).clamp(MIN_TIME_SCALE, MAX_TIME_SCALE)
+            }
+            Some(VirtualKeyCode::RBracket) => {
+                self.universe.time_scale =
+                    (self.universe.time_scale * 2.0).clamp(MIN_TIME_SCALE, MAX_TIME_SCALE)
+            }
+            Some(VirtualKeyCode::S) => {
+                if let Some(path) = &self.save_path {
+                    self.save(path);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl UniverseWindowHandler {
+    /// Advances the physics by the scaled real time from the last frame until `now`. A time
+    /// scale of zero stops it just like pausing.
+    fn advance(&mut self, screen_size: UVec2, now: Instant) {
+        let time_scale = self.universe.time_scale;
+        let running = !self.paused && time_scale > 0.0;
+        if running && self.variable_step {
+            // a single step over the whole frame, clamped so a stall does not make particles jump
+            let elapsed_s = now.duration_since(self.last_tick).as_secs_f64() as Scalar;
+            if elapsed_s > 0.0 {
+                let elapsed_s = elapsed_s.min(MAX_VARIABLE_STEP);
+                self.tick(screen_size, elapsed_s * time_scale);
+            }
+        } else if running {
+            // as many fixed steps as fit, carrying the remainder over to the next frame
+            let step = 1.0 / self.physics_hz;
+            self.accumulator += now.duration_since(self.last_tick).as_secs_f64() as Scalar;
+            let mut steps = 0;
+            while self.accumulator >= step && steps < MAX_STEPS_PER_FRAME {
+                self.tick(screen_size, step * time_scale);
+                self.accumulator -= step;
+                steps += 1;
+            }
+            if steps == MAX_STEPS_PER_FRAME {
+                // after a stall, drop the backlog instead of falling further and further behind
+                self.accumulator %= step;
+            }
+        }
+        self.last_tick = now;
+    }
+
+    /// Advances the universe by one physics step of `elapsed_s`.
+    /// `screen_size` places the mouse attractor using the view of the previous frame.
+    fn tick(&mut self, screen_size: UVec2, elapsed_s: Scalar) {
+        // particles enter and leave the universe before the quadtree is built
+        self.emit_particles(elapsed_s);
+        self.universe.expire_particles(elapsed_s);
+        self.universe.cull_escaped();
+        // runs before merging, so a merge crossing the threshold splits in the next step
+        self.universe
+            .split_heavy_particles(self.spawn_settings.density, &mut self.rng);
+        if self.color_mode == ColorMode::Temperature {
+            self.universe.cool_particles(elapsed_s);
+        }
+        if self.universe.particles.is_empty() {
+            return;
+        }
+
+        // the mouse attractor is placed using the view of the previous frame
+        let cursor_attractor = match (self.cursor.pressed, self.cursor.position, &self.camera) {
+            (true, Some(position), Some(camera)) => {
+                let transform = camera.screen_transform(screen_size);
+                let mass = if self.cursor.repel {
+                    -self.cursor_mass
+                } else {
+                    self.cursor_mass
+                };
+                Some(Particle {
+                    position: transform.screen_to_local(position),
+                    velocity: Vector2D { x: 0.0, y: 0.0 },
+                    radius: 0.0,
+                    mass,
+                    charge: 0.0,
+                    species: 0,
+                })
+            }
+            _ => None,
+        };
+        self.universe
+            .step(elapsed_s, cursor_attractor, self.spawn_settings.density);
+
+        if let Some(every) = self.fingerprint_every {
+            if self.universe.num_steps.is_multiple_of(every) {
+                println!(
+                    "step {}: fingerprint {:016x}",
+                    self.universe.num_steps,
+                    self.universe.state_hash()
+                );
+            }
+        }
+
+        if let Some(exporter) = &self.vtk_exporter {
+            if let Err(e) = exporter.export(self.universe.num_steps, &self.universe.particles) {
+                tracing::warn!("failed to export VTK snapshot: {}", e);
+            }
+        }
+
+        #[cfg(feature = "net")]
+        if let Some(status_server) = &mut self.status_server {
+            status_server.publish(
+                self.universe.num_steps,
+                self.universe.sim_time,
+                self.universe.grav_const,
+                self.universe.last_energy,
+                Diagnostics {
+                    culled: self.universe.culled,
+                    clamped: self.universe.clamped,
+                    speed_limited: self.universe.speed_limited,
+                    neighbor_rebuilds: self.universe.neighbor_list.rebuilds(),
+                },
+                &self.universe.particles,
+            );
+        }
+    }
+
+    /// Applies the live settings of the config file if it changed. The others are only reported,
+    /// they need a restart.
+    fn reload_config(&mut self) {
+        let Some(reload) = self.config.as_mut().and_then(ConfigWatcher::poll) else {
+            return;
+        };
+        match reload {
+            Ok(reload) => {
+                for setting in reload.live {
+                    self.apply_setting(setting);
+                }
+                for setting in reload.restart_required {
+                    tracing::warn!(
+                        "{} changed in the config file, restart to apply it",
+                        setting.key()
+                    );
+                }
+            }
+            Err(e) => tracing::warn!("{}, keeping the previous settings", e),
+        }
+    }
+
+    /// Applies a setting from the config file to the running simulation
+    fn apply_setting(&mut self, setting: Setting) {
+        match setting {
+            Setting::GravConst(grav_const) => self.universe.grav_const = grav_const,
+            Setting::Damping(damping) => self.universe.damping = damping,
+            Setting::Theta(theta) => self.universe.theta = theta,
+            Setting::Softening(softening) => self.universe.softening = softening,
+            Setting::TimeScale(time_scale) => {
+                self.universe.time_scale = time_scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE)
+            }
+            Setting::SwirlStrength(strength) => self.universe.swirl_strength = strength,
+            Setting::HotTemperature(temperature) => self.hot_temperature = temperature,
+            Setting::NumParticles(_) | Setting::Seed(_) | Setting::Scenario(_) => {}
+        }
+    }
+
+    /// Applies a console command. Breaks if the simulation should quit.
+    fn apply_command(&mut self, command: Command) -> ControlFlow<()> {
+        match command {
+            Command::Pause => self.paused = true,
+            Command::Resume => self.paused = false,
+            Command::SetGravConst(grav_const) => self.universe.grav_const = grav_const,
+            Command::SetDamping(damping) => self.universe.damping = damping,
+            Command::SetLeafCapacity(capacity) => self.universe.set_leaf_capacity(capacity),
+            Command::Spawn(count) => {
+                spawn_particles(
+                    &mut self.universe.particles,
+                    count,
+                    &self.spawn_settings,
+                    &self.universe.species,
+                    &mut self.rng,
+                );
+                self.universe.integrator.reset();
+            }
+            Command::Save(path) => self.save(&path),
+            Command::Status => println!(
+                "step {}, t = {:.2}: {} particles, {} culled, {} clamped and {} speed limited in \
+                 the last step, neighbor lists rebuilt in {} of {} checks",
+                self.universe.num_steps,
+                self.universe.sim_time,
+                self.universe.particles.len(),
+                self.universe.culled,
+                self.universe.clamped,
+                self.universe.speed_limited,
+                self.universe.neighbor_list.rebuilds(),
+                self.universe.neighbor_list.checks()
+            ),
+            Command::Tree => {
+                let stats = self.universe.quadtree.stats();
+                println!(
+                    "{} elements in {} nodes, {} levels deep: {} leaves, {} of them empty, {:.1} \
+                     elements per leaf on average and at most {}",
+                    stats.elements,
+                    stats.nodes,
+                    stats.depth,
+                    stats.leaves,
+                    stats.empty_leaves,
+                    stats.mean_leaf_occupancy(),
+                    stats.max_leaf_occupancy
+                );
+                let histogram: Vec<String> = stats
+                    .leaf_depth_histogram()
+                    .iter()
+                    .map(|leaves| leaves.to_string())
+                    .collect();
+                println!("leaves per level: {}", histogram.join(" "));
+            }
+            Command::Force(index, _) | Command::Impulse(index, _)
+                if index >= self.universe.particles.len() =>
+            {
+                println!("no particle with index {}", index)
+            }
+            Command::Force(index, force) => self.universe.apply_force(index, force),
+            Command::Impulse(index, impulse) => self.universe.apply_impulse(index, impulse),
+            Command::Quit => return ControlFlow::Break(()),
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Runs the warm-up steps before the first frame, reporting the progress every tenth of them.
+    fn warm_up(&mut self, screen_size: UVec2) {
+        let step = 1.0 / self.physics_hz;
+        let report_every = (self.warmup_ticks / 10).max(1);
+        let start = Instant::now();
+        for i in 1..=self.warmup_ticks {
+            self.tick(screen_size, step);
+            if i % report_every == 0 || i == self.warmup_ticks {
+                println!("warm-up: {}/{} steps", i, self.warmup_ticks);
+            }
+        }
+        println!("warm-up took {:.2?}", start.elapsed());
+        // the frames only catch up with the time after the warm-up
+        self.last_tick = Instant::now();
+    }
+
+    /// Adds the particles that the emitters produce during one step, up to the particle limit.
+    fn emit_particles(&mut self, elapsed_s: Scalar) {
+        let limit = self.universe.max_particles.unwrap_or(usize::MAX);
+        let count = self.universe.particles.len();
+        for emitter in &mut self.universe.emitters {
+            let due = emitter.due(elapsed_s) as usize;
+            let room = limit.saturating_sub(self.universe.particles.len());
+            for _ in 0..due.min(room) {
+                let spread = emitter.spread;
+                let mut particle = new_particle(
+                    emitter.position.x,
+                    emitter.position.y,
+                    &self.spawn_settings,
+                    &self.universe.species,
+                    &mut self.rng,
+                );
+                if spread > 0.0 {
+                    particle.velocity = Vector2D {
+                        x: self.rng.gen_range(-spread..=spread),
+                        y: self.rng.gen_range(-spread..=spread),
+                    };
+                }
+                self.universe.particles.push(particle);
+            }
+        }
+        if self.universe.particles.len() > count {
+            self.universe.integrator.reset();
+        }
+    }
+
+    /// Builds the quadtree over the current particles and writes it to a text file for
+    /// inspection, exiting if that fails.
+    fn dump_tree(&mut self, path: &Path) {
+        self.universe.quadtree.rebuild(&self.universe.particles);
+        let result =
+            File::create(path).and_then(|file| self.universe.quadtree.dump(BufWriter::new(file)));
+        if let Err(e) = result {
+            eprintln!("failed to dump the quadtree to {}: {}", path.display(), e);
+            std::process::exit(1)
+        }
+        println!(
+            "dumped the quadtree of step {} to {}",
+            self.universe.num_steps,
+            path.display()
+        );
+    }
+
+    /// Writes the current state to a binary state file, logging any failure.
+    fn save(&self, path: &Path) {
+        let config = StateConfig {
+            grav_const: self.universe.grav_const,
+            num_steps: self.universe.num_steps,
+            sim_time: self.universe.sim_time,
+        };
+        match save_state(path, &config, &self.universe.particles) {
+            Ok(()) => tracing::info!("saved state to {}", path.display()),
+            Err(e) => tracing::warn!("failed to save state to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Draws the phase timings of the recent frames relative to a 60 fps frame budget.
+fn draw_profiler(profiler: &ProfilerHud, graphics: &mut Graphics2D) {
+    // tree build, forces, integration, render prep, draw
+    const PHASE_COLORS: [Color; 5] = [
+        Color::YELLOW,
+        Color::RED,
+        Color::GREEN,
+        Color::CYAN,
+        Color::MAGENTA,
+    ];
+    let budget = Duration::from_secs(1) / 60;
+    for rect in profiler.layout(Vector2D { x: 10.0, y: 10.0 }, budget) {
+        let color = rect
+            .phase
+            .map_or(Color::DARK_GRAY, |phase| PHASE_COLORS[phase]);
+        graphics.draw_rectangle(
+            Rectangle::from_tuples((rect.min.x, rect.min.y), (rect.max.x, rect.max.y)),
+            color,
+        );
+    }
+}
+
+/// How new particles are initialized
+struct SpawnSettings {
+    /// Particles are placed uniformly in the rectangle from the origin to this size
+    width: Scalar,
+    height: Scalar,
+    velocity_distribution: VelocityDistribution,
+    random_charges: bool,
+    /// Masses are drawn uniformly from this range
+    mass_min: Scalar,
+    mass_max: Scalar,
+    /// Gives heavier particles a larger radius, keeping the area proportional to the mass
+    radius_by_mass: bool,
+    /// Derives the radius from the mass, overrides `radius_by_mass`
+    density: Option<Scalar>,
+}
+
+/// Evaluates the forces with Barnes-Hut trees, rebuilding one whenever the particles moved.
+struct BarnesHut<'a, T> {
+    /// Built from the positions at the beginning of the step, or a few substeps earlier
+    tree: &'a dyn ForceTree<T>,
+    /// Rebuilt for every evaluation at moved positions
+    scratch: RefCell<&'a mut dyn ForceTree<T>>,
+    params: &'a ForceParameters<'a, T>,
+}
+
+impl<T: Float + Default + Send + Sync> ForceEvaluator<T> for BarnesHut<'_, T> {
+    fn params(&self) -> &ForceParameters<'_, T> {
+        self.params
+    }
+
+    fn accelerations(
+        &self,
+        particles: &[Particle<T>],
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize {
+        let mut tree = self.scratch.borrow_mut();
+        let span = info_span!("tree_build").entered();
+        tree.rebuild(particles);
+        span.exit();
+        tree.accelerations(particles, self.params, accelerations)
+    }
+
+    fn current_accelerations(
+        &self,
+        particles: &[Particle<T>],
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize {
+        self.tree
+            .accelerations(particles, self.params, accelerations)
+    }
+
+    fn accelerations_of(
+        &self,
+        particles: &[Particle<T>],
+        active: &[bool],
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize {
+        let mut tree = self.scratch.borrow_mut();
+        let span = info_span!("tree_build").entered();
+        tree.rebuild(particles);
+        span.exit();
+        tree.accelerations_of(particles, active, self.params, accelerations)
+    }
+}
+
+/// Decides when the force trees are rebuilt. Without a threshold they are rebuilt at every
+/// opportunity, otherwise only once some particle moved farther than the threshold times the
+/// smallest leaf since the last build. Forces from a slightly outdated tree stay within the error
+/// of the approximation: the leaves still pull with the current positions, only the summaries
+/// and bounds lag behind.
+struct RebuildPolicy<T> {
+    threshold: Option<T>,
+    /// Positions at the last build, empty if it has to be repeated regardless
+    positions: Vec<Vector2D<T>>,
+    /// Largest displacement since the last build that does not need a rebuild
+    max_displacement: T,
+}
+
+impl<T> RebuildPolicy<T> {
+    /// Makes the next check ask for a rebuild, e.g. after particles were removed or the trees
+    /// replaced
+    fn invalidate(&mut self) {
+        self.positions.clear();
+    }
+}
+
+impl RebuildPolicy<Scalar> {
+    fn new(threshold: Option<Scalar>) -> RebuildPolicy<Scalar> {
+        RebuildPolicy {
+            threshold,
+            positions: Vec::new(),
+            max_displacement: 0.0,
+        }
+    }
+
+    /// Whether the trees have to be rebuilt for the particles. Added or removed particles always
+    /// need one, also when their number stayed the same.
+    fn needs_rebuild(&self, particles: &[Particle<Scalar>]) -> bool {
+        if self.threshold.is_none() || self.positions.len() != particles.len() {
+            return true;
+        }
+        let max_sq = self.max_displacement * self.max_displacement;
+        particles
+            .iter()
+            .zip(&self.positions)
+            .any(|(particle, built)| particle.position.distance_sq(built) > max_sq)
+    }
+
+    /// Remembers the positions the trees were just built from, and the side of their smallest
+    /// leaf holding particles
+    fn record_build(&mut self, particles: &[Particle<Scalar>], smallest_leaf: Option<Scalar>) {
+        let Some(threshold) = self.threshold else {
+            return;
+        };
+        self.positions.clear();
+        self.positions
+            .extend(particles.iter().map(|particle| particle.position));
+        self.max_displacement = threshold * smallest_leaf.unwrap_or(0.0);
+    }
+}
+
+/// Scales down every velocity faster than `max_speed`, keeping its direction.
+/// Returns how many particles were slowed down.
+fn limit_speeds(particles: &mut [Particle<Scalar>], max_speed: Scalar) -> usize {
+    let mut limited = 0;
+    for particle in particles {
+        if particle.velocity.length_sq() > max_speed * max_speed {
+            particle.velocity = particle.velocity.clamp_length(max_speed);
+            limited += 1;
+        }
+    }
+    limited
+}
+
+/// An empty quadtree whose leaves hold up to `leaf_capacity` particles, filled by
+/// [`ParticleQuadTree::rebuild`].
+fn empty_quadtree(leaf_capacity: usize) -> ParticleQuadTree<Scalar> {
+    ParticleQuadTree::new(Vector2D::default(), 0.0, 0.0, leaf_capacity)
+}
+
+/// Adds the initial particles of the selected scenario.
+/// Returns the index of the central particle, if the scenario has a single one.
+fn spawn_scenario<R: Rng>(
+    particles: &mut Vec<Particle<Scalar>>,
+    args: &Args,
+    grav_const: Scalar,
+    rng: &mut R,
+) -> Option<usize> {
+    let gravity = Gravity {
+        grav_const,
+        softening: args.softening,
+        exponent: args.force_exponent,
+    };
+    let count = args.num_particles as usize;
+    match args.scenario {
+        // scattered randomly in on_start
+        Scenario::Default | Scenario::Uniform => {}
+        Scenario::Disc => {
+            let disc = Disc {
+                center: Vector2D::default(),
+                velocity: Vector2D::default(),
+                central_mass: args.disc_mass,
+                inner_radius: args.disc_inner_radius,
+                outer_radius: args.disc_outer_radius,
+                count,
+            };
+            return Some(disc.generate(&gravity, rng, particles));
+        }
+        Scenario::Ring => Ring {
+            center: Vector2D::default(),
+            radius: args.ring_radius,
+            jitter: args.ring_jitter,
+            speed: args.ring_speed,
+            count,
+        }
+        .generate(rng, particles),
+        Scenario::Plummer => Plummer {
+            center: Vector2D::default(),
+            scale_radius: args.plummer_radius,
+            total_mass: args.plummer_mass,
+            count,
+        }
+        .generate(&gravity, rng, particles),
+        Scenario::TwoGalaxies => two_galaxies(
+            &gravity,
+            args.galaxy_separation,
+            args.galaxy_mass,
+            count,
+            args.galaxy_split,
+            rng,
+            particles,
+        ),
+    }
+    None
+}
+
+/// Adds the given number of particles at random positions.
+fn spawn_particles<R: Rng>(
+    particles: &mut Vec<Particle<Scalar>>,
+    count: u32,
+    settings: &SpawnSettings,
+    species: &[SpeciesConfig],
+    rng: &mut R,
+) {
+    let first_new = particles.len();
+    (0..count).for_each(|_| {
+        let x = rng.gen_range(0.0..settings.width);
+        let y = rng.gen_range(0.0..settings.height);
+        particles.push(new_particle(x, y, settings, species, rng));
+    });
+    settings
+        .velocity_distribution
+        .assign(rng, &mut particles[first_new..]);
+}
+
+/// Creates a resting particle at the given position with mass, charge and radius drawn
+/// according to the settings. With species, one is picked at random and provides the mass
+/// and radius.
+fn new_particle<R: Rng>(
+    x: Scalar,
+    y: Scalar,
+    settings: &SpawnSettings,
+    species: &[SpeciesConfig],
+    rng: &mut R,
+) -> Particle<Scalar> {
+    let species_index = if species.is_empty() {
+        0
+    } else {
+        SpeciesConfig::choose(species, rng.gen())
+    };
+    let species_config = species.get(species_index as usize);
+    let mass = if let Some(species_config) = species_config {
+        species_config.mass
+    } else if settings.mass_max > settings.mass_min {
+        rng.gen_range(settings.mass_min..=settings.mass_max)
+    } else {
+        settings.mass_min
+    };
+    let charge = if !settings.random_charges {
+        0.0
+    } else if rng.gen() {
+        1.0
+    } else {
+        -1.0
+    };
+    let mut particle = create_particle(x, y, mass, charge);
+    particle.species = species_index;
+    if let Some(density) = settings.density {
+        particle.radius = Particle::radius_for_mass(mass, density);
+    } else if settings.radius_by_mass {
+        particle.radius = mass.sqrt();
+    } else if let Some(species_config) = species_config {
+        particle.radius = species_config.radius;
+    }
+    particle
+}
+
+/// Adds a square grid of particles where each particle is connected to its horizontal,
+/// vertical and diagonal neighbors by springs, forming a simple soft body.
+fn spawn_spring_grid(
+    particles: &mut Vec<Particle<Scalar>>,
+    springs: &mut Vec<Spring<Scalar>>,
+    size: u32,
+) {
+    const SPACING: Scalar = 5.0;
+    let size = size as usize;
+    let first = particles.len();
+    for row in 0..size {
+        for column in 0..size {
+            let x = 250.0 + (column as Scalar - size as Scalar / 2.0) * SPACING;
+            let y = 50.0 + (row as Scalar - size as Scalar / 2.0) * SPACING;
+            particles.push(create_particle(x, y, 1.0, 0.0));
+        }
+    }
+
+    let index = |row: usize, column: usize| first + row * size + column;
+    let mut connect = |a: usize, b: usize, rest_length: Scalar| {
+        springs.push(Spring {
+            a,
+            b,
+            rest_length,
+            stiffness: 50.0,
+            damping: 1.0,
+        })
+    };
+    for row in 0..size {
+        for column in 0..size {
+            if column + 1 < size {
+                connect(index(row, column), index(row, column + 1), SPACING);
+            }
+            if row + 1 < size {
+                connect(index(row, column), index(row + 1, column), SPACING);
+            }
+            if row + 1 < size && column + 1 < size {
+                let diagonal = SPACING * consts::SQRT_2;
+                connect(index(row, column), index(row + 1, column + 1), diagonal);
+                connect(index(row, column + 1), index(row + 1, column), diagonal);
+            }
+        }
+    }
+}
+
+/// Fills the left part of the SPH tank with a block of fluid that collapses once the
+/// simulation starts.
+fn spawn_dam_break(particles: &mut Vec<Particle<Scalar>>, sph: &SphParameters) {
+    // half the smoothing length gives every particle a couple of neighbors in each direction
+    let spacing = sph.smoothing_length / 2.0;
+    let width = (sph.tank_max.x - sph.tank_min.x) * 0.3;
+    let height = (sph.tank_max.y - sph.tank_min.y) * 0.8;
+    let columns = (width / spacing) as u32;
+    let rows = (height / spacing) as u32;
+    for row in 0..rows {
+        for column in 0..columns {
+            let x = sph.tank_min.x + (column as Scalar + 0.5) * spacing;
+            let y = sph.tank_max.y - (row as Scalar + 0.5) * spacing;
+            particles.push(create_particle(x, y, 1.0, 0.0));
+        }
+    }
+}
+
+/// Draws the outline of an obstacle as a closed polygon.
+fn draw_obstacle(
+    obstacle: &Obstacle<Scalar>,
+    transform: &ScreenTransform,
+    graphics: &mut Graphics2D,
+) {
+    const SEGMENTS: usize = 48;
+    let point = |i: usize| {
+        let angle = i as Scalar / SEGMENTS as Scalar * consts::TAU;
+        transform.local_to_screen(Vector2D {
+            x: obstacle.center.x + obstacle.radius * angle.cos(),
+            y: obstacle.center.y + obstacle.radius * angle.sin(),
+        })
+    };
+    for i in 0..SEGMENTS {
+        let (start, end) = (point(i), point(i + 1));
+        graphics.draw_line((start.x, start.y), (end.x, end.y), 1.0, Color::LIGHT_GRAY);
+    }
+}
+
+/// Draws the outline of the world rectangle.
+fn draw_bounds(bounds: &Bounds, transform: &ScreenTransform, graphics: &mut Graphics2D) {
+    let corners = [
+        bounds.min,
+        Vector2D {
+            x: bounds.max.x,
+            y: bounds.min.y,
+        },
+        bounds.max,
+        Vector2D {
+            x: bounds.min.x,
+            y: bounds.max.y,
+        },
+    ]
+    .map(|corner| transform.local_to_screen(corner));
+    for i in 0..corners.len() {
+        let (start, end) = (corners[i], corners[(i + 1) % corners.len()]);
+        graphics.draw_line((start.x, start.y), (end.x, end.y), 1.0, Color::LIGHT_GRAY);
+    }
+}
+
+/// A particle mapped to screen space, ready to be drawn
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct RenderInstance {
+    position: Vector2D<f32>,
+    radius: f32,
+    color: Color,
+}
+
+/// Maps every particle that is visible on screen to a render instance.
+/// With ages and a lifetime, particles fade out during their last second.
+/// With temperatures, particles are colored by temperature relative to the hot temperature
+/// instead of by species.
+/// The buffer is cleared first, but keeps its capacity from previous frames.
+fn prepare_render_instances(
+    particles: &[Particle<Scalar>],
+    species: &[SpeciesConfig],
+    fade: Option<(&[Scalar], Scalar)>,
+    heat: Option<(&[Scalar], Scalar)>,
+    transform: &ScreenTransform,
+    instances: &mut Vec<RenderInstance>,
+) {
+    instances.clear();
+    #[cfg(not(feature = "parallel"))]
+    instances.extend(
+        particles.iter().enumerate().filter_map(|(i, particle)| {
+            render_instance(i, particle, species, fade, heat, transform)
+        }),
+    );
+    #[cfg(feature = "parallel")]
+    instances.par_extend(
+        particles
+            .par_iter()
+            .enumerate()
+            .filter_map(|(i, particle)| {
+                render_instance(i, particle, species, fade, heat, transform)
+            }),
+    );
+}
+
+/// Maps the particle at `index` to a render instance, `None` if it is not visible on screen.
+fn render_instance(
+    index: usize,
+    particle: &Particle<Scalar>,
+    species: &[SpeciesConfig],
+    fade: Option<(&[Scalar], Scalar)>,
+    heat: Option<(&[Scalar], Scalar)>,
+    transform: &ScreenTransform,
+) -> Option<RenderInstance> {
+    let position = transform.local_to_screen(particle.position);
+    let radius = particle.radius as f32;
+    if !transform.is_visible(position, radius) {
+        return None;
+    }
+    let (r, g, b) = match heat {
+        Some((temperatures, hot)) => {
+            temperature_color((temperatures.get(index).copied().unwrap_or(0.0) / hot) as f32)
+        }
+        None => species
+            .get(particle.species as usize)
+            .map_or((1.0, 1.0, 1.0), |species| species.color),
+    };
+    let opacity = fade.map_or(1.0, |(ages, lifetime)| {
+        (lifetime - ages.get(index).copied().unwrap_or(0.0)).clamp(0.0, 1.0) as f32
+    });
+    Some(RenderInstance {
+        position,
+        radius,
+        color: Color::from_rgba(r, g, b, opacity),
+    })
+}
+
+/// Maps a temperature relative to the hot temperature to a color, blue when cold, white
+/// halfway and red at or above the hot temperature.
+fn temperature_color(relative: f32) -> (f32, f32, f32) {
+    let relative = relative.clamp(0.0, 1.0);
+    if relative < 0.5 {
+        let white = relative * 2.0;
+        (white, white, 1.0)
+    } else {
+        let white = 2.0 - relative * 2.0;
+        (1.0, white, white)
+    }
+}
+
+/// State of the mouse, which creates a temporary attractor while the left button is held
+#[derive(Default)]
+struct Cursor {
+    position: Option<Vector2D<f32>>, // in screen coordinates
+    pressed: bool,
+    repel: bool, // shift is held, so the attractor pushes particles away instead
+    pin: bool,   // ctrl is held, so a click pins or releases the particle under the cursor
+}
+
+/// Farthest distance in pixels from the cursor at which a ctrl-click picks a particle
+const PIN_PICK_DISTANCE: f32 = 10.0;
+
+/// Change of the swirl strength per key press
+const SWIRL_STEP: Scalar = 5.0;
+
+/// Change of the angular velocity of the reference frame per key press, in radians per second
+const FRAME_ROTATION_STEP: Scalar = 0.01;
+
+/// Most physics steps run in one frame to catch up with real time
+const MAX_STEPS_PER_FRAME: u32 = 8;
+
+/// Most bins of the block timesteps, the finest step divides a step into 2^16
+const MAX_BLOCK_LEVELS: u32 = 16;
+
+/// Longest step in seconds with a variable timestep
+const MAX_VARIABLE_STEP: Scalar = 0.1;
+
+/// Range of the time scale reachable with the keyboard, a time scale of zero is only set from the
+/// command line
+const MIN_TIME_SCALE: Scalar = 1.0 / 64.0;
+const MAX_TIME_SCALE: Scalar = 64.0;
+
+/// Fraction of the remaining distance to the particle bounds that the camera covers each frame
+const CAMERA_SMOOTHING: Scalar = 0.1;
+
+/// The region of the universe shown on screen
+#[derive(Copy, Clone)]
+struct Camera {
+    center: Vector2D<Scalar>,
+    width: Scalar,
+    height: Scalar,
+}
+
+impl Camera {
+    fn screen_transform(&self, screen_size: UVec2) -> ScreenTransform {
+        ScreenTransform {
+            univ_width: self.width,
+            univ_height: self.height,
+            univ_center: self.center,
+            screen_width: screen_size.x as f32,
+            screen_height: screen_size.y as f32,
+        }
+    }
+
+    /// Moves the camera part of the way towards the target, so that quickly changing bounds
+    /// (e.g. an expanding universe) do not make the view jump from frame to frame.
+    fn approach(&mut self, target: &Camera, rate: Scalar) -> &mut Camera {
+        self.center = &self.center + (target.center - self.center) * rate;
+        self.width += (target.width - self.width) * rate;
+        self.height += (target.height - self.height) * rate;
+        self
+    }
+}
+
+/// Transformation from universe coordinates to screen coordinates, which are always `f32`
+struct ScreenTransform {
+    univ_width: Scalar,
+    univ_height: Scalar,
+    univ_center: Vector2D<Scalar>,
+    screen_width: f32,
+    screen_height: f32,
+}
+
+impl ScreenTransform {
+    /// Pixels per universe unit, the same along both axes so that shapes keep their aspect
+    /// ratio. The universe is fitted into the screen along its tighter axis and the other one is
+    /// letterboxed. An axis without extent (all particles on a line) does not constrain the
+    /// scale, and without any extent (a single particle) one unit maps to one pixel.
+    fn scale(&self) -> Scalar {
+        let fit = |screen: f32, univ: Scalar| (univ > 0.0).then(|| screen as Scalar / univ);
+        match (
+            fit(self.screen_width, self.univ_width),
+            fit(self.screen_height, self.univ_height),
+        ) {
+            (Some(x), Some(y)) => x.min(y),
+            (Some(scale), None) | (None, Some(scale)) => scale,
+            (None, None) => 1.0,
+        }
+    }
+
+    /// The inverse of `local_to_screen`.
+    fn screen_to_local(&self, p: Vector2D<f32>) -> Vector2D<Scalar> {
+        let scale = self.scale();
+        Vector2D {
+            x: (p.x - self.screen_width / 2.0) as Scalar / scale + self.univ_center.x,
+            y: (p.y - self.screen_height / 2.0) as Scalar / scale + self.univ_center.y,
+        }
+    }
+
+    /// Maps the universe center to the screen center.
+    fn local_to_screen(&self, p: Vector2D<Scalar>) -> Vector2D<f32> {
+        let scale = self.scale();
+        Vector2D {
+            x: ((p.x - self.univ_center.x) * scale) as f32 + self.screen_width / 2.0,
+            y: ((p.y - self.univ_center.y) * scale) as f32 + self.screen_height / 2.0,
+        }
+    }
+
+    /// Checks whether a circle at the given screen position overlaps the viewport.
+    fn is_visible(&self, screen_pos: Vector2D<f32>, radius: f32) -> bool {
+        screen_pos.x + radius >= 0.0
+            && screen_pos.x - radius <= self.screen_width
+            && screen_pos.y + radius >= 0.0
+            && screen_pos.y - radius <= self.screen_height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// A universe holding the given particles, with the defaults of the command line except for
+    /// a softening of 1
+    fn universe(particles: Vec<Particle<Scalar>>) -> Universe<Scalar> {
+        Universe {
+            particles,
+            integrator: Box::<EulerIntegrator<Scalar>>::default(),
+            quadtree: empty_quadtree(100),
+            scratch_quadtree: empty_quadtree(100),
+            tree_backend: TreeBackend::Pointer,
+            morton_tree: MortonTree::new(100),
+            scratch_morton_tree: MortonTree::new(100),
+            grav_const: 10.0,
+            force_exponent: 1.0,
+            solid_interior: false,
+            softening: 1.0,
+            theta: 0.7,
+            coulomb_const: 0.0,
+            repulsion: None,
+            damping: 0.0,
+            uniform_field: Vector2D::default(),
+            swirl_strength: 0.0,
+            swirl_center: Vector2D::default(),
+            frame_rotation: 0.0,
+            frame_center: Vector2D::default(),
+            attractors: Vec::new(),
+            obstacles: Vec::new(),
+            springs: Vec::new(),
+            emitters: Vec::new(),
+            max_particles: None,
+            lifetime: None,
+            ages: Vec::new(),
+            fade_out: false,
+            sph: None,
+            collisions: false,
+            restitution: 1.0,
+            friction: 0.0,
+            merge_on_collision: false,
+            boundary: Boundary::Open,
+            culled: 0,
+            max_acceleration: None,
+            max_speed: None,
+            clamped: 0,
+            speed_limited: 0,
+            substeps: 1,
+            rebuild_every: 1,
+            rebuild_policy: RebuildPolicy::new(None),
+            neighbor_list: NeighborList::new(0.0),
+            time_scale: 1.0,
+            energy_every: None,
+            last_energy: None,
+            fission_threshold: None,
+            species: Vec::new(),
+            forces: Vec::new(),
+            pinned: Vec::new(),
+            temperatures: Vec::new(),
+            cooling_time: 2.0,
+            num_particles: 0,
+            num_steps: 0,
+            sim_time: 0.0,
+        }
+    }
+
+    /// Particles at rest of mass 1 in a 100 by 100 square
+    fn spawn_settings() -> SpawnSettings {
+        SpawnSettings {
+            width: 100.0,
+            height: 100.0,
+            velocity_distribution: VelocityDistribution::Zero,
+            random_charges: false,
+            mass_min: 1.0,
+            mass_max: 1.0,
+            radius_by_mass: false,
+            density: None,
+        }
+    }
+
+    /// A window handler around the universe, spawning particles with `spawn_settings`
+    fn handler(universe: Universe<Scalar>) -> UniverseWindowHandler {
+        UniverseWindowHandler {
+            universe,
+            last_tick: Instant::now(),
+            physics_hz: 60.0,
+            accumulator: 0.0,
+            variable_step: false,
+            warmup_ticks: 0,
+            rng: StdRng::seed_from_u64(0),
+            spawn_settings: spawn_settings(),
+            paused: false,
+            console: None,
+            config: None,
+            render_instances: Vec::new(),
+            step_report: Arc::new(Mutex::new(StepReport::default())),
+            profiler: ProfilerHud::new(),
+            camera: None,
+            cursor: Cursor::default(),
+            shown_count: 0,
+            shown_time_scale: 1.0,
+            cursor_mass: 1000.0,
+            save_path: None,
+            reverse_on_start: false,
+            color_mode: ColorMode::Species,
+            hot_temperature: 10.0,
+            fingerprint_every: None,
+            vtk_exporter: None,
+            dump_tree_path: None,
+            #[cfg(feature = "net")]
+            status_server: None,
+        }
+    }
+
+    #[test]
+    fn render_instances_skip_particles_outside_the_view() {
+        let camera = Camera {
+            center: Vector2D { x: 0.0, y: 0.0 },
+            width: 100.0,
+            height: 100.0,
+        };
+        let transform = camera.screen_transform(UVec2::new(200, 200));
+        let particles = [
+            create_particle(0.0, 0.0, 1.0, 0.0),
+            create_particle(25.0, -50.0, 1.0, 0.0),
+            create_particle(1000.0, 0.0, 1.0, 0.0),
+        ];
+        let mut instances = vec![RenderInstance {
+            position: Vector2D { x: 0.0, y: 0.0 },
+            radius: 0.0,
+            color: Color::BLACK,
+        }];
+        prepare_render_instances(&particles, &[], None, None, &transform, &mut instances);
+        let positions: Vec<_> = instances.iter().map(|instance| instance.position).collect();
+        assert_eq!(
+            positions,
+            [
+                Vector2D { x: 100.0, y: 100.0 },
+                Vector2D { x: 150.0, y: 0.0 }
+            ]
+        );
+        assert!(instances.iter().all(|instance| instance.radius == 1.0));
+    }
+
+    /// Particles spread beyond a 100 by 100 view, with random species, ages and temperatures
+    fn render_scene(
+        count: usize,
+    ) -> (
+        Vec<Particle<Scalar>>,
+        Vec<Scalar>,
+        Vec<Scalar>,
+        Vec<SpeciesConfig>,
+    ) {
+        let mut rng = StdRng::seed_from_u64(210);
+        let species: Vec<SpeciesConfig> = ["gas:1", "stars:1"]
+            .iter()
+            .map(|species| species.parse().unwrap())
+            .collect();
+        let particles = (0..count)
+            .map(|_| {
+                let mut particle = create_particle(
+                    rng.gen_range(-80.0..80.0),
+                    rng.gen_range(-80.0..80.0),
+                    1.0,
+                    0.0,
+                );
+                particle.species = rng.gen_range(0..2);
+                particle
+            })
+            .collect();
+        let ages = (0..count).map(|_| rng.gen_range(0.0..2.0)).collect();
+        let temperatures = (0..count).map(|_| rng.gen_range(0.0..100.0)).collect();
+        (particles, ages, temperatures, species)
+    }
+
+    #[test]
+    fn parallel_render_instances_match_serial_ones() {
+        let (particles, ages, temperatures, species) = render_scene(10_000);
+        let camera = Camera {
+            center: Vector2D { x: 0.0, y: 0.0 },
+            width: 100.0,
+            height: 100.0,
+        };
+        let transform = camera.screen_transform(UVec2::new(300, 200));
+        for (fade, heat) in [
+            (None, None),
+            (Some((&ages[..], 1.5)), None),
+            (None, Some((&temperatures[..], 50.0))),
+        ] {
+            let mut instances = Vec::new();
+            prepare_render_instances(&particles, &species, fade, heat, &transform, &mut instances);
+            let serial: Vec<_> = particles
+                .iter()
+                .enumerate()
+                .filter_map(|(i, particle)| {
+                    render_instance(i, particle, &species, fade, heat, &transform)
+                })
+                .collect();
+            assert!(!serial.is_empty() && serial.len() < particles.len());
+            assert_eq!(instances, serial);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn render_prep_scales_with_threads() {
+        let (particles, ages, _, species) = render_scene(500_000);
+        let camera = Camera {
+            center: Vector2D { x: 0.0, y: 0.0 },
+            width: 100.0,
+            height: 100.0,
+        };
+        let transform = camera.screen_transform(UVec2::new(1600, 900));
+        let prep_time = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut instances = Vec::new();
+            pool.install(|| {
+                let start = Instant::now();
+                for _ in 0..10 {
+                    prepare_render_instances(
+                        &particles,
+                        &species,
+                        Some((&ages, 1.5)),
+                        None,
+                        &transform,
+                        &mut instances,
+                    );
+                }
+                start.elapsed().as_secs_f64() / 10.0
+            })
+        };
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let one = prep_time(1);
+        let mut threads = 2;
+        while threads <= cores {
+            let time = prep_time(threads);
+            println!(
+                "{} threads: {:.2?} ms per frame, {:.2}x the speed of 1 thread ({:.2?} ms)",
+                threads,
+                time * 1e3,
+                one / time,
+                one * 1e3
+            );
+            assert!(time < one, "{} threads are not faster than 1", threads);
+            threads *= 2;
+        }
+    }
+
+    #[test]
+    fn commands_change_the_universe() {
+        let mut handler = handler(universe(vec![create_particle(0.0, 0.0, 2.0, 0.0)]));
+        let commands = [
+            Command::Pause,
+            Command::SetGravConst(20.0),
+            Command::SetDamping(0.5),
+            Command::Spawn(3),
+            Command::Impulse(0, Vector2D { x: 4.0, y: 0.0 }),
+            Command::Force(1, Vector2D { x: 0.0, y: 1.0 }),
+            // out of range, only reported
+            Command::Force(10, Vector2D { x: 1.0, y: 1.0 }),
+        ];
+        for command in commands {
+            assert!(handler.apply_command(command).is_continue());
+        }
+        assert!(handler.paused);
+        assert_eq!(handler.universe.grav_const, 20.0);
+        assert_eq!(handler.universe.damping, 0.5);
+        assert_eq!(handler.universe.particles.len(), 4);
+        assert_eq!(
+            handler.universe.particles[0].velocity,
+            Vector2D { x: 2.0, y: 0.0 }
+        );
+        assert_eq!(handler.universe.forces[1], Vector2D { x: 0.0, y: 1.0 });
+
+        assert!(handler.apply_command(Command::Resume).is_continue());
+        assert!(!handler.paused);
+        assert!(handler.apply_command(Command::Quit).is_break());
+    }
+
+    #[test]
+    fn leaf_capacities_are_auto_or_positive() {
+        assert_eq!("auto".parse(), Ok(LeafCapacity::Auto));
+        assert_eq!("1".parse(), Ok(LeafCapacity::Fixed(1)));
+        assert_eq!("10000".parse(), Ok(LeafCapacity::Fixed(10_000)));
+        for invalid in ["0", "-4", "", "Auto", "eight", "8.5"] {
+            assert!(invalid.parse::<LeafCapacity>().is_err(), "{}", invalid);
+        }
+        assert_eq!(LeafCapacity::Fixed(3).resolve(100_000), 3);
+        assert_eq!(LeafCapacity::Auto.resolve(500), 8);
+        assert_eq!(LeafCapacity::Auto.resolve(100_000), 32);
+    }
+
+    #[test]
+    fn help_lists_the_keys() {
+        let help = Args::command().render_help().to_string();
+        assert!(help.contains("[ / ]   halve / double the time scale"));
+        assert!(help.contains("; / '   weaken / strengthen the swirl"));
+    }
+
+    #[test]
+    fn config_settings_change_the_universe_or_the_arguments() {
+        let mut handler = handler(universe(Vec::new()));
+        for setting in [
+            Setting::Theta(0.3),
+            Setting::Softening(2.0),
+            Setting::TimeScale(1e9),
+            Setting::HotTemperature(5.0),
+            // needs a restart, ignored
+            Setting::NumParticles(7),
+        ] {
+            handler.apply_setting(setting);
+        }
+        assert_eq!(handler.universe.theta, 0.3);
+        assert_eq!(handler.universe.softening, 2.0);
+        assert_eq!(handler.universe.time_scale, MAX_TIME_SCALE);
+        assert_eq!(handler.hot_temperature, 5.0);
+        assert_eq!(handler.universe.num_particles, 0);
+
+        let mut args = Args::parse_from(["gravity", "--num-particles", "10"]);
+        args.apply_setting(&Setting::NumParticles(7)).unwrap();
+        args.apply_setting(&Setting::Scenario(String::from("two-galaxies")))
+            .unwrap();
+        assert_eq!(args.num_particles, 7);
+        assert_eq!(args.scenario, Scenario::TwoGalaxies);
+        assert!(args
+            .apply_setting(&Setting::Scenario(String::from("spiral")))
+            .is_err());
+    }
+
+    #[test]
+    fn scripted_console_session() {
+        let script =
+            "pause\nset grav_const 20\n\nbogus\nspawn 5\nset leaf_capacity 2\nresume\nquit\n";
+        let console = Console::from_reader(std::io::Cursor::new(script));
+        let mut commands = Vec::new();
+        let start = Instant::now();
+        while commands.len() < 6 {
+            assert!(start.elapsed() < Duration::from_secs(5), "{:?}", commands);
+            commands.extend(console.drain());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut handler = handler(universe(vec![create_particle(0.0, 0.0, 1.0, 0.0)]));
+        let mut quit = false;
+        for command in commands {
+            quit |= handler.apply_command(command).is_break();
+        }
+        assert!(quit);
+        assert!(!handler.paused);
+        assert_eq!(handler.universe.grav_const, 20.0);
+        assert_eq!(handler.universe.particles.len(), 6);
+        handler.universe.step(0.01, None, None);
+        assert_eq!(handler.universe.quadtree.stats().max_leaf_occupancy, 2);
+    }
+
+    /// Name of every new span together with the name of its parent
+    type SpanLog = Arc<Mutex<Vec<(&'static str, Option<&'static str>)>>>;
+
+    struct SpanRecorder(SpanLog);
+
+    impl<S> tracing_subscriber::Layer<S> for SpanRecorder
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            _attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|parent| parent.name());
+            self.0.lock().unwrap().push((span.name(), parent));
+        }
+    }
+
+    #[test]
+    fn step_phases_are_spans_inside_the_frame() {
+        let spans = SpanLog::default();
+        let subscriber = tracing_subscriber::registry().with(SpanRecorder(Arc::clone(&spans)));
+        let mut universe = universe(vec![
+            create_particle(0.0, 0.0, 1.0, 0.0),
+            create_particle(10.0, 0.0, 1.0, 0.0),
+        ]);
+        universe.collisions = true;
+        tracing::subscriber::with_default(subscriber, || {
+            let _frame = info_span!("frame").entered();
+            universe.step(1.0 / 60.0, None, None);
+        });
+
+        let frame = Some("frame");
+        let expected = [
+            ("frame", None),
+            ("tree_build", frame),
+            ("forces", frame),
+            // the kick and the drift
+            ("integration", frame),
+            ("integration", frame),
+            ("collisions", frame),
+        ];
+        assert_eq!(*spans.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn solar_units_give_a_one_year_orbit_at_one_au() {
+        // a light planet around an immovable sun of one solar mass
+        let grav_const = Units::SOLAR.grav_const() as Scalar;
+        let mut planet = create_particle(1.0, 0.0, 1e-6, 0.0);
+        planet.velocity = Vector2D {
+            x: 0.0,
+            y: grav_const.sqrt(),
+        };
+        planet.radius = 1e-3;
+        let mut universe = universe(vec![planet]);
+        universe.attractors = vec![create_particle(0.0, 0.0, 1.0, 0.0)];
+        universe.grav_const = grav_const;
+        universe.force_exponent = 2.0;
+        universe.softening = 0.0;
+        universe.integrator = Box::<LeapfrogIntegrator<Scalar>>::default();
+
+        // dt in years
+        let steps = 2000;
+        for step in 1..=steps {
+            universe.step(1.0 / steps as Scalar, None, None);
+            let position = universe.particles[0].position;
+            if step == steps / 2 {
+                assert!((position.x + 1.0).abs() < 1e-2, "{:?}", position);
+                assert!(position.y.abs() < 1e-2, "{:?}", position);
+            }
+        }
+        let position = universe.particles[0].position;
+        assert!((position.x - 1.0).abs() < 1e-2, "{:?}", position);
+        assert!(position.y.abs() < 1e-2, "{:?}", position);
+    }
+
+    /// Fingerprints after each of the given number of steps of a seeded cloud of `count`
+    /// colliding particles
+    fn fingerprints(seed: u64, count: usize, steps: usize) -> Vec<u64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let particles = (0..count)
+            .map(|_| {
+                let mut particle = create_particle(
+                    rng.gen_range(-100.0..100.0),
+                    rng.gen_range(-100.0..100.0),
+                    rng.gen_range(0.5..2.0),
+                    0.0,
+                );
+                particle.velocity = Vector2D {
+                    x: rng.gen_range(-1.0..1.0),
+                    y: rng.gen_range(-1.0..1.0),
+                };
+                particle
+            })
+            .collect();
+        let mut universe = universe(particles);
+        universe.collisions = true;
+        (0..steps)
+            .map(|_| {
+                universe.step(0.01, None, None);
+                universe.state_hash()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fingerprints_repeat_between_runs() {
+        assert_eq!(fingerprints(221, 2000, 10), fingerprints(221, 2000, 10));
+    }
+
+    #[test]
+    fn state_hashes_follow_the_seed() {
+        let first = fingerprints(303, 200, 100);
+        assert_eq!(first, fingerprints(303, 200, 100));
+        let other = fingerprints(304, 200, 100);
+        assert!(first.iter().zip(&other).all(|(a, b)| a != b));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn fingerprints_do_not_depend_on_the_number_of_threads() {
+        let run_on = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| fingerprints(221, 2000, 10))
+        };
+        assert_eq!(run_on(1), run_on(4));
+    }
+
+    #[test]
+    fn merging_removes_the_absorbed_particles() {
+        let mut particles = vec![
+            create_particle(0.0, 0.0, 1.0, 0.0),
+            create_particle(1.0, 0.0, 1.0, 0.0),
+            create_particle(50.0, 0.0, 1.0, 0.0),
+        ];
+        particles[1].velocity = Vector2D { x: 2.0, y: 0.0 };
+        let mut universe = universe(particles);
+        universe.grav_const = 0.0;
+        universe.merge_on_collision = true;
+        universe.step(0.01, None, None);
+        assert_eq!(universe.particles.len(), 2);
+        assert_eq!(universe.particles[0].mass, 2.0);
+        assert_eq!(universe.particles[0].velocity, Vector2D { x: 1.0, y: 0.0 });
+    }
+
+    #[test]
+    fn repulsion_balances_gravity_at_a_stable_separation() {
+        let mut universe = universe(vec![
+            create_particle(-1.5, 0.0, 1.0, 0.0),
+            create_particle(1.5, 0.0, 1.0, 0.0),
+        ]);
+        universe.softening = 0.0;
+        universe.damping = 2.0;
+        universe.repulsion = Some(Repulsion {
+            strength: 80.0,
+            exponent: 4,
+            cutoff: 5.0,
+        });
+        for _ in 0..3000 {
+            universe.step(0.01, None, None);
+        }
+        // G m / r = A / (m r^4) at r^3 = A / (G m^2) = 8
+        let separation = (universe.particles[1].position - universe.particles[0].position).length();
+        assert!((separation - 2.0).abs() < 1e-3, "separation {}", separation);
+    }
+
+    #[test]
+    fn cached_neighbor_lists_give_the_same_trajectories_as_fresh_ones() {
+        let mut rng = StdRng::seed_from_u64(209);
+        let particles: Vec<Particle<Scalar>> = (0..200)
+            .map(|_| {
+                let mut particle = create_particle(
+                    rng.gen_range(-20.0..20.0),
+                    rng.gen_range(-20.0..20.0),
+                    1.0,
+                    0.0,
+                );
+                particle.velocity = Vector2D {
+                    x: rng.gen_range(-1.0..1.0),
+                    y: rng.gen_range(-1.0..1.0),
+                };
+                particle
+            })
+            .collect();
+        let run = |skin: Scalar| {
+            let mut universe = universe(particles.clone());
+            universe.grav_const = 0.0;
+            universe.repulsion = Some(Repulsion {
+                strength: 5.0,
+                exponent: 2,
+                cutoff: 2.0,
+            });
+            universe.neighbor_list = NeighborList::new(skin);
+            for _ in 0..200 {
+                universe.step(0.005, None, None);
+            }
+            universe
+        };
+        // pairs listed only because of the skin push with exactly zero, so the sums match
+        let fresh = run(0.0);
+        let cached = run(1.0);
+        assert_eq!(cached.state_hash(), fresh.state_hash());
+        assert_eq!(fresh.neighbor_list.rebuilds(), 200);
+        assert!(
+            cached.neighbor_list.rebuilds() < 50,
+            "{} rebuilds",
+            cached.neighbor_list.rebuilds()
+        );
+    }
+
+    #[test]
+    fn damping_decays_the_velocity_exponentially() {
+        let mut particle = create_particle(0.0, 0.0, 1.0, 0.0);
+        particle.velocity = Vector2D { x: 10.0, y: 0.0 };
+        let mut universe = universe(vec![particle]);
+        universe.damping = 0.5;
+        // v(t) = v0 exp(-damping t), so the velocity drops by e within 1 / damping = 2 seconds
+        for expected in [10.0 / consts::E, 10.0 / consts::E / consts::E] {
+            for _ in 0..2000 {
+                universe.step(0.001, None, None);
+            }
+            let speed = universe.particles[0].velocity.x;
+            assert!((speed / expected - 1.0).abs() < 1e-3, "speed {}", speed);
+        }
+    }
+
+    #[test]
+    fn a_uniform_field_gives_a_parabola() {
+        let mut particle = create_particle(1.0, 2.0, 1.0, 0.0);
+        particle.velocity = Vector2D { x: 3.0, y: 5.0 };
+        let mut universe = universe(vec![particle]);
+        universe.integrator = Box::<LeapfrogIntegrator<Scalar>>::default();
+        universe.uniform_field = Vector2D { x: 0.0, y: -9.75 };
+        for step in 1..=100 {
+            universe.step(0.01, None, None);
+            // x = x0 + vx t, y = y0 + vy t + g t^2 / 2
+            let t = step as Scalar * 0.01;
+            let expected = Vector2D {
+                x: 1.0 + 3.0 * t,
+                y: 2.0 + 5.0 * t - 9.75 * t * t / 2.0,
+            };
+            let error = (universe.particles[0].position - expected).length();
+            assert!(error < 1e-4, "error {} at {}", error, t);
+        }
+    }
+
+    #[test]
+    fn close_passes_by_an_attractor_stay_finite() {
+        let mut particle = create_particle(-5.0, 0.0, 1.0, 0.0);
+        particle.velocity = Vector2D { x: 10.0, y: 0.0 };
+        let mut universe = universe(vec![particle]);
+        universe.integrator = Box::<LeapfrogIntegrator<Scalar>>::default();
+        let black_hole = create_particle(0.0, 0.0, 10_000.0, 0.0);
+        universe.attractors.push(black_hole);
+        // falls straight through the center of the attractor and back, with a speed limited by
+        // the softened potential G M ln(r^2 + s^2) / 2
+        let max_speed = (100.0 as Scalar + 10.0 * 10_000.0 * (26.0 as Scalar).ln()).sqrt();
+        for _ in 0..2000 {
+            universe.step(0.0005, None, None);
+            let velocity = universe.particles[0].velocity;
+            assert!(velocity.x.is_finite() && velocity.y.is_finite());
+            assert!(
+                velocity.length() < 1.01 * max_speed,
+                "speed {}",
+                velocity.length()
+            );
+        }
+        assert_eq!(universe.attractors[0].position, black_hole.position);
+    }
+
+    #[test]
+    fn random_masses_stay_in_range_and_add_up_at_the_root() {
+        let settings = SpawnSettings {
+            mass_min: 0.5,
+            mass_max: 4.0,
+            ..spawn_settings()
+        };
+        let mut particles = Vec::new();
+        spawn_particles(
+            &mut particles,
+            1000,
+            &settings,
+            &[],
+            &mut StdRng::seed_from_u64(263),
+        );
+        assert!(particles.iter().all(|p| (0.5..=4.0).contains(&p.mass)));
+        // not all the same
+        assert!(particles.iter().any(|p| p.mass < 1.0) && particles.iter().any(|p| p.mass > 3.5));
+
+        let tree = ParticleQuadTree::build(&particles, 8);
+        let total: Scalar = particles.iter().map(|p| p.mass).sum();
+        let center = particles.iter().fold(Vector2D::default(), |sum, p| {
+            &sum + p.position * (p.mass / total)
+        });
+        assert!((tree.summary().mass / total - 1.0).abs() < 1e-4);
+        assert!((tree.summary().position - center).length() < 1e-3);
+    }
+
+    #[test]
+    fn negative_gravity_pushes_particles_apart_symmetrically() {
+        let mut universe = universe(vec![
+            create_particle(-1.0, 0.0, 1.0, 0.0),
+            create_particle(1.0, 0.0, 1.0, 0.0),
+        ]);
+        universe.grav_const = -10.0;
+        for _ in 0..10 {
+            universe.step(0.01, None, None);
+        }
+        let (left, right) = (universe.particles[0], universe.particles[1]);
+        assert!(left.velocity.x < 0.0 && right.velocity.x > 0.0);
+        assert_eq!(left.velocity.x, -right.velocity.x);
+        assert_eq!(left.position.x, -right.position.x);
+        assert_eq!((left.velocity.y, right.velocity.y), (0.0, 0.0));
+    }
+
+    #[test]
+    fn emitters_add_particles_at_their_rate_up_to_the_limit() {
+        let mut universe = universe(Vec::new());
+        universe.grav_const = 0.0;
+        universe
+            .emitters
+            .push(Emitter::new(Vector2D { x: 50.0, y: 50.0 }, 25.0, 1.0));
+        let mut handler = handler(universe);
+        let mut counts = Vec::new();
+        for _ in 0..10 {
+            handler.tick(UVec2::new(100, 100), 0.1);
+            counts.push(handler.universe.particles.len());
+        }
+        // 2.5 particles per step, the fractions carry over
+        assert_eq!(counts, [2, 5, 7, 10, 12, 15, 17, 20, 22, 25]);
+
+        handler.universe.max_particles = Some(30);
+        for _ in 0..10 {
+            handler.tick(UVec2::new(100, 100), 0.1);
+        }
+        assert_eq!(handler.universe.particles.len(), 30);
+    }
+
+    #[test]
+    fn emitted_particles_start_with_fresh_verlet_accelerations() {
+        let mut light = create_particle(40.0, 50.0, 1.0, 0.0);
+        light.velocity = Vector2D { x: 0.0, y: 5.0 };
+        let mut binary = universe(vec![create_particle(50.0, 50.0, 1000.0, 0.0), light]);
+        binary.integrator = Box::<VerletIntegrator<Scalar>>::default();
+        let mut handler = handler(binary);
+        handler.tick(UVec2::new(100, 100), 0.1);
+        let before = handler.universe.particles.clone();
+
+        // exactly one particle in the next step, at rest
+        handler
+            .universe
+            .emitters
+            .push(Emitter::new(Vector2D { x: 60.0, y: 50.0 }, 10.0, 0.0));
+        handler.tick(UVec2::new(100, 100), 0.1);
+        assert_eq!(handler.universe.particles.len(), 3);
+        let emitted = handler.universe.particles[2];
+        assert!(emitted.velocity.x < 0.0, "not pulled towards the heavy one");
+
+        // the same step from scratch, with nothing carried over by the integrator
+        let mut particles = before;
+        particles.push(Particle {
+            position: Vector2D { x: 60.0, y: 50.0 },
+            velocity: Vector2D::default(),
+            ..emitted
+        });
+        let mut fresh = universe(particles);
+        fresh.integrator = Box::<VerletIntegrator<Scalar>>::default();
+        fresh.step(0.1, None, None);
+        assert_eq!(handler.universe.state_hash(), fresh.state_hash());
+    }
+
+    #[test]
+    fn particles_expire_after_their_lifetime() {
+        let mut universe = universe(Vec::new());
+        universe.lifetime = Some(1.0);
+        universe
+            .emitters
+            .push(Emitter::new(Vector2D { x: 50.0, y: 50.0 }, 20.0, 1.0));
+        let mut handler = handler(universe);
+        for _ in 0..20 {
+            handler.tick(UVec2::new(100, 100), 0.05);
+        }
+        assert!(!handler.universe.particles.is_empty());
+        assert_eq!(
+            handler.universe.ages.len(),
+            handler.universe.particles.len()
+        );
+
+        // no new particles, all existing ones are gone a little more than one lifetime later
+        handler.universe.emitters.clear();
+        for _ in 0..25 {
+            handler.tick(UVec2::new(100, 100), 0.05);
+        }
+        assert!(handler.universe.particles.is_empty());
+        assert!(handler.universe.ages.is_empty());
+    }
+
+    #[test]
+    fn disc_particles_stay_on_circular_orbits() {
+        let mut particles = Vec::new();
+        let disc = Disc {
+            center: Vector2D::default(),
+            velocity: Vector2D::default(),
+            central_mass: 1000.0,
+            inner_radius: 50.0,
+            outer_radius: 50.0,
+            count: 1,
+        };
+        let gravity = Gravity {
+            grav_const: 10.0,
+            softening: 1.0,
+            exponent: 1.0,
+        };
+        disc.generate(&gravity, &mut StdRng::seed_from_u64(270), &mut particles);
+        let mut universe = universe(particles);
+        // a period is 2 pi r / v with v of about sqrt(G M) = 100, run for three
+        for _ in 0..2000 {
+            universe.step(0.005, None, None);
+            let r = (universe.particles[1].position - universe.particles[0].position).length();
+            assert!((r / 50.0 - 1.0).abs() < 0.1, "radius {}", r);
+        }
+    }
+
+    #[test]
+    fn spawned_particles_lie_inside_the_spawn_region() {
+        let settings = SpawnSettings {
+            width: 1280.0 * 0.5,
+            height: 720.0 * 0.5,
+            ..spawn_settings()
+        };
+        let mut particles = Vec::new();
+        spawn_particles(
+            &mut particles,
+            2000,
+            &settings,
+            &[],
+            &mut StdRng::seed_from_u64(272),
+        );
+        assert_eq!(particles.len(), 2000);
+        for particle in &particles {
+            assert!((0.0..640.0).contains(&particle.position.x));
+            assert!((0.0..360.0).contains(&particle.position.y));
+        }
+    }
+
+    #[test]
+    fn wrapping_particles_cross_the_seam_with_the_same_velocity() {
+        let mut particle = create_particle(95.0, 20.0, 1.0, 0.0);
+        particle.velocity = Vector2D { x: 3.0, y: 0.0 };
+        let mut universe = universe(vec![particle, create_particle(50.0, 40.0, 1.0, 0.0)]);
+        universe.grav_const = 0.0;
+        universe.boundary = Boundary::Wrap("0,0,100,50".parse().unwrap());
+        let mut xs = Vec::new();
+        for _ in 0..10 {
+            universe.step(0.5, None, None);
+            assert_eq!(universe.particles.len(), 2);
+            assert_eq!(universe.particles[0].velocity, Vector2D { x: 3.0, y: 0.0 });
+            xs.push(universe.particles[0].position.x);
+        }
+        assert_eq!(xs, [96.5, 98.0, 99.5, 1.0, 2.5, 4.0, 5.5, 7.0, 8.5, 10.0]);
+    }
+
+    #[test]
+    fn wrapping_particles_attract_across_the_seam() {
+        let mut universe = universe(vec![
+            create_particle(1.0, 25.0, 1.0, 0.0),
+            create_particle(99.0, 25.0, 1.0, 0.0),
+        ]);
+        universe.boundary = Boundary::Wrap("0,0,100,50".parse().unwrap());
+        universe.step(0.01, None, None);
+        // the nearest image of each lies on the other side of the seam
+        assert!(universe.particles[0].velocity.x < 0.0);
+        assert!(universe.particles[1].velocity.x > 0.0);
+    }
+
+    #[test]
+    fn particles_leaving_the_kill_radius_are_removed() {
+        let mut runaway = create_particle(10.0, 0.0, 1.0, 0.0);
+        runaway.velocity = Vector2D { x: 100.0, y: 0.0 };
+        let mut universe = universe(vec![
+            create_particle(0.0, 0.0, 1.0, 0.0),
+            create_particle(3.0, 0.0, 1.0, 0.0),
+            create_particle(0.0, 3.0, 1.0, 0.0),
+            runaway,
+        ]);
+        universe.boundary = Boundary::Kill { radius: 50.0 };
+        let mut handler = handler(universe);
+        let mut ticks = 0;
+        while handler.universe.particles.len() == 4 {
+            let particles = &handler.universe.particles;
+            let center = particles
+                .iter()
+                .fold(Vector2D::default(), |sum, p| &sum + p.position * 0.25);
+            let escaped = (particles[3].position - center).length() > 50.0;
+            handler.tick(UVec2::new(100, 100), 0.05);
+            assert_eq!(handler.universe.particles.len() == 3, escaped);
+            ticks += 1;
+            assert!(ticks < 100);
+        }
+        assert_eq!(handler.universe.culled, 1);
+
+        let before: Vec<_> = handler
+            .universe
+            .particles
+            .iter()
+            .map(|p| p.position)
+            .collect();
+        for _ in 0..10 {
+            handler.tick(UVec2::new(100, 100), 0.05);
+        }
+        assert_eq!(handler.universe.particles.len(), 3);
+        for (particle, before) in handler.universe.particles.iter().zip(before) {
+            assert!(particle.position.x.is_finite() && particle.position.y.is_finite());
+            assert_ne!(particle.position, before);
+        }
+    }
+
+    #[test]
+    fn clamped_accelerations_bound_the_velocities() {
+        let mut universe = universe(vec![
+            create_particle(0.0, 0.0, 1000.0, 0.0),
+            create_particle(0.01, 0.0, 1000.0, 0.0),
+            create_particle(1000.0, 0.0, 1.0, 0.0),
+        ]);
+        universe.softening = 0.001;
+        universe.max_acceleration = Some(100.0);
+        universe.step(0.01, None, None);
+        for particle in &universe.particles {
+            assert!(particle.velocity.length() <= 100.0 * 0.01 * 1.0001);
+        }
+        // the close pair is clamped, the distant particle is not
+        assert_eq!(universe.clamped, 2);
+        assert!(universe.particles[2].velocity.length() < 0.5);
+    }
+
+    #[test]
+    fn reversed_time_retraces_the_trajectory() {
+        let mut a = create_particle(-10.0, 0.0, 100.0, 0.0);
+        let mut b = create_particle(10.0, 0.0, 100.0, 0.0);
+        a.velocity = Vector2D { x: 0.0, y: -15.0 };
+        b.velocity = Vector2D { x: 0.0, y: 15.0 };
+        let mut universe = universe(vec![a, b]);
+        universe.integrator = Box::<LeapfrogIntegrator<Scalar>>::default();
+        for _ in 0..500 {
+            universe.step(0.01, None, None);
+        }
+        assert!((universe.particles[0].position - a.position).length() > 5.0);
+        universe.reverse_time();
+        for _ in 0..500 {
+            universe.step(0.01, None, None);
+        }
+        for (particle, start) in universe.particles.iter().zip([a, b]) {
+            let error = (particle.position - start.position).length();
+            assert!(error < 1e-2, "error {}", error);
+            assert!((&particle.velocity + start.velocity).length() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn fission_conserves_mass_and_momentum() {
+        let mut heavy = create_particle(0.0, 0.0, 16.0, 2.0);
+        heavy.velocity = Vector2D { x: 3.0, y: -1.0 };
+        let mut heavier = create_particle(50.0, 0.0, 40.0, 0.0);
+        heavier.velocity = Vector2D { x: -2.0, y: 0.5 };
+        let mut universe = universe(vec![heavy, create_particle(20.0, 0.0, 5.0, 0.0), heavier]);
+        universe.fission_threshold = Some(10.0);
+        let totals = |particles: &[Particle<Scalar>]| {
+            particles.iter().fold(
+                (0.0, 0.0, Vector2D::default()),
+                |(mass, charge, momentum), p| {
+                    (
+                        mass + p.mass,
+                        charge + p.charge,
+                        &momentum + p.velocity * p.mass,
+                    )
+                },
+            )
+        };
+        let (mass, charge, momentum) = totals(&universe.particles);
+
+        let splits = universe.split_heavy_particles(None, &mut StdRng::seed_from_u64(282));
+        assert_eq!(splits, 2);
+        assert_eq!(universe.particles.len(), 5);
+        let (split_mass, split_charge, split_momentum) = totals(&universe.particles);
+        assert_eq!((split_mass, split_charge), (mass, charge));
+        assert!((split_momentum - momentum).length() < 1e-4);
+        // the halves of 40 are still heavier than the threshold and split in the next step
+        assert_eq!(
+            universe.split_heavy_particles(None, &mut StdRng::seed_from_u64(282)),
+            2
+        );
+        assert_eq!(universe.particles.len(), 7);
+    }
+
+    #[test]
+    fn swirl_turns_particles_counter_clockwise_around_its_center() {
+        let center = Vector2D { x: 5.0, y: 5.0 };
+        let mut universe = universe(vec![
+            create_particle(15.0, 5.0, 1.0, 0.0),
+            create_particle(5.0, -15.0, 1.0, 0.0),
+        ]);
+        universe.grav_const = 0.0;
+        universe.swirl_strength = 5.0;
+        universe.swirl_center = center;
+
+        // from rest, the first kick is tangential with the strength as magnitude
+        universe.step(0.01, None, None);
+        assert!((universe.particles[0].velocity - Vector2D { x: 0.0, y: 0.05 }).length() < 1e-6);
+        assert!((universe.particles[1].velocity - Vector2D { x: 0.05, y: 0.0 }).length() < 1e-6);
+
+        // without a central force the path spirals outwards, but always in the same direction
+        let angle = |p: &Particle<Scalar>| (p.position.y - center.y).atan2(p.position.x - center.x);
+        let mut turned = 0.0;
+        let mut previous = angle(&universe.particles[0]);
+        for _ in 0..1000 {
+            universe.step(0.01, None, None);
+            let current = angle(&universe.particles[0]);
+            let delta = (current - previous + consts::PI).rem_euclid(consts::TAU) - consts::PI;
+            assert!(delta > 0.0);
+            turned += delta;
+            previous = current;
+        }
+        assert!(turned > consts::FRAC_PI_2);
+    }
+
+    #[test]
+    fn external_forces_add_to_gravity() {
+        let pair = || {
+            universe(vec![
+                create_particle(0.0, 0.0, 2.0, 0.0),
+                create_particle(10.0, 0.0, 1.0, 0.0),
+            ])
+        };
+        let mut gravity_only = pair();
+        gravity_only.step(0.1, None, None);
+        let mut pushed = pair();
+        pushed.apply_force(0, Vector2D { x: 4.0, y: -6.0 });
+        pushed.step(0.1, None, None);
+        // a = F / m over 0.1 seconds
+        let extra = pushed.particles[0].velocity - gravity_only.particles[0].velocity;
+        assert!((extra - Vector2D { x: 0.2, y: -0.3 }).length() < 1e-6);
+        assert_eq!(
+            pushed.particles[1].velocity,
+            gravity_only.particles[1].velocity
+        );
+
+        // the force only lasts one step, the pushed particle only feels slightly different gravity
+        // at its shifted position afterwards
+        let (before_gravity, before_pushed) = (
+            gravity_only.particles[0].velocity,
+            pushed.particles[0].velocity,
+        );
+        gravity_only.step(0.1, None, None);
+        pushed.step(0.1, None, None);
+        let change_gravity = gravity_only.particles[0].velocity - before_gravity;
+        let change_pushed = pushed.particles[0].velocity - before_pushed;
+        assert!((change_pushed - change_gravity).length() < 1e-3);
+
+        let before = pushed.particles[1].velocity;
+        pushed.apply_impulse(1, Vector2D { x: 0.0, y: 2.0 });
+        assert_eq!(
+            pushed.particles[1].velocity,
+            &before + Vector2D { x: 0.0, y: 2.0 }
+        );
+    }
+
+    #[test]
+    fn pinned_particles_stay_put_while_attracting_others() {
+        let star = create_particle(0.0, 0.0, 1000.0, 0.0);
+        let mut satellite = create_particle(50.0, 0.0, 1.0, 0.0);
+        satellite.velocity = Vector2D { x: 0.0, y: 100.0 };
+        let mut universe = universe(vec![star, satellite]);
+        universe.toggle_pin(0);
+        // about half of the period 2 pi r / v
+        for _ in 0..300 {
+            universe.step(0.005, None, None);
+            assert_eq!(universe.particles[0].position, star.position);
+            assert_eq!(universe.particles[0].velocity, star.velocity);
+        }
+        // the satellite is on the other side of the star, on a roughly circular orbit
+        let r = universe.particles[1].position.length();
+        assert!((r / 50.0 - 1.0).abs() < 0.1, "radius {}", r);
+        assert!(universe.particles[1].position.x < 0.0);
+    }
+
+    #[test]
+    fn corotating_particles_stay_at_rest_in_the_rotating_frame() {
+        let mut universe = universe(vec![create_particle(50.0, 0.0, 1.0, 0.0)]);
+        universe
+            .attractors
+            .push(create_particle(0.0, 0.0, 1000.0, 0.0));
+        // the angular velocity of a circular orbit, with the softening of 1
+        let speed = Gravity {
+            grav_const: 10.0,
+            softening: 1.0,
+            exponent: 1.0,
+        }
+        .circular_speed(1000.0, 50.0);
+        universe.frame_rotation = speed / 50.0;
+        // about a quarter of an orbit in the inertial frame
+        for _ in 0..400 {
+            universe.step(0.002, None, None);
+        }
+        let drift = (universe.particles[0].position - Vector2D { x: 50.0, y: 0.0 }).length();
+        assert!(drift < 0.5, "drift {}", drift);
+        assert!(universe.particles[0].velocity.length() < 0.5);
+    }
+
+    /// Two equal masses on a circular orbit around their center of mass at the origin, 20 apart
+    fn binary(integrator: Box<dyn Integrator<Scalar>>) -> Universe<Scalar> {
+        // each is pulled by G m d / (d^2 + s^2) towards the other and circles at radius 10
+        let acceleration = 10.0 * 100.0 * 20.0 / (400.0 + 1.0);
+        let speed = (acceleration * 10.0 as Scalar).sqrt();
+        let mut a = create_particle(-10.0, 0.0, 100.0, 0.0);
+        let mut b = create_particle(10.0, 0.0, 100.0, 0.0);
+        a.velocity = Vector2D { x: 0.0, y: -speed };
+        b.velocity = Vector2D { x: 0.0, y: speed };
+        let mut universe = universe(vec![a, b]);
+        universe.integrator = integrator;
+        universe
+    }
+
+    #[test]
+    fn double_buffered_trajectories_match_the_in_place_ones() {
+        let mut rng = StdRng::seed_from_u64(211);
+        let particles: Vec<_> = (0..500)
+            .map(|_| {
+                create_particle(
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(0.5..2.0),
+                    0.0,
+                )
+            })
+            .collect();
+        let hashes = |double_buffer| {
+            let mut universe = universe(particles.clone());
+            universe.integrator = Box::new(EulerIntegrator::new(double_buffer));
+            (0..100)
+                .map(|_| {
+                    universe.step(0.01, None, None);
+                    universe.state_hash()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(hashes(true), hashes(false));
+    }
+
+    /// The kinetic energy plus the softened potential G m1 m2 ln(r^2 + s^2) / 2 of a binary
+    fn binary_energy(universe: &Universe<Scalar>) -> Scalar {
+        let [a, b] = [universe.particles[0], universe.particles[1]];
+        let kinetic = (a.mass * a.velocity.length_sq() + b.mass * b.velocity.length_sq()) / 2.0;
+        let r_sq = (b.position - a.position).length_sq();
+        kinetic + 10.0 * a.mass * b.mass * (r_sq + 1.0).ln() / 2.0
+    }
+
+    #[test]
+    fn leapfrog_drifts_far_less_energy_than_euler() {
+        let max_drift = |integrator| {
+            let mut universe = binary(integrator);
+            let initial = binary_energy(&universe);
+            (0..400)
+                .map(|_| {
+                    universe.step(0.01, None, None);
+                    ((binary_energy(&universe) - initial) / initial).abs()
+                })
+                .fold(0.0, Scalar::max)
+        };
+        let euler = max_drift(Box::<EulerIntegrator<Scalar>>::default());
+        let leapfrog = max_drift(Box::<LeapfrogIntegrator<Scalar>>::default());
+        assert!(
+            leapfrog * 10.0 < euler,
+            "leapfrog {}, euler {}",
+            leapfrog,
+            euler
+        );
+    }
+
+    #[test]
+    fn verlet_keeps_a_circular_orbit_bounded() {
+        let mut universe = binary(Box::<VerletIntegrator<Scalar>>::default());
+        // almost 18 periods
+        for _ in 0..5000 {
+            universe.step(0.01, None, None);
+            let separation =
+                (universe.particles[1].position - universe.particles[0].position).length();
+            assert!(
+                (separation / 20.0 - 1.0).abs() < 0.05,
+                "separation {}",
+                separation
+            );
+        }
+    }
+
+    #[test]
+    fn a_huge_impulse_leaves_the_particle_at_the_speed_limit() {
+        let mut universe = universe(vec![create_particle(0.0, 0.0, 2.0, 0.0)]);
+        universe.grav_const = 0.0;
+        universe.max_speed = Some(10.0);
+        universe.apply_impulse(0, Vector2D { x: 3e6, y: 4e6 });
+        universe.step(0.1, None, None);
+        let particle = universe.particles[0];
+        let speed = particle.velocity.length();
+        assert!(
+            (speed - 10.0).abs() <= 10.0 * Scalar::EPSILON * 4.0,
+            "speed {}",
+            speed
+        );
+        let direction = particle.velocity * (1.0 / speed);
+        assert!((direction.x - 0.6).abs() < 1e-6 && (direction.y - 0.8).abs() < 1e-6);
+        // it never moved faster than the limit either
+        assert!(particle.position.length() <= 1.0 + 1e-5);
+    }
+
+    #[test]
+    fn block_steps_conserve_momentum_across_bins() {
+        // the light body needs steps of dt / 4, the heavy one is accurate with the whole step
+        let mut heavy = create_particle(0.0, 0.0, 1000.0, 0.0);
+        let mut light = create_particle(10.0, 0.0, 1.0, 0.0);
+        let speed = (10.0 * 1000.0 * 10.0 * 10.0 / 101.0 as Scalar).sqrt();
+        light.velocity = Vector2D { x: 0.0, y: speed };
+        heavy.velocity = Vector2D {
+            x: 0.0,
+            y: -speed / 1000.0,
+        };
+        let mut universe = universe(vec![heavy, light]);
+        universe.integrator = Box::new(BlockIntegrator::new(6, 0.1));
+        // about ten orbits
+        for _ in 0..600 {
+            universe.step(0.01, None, None);
+            let [a, b] = [universe.particles[0], universe.particles[1]];
+            let momentum = &(a.velocity * a.mass) + b.velocity * b.mass;
+            assert!(momentum.length() < 0.01 * speed, "momentum {:?}", momentum);
+        }
+    }
+
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn block_steps_are_faster_on_a_concentrated_cluster() {
+        let mut particles = Vec::new();
+        let gravity = Gravity {
+            grav_const: 10.0,
+            softening: 1.0,
+            exponent: 1.0,
+        };
+        Plummer {
+            center: Vector2D::default(),
+            scale_radius: 50.0,
+            total_mass: 5000.0,
+            count: 2000,
+        }
+        .generate(&gravity, &mut StdRng::seed_from_u64(301), &mut particles);
+        let frames = 10;
+        let run = |integrator: Box<dyn Integrator<Scalar>>, substeps| {
+            let mut universe = universe(particles.clone());
+            universe.integrator = integrator;
+            universe.substeps = substeps;
+            universe.energy_every = Some(frames);
+            let start = Instant::now();
+            universe.step(0.01, None, None);
+            let initial = universe.last_energy.unwrap().1.total;
+            for _ in 0..frames {
+                universe.step(0.01, None, None);
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            let total = universe.last_energy.unwrap().1.total;
+            (elapsed, ((total - initial) / initial).abs())
+        };
+        let (block, block_error) = run(Box::new(BlockIntegrator::new(6, 0.1)), 1);
+        // the finest block step for every particle
+        let (leapfrog, leapfrog_error) = run(Box::<LeapfrogIntegrator<Scalar>>::default(), 64);
+        println!(
+            "block: {:.2} s, energy error {:.1e}; leapfrog at the finest step: {:.2} s, energy \
+             error {:.1e}",
+            block, block_error, leapfrog, leapfrog_error
+        );
+        assert!(
+            block * 2.0 < leapfrog,
+            "block {} s, leapfrog {} s",
+            block,
+            leapfrog
+        );
+    }
+
+    #[test]
+    fn rk4_follows_the_analytic_orbit_for_a_period() {
+        let mut universe = binary(Box::<Rk4Integrator<Scalar>>::default());
+        let start: Vec<_> = universe.particles.iter().map(|p| p.position).collect();
+        let speed = universe.particles[1].velocity.y;
+        let period = consts::TAU * 10.0 / speed;
+        let steps = 200;
+        for step in 1..=steps {
+            universe.step(period / steps as Scalar, None, None);
+            // both circle the origin counter-clockwise at the same angular velocity
+            let angle = consts::TAU * step as Scalar / steps as Scalar;
+            let expected = Vector2D::from_polar(10.0, angle);
+            let error = (universe.particles[1].position - expected).length();
+            assert!(error < 1e-3, "error {} at step {}", error, step);
+        }
+        for (particle, start) in universe.particles.iter().zip(start) {
+            assert!((particle.position - start).length() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn displacement_scales_with_the_step_length() {
+        let displacement = |elapsed_s: Scalar| {
+            let mut particle = create_particle(10.0, 20.0, 1.0, 0.0);
+            particle.velocity = Vector2D { x: 3.0, y: -4.0 };
+            let mut universe = universe(vec![particle]);
+            universe.step(elapsed_s, None, None);
+            universe.particles[0].position - particle.position
+        };
+        let short = displacement(1.0 / 60.0);
+        for factor in [2.0, 3.0, 6.0] {
+            let long = displacement(factor / 60.0);
+            assert!((long - short * factor).length() < 1e-4);
+        }
+        assert!(
+            (short
+                - Vector2D {
+                    x: 0.05,
+                    y: -1.0 / 15.0
+                })
+            .length()
+                < 1e-6
+        );
+    }
+
+    /// The largest relative energy error of the binary over 400 frames of 1/100 s
+    fn binary_drift(universe: &mut Universe<Scalar>) -> Scalar {
+        let initial = binary_energy(universe);
+        (0..400)
+            .map(|_| {
+                universe.step(0.01, None, None);
+                ((binary_energy(universe) - initial) / initial).abs()
+            })
+            .fold(0.0, Scalar::max)
+    }
+
+    #[test]
+    fn substeps_reduce_the_energy_drift() {
+        let mut single = binary(Box::<EulerIntegrator<Scalar>>::default());
+        let mut quadruple = binary(Box::<EulerIntegrator<Scalar>>::default());
+        quadruple.substeps = 4;
+        let (single, quadruple) = (binary_drift(&mut single), binary_drift(&mut quadruple));
+        assert!(
+            quadruple * 2.0 < single,
+            "1 substep {}, 4 substeps {}",
+            single,
+            quadruple
+        );
+    }
+
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn substep_cost_grows_linearly() {
+        let mut rng = StdRng::seed_from_u64(298);
+        let particles: Vec<_> = (0..5000)
+            .map(|_| {
+                create_particle(
+                    rng.gen_range(0.0..500.0),
+                    rng.gen_range(0.0..500.0),
+                    1.0,
+                    0.0,
+                )
+            })
+            .collect();
+        let frame_time = |substeps| {
+            let mut universe = universe(particles.clone());
+            universe.substeps = substeps;
+            let start = Instant::now();
+            for _ in 0..10 {
+                universe.step(0.01, None, None);
+            }
+            start.elapsed().as_secs_f64() / 10.0
+        };
+        let (one, four) = (frame_time(1), frame_time(4));
+        println!(
+            "1 substep: {:.2?} s per frame, 4 substeps: {:.2?} s per frame",
+            one, four
+        );
+        assert!((2.0..6.0).contains(&(four / one)), "ratio {}", four / one);
+    }
+
+    #[test]
+    fn time_scale_multiplies_the_displacement_per_frame() {
+        let displacement = |time_scale: Scalar| {
+            let mut particle = create_particle(10.0, 10.0, 1.0, 0.0);
+            particle.velocity = Vector2D { x: 6.0, y: 0.0 };
+            let mut universe = universe(vec![particle]);
+            universe.time_scale = time_scale;
+            let mut handler = handler(universe);
+            // six steps of 1/60 s
+            let start = handler.last_tick;
+            handler.advance(UVec2::new(100, 100), start + Duration::from_millis(105));
+            (
+                handler.universe.particles[0].position.x - 10.0,
+                handler.universe.num_steps,
+            )
+        };
+        let (normal, steps) = displacement(1.0);
+        assert_eq!(steps, 6);
+        assert!((normal - 0.6).abs() < 1e-4);
+        let (doubled, _) = displacement(2.0);
+        assert!((doubled - 2.0 * normal).abs() < 1e-4);
+        assert_eq!(displacement(0.0), (0.0, 0));
+    }
+
+    #[test]
+    fn fixed_steps_carry_the_remainder_over_and_drop_a_stalled_backlog() {
+        let mut handler = handler(universe(vec![create_particle(10.0, 10.0, 1.0, 0.0)]));
+        // steps of exactly 15.625 ms
+        handler.physics_hz = 64.0;
+        let mut now = handler.last_tick;
+        let mut frame = |handler: &mut UniverseWindowHandler, millis| {
+            now += Duration::from_millis(millis);
+            handler.advance(UVec2::new(100, 100), now);
+            (handler.universe.num_steps, handler.accumulator)
+        };
+        let (steps, remainder) = frame(&mut handler, 40);
+        assert_eq!(steps, 2);
+        assert!(
+            (remainder - 0.00875).abs() < 1e-6,
+            "remainder {}",
+            remainder
+        );
+        // the remainder and this frame add up to one more step
+        let (steps, remainder) = frame(&mut handler, 10);
+        assert_eq!(steps, 3);
+        assert!(
+            (remainder - 0.003125).abs() < 1e-6,
+            "remainder {}",
+            remainder
+        );
+        // a stalled second runs at most MAX_STEPS_PER_FRAME steps and forgets the rest
+        let (steps, remainder) = frame(&mut handler, 1000);
+        assert_eq!(steps, 3 + MAX_STEPS_PER_FRAME as u64);
+        assert!(
+            (0.0..1.0 / 64.0).contains(&remainder),
+            "remainder {}",
+            remainder
+        );
+        let (steps, _) = frame(&mut handler, 0);
+        assert_eq!(steps, 3 + MAX_STEPS_PER_FRAME as u64);
+    }
+
+    #[test]
+    fn warming_up_an_empty_universe_does_nothing() {
+        let mut handler = handler(universe(Vec::new()));
+        handler.warmup_ticks = 100;
+        handler.warm_up(UVec2::new(100, 100));
+        assert!(handler.universe.particles.is_empty());
+        assert_eq!(handler.universe.num_steps, 0);
+    }
+
+    /// Only 1/r gravity, at the precision of `T`
+    #[cfg(feature = "f64")]
+    fn gravity_only<'a, T: Float + Default>() -> ForceParameters<'a, T> {
+        let zero = T::default();
+        ForceParameters {
+            grav_const: T::from(10.0).unwrap(),
+            force_exponent: T::one(),
+            solid_interior: false,
+            softening: T::from(0.5).unwrap(),
+            theta: T::from(0.5).unwrap(),
+            coulomb_const: zero,
+            repulsion: None,
+            neighbors: None,
+            damping: zero,
+            species_drag: &[],
+            uniform_field: Vector2D::default(),
+            swirl_strength: zero,
+            swirl_center: Vector2D::default(),
+            frame_rotation: zero,
+            frame_center: Vector2D::default(),
+            attractors: &[],
+            obstacles: &[],
+            springs: &[],
+            extra_accelerations: &[],
+            external_forces: &[],
+            pinned: &[],
+            period: None,
+            max_acceleration: None,
+        }
+    }
+
+    /// The positions after each of the given numbers of leapfrog steps of 0.01 under gravity,
+    /// simulated at the precision of `T`
+    #[cfg(feature = "f64")]
+    fn trajectory<T>(particles: &[Particle<T>], checkpoints: &[usize]) -> Vec<Vec<Vector2D<f64>>>
+    where
+        T: Float + Default + std::fmt::Display + Send + Sync,
+        ParticleQuadTree<T>: ForceTree<T>,
+    {
+        let mut particles = particles.to_vec();
+        let params = gravity_only::<T>();
+        let mut tree = ParticleQuadTree::build(&particles, 8);
+        let mut scratch = ParticleQuadTree::build(&particles, 8);
+        let mut integrator = LeapfrogIntegrator::default();
+        let dt = T::from(0.01).unwrap();
+        let mut positions = Vec::new();
+        for step in 1..=*checkpoints.last().unwrap() {
+            ForceTree::rebuild(&mut tree, &particles);
+            let forces = BarnesHut {
+                tree: &tree,
+                scratch: RefCell::new(&mut scratch),
+                params: &params,
+            };
+            integrator.step(&mut particles, &forces, dt);
+            if checkpoints.contains(&step) {
+                positions.push(
+                    particles
+                        .iter()
+                        .map(|particle| Vector2D {
+                            x: particle.position.x.to_f64().unwrap(),
+                            y: particle.position.y.to_f64().unwrap(),
+                        })
+                        .collect(),
+                );
+            }
+        }
+        positions
+    }
+
+    #[cfg(feature = "f64")]
+    #[test]
+    fn f32_runs_drift_away_from_f64_ones() {
+        let gravity = Gravity {
+            grav_const: 10.0,
+            softening: 0.5,
+            exponent: 1.0,
+        };
+        let mut particles = Vec::new();
+        Plummer {
+            center: Vector2D::default(),
+            scale_radius: 10.0,
+            total_mass: 100.0,
+            count: 30,
+        }
+        .generate(&gravity, &mut StdRng::seed_from_u64(337), &mut particles);
+        let single: Vec<Particle<f32>> = particles
+            .iter()
+            .map(|particle| Particle {
+                position: Vector2D {
+                    x: particle.position.x as f32,
+                    y: particle.position.y as f32,
+                },
+                velocity: Vector2D {
+                    x: particle.velocity.x as f32,
+                    y: particle.velocity.y as f32,
+                },
+                radius: particle.radius as f32,
+                mass: particle.mass as f32,
+                charge: particle.charge as f32,
+                species: particle.species,
+            })
+            .collect();
+        let checkpoints = [10, 100, 1000, 3000, 10_000];
+        let double = trajectory(&particles, &checkpoints);
+        let single = trajectory(&single, &checkpoints);
+        // the largest distance between the same particle in both runs
+        let deviations: Vec<f64> = double
+            .iter()
+            .zip(&single)
+            .map(|(a, b)| {
+                a.iter()
+                    .zip(b)
+                    .map(|(a, b)| a.distance_sq(b).sqrt())
+                    .fold(0.0, f64::max)
+            })
+            .collect();
+        // both start from the same rounded state, then rounding errors grow with every close
+        // encounter until the clusters no longer resemble each other
+        assert!(deviations[0] < 1e-4, "deviations {:?}", deviations);
+        assert!(deviations[1] < 1e-2, "deviations {:?}", deviations);
+        assert!(deviations[4] > 10.0, "deviations {:?}", deviations);
+    }
+
+    #[test]
+    fn a_square_universe_maps_to_square_pixels_on_a_wide_screen() {
+        let camera = Camera {
+            center: Vector2D { x: 50.0, y: -20.0 },
+            width: 100.0,
+            height: 100.0,
+        };
+        let transform = camera.screen_transform(UVec2::new(1600, 900));
+        // the corners of a square of side 40 around a point off the center
+        let corners = [(10.0, -30.0), (50.0, -30.0), (50.0, 10.0), (10.0, 10.0)]
+            .map(|(x, y)| transform.local_to_screen(Vector2D { x, y }));
+        for i in 0..4 {
+            let side = corners[(i + 1) % 4] - corners[i];
+            // every side is 40 units at 9 pixels per unit, the height decides
+            assert!(
+                (side.length_sq().sqrt() - 360.0).abs() < 1e-3,
+                "{:?}",
+                corners
+            );
+            let next = corners[(i + 2) % 4] - corners[(i + 1) % 4];
+            assert!((side.x * next.x + side.y * next.y).abs() < 1e-3);
+        }
+        // centered, with empty bars of 350 pixels left and right
+        let left = transform.local_to_screen(Vector2D { x: 0.0, y: -20.0 });
+        let right = transform.local_to_screen(Vector2D { x: 100.0, y: -20.0 });
+        assert!((left.x - 350.0).abs() < 1e-3 && (right.x - 1250.0).abs() < 1e-3);
+        assert!((left.y - 450.0).abs() < 1e-3);
+        let back = transform.screen_to_local(corners[2]);
+        assert!((back.x - 50.0).abs() < 1e-3 && (back.y - 10.0).abs() < 1e-3);
+        // particles on a vertical line still fit by their height
+        let line = Camera {
+            width: 0.0,
+            ..camera
+        };
+        let top = line
+            .screen_transform(UVec2::new(1600, 900))
+            .local_to_screen(Vector2D { x: 50.0, y: -70.0 });
+        assert!((top.x - 800.0).abs() < 1e-3 && top.y.abs() < 1e-3);
+    }
 }
@@ -1,3 +1,24 @@
 pub mod vector2d;
 pub mod particle_quad_tree;
-pub mod particle;
\ No newline at end of file
+pub mod morton_tree;
+pub mod particle;
+pub mod scenario;
+pub mod boundary;
+pub mod config_file;
+pub mod console;
+pub mod emitter;
+pub mod fingerprint;
+pub mod integrator;
+pub mod neighbor_list;
+pub mod obstacle;
+pub mod profiler;
+pub mod snapshot;
+pub mod species;
+pub mod sph;
+pub mod spring;
+pub mod state_file;
+pub mod units;
+pub mod velocity_distribution;
+pub mod vtk;
+#[cfg(feature = "net")]
+pub mod status_server;
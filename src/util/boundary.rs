@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+use crate::util::particle::Particle;
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+/// An axis-aligned rectangle of the world
+#[derive(Copy, Clone, Debug)]
+pub struct Bounds {
+    pub(crate) min: Vector2D<Scalar>,
+    pub(crate) max: Vector2D<Scalar>,
+}
+
+impl Bounds {
+    pub fn size(&self) -> Vector2D<Scalar> {
+        self.max - self.min
+    }
+
+    /// The smallest rectangle containing all particle centers, if there are any.
+    pub fn enclosing(particles: &[Particle<Scalar>]) -> Option<Bounds> {
+        let first = particles.first()?.position;
+        Some(particles.iter().fold(
+            Bounds {
+                min: first,
+                max: first,
+            },
+            |bounds, particle| Bounds {
+                min: Vector2D {
+                    x: bounds.min.x.min(particle.position.x),
+                    y: bounds.min.y.min(particle.position.y),
+                },
+                max: Vector2D {
+                    x: bounds.max.x.max(particle.position.x),
+                    y: bounds.max.y.max(particle.position.y),
+                },
+            },
+        ))
+    }
+}
+
+/// What happens to particles at the edge of the world
+#[derive(Copy, Clone, Debug)]
+pub enum Boundary {
+    /// The world is unbounded
+    Open,
+    /// Particles bounce off the edges of the rectangle
+    Reflect(Bounds),
+    /// Opposite edges of the rectangle are connected, particles leaving on one side re-enter
+    /// on the other
+    Wrap(Bounds),
+    /// Particles farther than the radius from the center of mass are removed
+    Kill { radius: Scalar },
+}
+
+impl Boundary {
+    /// Applies the boundary to all particles after they moved. Bouncing off a wall keeps
+    /// `restitution` times the velocity towards it.
+    pub fn apply(&self, particles: &mut [Particle<Scalar>], restitution: Scalar) {
+        match self {
+            Boundary::Open | Boundary::Kill { .. } => {}
+            Boundary::Reflect(bounds) => {
+                for particle in particles {
+                    let (position, velocity) = (&mut particle.position, &mut particle.velocity);
+                    // the axes are independent, so a particle hitting a corner flips both components
+                    reflect_axis(
+                        &mut position.x,
+                        &mut velocity.x,
+                        bounds.min.x + particle.radius,
+                        bounds.max.x - particle.radius,
+                        restitution,
+                    );
+                    reflect_axis(
+                        &mut position.y,
+                        &mut velocity.y,
+                        bounds.min.y + particle.radius,
+                        bounds.max.y - particle.radius,
+                        restitution,
+                    );
+                }
+            }
+            Boundary::Wrap(bounds) => {
+                let size = bounds.size();
+                for particle in particles {
+                    let position = &mut particle.position;
+                    position.x = bounds.min.x + (position.x - bounds.min.x).rem_euclid(size.x);
+                    position.y = bounds.min.y + (position.y - bounds.min.y).rem_euclid(size.y);
+                }
+            }
+        }
+    }
+
+    /// The ascending indices of the particles that left the kill radius.
+    pub fn escaped(&self, particles: &[Particle<Scalar>]) -> Vec<usize> {
+        let Boundary::Kill { radius } = *self else {
+            return Vec::new();
+        };
+        let total_mass: Scalar = particles.iter().map(|particle| particle.mass).sum();
+        if particles.is_empty() || total_mass <= 0.0 {
+            return Vec::new();
+        }
+        let center = particles.iter().fold(Vector2D::default(), |sum, particle| {
+            &sum + particle.position * particle.mass
+        }) * (1.0 / total_mass);
+        (0..particles.len())
+            .filter(|&i| (particles[i].position - center).length_sq() > radius * radius)
+            .collect()
+    }
+
+    /// The rectangle of a bounded world.
+    pub fn bounds(&self) -> Option<Bounds> {
+        match self {
+            Boundary::Open | Boundary::Kill { .. } => None,
+            Boundary::Reflect(bounds) | Boundary::Wrap(bounds) => Some(*bounds),
+        }
+    }
+
+    /// The size of a periodic world.
+    pub fn period(&self) -> Option<Vector2D<Scalar>> {
+        match self {
+            Boundary::Wrap(bounds) => Some(bounds.size()),
+            _ => None,
+        }
+    }
+}
+
+/// Clamps a coordinate into `min..=max` and makes the velocity point back inside.
+fn reflect_axis(
+    position: &mut Scalar,
+    velocity: &mut Scalar,
+    min: Scalar,
+    max: Scalar,
+    restitution: Scalar,
+) {
+    if *position < min {
+        *position = min;
+        *velocity = velocity.abs() * restitution;
+    } else if *position > max {
+        *position = max;
+        *velocity = -velocity.abs() * restitution;
+    }
+}
+
+/// Parses bounds given as `x0,y0,x1,y1`.
+impl FromStr for Bounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<Scalar>())
+            .collect::<Result<Vec<Scalar>, _>>()
+            .map_err(|_| format!("invalid bounds: {}", s))?;
+        match values.as_slice() {
+            [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Ok(Bounds {
+                min: Vector2D { x: *x0, y: *y0 },
+                max: Vector2D { x: *x1, y: *y1 },
+            }),
+            [_, _, _, _] => Err(format!("bounds must have x0 < x1 and y0 < y1, got: {}", s)),
+            _ => Err(format!("expected x0,y0,x1,y1 for bounds, got: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::particle::create_particle;
+
+    fn moving(x: Scalar, y: Scalar, vx: Scalar, vy: Scalar) -> Particle<Scalar> {
+        let mut particle = create_particle(x, y, 1.0, 0.0);
+        particle.velocity = Vector2D { x: vx, y: vy };
+        particle
+    }
+
+    #[test]
+    fn walls_reflect_particles_at_the_same_speed() {
+        let boundary = Boundary::Reflect("0,0,100,50".parse().unwrap());
+        let mut particles = [moving(100.5, 20.0, 7.0, 0.0), moving(-3.0, 20.0, -2.0, 1.0)];
+        boundary.apply(&mut particles, 1.0);
+        // clamped so that the particles of radius 1 lie inside
+        assert_eq!(particles[0].position, Vector2D { x: 99.0, y: 20.0 });
+        assert_eq!(particles[0].velocity, Vector2D { x: -7.0, y: 0.0 });
+        assert_eq!(particles[1].position, Vector2D { x: 1.0, y: 20.0 });
+        assert_eq!(particles[1].velocity, Vector2D { x: 2.0, y: 1.0 });
+    }
+
+    #[test]
+    fn corners_flip_both_components() {
+        let boundary = Boundary::Reflect("0,0,100,50".parse().unwrap());
+        let mut particles = [moving(101.0, 52.0, 3.0, 4.0)];
+        boundary.apply(&mut particles, 0.5);
+        assert_eq!(particles[0].position, Vector2D { x: 99.0, y: 49.0 });
+        assert_eq!(particles[0].velocity, Vector2D { x: -1.5, y: -2.0 });
+    }
+
+    #[test]
+    fn rejects_inverted_bounds() {
+        assert_eq!(
+            "10,0,5,5".parse::<Bounds>().unwrap_err(),
+            "bounds must have x0 < x1 and y0 < y1, got: 10,0,5,5"
+        );
+    }
+}
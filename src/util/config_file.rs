@@ -0,0 +1,288 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::Scalar;
+
+/// One line of a config file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Setting {
+    GravConst(Scalar),
+    Damping(Scalar),
+    Theta(Scalar),
+    Softening(Scalar),
+    TimeScale(Scalar),
+    SwirlStrength(Scalar),
+    HotTemperature(Scalar),
+    /// Only read when the simulation starts, like the ones below
+    NumParticles(u32),
+    Seed(u64),
+    /// The name of a scenario as on the command line
+    Scenario(String),
+}
+
+impl Setting {
+    /// Whether the setting can be applied to a running simulation. The others decide how the
+    /// particles are created and need a restart.
+    pub fn is_live(&self) -> bool {
+        !matches!(
+            self,
+            Setting::NumParticles(_) | Setting::Seed(_) | Setting::Scenario(_)
+        )
+    }
+
+    /// The key of the setting in the file
+    pub fn key(&self) -> &'static str {
+        match self {
+            Setting::GravConst(_) => "grav_const",
+            Setting::Damping(_) => "damping",
+            Setting::Theta(_) => "theta",
+            Setting::Softening(_) => "softening",
+            Setting::TimeScale(_) => "time_scale",
+            Setting::SwirlStrength(_) => "swirl_strength",
+            Setting::HotTemperature(_) => "hot_temperature",
+            Setting::NumParticles(_) => "num_particles",
+            Setting::Seed(_) => "seed",
+            Setting::Scenario(_) => "scenario",
+        }
+    }
+}
+
+/// Parses a config file in a small subset of TOML: one `key = value` per line, numbers as they
+/// are, strings in double quotes and comments starting with `#`. Unknown keys are errors, so
+/// typos do not go unnoticed.
+pub fn parse_config(text: &str) -> Result<Vec<Setting>, String> {
+    let mut settings = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = value, got: {}", number + 1, line))?;
+        let (key, value) = (key.trim(), value.trim());
+        let invalid = || format!("line {}: invalid value for {}: {}", number + 1, key, value);
+        let scalar = || {
+            value
+                .parse::<Scalar>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .ok_or_else(invalid)
+        };
+        let setting = match key {
+            "grav_const" => Setting::GravConst(scalar()?),
+            "damping" => Setting::Damping(scalar()?),
+            "theta" => Setting::Theta(scalar()?),
+            "softening" => Setting::Softening(scalar()?),
+            "time_scale" => Setting::TimeScale(scalar()?),
+            "swirl_strength" => Setting::SwirlStrength(scalar()?),
+            "hot_temperature" => Setting::HotTemperature(scalar()?),
+            "num_particles" => Setting::NumParticles(value.parse().map_err(|_| invalid())?),
+            "seed" => Setting::Seed(value.parse().map_err(|_| invalid())?),
+            "scenario" => Setting::Scenario(
+                value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .ok_or_else(invalid)?
+                    .to_string(),
+            ),
+            _ => return Err(format!("line {}: unknown setting: {}", number + 1, key)),
+        };
+        settings.push(setting);
+    }
+    Ok(settings)
+}
+
+/// What changed when a watched config file was edited
+#[derive(Debug, PartialEq)]
+pub struct Reload {
+    /// Every live setting of the new file, to be applied right away
+    pub(crate) live: Vec<Setting>,
+    /// The settings that need a restart and differ from the ones the run started with
+    pub(crate) restart_required: Vec<Setting>,
+}
+
+/// A config file that is read again whenever its modification time changes
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    /// From the last file that could be parsed
+    settings: Vec<Setting>,
+}
+
+impl ConfigWatcher {
+    /// Reads and parses the config file, failing if either does not work.
+    pub fn load(path: &Path) -> Result<ConfigWatcher, String> {
+        let modified = modified(path);
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read config {}: {}", path.display(), e))?;
+        let settings =
+            parse_config(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e))?;
+        Ok(ConfigWatcher {
+            path: path.to_path_buf(),
+            modified,
+            settings,
+        })
+    }
+
+    /// The settings of the last file that could be parsed
+    pub fn settings(&self) -> &[Setting] {
+        &self.settings
+    }
+
+    /// Reads the file again if it was modified since the last check. Returns `None` if it was
+    /// not, and an error if it cannot be read or parsed, in which case the previous settings stay.
+    pub fn poll(&mut self) -> Option<Result<Reload, String>> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        let settings = match fs::read_to_string(&self.path) {
+            Ok(text) => parse_config(&text),
+            Err(e) => Err(e.to_string()),
+        };
+        Some(
+            settings
+                .map(|settings| {
+                    let restart_required = settings
+                        .iter()
+                        .filter(|setting| !setting.is_live() && !self.settings.contains(setting))
+                        .cloned()
+                        .collect();
+                    // the run keeps the settings it started with
+                    self.settings.retain(|setting| !setting.is_live());
+                    let live: Vec<Setting> = settings
+                        .into_iter()
+                        .filter(|setting| setting.is_live())
+                        .collect();
+                    self.settings.extend(live.iter().cloned());
+                    Reload {
+                        live,
+                        restart_required,
+                    }
+                })
+                .map_err(|e| format!("invalid config {}: {}", self.path.display(), e)),
+        )
+    }
+}
+
+/// The modification time of the file, `None` if it cannot be read
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn settings_are_classified_as_live_or_restart_required() {
+        let text = "# physics\n\
+                    grav_const = -2.5\n\
+                    damping = 0.1  # a little drag\n\
+                    theta = 0.5\n\
+                    softening = 1\n\
+                    time_scale = 2\n\
+                    swirl_strength = 3\n\
+                    hot_temperature = 20\n\
+                    \n\
+                    num_particles = 5000\n\
+                    seed = 42\n\
+                    scenario = \"disc\"\n";
+        let settings = parse_config(text).unwrap();
+        let live: Vec<&str> = settings
+            .iter()
+            .filter(|setting| setting.is_live())
+            .map(Setting::key)
+            .collect();
+        assert_eq!(
+            live,
+            [
+                "grav_const",
+                "damping",
+                "theta",
+                "softening",
+                "time_scale",
+                "swirl_strength",
+                "hot_temperature"
+            ]
+        );
+        assert_eq!(settings[0], Setting::GravConst(-2.5));
+        assert_eq!(
+            settings[7..],
+            [
+                Setting::NumParticles(5000),
+                Setting::Seed(42),
+                Setting::Scenario(String::from("disc"))
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        for text in [
+            "grav_const 10",
+            "grav_const = ten",
+            "damping = inf",
+            "num_particles = -3",
+            "scenario = disc",
+            "particle_count = 10",
+        ] {
+            assert!(parse_config(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn edits_are_picked_up_and_bad_ones_keep_the_previous_settings() {
+        let path = std::env::temp_dir().join(format!("config_{}.toml", std::process::id()));
+        let mut age = 100;
+        // a distinct modification time for every write, however coarse the file system is
+        let mut write = |text: &str| {
+            fs::write(&path, text).unwrap();
+            let time = SystemTime::now() - Duration::from_secs(age);
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+            age -= 1;
+        };
+        write("grav_const = 10\nnum_particles = 100\n");
+        let mut watcher = ConfigWatcher::load(&path).unwrap();
+        assert_eq!(watcher.poll(), None);
+
+        write("grav_const = 20\nnum_particles = 200\n");
+        assert_eq!(
+            watcher.poll(),
+            Some(Ok(Reload {
+                live: vec![Setting::GravConst(20.0)],
+                restart_required: vec![Setting::NumParticles(200)],
+            }))
+        );
+
+        write("grav_const = = 30\n");
+        assert!(watcher.poll().unwrap().is_err());
+        assert_eq!(
+            watcher.settings(),
+            [Setting::NumParticles(100), Setting::GravConst(20.0)]
+        );
+        assert_eq!(watcher.poll(), None);
+
+        write("grav_const = 30\nnum_particles = 100\n");
+        assert_eq!(
+            watcher.poll(),
+            Some(Ok(Reload {
+                live: vec![Setting::GravConst(30.0)],
+                restart_required: Vec::new(),
+            }))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
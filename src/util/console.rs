@@ -0,0 +1,191 @@
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+pub const USAGE: &str = "commands:
+  pause                 stop advancing the simulation
+  resume                continue advancing the simulation
+  set grav_const <value> change the gravitational constant
+  set damping <value>   change the drag coefficient, 0 disables drag
+  set leaf_capacity <n> change how many particles a leaf of the force tree holds
+  spawn <count>         add particles using the default distribution
+  force <i> <x> <y>     push particle i with the given force during the next step
+  impulse <i> <x> <y>   change the momentum of particle i right away
+  save <path>           write the current state to a binary state file
+  status                print the step, the particle count and how many were culled or clamped
+  tree                  print the shape of the force tree of the last step
+  quit                  exit the simulation";
+
+/// A command entered on the interactive console
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    SetGravConst(Scalar),
+    SetDamping(Scalar),
+    SetLeafCapacity(usize),
+    Spawn(u32),
+    Save(PathBuf),
+    Force(usize, Vector2D<Scalar>),
+    Impulse(usize, Vector2D<Scalar>),
+    Status,
+    Tree,
+    Quit,
+}
+
+/// Parses a single console line into a command.
+/// Returns an error message if the line is not a valid command.
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["pause"] => Ok(Command::Pause),
+        ["resume"] => Ok(Command::Resume),
+        ["set", "grav_const", value] => value
+            .parse()
+            .map(Command::SetGravConst)
+            .map_err(|_| format!("invalid value for grav_const: {}", value)),
+        ["set", "damping", value] => value
+            .parse()
+            .map(Command::SetDamping)
+            .map_err(|_| format!("invalid value for damping: {}", value)),
+        ["set", "leaf_capacity", value] => match value.parse() {
+            Ok(capacity) if capacity > 0 => Ok(Command::SetLeafCapacity(capacity)),
+            _ => Err(format!("invalid value for leaf_capacity: {}", value)),
+        },
+        ["set", name, _] => Err(format!("unknown setting: {}", name)),
+        ["spawn", count] => count
+            .parse()
+            .map(Command::Spawn)
+            .map_err(|_| format!("invalid particle count: {}", count)),
+        ["force", index, x, y] => {
+            parse_particle_vector(index, x, y).map(|(index, force)| Command::Force(index, force))
+        }
+        ["impulse", index, x, y] => parse_particle_vector(index, x, y)
+            .map(|(index, impulse)| Command::Impulse(index, impulse)),
+        ["save", path] => Ok(Command::Save(PathBuf::from(path))),
+        ["status"] => Ok(Command::Status),
+        ["tree"] => Ok(Command::Tree),
+        ["quit"] => Ok(Command::Quit),
+        _ => Err(format!("unknown command: {}", line.trim())),
+    }
+}
+
+/// Parses a particle index followed by the components of a vector.
+fn parse_particle_vector(
+    index: &str,
+    x: &str,
+    y: &str,
+) -> Result<(usize, Vector2D<Scalar>), String> {
+    let index = index
+        .parse()
+        .map_err(|_| format!("invalid particle index: {}", index))?;
+    match (x.parse(), y.parse()) {
+        (Ok(x), Ok(y)) => Ok((index, Vector2D { x, y })),
+        _ => Err(format!("invalid vector: {} {}", x, y)),
+    }
+}
+
+/// Reads commands from stdin on a background thread and queues them until the simulation is
+/// ready to apply them.
+pub struct Console {
+    commands: Receiver<Command>,
+}
+
+impl Console {
+    pub fn start() -> Console {
+        Console::from_reader(BufReader::new(std::io::stdin()))
+    }
+
+    /// Like [`Console::start`], but reads the commands from the given reader, e.g. a script.
+    pub fn from_reader<R: BufRead + Send + 'static>(reader: R) -> Console {
+        let (sender, commands) = mpsc::channel();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_command(&line) {
+                    Ok(command) => {
+                        if sender.send(command).is_err() {
+                            // the simulation is gone, nobody is listening anymore
+                            break;
+                        }
+                    }
+                    Err(message) => println!("{}\n{}", message, USAGE),
+                }
+            }
+        });
+        Console { commands }
+    }
+
+    /// Returns all commands entered since the last call, in order.
+    pub fn drain(&self) -> impl Iterator<Item = Command> + '_ {
+        self.commands.try_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_command() {
+        let cases = [
+            ("pause", Command::Pause),
+            ("resume", Command::Resume),
+            ("set grav_const 20", Command::SetGravConst(20.0)),
+            ("set grav_const -1.5", Command::SetGravConst(-1.5)),
+            ("set damping 0.25", Command::SetDamping(0.25)),
+            ("set leaf_capacity 16", Command::SetLeafCapacity(16)),
+            ("spawn 500", Command::Spawn(500)),
+            (
+                "force 3 1.5 -2",
+                Command::Force(3, Vector2D { x: 1.5, y: -2.0 }),
+            ),
+            (
+                "impulse 0 0 4",
+                Command::Impulse(0, Vector2D { x: 0.0, y: 4.0 }),
+            ),
+            ("save state.bin", Command::Save(PathBuf::from("state.bin"))),
+            ("status", Command::Status),
+            ("tree", Command::Tree),
+            ("quit", Command::Quit),
+        ];
+        for (line, command) in cases {
+            assert_eq!(parse_command(line), Ok(command), "{}", line);
+        }
+    }
+
+    #[test]
+    fn ignores_surrounding_whitespace() {
+        assert_eq!(
+            parse_command("  set   grav_const\t20  "),
+            Ok(Command::SetGravConst(20.0))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_lines_with_a_message() {
+        let cases = [
+            ("jump", "unknown command: jump"),
+            ("pause now", "unknown command: pause now"),
+            ("set gravity 1", "unknown setting: gravity"),
+            (
+                "set grav_const strong",
+                "invalid value for grav_const: strong",
+            ),
+            ("set leaf_capacity 0", "invalid value for leaf_capacity: 0"),
+            ("spawn -3", "invalid particle count: -3"),
+            ("force x 1 2", "invalid particle index: x"),
+            ("impulse 1 up 2", "invalid vector: up 2"),
+        ];
+        for (line, message) in cases {
+            assert_eq!(parse_command(line), Err(String::from(message)), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+/// A source that continuously adds particles at a fixed position
+#[derive(Copy, Clone, Debug)]
+pub struct Emitter {
+    pub(crate) position: Vector2D<Scalar>,
+    /// Particles per second of simulated time
+    pub(crate) rate: Scalar,
+    /// New particles get velocity components drawn uniformly from `-spread..=spread`
+    pub(crate) spread: Scalar,
+    /// Fractional particles carried over to the next step
+    pending: Scalar,
+}
+
+impl Emitter {
+    pub fn new(position: Vector2D<Scalar>, rate: Scalar, spread: Scalar) -> Emitter {
+        Emitter {
+            position,
+            rate,
+            spread,
+            pending: 0.0,
+        }
+    }
+
+    /// Returns how many particles are due after the given time has passed.
+    /// Fractions are kept, so low rates still emit particles over several steps.
+    pub fn due(&mut self, elapsed_s: Scalar) -> u32 {
+        self.pending += self.rate * elapsed_s;
+        let count = self.pending.floor();
+        self.pending -= count;
+        count as u32
+    }
+}
+
+/// Parses an emitter given as `x,y,rate` or `x,y,rate,spread`.
+/// The spread defaults to 0.
+impl FromStr for Emitter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<Scalar>())
+            .collect::<Result<Vec<Scalar>, _>>()
+            .map_err(|_| format!("invalid emitter: {}", s))?;
+        let (x, y, rate, spread) = match values.as_slice() {
+            [x, y, rate] => (*x, *y, *rate, 0.0),
+            [x, y, rate, spread] => (*x, *y, *rate, *spread),
+            _ => {
+                return Err(format!(
+                    "expected x,y,rate[,spread] for an emitter, got: {}",
+                    s
+                ))
+            }
+        };
+        if rate < 0.0 || spread < 0.0 {
+            return Err(format!(
+                "emitter rate and spread must not be negative: {}",
+                s
+            ));
+        }
+        Ok(Emitter::new(Vector2D { x, y }, rate, spread))
+    }
+}
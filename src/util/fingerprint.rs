@@ -0,0 +1,25 @@
+use crate::util::particle::Particle;
+use crate::Scalar;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hashes the exact bit patterns of all particle positions and velocities (FNV-1a), so that
+/// two runs can be compared cheaply. Any difference in the state, however small, changes it.
+pub fn fingerprint(particles: &[Particle<Scalar>]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for particle in particles {
+        for value in [
+            particle.position.x,
+            particle.position.y,
+            particle.velocity.x,
+            particle.velocity.y,
+        ] {
+            for byte in value.to_bits().to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        }
+    }
+    hash
+}
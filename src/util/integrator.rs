@@ -0,0 +1,726 @@
+use std::ops::{Add, Mul, Sub};
+
+use num_traits::Float;
+use tracing::info_span;
+
+use crate::util::particle_quad_tree::ForceParameters;
+use crate::util::vector2d::Vector2D;
+use crate::Particle;
+
+/// Evaluates the acceleration of every particle for a given state of the particles.
+pub trait ForceEvaluator<T> {
+    /// The parameters of the forces, which also describe drag, pinned particles and obstacles.
+    fn params(&self) -> &ForceParameters<'_, T>;
+
+    /// Writes the acceleration of every particle in `particles`, which may be a hypothetical state
+    /// that differs from the real particles, to `accelerations`.
+    /// Returns how many accelerations were clamped.
+    fn accelerations(
+        &self,
+        particles: &[Particle<T>],
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize;
+
+    /// Like `accelerations`, but only valid for the state at the beginning of the step, which
+    /// lets evaluators reuse structures built from it.
+    fn current_accelerations(
+        &self,
+        particles: &[Particle<T>],
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize {
+        self.accelerations(particles, accelerations)
+    }
+
+    /// Like `accelerations`, but only the entries of the particles marked in `active` are used,
+    /// so an evaluator may skip the others and leave them zero.
+    fn accelerations_of(
+        &self,
+        particles: &[Particle<T>],
+        active: &[bool],
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize {
+        let _ = active;
+        self.accelerations(particles, accelerations)
+    }
+}
+
+/// A scheme that advances the particles by one step.
+pub trait Integrator<T> {
+    /// Advances `particles` by `dt`, keeping pinned particles in place.
+    /// Returns how many accelerations were clamped.
+    fn step(
+        &mut self,
+        particles: &mut [Particle<T>],
+        forces: &dyn ForceEvaluator<T>,
+        dt: T,
+    ) -> usize;
+
+    /// Forgets any state carried over between steps, called whenever particles are added or
+    /// removed.
+    fn reset(&mut self) {}
+}
+
+/// Semi-implicit Euler: kick by the full step, then drift.
+#[derive(Default)]
+pub struct EulerIntegrator<T> {
+    accelerations: Vec<Vector2D<T>>, // reused across steps to avoid reallocation
+    double_buffer: bool,
+    back_buffer: Vec<Particle<T>>, // only used when double buffering
+}
+
+impl<T> EulerIntegrator<T> {
+    /// With `double_buffer`, the kick and drift write to a copy of the particles that replaces
+    /// them at the end of the step, so nothing reads a particle that was already updated.
+    pub fn new(double_buffer: bool) -> EulerIntegrator<T> {
+        EulerIntegrator {
+            accelerations: Vec::new(),
+            double_buffer,
+            back_buffer: Vec::new(),
+        }
+    }
+}
+
+impl<T> Integrator<T> for EulerIntegrator<T>
+where
+    T: Float + Default,
+{
+    fn step(
+        &mut self,
+        particles: &mut [Particle<T>],
+        forces: &dyn ForceEvaluator<T>,
+        dt: T,
+    ) -> usize {
+        let params = forces.params();
+        let clamped = forces.current_accelerations(particles, &mut self.accelerations);
+        if self.double_buffer {
+            self.back_buffer.clear();
+            self.back_buffer.extend_from_slice(particles);
+            accelerate(&mut self.back_buffer, &self.accelerations, params, dt);
+            drift(&mut self.back_buffer, params, dt);
+            particles.copy_from_slice(&self.back_buffer);
+        } else {
+            accelerate(particles, &self.accelerations, params, dt);
+            drift(particles, params, dt);
+        }
+        clamped
+    }
+}
+
+/// Kick-drift-kick leapfrog, evaluates the forces twice per step but conserves energy far better
+/// on orbits.
+#[derive(Default)]
+pub struct LeapfrogIntegrator<T> {
+    accelerations: Vec<Vector2D<T>>,
+}
+
+impl<T> Integrator<T> for LeapfrogIntegrator<T>
+where
+    T: Float + Default,
+{
+    fn step(
+        &mut self,
+        particles: &mut [Particle<T>],
+        forces: &dyn ForceEvaluator<T>,
+        dt: T,
+    ) -> usize {
+        let params = forces.params();
+        let half_step = dt / T::from(2).unwrap();
+        let first = forces.current_accelerations(particles, &mut self.accelerations);
+        accelerate(particles, &self.accelerations, params, half_step);
+        drift(particles, params, dt);
+        let second = forces.accelerations(particles, &mut self.accelerations);
+        accelerate(particles, &self.accelerations, params, half_step);
+        first.max(second)
+    }
+}
+
+/// Velocity Verlet, the same update as leapfrog but reusing the accelerations from the end of the
+/// previous step, so the forces are evaluated once per step.
+#[derive(Default)]
+pub struct VerletIntegrator<T> {
+    accelerations: Vec<Vector2D<T>>, // at the end of the last step
+}
+
+impl<T> Integrator<T> for VerletIntegrator<T>
+where
+    T: Float + Default,
+{
+    fn step(
+        &mut self,
+        particles: &mut [Particle<T>],
+        forces: &dyn ForceEvaluator<T>,
+        dt: T,
+    ) -> usize {
+        // x += v dt + a dt^2 / 2, then v += (a + a') dt / 2 with the new acceleration a'
+        let params = forces.params();
+        let half_step = dt / T::from(2).unwrap();
+        if self.accelerations.len() != particles.len() {
+            // the first step, or particles were added since the last one
+            forces.current_accelerations(particles, &mut self.accelerations);
+        }
+        accelerate(particles, &self.accelerations, params, half_step);
+        drift(particles, params, dt);
+        let clamped = forces.accelerations(particles, &mut self.accelerations);
+        accelerate(particles, &self.accelerations, params, half_step);
+        if !params.external_forces.is_empty() {
+            // the external forces only act during this step
+            self.accelerations.clear();
+        }
+        clamped
+    }
+
+    fn reset(&mut self) {
+        self.accelerations.clear();
+    }
+}
+
+/// Classical fourth order Runge-Kutta, evaluates the forces four times per step. Very accurate,
+/// but only recommended for small numbers of particles.
+#[derive(Default)]
+pub struct Rk4Integrator<T> {
+    accelerations: [Vec<Vector2D<T>>; 4],
+    velocities: [Vec<Vector2D<T>>; 4],
+    stage: Vec<Particle<T>>, // scratch copy holding the intermediate states
+}
+
+impl<T> Integrator<T> for Rk4Integrator<T>
+where
+    T: Float + Default,
+{
+    /// The intermediate stages are evaluated on a scratch copy, so `particles` only changes once
+    /// all four derivatives are known. Drag and the Coriolis term enter every stage as explicit
+    /// accelerations. Returns how many accelerations were clamped in the first stage.
+    fn step(
+        &mut self,
+        particles: &mut [Particle<T>],
+        forces: &dyn ForceEvaluator<T>,
+        dt: T,
+    ) -> usize {
+        let params = forces.params();
+        let two = T::from(2).unwrap();
+        let clamped = forces.current_accelerations(particles, &mut self.accelerations[0]);
+        add_velocity_accelerations(particles, params, &mut self.accelerations[0]);
+        self.velocities[0].clear();
+        self.velocities[0].extend(particles.iter().map(|p| p.velocity));
+
+        self.stage.clear();
+        self.stage.extend_from_slice(particles);
+        for (k, h) in [(1, dt / two), (2, dt / two), (3, dt)] {
+            // x = x0 + v_{k-1} * h, v = v0 + a_{k-1} * h
+            for (index, (scratch, particle)) in
+                self.stage.iter_mut().zip(particles.iter()).enumerate()
+            {
+                if is_pinned(params, index) {
+                    continue;
+                }
+                scratch.position = &particle.position + self.velocities[k - 1][index] * h;
+                scratch.velocity = &particle.velocity + self.accelerations[k - 1][index] * h;
+            }
+            forces.accelerations(&self.stage, &mut self.accelerations[k]);
+            add_velocity_accelerations(&self.stage, params, &mut self.accelerations[k]);
+            self.velocities[k].clear();
+            self.velocities[k].extend(self.stage.iter().map(|p| p.velocity));
+        }
+
+        let sixth = dt / T::from(6).unwrap();
+        let weighted = |stages: &[Vec<Vector2D<T>>; 4], index: usize| {
+            let middle = &stages[1][index] + stages[2][index];
+            let sum = &(&stages[0][index] + middle * two) + stages[3][index];
+            sum * sixth
+        };
+        for (index, particle) in particles.iter_mut().enumerate() {
+            if is_pinned(params, index) {
+                continue;
+            }
+            particle.position = &particle.position + weighted(&self.velocities, index);
+            particle.velocity = &particle.velocity + weighted(&self.accelerations, index);
+            for obstacle in params.obstacles {
+                obstacle.resolve(particle);
+            }
+        }
+        clamped
+    }
+}
+
+/// Kick-drift-kick leapfrog with individual timesteps: every particle takes steps of `dt / 2^bin`,
+/// with a power of two bin chosen from its acceleration at the start of each step. All particles
+/// drift with the finest step, but only those whose step ends are evaluated and kicked, so the
+/// forces of the outskirts of a cluster are evaluated far less often than those of its core.
+pub struct BlockIntegrator<T> {
+    /// Highest bin, the finest step is `dt / 2^max_level`
+    max_level: u32,
+    /// A particle's step is at most `accuracy * sqrt(length / |a|)`, where the length is the
+    /// larger of the softening and its radius
+    accuracy: T,
+    accelerations: Vec<Vector2D<T>>, // at the end of the last kick of every particle
+    evaluated: Vec<Vector2D<T>>,
+    bins: Vec<u32>,
+    active: Vec<bool>,
+}
+
+impl<T: Default> BlockIntegrator<T> {
+    pub fn new(max_level: u32, accuracy: T) -> Self {
+        BlockIntegrator {
+            max_level,
+            accuracy,
+            accelerations: Vec::new(),
+            evaluated: Vec::new(),
+            bins: Vec::new(),
+            active: Vec::new(),
+        }
+    }
+}
+
+impl<T> BlockIntegrator<T>
+where
+    T: Float + Default,
+{
+    /// The lowest bin whose step `dt / 2^bin` satisfies the accuracy criterion, at most the
+    /// highest bin.
+    fn bin(&self, particle: &Particle<T>, acceleration: Vector2D<T>, softening: T, dt: T) -> u32 {
+        let length = if softening > particle.radius {
+            softening
+        } else {
+            particle.radius
+        };
+        // step^2 * |a| <= accuracy^2 * length, without dividing by a vanishing acceleration
+        let limit = self.accuracy * self.accuracy * length;
+        let magnitude = acceleration.length();
+        let two = T::from(2).unwrap();
+        let mut step = dt;
+        let mut bin = 0;
+        while bin < self.max_level && step * step * magnitude > limit {
+            step = step / two;
+            bin += 1;
+        }
+        bin
+    }
+}
+
+impl<T> Integrator<T> for BlockIntegrator<T>
+where
+    T: Float + Default,
+{
+    fn step(
+        &mut self,
+        particles: &mut [Particle<T>],
+        forces: &dyn ForceEvaluator<T>,
+        dt: T,
+    ) -> usize {
+        let params = forces.params();
+        let mut clamped = 0;
+        if self.accelerations.len() != particles.len() {
+            // the first step, or particles were added since the last one
+            clamped = forces.current_accelerations(particles, &mut self.accelerations);
+        }
+        self.bins.clear();
+        for (particle, acceleration) in particles.iter().zip(&self.accelerations) {
+            let bin = self.bin(particle, *acceleration, params.softening, dt);
+            self.bins.push(bin);
+        }
+
+        // only as fine as the highest occupied bin requires
+        let finest = self.bins.iter().copied().max().unwrap_or(0);
+        let fine_steps = 1usize << finest;
+        let fine_step = dt / T::from(fine_steps).unwrap();
+        let two = T::from(2).unwrap();
+        for s in 0..fine_steps {
+            // opening half kicks of the particles whose step begins now
+            for (index, particle) in particles.iter_mut().enumerate() {
+                let steps = fine_steps >> self.bins[index];
+                if s % steps == 0 && !is_pinned(params, index) {
+                    let half_step = fine_step * T::from(steps).unwrap() / two;
+                    let delta_v = self.accelerations[index] * half_step;
+                    particle.velocity = kicked_velocity(particle, delta_v, params, half_step);
+                }
+            }
+            drift(particles, params, fine_step);
+
+            // closing half kicks of the particles whose step ends now, at the end of the whole
+            // step these are all particles, so everyone is synchronized again
+            self.active.clear();
+            self.active.extend(
+                self.bins
+                    .iter()
+                    .map(|bin| (s + 1) % (fine_steps >> bin) == 0),
+            );
+            let evaluated = forces.accelerations_of(particles, &self.active, &mut self.evaluated);
+            clamped = clamped.max(evaluated);
+            for (index, particle) in particles.iter_mut().enumerate() {
+                if !self.active[index] {
+                    continue;
+                }
+                self.accelerations[index] = self.evaluated[index];
+                if is_pinned(params, index) {
+                    continue;
+                }
+                let steps = fine_steps >> self.bins[index];
+                let half_step = fine_step * T::from(steps).unwrap() / two;
+                let delta_v = self.accelerations[index] * half_step;
+                particle.velocity = kicked_velocity(particle, delta_v, params, half_step);
+            }
+        }
+        if !params.external_forces.is_empty() {
+            // the external forces only act during this step
+            self.accelerations.clear();
+        }
+        clamped
+    }
+
+    fn reset(&mut self) {
+        self.accelerations.clear();
+    }
+}
+
+/// Adds the velocity dependent acceleration of every particle to `accelerations`.
+fn add_velocity_accelerations<T>(
+    particles: &[Particle<T>],
+    params: &ForceParameters<'_, T>,
+    accelerations: &mut [Vector2D<T>],
+) where
+    T: Copy + Default + Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+{
+    for (acceleration, particle) in accelerations.iter_mut().zip(particles) {
+        *acceleration = &*acceleration + velocity_acceleration(particle, params);
+    }
+}
+
+/// Adds the Coriolis acceleration `-2 * omega x v` of a rotating frame to the velocity `after`,
+/// which already includes all other changes of the step from `before`. The term is integrated
+/// implicitly on the average of both velocities, so it only turns the velocity; an explicit step
+/// would add energy every step and blow up for fast rotations.
+fn apply_coriolis<T>(
+    before: Vector2D<T>,
+    after: Vector2D<T>,
+    params: &ForceParameters<'_, T>,
+    elapsed_s: T,
+) -> Vector2D<T>
+where
+    T: Float + Default,
+{
+    let turn = params.frame_rotation * elapsed_s;
+    if turn == T::default() {
+        return after;
+    }
+    // solves v' = after - turn * perp(before + v') for v'
+    let w = after - before.perpendicular() * turn;
+    let one = T::from(1).unwrap();
+    (w - w.perpendicular() * turn) * (one / (one + turn * turn))
+}
+
+/// Changes the velocities of all elements that are not pinned by the given accelerations acting
+/// for `elapsed_s`, including drag and the Coriolis term, without moving them.
+pub fn accelerate<T>(
+    elements: &mut [Particle<T>],
+    accelerations: &[Vector2D<T>],
+    params: &ForceParameters<'_, T>,
+    elapsed_s: T,
+) where
+    T: Float + Default,
+{
+    let _span = info_span!("integration", particles = elements.len()).entered();
+    for (index, (particle, acceleration)) in elements.iter_mut().zip(accelerations).enumerate() {
+        if !is_pinned(params, index) {
+            let delta_v = *acceleration * elapsed_s;
+            particle.velocity = kicked_velocity(particle, delta_v, params, elapsed_s);
+        }
+    }
+}
+
+/// The acceleration of `particle` that depends on its velocity: drag and, in a rotating frame, the
+/// Coriolis term `-2 * omega x v`. The kicks integrate these implicitly instead.
+pub fn velocity_acceleration<T>(
+    particle: &Particle<T>,
+    params: &ForceParameters<'_, T>,
+) -> Vector2D<T>
+where
+    T: Copy + Default + Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+{
+    let drag = particle.velocity * (T::default() - damping(params, particle));
+    let omega = params.frame_rotation;
+    let coriolis = particle.velocity.perpendicular() * (T::default() - omega - omega);
+    &drag + coriolis
+}
+
+/// Whether the element at `index` is kept in place.
+pub fn is_pinned<T>(params: &ForceParameters<'_, T>, index: usize) -> bool {
+    params.pinned.get(index).copied().unwrap_or(false)
+}
+
+/// The velocity of `particle` after adding `delta_v`, removing drag and turning it by the Coriolis
+/// term over `elapsed_s`.
+fn kicked_velocity<T>(
+    particle: &Particle<T>,
+    delta_v: Vector2D<T>,
+    params: &ForceParameters<'_, T>,
+    elapsed_s: T,
+) -> Vector2D<T>
+where
+    T: Float + Default,
+{
+    let drag = particle.velocity * (damping(params, particle) * elapsed_s);
+    let velocity = (&particle.velocity + delta_v) - drag;
+    apply_coriolis(particle.velocity, velocity, params, elapsed_s)
+}
+
+/// Moves all elements that are not pinned along their velocities for `elapsed_s` and lets them
+/// bounce off the obstacles.
+pub fn drift<T>(elements: &mut [Particle<T>], params: &ForceParameters<'_, T>, elapsed_s: T)
+where
+    T: Float + Default,
+{
+    let _span = info_span!("integration", particles = elements.len()).entered();
+    for (index, particle) in elements.iter_mut().enumerate() {
+        if is_pinned(params, index) {
+            continue;
+        }
+        particle.position = &particle.position + (particle.velocity * elapsed_s);
+        for obstacle in params.obstacles {
+            obstacle.resolve(particle);
+        }
+    }
+}
+
+/// The global damping plus the drag of the particle's species.
+fn damping<T: Copy + Default + Add<Output = T>>(
+    params: &ForceParameters<'_, T>,
+    particle: &Particle<T>,
+) -> T {
+    let species_drag = params.species_drag.get(particle.species as usize);
+    params.damping + species_drag.copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::particle::create_particle;
+    use crate::util::particle_quad_tree::{ParticleQuadTree, QuadtreeVisitorMut};
+    use crate::Scalar;
+    use std::cell::RefCell;
+    use std::ops::ControlFlow;
+
+    /// Accelerates every particle towards the mean position of the others, and remembers the
+    /// states it was evaluated for
+    struct Centering<'a> {
+        params: ForceParameters<'a, Scalar>,
+        seen: RefCell<Vec<Vec<Particle<Scalar>>>>,
+    }
+
+    impl ForceEvaluator<Scalar> for Centering<'_> {
+        fn params(&self) -> &ForceParameters<'_, Scalar> {
+            &self.params
+        }
+
+        fn accelerations(
+            &self,
+            particles: &[Particle<Scalar>],
+            accelerations: &mut Vec<Vector2D<Scalar>>,
+        ) -> usize {
+            self.seen.borrow_mut().push(particles.to_vec());
+            *accelerations = centering(particles);
+            0
+        }
+    }
+
+    fn centering(particles: &[Particle<Scalar>]) -> Vec<Vector2D<Scalar>> {
+        let others = (particles.len() - 1) as Scalar;
+        particles
+            .iter()
+            .enumerate()
+            .map(|(i, particle)| {
+                let mut sum = Vector2D::default();
+                for (j, other) in particles.iter().enumerate() {
+                    if j != i {
+                        sum = &sum + (other.position - particle.position);
+                    }
+                }
+                sum * (1.0 / others)
+            })
+            .collect()
+    }
+
+    fn no_forces() -> ForceParameters<'static, Scalar> {
+        ForceParameters {
+            grav_const: 0.0,
+            force_exponent: 1.0,
+            solid_interior: false,
+            softening: 0.0,
+            theta: 0.5,
+            coulomb_const: 0.0,
+            repulsion: None,
+            neighbors: None,
+            damping: 0.0,
+            species_drag: &[],
+            uniform_field: Vector2D::default(),
+            swirl_strength: 0.0,
+            swirl_center: Vector2D::default(),
+            frame_rotation: 0.0,
+            frame_center: Vector2D::default(),
+            attractors: &[],
+            obstacles: &[],
+            springs: &[],
+            extra_accelerations: &[],
+            external_forces: &[],
+            pinned: &[],
+            period: None,
+            max_acceleration: None,
+        }
+    }
+
+    #[test]
+    fn double_buffered_particles_only_read_the_state_before_the_step() {
+        let mut particles = vec![
+            create_particle(0.0, 0.0, 1.0, 0.0),
+            create_particle(4.0, 0.0, 1.0, 0.0),
+            create_particle(0.0, 3.0, 1.0, 0.0),
+        ];
+        particles[1].velocity = Vector2D { x: 0.5, y: -1.0 };
+        let before = particles.clone();
+        let forces = Centering {
+            params: no_forces(),
+            seen: RefCell::new(Vec::new()),
+        };
+        let dt = 0.1;
+        EulerIntegrator::new(true).step(&mut particles, &forces, dt);
+
+        let seen = forces.seen.borrow();
+        assert_eq!(seen.len(), 1);
+        let snapshot: Vec<_> = seen[0].iter().map(|p| (p.position, p.velocity)).collect();
+        let initial: Vec<_> = before.iter().map(|p| (p.position, p.velocity)).collect();
+        assert_eq!(snapshot, initial);
+        // every particle moves from its own old state with the acceleration of the old state
+        for ((particle, old), acceleration) in particles.iter().zip(&before).zip(centering(&before))
+        {
+            let velocity = &old.velocity + acceleration * dt;
+            assert_eq!(particle.velocity, velocity);
+            assert_eq!(particle.position, &old.position + velocity * dt);
+        }
+    }
+
+    #[test]
+    fn euler_steps_match_the_update_rule_before_the_integrator_trait() {
+        let mut particles = vec![
+            create_particle(0.0, 0.0, 1.0, 0.0),
+            create_particle(4.0, 1.0, 1.0, 0.0),
+            create_particle(-1.0, 3.0, 1.0, 0.0),
+            create_particle(2.0, -2.0, 1.0, 0.0),
+        ];
+        particles[1].velocity = Vector2D { x: 0.5, y: -1.0 };
+        particles[2].species = 1;
+        particles[3].velocity = Vector2D { x: -0.2, y: 0.3 };
+        let species_drag = [0.0, 0.3];
+        let pinned = [false, false, false, true];
+        let params = ForceParameters {
+            damping: 0.05,
+            species_drag: &species_drag,
+            frame_rotation: 0.2,
+            pinned: &pinned,
+            ..no_forces()
+        };
+        let dt = 0.05;
+
+        // the kick and drift of the old ParticleQuadTree::tick, written out
+        let mut expected = particles.clone();
+        for _ in 0..50 {
+            let accelerations = centering(&expected);
+            for (index, (particle, acceleration)) in
+                expected.iter_mut().zip(accelerations).enumerate()
+            {
+                if pinned[index] {
+                    continue;
+                }
+                let damping = params.damping + species_drag[particle.species as usize];
+                let drag = particle.velocity * (damping * dt);
+                let after = (&particle.velocity + acceleration * dt) - drag;
+                let turn = params.frame_rotation * dt;
+                let w = after - particle.velocity.perpendicular() * turn;
+                particle.velocity = (w - w.perpendicular() * turn) * (1.0 / (1.0 + turn * turn));
+                particle.position = &particle.position + (particle.velocity * dt);
+            }
+        }
+
+        for double_buffer in [false, true] {
+            let forces = Centering {
+                params,
+                seen: RefCell::new(Vec::new()),
+            };
+            let mut integrator = EulerIntegrator::new(double_buffer);
+            let mut actual = particles.clone();
+            for _ in 0..50 {
+                integrator.step(&mut actual, &forces, dt);
+            }
+            let state = |particles: &[Particle<Scalar>]| -> Vec<_> {
+                particles.iter().map(|p| (p.position, p.velocity)).collect()
+            };
+            assert_eq!(
+                state(&actual),
+                state(&expected),
+                "double buffered: {}",
+                double_buffer
+            );
+        }
+    }
+
+    /// Applies the drag of a kick as a visitor, leaf after leaf instead of in index order
+    struct DragVisitor<'a> {
+        params: ForceParameters<'a, Scalar>,
+        dt: Scalar,
+    }
+
+    impl QuadtreeVisitorMut<Scalar> for DragVisitor<'_> {
+        fn visit_element(
+            &mut self,
+            _leaf: &ParticleQuadTree<Scalar>,
+            _index: usize,
+            particle: &mut Particle<Scalar>,
+        ) -> ControlFlow<()> {
+            let drag = particle.velocity * (damping(&self.params, particle) * self.dt);
+            particle.velocity = particle.velocity - drag;
+            ControlFlow::Continue(())
+        }
+    }
+
+    #[test]
+    fn drag_applied_by_a_visitor_matches_the_kick() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(319);
+        let particles: Vec<_> = (0..500)
+            .map(|i| {
+                let mut particle = create_particle(
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                    1.0,
+                    0.0,
+                );
+                particle.velocity = Vector2D {
+                    x: rng.gen_range(-5.0..5.0),
+                    y: rng.gen_range(-5.0..5.0),
+                };
+                particle.species = (i % 3) as u8;
+                particle
+            })
+            .collect();
+        let species_drag = [0.0, 0.2, 1.5];
+        let params = ForceParameters {
+            damping: 0.1,
+            species_drag: &species_drag,
+            ..no_forces()
+        };
+        let dt = 0.02;
+        // drag only changes velocities, so the tree stays valid for every step
+        let tree = ParticleQuadTree::build(&particles, 8);
+        let no_accelerations = vec![Vector2D::default(); particles.len()];
+        let mut kicked = particles.clone();
+        let mut visited = particles.clone();
+        for _ in 0..100 {
+            accelerate(&mut kicked, &no_accelerations, &params, dt);
+            let mut drag = DragVisitor { params, dt };
+            assert!(tree.visit_mut(&mut visited, &mut drag).is_continue());
+        }
+        for ((kicked, visited), before) in kicked.iter().zip(&visited).zip(&particles) {
+            assert_eq!(visited.velocity, kicked.velocity);
+            assert_eq!(visited.position, before.position);
+            assert!(visited.velocity.length() < before.velocity.length());
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use std::ops::Add;
+
+use num_traits::Float;
+
+use crate::util::particle_quad_tree::{
+    displacement, enclosing_bounds, interior_radius, pull, summarize, ForceParameters, ForceStats,
+    ForceTree,
+};
+use crate::util::vector2d::Vector2D;
+use crate::Particle;
+
+/// Bits per coordinate of the grid the elements are quantized to, one for every level below the
+/// root. Cells of the finest level hold any number of elements, like the deepest leaves of a
+/// [`ParticleQuadTree`](crate::util::particle_quad_tree::ParticleQuadTree).
+const KEY_BITS: u32 = 24;
+
+/// A linear quadtree for force evaluation. The elements are quantized to a square grid over their
+/// bounds and sorted by the Morton code of their cell, which interleaves the bits of both grid
+/// coordinates (Z-order curve). The elements of every node are then a contiguous range of the
+/// sorted order, and the nodes follow from the common prefixes of the codes. Building it sorts
+/// once instead of partitioning the elements on every level, and the nodes lie in one array.
+pub struct MortonTree<T> {
+    max_capacity: usize,
+    /// Morton code of every element together with its index, sorted by code
+    keys: Vec<(u64, usize)>,
+    /// Second buffer of the radix sort, kept to reuse it
+    sorted_keys: Vec<(u64, usize)>,
+    /// Nodes in depth-first order, the children in the order top left, top right, bottom left,
+    /// bottom right. Empty children are left out.
+    nodes: Vec<MortonNode<T>>,
+}
+
+struct MortonNode<T> {
+    /// Corner with the smallest coordinates
+    min: Vector2D<T>,
+    width: T,
+    summary_particle: Particle<T>,
+    /// The elements of this node are `keys[start..end]`
+    start: usize,
+    end: usize,
+    /// Index of the first node after the descendants of this one
+    next: usize,
+    is_leaf: bool,
+}
+
+impl<T: Float + Default> MortonTree<T> {
+    pub(crate) fn new(max_capacity: usize) -> MortonTree<T> {
+        MortonTree {
+            max_capacity,
+            keys: Vec::new(),
+            sorted_keys: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// The side of the smallest leaf, `None` if the tree is empty
+    pub(crate) fn smallest_leaf(&self) -> Option<T> {
+        self.nodes
+            .iter()
+            .filter(|node| node.is_leaf)
+            .map(|node| node.width)
+            .reduce(|smallest, width| if width < smallest { width } else { smallest })
+    }
+
+    /// Appends the node of the given cell holding `keys[start..end]` and all nodes below it,
+    /// then sets its summary from theirs
+    fn build_node(
+        &mut self,
+        elements: &[Particle<T>],
+        (start, end): (usize, usize),
+        level: u32,
+        (cell_x, cell_y): (usize, usize),
+        (origin, size): (Vector2D<T>, T),
+    ) {
+        let index = self.nodes.len();
+        let width = size / T::from(1 << level).unwrap();
+        let min = Vector2D {
+            x: origin.x + T::from(cell_x).unwrap() * width,
+            y: origin.y + T::from(cell_y).unwrap() * width,
+        };
+        let is_leaf = end - start <= self.max_capacity || level == KEY_BITS;
+        self.nodes.push(MortonNode {
+            min,
+            width,
+            summary_particle: summarize(std::iter::empty::<(&Particle<T>, usize)>(), min).0,
+            start,
+            end,
+            next: 0,
+            is_leaf,
+        });
+
+        let summary_particle = if is_leaf {
+            let parts = self.keys[start..end]
+                .iter()
+                .map(|&(_, i)| (&elements[i], 1));
+            summarize(parts, min).0
+        } else {
+            // the two bits of this level select the child, the one of y first, so the codes
+            // sort top left, top right, bottom left, bottom right
+            let shift = 2 * (KEY_BITS - 1 - level);
+            let mut child_start = start;
+            for quadrant in 0..4 {
+                let child_end = start
+                    + self.keys[start..end]
+                        .partition_point(|&(key, _)| (key >> shift) & 3 <= quadrant);
+                if child_end > child_start {
+                    let child_cell = (
+                        2 * cell_x + (quadrant & 1) as usize,
+                        2 * cell_y + (quadrant >> 1) as usize,
+                    );
+                    self.build_node(
+                        elements,
+                        (child_start, child_end),
+                        level + 1,
+                        child_cell,
+                        (origin, size),
+                    );
+                }
+                child_start = child_end;
+            }
+            let descendants_end = self.nodes.len();
+            let children = std::iter::successors(Some(index + 1), |&child| {
+                Some(self.nodes[child].next).filter(|&next| next < descendants_end)
+            });
+            let parts = children.map(|child| {
+                let child = &self.nodes[child];
+                (&child.summary_particle, child.end - child.start)
+            });
+            summarize(parts, min).0
+        };
+        self.nodes[index].summary_particle = summary_particle;
+        self.nodes[index].next = self.nodes.len();
+    }
+}
+
+impl<T: Copy + PartialOrd + Add<Output = T>> MortonNode<T> {
+    fn contains(&self, point: Vector2D<T>) -> bool {
+        point.x >= self.min.x
+            && point.y >= self.min.y
+            && point.x <= self.min.x + self.width
+            && point.y <= self.min.y + self.width
+    }
+}
+
+impl<T: Float + Default + Send + Sync> ForceTree<T> for MortonTree<T> {
+    /// Rebuilds the tree over all elements on a grid covering their bounds. The storage of the
+    /// previous build is reused.
+    fn rebuild(&mut self, elements: &[Particle<T>]) {
+        self.nodes.clear();
+        self.keys.clear();
+        if elements.is_empty() {
+            return;
+        }
+
+        let (min, max) = enclosing_bounds(elements);
+        let (width, height) = (max.x - min.x, max.y - min.y);
+        let size = if width > height { width } else { height };
+        let cells = 1 << KEY_BITS;
+        let scale = if size > T::default() {
+            T::from(cells).unwrap() / size
+        } else {
+            T::default()
+        };
+        let cell = |value: T| (value * scale).to_usize().unwrap_or(0).min(cells - 1);
+        self.keys
+            .extend(elements.iter().enumerate().map(|(index, element)| {
+                let x = cell(element.position.x - min.x) as u64;
+                let y = cell(element.position.y - min.y) as u64;
+                ((spread_bits(y) << 1) | spread_bits(x), index)
+            }));
+        radix_sort(&mut self.keys, &mut self.sorted_keys);
+
+        self.build_node(elements, (0, elements.len()), 0, (0, 0), (min, size));
+    }
+
+    /// Like [`ParticleQuadTree`](crate::util::particle_quad_tree::ParticleQuadTree), a node that
+    /// appears small from the element's position is approximated by its summary particle.
+    /// Skipping a node continues with the node after its descendants, so the traversal walks the
+    /// node array front to back without recursion.
+    fn accumulate_pull(
+        &self,
+        elements: &[Particle<T>],
+        index: usize,
+        params: &ForceParameters<'_, T>,
+        elapsed_s: T,
+        delta_v: &mut Vector2D<T>,
+        stats: &mut ForceStats,
+    ) {
+        let element = &elements[index];
+        let position = element.position;
+        let mut current = 0;
+        while let Some(node) = self.nodes.get(current) {
+            let distance_sq =
+                displacement(position, node.summary_particle.position, params.period).length_sq();
+            let is_far = node.width * node.width < params.theta * params.theta * distance_sq;
+            if is_far && !node.contains(position) {
+                let pull = pull(element, &node.summary_particle, params, T::default());
+                *delta_v = &*delta_v + (pull * elapsed_s);
+                stats.approximations += 1;
+                current = node.next;
+            } else if node.is_leaf {
+                for &(_, other) in &self.keys[node.start..node.end] {
+                    if other == index {
+                        continue;
+                    }
+                    let pull = pull(
+                        element,
+                        &elements[other],
+                        params,
+                        interior_radius(element, &elements[other], params),
+                    );
+                    *delta_v = &*delta_v + (pull * elapsed_s);
+                    if let (Some(repulsion), None) = (&params.repulsion, params.neighbors) {
+                        let push = repulsion.push(element, &elements[other], params.period);
+                        *delta_v = &*delta_v + (push * elapsed_s);
+                    }
+                    stats.interactions += 1;
+                }
+                current = node.next;
+            } else {
+                current += 1;
+            }
+        }
+    }
+}
+
+/// Moves the lower `KEY_BITS` bits of the value to the even bit positions, so that two of them
+/// interleave into a Morton code
+fn spread_bits(value: u64) -> u64 {
+    let mut value = value & 0xffff_ffff;
+    value = (value | (value << 16)) & 0x0000_ffff_0000_ffff;
+    value = (value | (value << 8)) & 0x00ff_00ff_00ff_00ff;
+    value = (value | (value << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | (value << 2)) & 0x3333_3333_3333_3333;
+    (value | (value << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Sorts the codes byte by byte, least significant first. Every pass is stable, so elements in
+/// the same cell keep the order of their indices, and the leaves do not depend on the sorting.
+fn radix_sort(keys: &mut Vec<(u64, usize)>, buffer: &mut Vec<(u64, usize)>) {
+    buffer.clear();
+    buffer.resize(keys.len(), (0, 0));
+    let mut shift = 0;
+    while shift < 2 * KEY_BITS {
+        let mut offsets = [0; 256];
+        for &(key, _) in keys.iter() {
+            offsets[(key >> shift) as usize & 0xff] += 1;
+        }
+        let mut total = 0;
+        for offset in offsets.iter_mut() {
+            let count = *offset;
+            *offset = total;
+            total += count;
+        }
+        for &entry in keys.iter() {
+            let digit = (entry.0 >> shift) as usize & 0xff;
+            buffer[offsets[digit]] = entry;
+            offsets[digit] += 1;
+        }
+        std::mem::swap(keys, buffer);
+        shift += 8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::particle_quad_tree::ParticleQuadTree;
+    use rand::{Rng, SeedableRng};
+    use rand_distr::StandardNormal;
+
+    /// Plain 1/r gravity with the given opening angle, and nothing else
+    fn params(theta: f64) -> ForceParameters<'static, f64> {
+        ForceParameters {
+            grav_const: 10.0,
+            force_exponent: 1.0,
+            solid_interior: false,
+            softening: 0.5,
+            theta,
+            coulomb_const: 0.0,
+            repulsion: None,
+            neighbors: None,
+            damping: 0.0,
+            species_drag: &[],
+            uniform_field: Vector2D::default(),
+            swirl_strength: 0.0,
+            swirl_center: Vector2D::default(),
+            frame_rotation: 0.0,
+            frame_center: Vector2D::default(),
+            attractors: &[],
+            obstacles: &[],
+            springs: &[],
+            extra_accelerations: &[],
+            external_forces: &[],
+            pinned: &[],
+            period: None,
+            max_acceleration: None,
+        }
+    }
+
+    /// Two clusters of different width on a sparse background
+    fn clusters(seed: u64, count: usize) -> Vec<Particle<f64>> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|i| {
+                let (center, spread) = match i % 3 {
+                    0 => (Vector2D { x: -40.0, y: 10.0 }, 5.0),
+                    1 => (Vector2D { x: 30.0, y: -20.0 }, 20.0),
+                    _ => (Vector2D::default(), 100.0),
+                };
+                let x: f64 = rng.sample(StandardNormal);
+                let y: f64 = rng.sample(StandardNormal);
+                Particle {
+                    position: Vector2D {
+                        x: center.x + spread * x,
+                        y: center.y + spread * y,
+                    },
+                    velocity: Vector2D::default(),
+                    radius: 1.0,
+                    mass: rng.gen_range(0.5..2.0),
+                    charge: 0.0,
+                    species: 0,
+                }
+            })
+            .collect()
+    }
+
+    fn accelerations(
+        tree: &mut dyn ForceTree<f64>,
+        elements: &[Particle<f64>],
+        params: &ForceParameters<'_, f64>,
+    ) -> Vec<Vector2D<f64>> {
+        tree.rebuild(elements);
+        let mut accelerations = Vec::new();
+        tree.accelerations(elements, params, &mut accelerations);
+        accelerations
+    }
+
+    /// The root mean square of the differences relative to the one of the exact values
+    fn relative_error(approximated: &[Vector2D<f64>], exact: &[Vector2D<f64>]) -> f64 {
+        let (error_sq, norm_sq) =
+            approximated
+                .iter()
+                .zip(exact)
+                .fold((0.0, 0.0), |(error_sq, norm_sq), (a, b)| {
+                    (error_sq + (*a - *b).length_sq(), norm_sq + b.length_sq())
+                });
+        (error_sq / norm_sq).sqrt()
+    }
+
+    #[test]
+    fn forces_agree_with_the_pointer_tree() {
+        let elements = clusters(324, 2000);
+        let mut morton = MortonTree::new(8);
+        let mut pointer = ParticleQuadTree::build(&[], 8);
+        // without approximations both sum up every pair
+        let exact = accelerations(&mut pointer, &elements, &params(0.0));
+        let morton_exact = accelerations(&mut morton, &elements, &params(0.0));
+        assert!(relative_error(&morton_exact, &exact) < 1e-12);
+        for theta in [0.3, 0.5, 0.8] {
+            let from_morton = accelerations(&mut morton, &elements, &params(theta));
+            let from_pointer = accelerations(&mut pointer, &elements, &params(theta));
+            let morton_error = relative_error(&from_morton, &exact);
+            let pointer_error = relative_error(&from_pointer, &exact);
+            assert!(
+                relative_error(&from_morton, &from_pointer) < 0.03,
+                "theta {}: the trees differ by {}",
+                theta,
+                relative_error(&from_morton, &from_pointer)
+            );
+            assert!(
+                morton_error < 2.0 * pointer_error + 1e-3,
+                "theta {}: errors {} and {}",
+                theta,
+                morton_error,
+                pointer_error
+            );
+        }
+    }
+
+    #[test]
+    fn forces_stay_close_to_brute_force_at_extreme_leaf_capacities() {
+        let elements = clusters(321, 2000);
+        let exact = accelerations(
+            &mut ParticleQuadTree::build(&[], 8),
+            &elements,
+            &params(0.0),
+        );
+        for capacity in [1, 10_000] {
+            let trees: [Box<dyn ForceTree<f64>>; 2] = [
+                Box::new(MortonTree::new(capacity)),
+                Box::new(ParticleQuadTree::build(&[], capacity)),
+            ];
+            for mut tree in trees {
+                let error =
+                    relative_error(&accelerations(&mut *tree, &elements, &params(0.7)), &exact);
+                // a single leaf sums up every pair
+                let tolerance = if capacity > elements.len() {
+                    1e-12
+                } else {
+                    0.05
+                };
+                assert!(error < tolerance, "capacity {}: error {}", capacity, error);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn morton_trees_build_faster_for_many_elements() {
+        use std::time::Instant;
+        let params = params(0.7);
+        for count in [10_000, 100_000, 500_000] {
+            let elements = clusters(3240, count);
+            let time = |tree: &mut dyn ForceTree<f64>| {
+                let start = Instant::now();
+                tree.rebuild(&elements);
+                let build = start.elapsed();
+                let mut accelerations = Vec::new();
+                tree.accelerations(&elements, &params, &mut accelerations);
+                (build, start.elapsed() - build)
+            };
+            let (morton_build, morton_forces) = time(&mut MortonTree::new(8));
+            let (pointer_build, pointer_forces) = time(&mut ParticleQuadTree::build(&[], 8));
+            println!(
+                "{} elements: Morton built in {:.2?}, forces in {:.2?}; pointer built in {:.2?}, \
+                 forces in {:.2?}",
+                count, morton_build, morton_forces, pointer_build, pointer_forces
+            );
+        }
+    }
+}
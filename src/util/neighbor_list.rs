@@ -0,0 +1,209 @@
+use num_traits::Float;
+
+use crate::util::particle::Particle;
+use crate::util::particle_quad_tree::ParticleQuadTree;
+use crate::util::vector2d::Vector2D;
+
+/// Leaf capacity of the tree the lists are searched in
+const LEAF_CAPACITY: usize = 16;
+
+/// Verlet neighbor lists for the short-range forces: every particle lists the others within the
+/// cutoff plus a skin distance. While no particle moved farther than half the skin since the
+/// lists were built, no pair can have come closer than the cutoff without being listed, so the
+/// lists are reused instead of searching the neighbors in every step. With a skin of zero they
+/// are rebuilt whenever any particle moved. Pairs across the seam of a periodic world are not
+/// listed.
+pub struct NeighborList<T> {
+    skin: T,
+    /// The cutoff the lists were built for
+    cutoff: T,
+    /// The neighbors of particle `i` are `indices[offsets[i]..offsets[i + 1]]`, ascending
+    offsets: Vec<usize>,
+    indices: Vec<usize>,
+    /// Positions at the last build, empty if the lists have to be rebuilt regardless
+    positions: Vec<Vector2D<T>>,
+    /// Rebuilt from the current positions for every build of the lists
+    tree: ParticleQuadTree<T>,
+    rebuilds: u64,
+    checks: u64,
+}
+
+impl<T> NeighborList<T> {
+    /// Makes the next update rebuild the lists, e.g. after particles were removed
+    pub fn invalidate(&mut self) {
+        self.positions.clear();
+    }
+
+    /// The indices of the particles listed as neighbors of the one at `index`, in ascending order
+    pub fn neighbors(&self, index: usize) -> &[usize] {
+        &self.indices[self.offsets[index]..self.offsets[index + 1]]
+    }
+
+    /// How often the lists were rebuilt
+    pub fn rebuilds(&self) -> u64 {
+        self.rebuilds
+    }
+
+    /// How often the lists were checked for a rebuild, rebuilds included
+    pub fn checks(&self) -> u64 {
+        self.checks
+    }
+}
+
+impl<T: Float + Default + std::fmt::Display + Send + Sync> NeighborList<T> {
+    pub fn new(skin: T) -> NeighborList<T> {
+        NeighborList {
+            skin,
+            cutoff: T::default(),
+            offsets: vec![0],
+            indices: Vec::new(),
+            positions: Vec::new(),
+            tree: ParticleQuadTree::new(
+                Vector2D::default(),
+                T::default(),
+                T::default(),
+                LEAF_CAPACITY,
+            ),
+            rebuilds: 0,
+            checks: 0,
+        }
+    }
+
+    /// Whether the lists have to be rebuilt for the particles and the cutoff. Added or removed
+    /// particles always need a rebuild.
+    pub fn needs_rebuild(&self, particles: &[Particle<T>], cutoff: T) -> bool {
+        if self.positions.len() != particles.len() || cutoff != self.cutoff {
+            return true;
+        }
+        let half_skin = self.skin / T::from(2).unwrap();
+        let max_sq = half_skin * half_skin;
+        particles
+            .iter()
+            .zip(&self.positions)
+            .any(|(particle, built)| particle.position.distance_sq(built) > max_sq)
+    }
+
+    /// Rebuilds the lists for the cutoff if the particles moved too far since the last build.
+    /// Returns whether they were rebuilt.
+    pub fn update(&mut self, particles: &[Particle<T>], cutoff: T) -> bool {
+        self.checks += 1;
+        if !self.needs_rebuild(particles, cutoff) {
+            return false;
+        }
+        self.rebuild(particles, cutoff);
+        true
+    }
+
+    /// Lists the neighbors within the cutoff plus the skin of every particle, with one radius
+    /// query each. They are sorted, so the order in which the forces of the neighbors add up
+    /// does not depend on the shape of the tree.
+    fn rebuild(&mut self, particles: &[Particle<T>], cutoff: T) {
+        self.tree.rebuild(particles);
+        let reach = cutoff + self.skin;
+        self.offsets.clear();
+        self.offsets.push(0);
+        self.indices.clear();
+        let mut candidates = Vec::new();
+        for (i, particle) in particles.iter().enumerate() {
+            candidates.clear();
+            self.tree
+                .query_radius(particles, particle.position, reach, &mut candidates);
+            candidates.sort_unstable();
+            self.indices.extend(candidates.iter().filter(|&&j| j != i));
+            self.offsets.push(self.indices.len());
+        }
+        self.positions.clear();
+        self.positions
+            .extend(particles.iter().map(|particle| particle.position));
+        self.cutoff = cutoff;
+        self.rebuilds += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::particle::create_particle;
+    use crate::Scalar;
+    use rand::{Rng, SeedableRng};
+
+    /// The indices of all other particles within the cutoff of the one at `index`, ascending
+    fn brute_force(particles: &[Particle<Scalar>], index: usize, cutoff: Scalar) -> Vec<usize> {
+        (0..particles.len())
+            .filter(|&j| {
+                j != index
+                    && particles[j]
+                        .position
+                        .distance_sq(&particles[index].position)
+                        <= cutoff * cutoff
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fresh_lists_hold_exactly_the_neighbors_within_the_cutoff() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(209);
+        let particles: Vec<Particle<Scalar>> = (0..300)
+            .map(|_| create_particle(rng.gen_range(0.0..50.0), rng.gen_range(0.0..50.0), 1.0, 0.0))
+            .collect();
+        let mut list = NeighborList::new(0.0);
+        assert!(list.update(&particles, 4.0));
+        for i in 0..particles.len() {
+            assert_eq!(list.neighbors(i), brute_force(&particles, i, 4.0));
+        }
+    }
+
+    #[test]
+    fn slowly_drifting_particles_rarely_rebuild_and_never_miss_a_neighbor() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2090);
+        let mut particles: Vec<Particle<Scalar>> = (0..200)
+            .map(|_| {
+                let mut particle =
+                    create_particle(rng.gen_range(0.0..40.0), rng.gen_range(0.0..40.0), 1.0, 0.0);
+                particle.velocity = Vector2D {
+                    x: rng.gen_range(-1.0..1.0),
+                    y: rng.gen_range(-1.0..1.0),
+                };
+                particle
+            })
+            .collect();
+        let (cutoff, skin, dt) = (3.0, 1.0, 0.01);
+        let mut list = NeighborList::new(skin);
+        let steps = 500;
+        for _ in 0..steps {
+            list.update(&particles, cutoff);
+            for i in 0..particles.len() {
+                let listed = list.neighbors(i);
+                for j in brute_force(&particles, i, cutoff) {
+                    assert!(listed.contains(&j), "{} missing from the list of {}", j, i);
+                }
+            }
+            for particle in &mut particles {
+                particle.position = &particle.position + particle.velocity * dt;
+            }
+        }
+        // at a speed of at most sqrt(2) the half skin lasts more than 35 steps
+        assert_eq!(list.checks(), steps);
+        assert!(
+            list.rebuilds() <= steps / 30,
+            "{} rebuilds",
+            list.rebuilds()
+        );
+    }
+
+    #[test]
+    fn removed_particles_force_a_rebuild() {
+        let particles = vec![
+            create_particle(0.0, 0.0, 1.0, 0.0),
+            create_particle(1.0, 0.0, 1.0, 0.0),
+            create_particle(2.0, 0.0, 1.0, 0.0),
+        ];
+        let mut list = NeighborList::new(5.0);
+        list.update(&particles, 1.5);
+        assert!(!list.update(&particles, 1.5));
+        assert!(list.update(&particles[..2], 1.5));
+        assert_eq!(list.neighbors(1), [0]);
+        list.invalidate();
+        assert!(list.update(&particles[..2], 1.5));
+    }
+}
@@ -0,0 +1,113 @@
+use std::str::FromStr;
+
+use num_traits::Float;
+
+use crate::util::particle::Particle;
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+/// An immovable circle that particles bounce off
+#[derive(Copy, Clone, Debug)]
+pub struct Obstacle<T> {
+    pub(crate) center: Vector2D<T>,
+    pub(crate) radius: T,
+    /// Fraction of the normal velocity kept after a bounce, 1 is perfectly elastic
+    pub(crate) restitution: T,
+}
+
+impl<T: Float + Default> Obstacle<T> {
+    /// Pushes a particle that penetrates the obstacle back onto its surface and reflects its
+    /// velocity about the contact normal, if it is moving inwards.
+    pub fn resolve(&self, particle: &mut Particle<T>) {
+        let zero = T::default();
+        let one = T::from(1).unwrap();
+        let v_dir = particle.position - self.center;
+        let distance_sq = v_dir.length_sq();
+        let min_distance = self.radius + particle.radius;
+        if distance_sq >= min_distance * min_distance {
+            return;
+        }
+
+        let distance = distance_sq.sqrt();
+        let normal = if distance > zero {
+            v_dir * (one / distance)
+        } else {
+            // a particle exactly at the center has no defined normal, so push it out horizontally
+            Vector2D { x: one, y: zero }
+        };
+        particle.position = &self.center + normal * min_distance;
+
+        let normal_speed = particle.velocity.x * normal.x + particle.velocity.y * normal.y;
+        if normal_speed < zero {
+            particle.velocity =
+                particle.velocity - normal * ((one + self.restitution) * normal_speed);
+        }
+    }
+}
+
+/// Parses an obstacle given as `x,y,radius` or `x,y,radius,restitution`.
+/// The restitution defaults to 1.
+impl FromStr for Obstacle<Scalar> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<Scalar>())
+            .collect::<Result<Vec<Scalar>, _>>()
+            .map_err(|_| format!("invalid obstacle: {}", s))?;
+        match values.as_slice() {
+            [x, y, radius] => Ok(Obstacle {
+                center: Vector2D { x: *x, y: *y },
+                radius: *radius,
+                restitution: 1.0,
+            }),
+            [x, y, radius, restitution] => Ok(Obstacle {
+                center: Vector2D { x: *x, y: *y },
+                radius: *radius,
+                restitution: *restitution,
+            }),
+            _ => Err(format!(
+                "expected x,y,radius[,restitution] for an obstacle, got: {}",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::particle::create_particle;
+
+    #[test]
+    fn reflects_a_particle_hitting_at_45_degrees() {
+        let obstacle: Obstacle<Scalar> = "0,0,10".parse().unwrap();
+        // touching the top of the obstacle while moving down and right
+        let mut particle = create_particle(0.0, 10.5, 1.0, 0.0);
+        particle.velocity = Vector2D { x: 1.0, y: -1.0 };
+        obstacle.resolve(&mut particle);
+        assert_eq!(particle.position, Vector2D { x: 0.0, y: 11.0 });
+        assert_eq!(particle.velocity, Vector2D { x: 1.0, y: 1.0 });
+    }
+
+    #[test]
+    fn pushes_out_a_particle_inside() {
+        let obstacle: Obstacle<Scalar> = "5,5,4,0.5".parse().unwrap();
+        let mut particle = create_particle(5.0, 3.0, 1.0, 0.0);
+        particle.velocity = Vector2D { x: 0.0, y: 2.0 };
+        obstacle.resolve(&mut particle);
+        assert_eq!(particle.position, Vector2D { x: 5.0, y: 0.0 });
+        // half of the inward velocity bounces back
+        assert_eq!(particle.velocity, Vector2D { x: 0.0, y: -1.0 });
+    }
+
+    #[test]
+    fn rejects_malformed_obstacles() {
+        assert_eq!(
+            "1,2".parse::<Obstacle<Scalar>>().unwrap_err(),
+            "expected x,y,radius[,restitution] for an obstacle, got: 1,2"
+        );
+        assert!("1,2,x".parse::<Obstacle<Scalar>>().is_err());
+    }
+}
@@ -1,10 +1,69 @@
+use num_traits::Float;
+
+use crate::util::particle_quad_tree::Positioned;
 use crate::util::vector2d::Vector2D;
+use crate::Scalar;
 
 /// A single two-dimensional particle
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Particle<T> {
     pub(crate) position: Vector2D<T>,
     pub(crate) velocity: Vector2D<T>,
     pub(crate) radius: T,
     pub(crate) mass: T,
-}
\ No newline at end of file
+    pub(crate) charge: T,
+    /// Index into the species of the universe
+    pub(crate) species: u8,
+}
+
+/// Creates a resting particle of radius 1.
+pub fn create_particle(x: Scalar, y: Scalar, mass: Scalar, charge: Scalar) -> Particle<Scalar> {
+    Particle {
+        position: Vector2D { x, y },
+        velocity: Vector2D { x: 0.0, y: 0.0 },
+        radius: 1.0,
+        mass,
+        charge,
+        species: 0,
+    }
+}
+
+impl<T: Float> Particle<T> {
+    /// The radius of a disc with the given mass and density (mass per area).
+    pub fn radius_for_mass(mass: T, density: T) -> T {
+        let pi = T::from(std::f64::consts::PI).unwrap();
+        (mass / (pi * density)).sqrt()
+    }
+}
+
+impl<T: Copy> Positioned<T> for Particle<T> {
+    fn position(&self) -> Vector2D<T> {
+        self.position
+    }
+
+    fn mass(&self) -> T {
+        self.mass
+    }
+
+    fn charge(&self) -> T {
+        self.charge
+    }
+
+    fn radius(&self) -> T {
+        self.radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn radius_keeps_the_area_proportional_to_the_mass() {
+        let density = 2.0;
+        for radius in [0.5, 1.0, 3.0] {
+            let mass = std::f64::consts::PI * radius * radius * density;
+            assert!((Particle::radius_for_mass(mass, density) - radius).abs() < 1e-12);
+        }
+    }
+}
@@ -1,104 +1,840 @@
-use std::ops::{Add, Div, Mul, Sub};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::ops::ControlFlow;
+use std::ops::{Add, Mul, Sub};
 
+use num_traits::Float;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use tracing::{field, info_span};
+
+use crate::util::neighbor_list::NeighborList;
+use crate::util::obstacle::Obstacle;
+use crate::util::spring::Spring;
 use crate::util::vector2d::Vector2D;
 use crate::Particle;
 
-pub trait QuadtreePointValue<T> {
-    fn from(value: usize) -> T;
-}
-
-impl QuadtreePointValue<f32> for f32 {
-    fn from(value: usize) -> f32 {
-        value as f32
+/// An element a [`QuadTree`] can hold. The summaries weight its position by its mass and add up
+/// its charge, which is zero unless an element type provides one. Only a loose tree looks at the
+/// radius, which is zero for point-like element types.
+pub trait Positioned<T> {
+    fn position(&self) -> Vector2D<T>;
+    fn mass(&self) -> T;
+    fn charge(&self) -> T
+    where
+        T: Default,
+    {
+        T::default()
+    }
+    fn radius(&self) -> T
+    where
+        T: Default,
+    {
+        T::default()
     }
 }
 
-pub struct ParticleQuadTree<T> {
-    pub center: Vector2D<T>,
+/// Depth at which leaves stop splitting and accept any number of elements, so that particles at
+/// the same position cannot make the splitting recurse forever. Every element of such a leaf
+/// interacts with every other one directly, which is quadratic in the leaf size but only happens
+/// for clumps far below the resolution of the tree.
+const MAX_DEPTH: usize = 24;
+
+/// Number of elements from which the four quadrants of the root are built on separate threads.
+/// Below it, starting the threads takes longer than building the tree.
+const PARALLEL_BUILD_MIN_ELEMENTS: usize = 20_000;
+
+/// A quadtree over elements that have a position and a mass, which it refers to by their index
+/// into a slice. Every node keeps a summary particle at the center of mass of its elements, or at
+/// their plain average if they are massless.
+///
+/// Elements are routed by their position alone. A tight tree, the default, treats them as points.
+/// A loose tree, see [`Self::build_loose`], treats them as discs and lets the queries reach over
+/// the rectangle of every node by the largest disc below it.
+pub struct QuadTree<T, E> {
+    center: Vector2D<T>,
     summary_particle: Particle<T>,
-    pub width: T,
-    pub height: T,
+    width: T,
+    height: T,
     max_capacity: usize,
+    /// Factor from the radius of an element to the radius of its disc in queries, `None` for a
+    /// tight tree
+    loose_factor: Option<T>,
+    /// The radius of the largest disc below this node, by which queries grow its rectangle. Zero
+    /// in a tight tree, and an upper bound after removals.
+    reach: T,
+    /// Distance from the root, which has depth 0
+    depth: usize,
     num_elements: usize,
-    node: QuadtreeNode<T>,
+    node: QuadtreeNode<T, E>,
+    /// Storage kept from the previous build for reuse, only used by the root
+    spare: SpareNodes<T, E>,
+    elements: PhantomData<fn(&E)>,
+}
+
+/// The quadtree over the particles of a universe, which also computes their forces
+pub type ParticleQuadTree<T> = QuadTree<T, Particle<T>>;
+
+/// Nodes and leaf storage that a rebuild removed from the tree, taken again by later splits
+struct SpareNodes<T, E> {
+    nodes: Vec<Box<QuadTree<T, E>>>,
+    /// Element lists of leaves that were split, each of them empty
+    leaves: Vec<Vec<usize>>,
+    /// The indices of all elements, partitioned among the leaves by the last build
+    indices: Vec<usize>,
+}
+
+impl<T, E> SpareNodes<T, E> {
+    /// Moves one of `shares` equal parts of the spare nodes and leaves into new storage
+    #[cfg(feature = "parallel")]
+    fn take_share(&mut self, shares: usize) -> Self {
+        SpareNodes {
+            nodes: self
+                .nodes
+                .split_off(self.nodes.len() - self.nodes.len() / shares),
+            leaves: self
+                .leaves
+                .split_off(self.leaves.len() - self.leaves.len() / shares),
+            indices: Vec::new(),
+        }
+    }
+}
+
+impl<T, E> Default for SpareNodes<T, E> {
+    fn default() -> Self {
+        SpareNodes {
+            nodes: Vec::new(),
+            leaves: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
 }
 
-enum QuadtreeNode<T> {
+enum QuadtreeNode<T, E> {
     Node {
-        top_left: Box<ParticleQuadTree<T>>,
-        top_right: Box<ParticleQuadTree<T>>,
-        bottom_left: Box<ParticleQuadTree<T>>,
-        bottom_right: Box<ParticleQuadTree<T>>,
+        top_left: Box<QuadTree<T, E>>,
+        top_right: Box<QuadTree<T, E>>,
+        bottom_left: Box<QuadTree<T, E>>,
+        bottom_right: Box<QuadTree<T, E>>,
     },
     Leaf {
-        element_indices: Vec<usize>, // only stores indices to actual particles
+        element_indices: Vec<usize>, // only stores indices to actual elements
     },
 }
 
-pub trait QuadtreeVisitor<T> {
-    fn visit_node(&mut self, tree: &ParticleQuadTree<T>);
-    fn visit_leaf_node(&mut self, tree: &ParticleQuadTree<T>, element_indices: &Vec<usize>);
-    fn visit_element(&mut self, index: usize);
+/// Constants of the force law
+#[derive(Copy, Clone)]
+pub struct ForceParameters<'a, T> {
+    pub(crate) grav_const: T,
+    /// Exponent `n` of the gravitational force `grav_const * m / r^n`, 1 is two-dimensional gravity
+    pub(crate) force_exponent: T,
+    /// Whether overlapping particles attract like uniform discs, linearly in the distance inside
+    /// the sum of their radii, instead of like point masses
+    pub(crate) solid_interior: bool,
+    /// Softening length, keeps forces finite when particles get very close
+    pub(crate) softening: T,
+    /// Barnes-Hut opening angle, smaller values are more accurate but slower. It is compared with
+    /// the longer side of a node over its distance, so elongated nodes are not mistaken for small
+    /// ones.
+    pub(crate) theta: T,
+    /// Coulomb constant, zero disables electrostatic forces
+    pub(crate) coulomb_const: T,
+    pub(crate) repulsion: Option<Repulsion<T>>,
+    /// Lists of the particles within the repulsion cutoff. If given, the repulsion acts between
+    /// the listed pairs instead of between the elements of the leaves the tree opens.
+    pub(crate) neighbors: Option<&'a NeighborList<T>>,
+    /// Linear drag coefficient, every step removes `damping * velocity * dt` from the velocity
+    pub(crate) damping: T,
+    /// Additional drag coefficient per species, missing species have none
+    pub(crate) species_drag: &'a [T],
+    /// Uniform acceleration acting on every particle, e.g. "down" gravity or wind
+    pub(crate) uniform_field: Vector2D<T>,
+    /// Magnitude of a counter-clockwise acceleration around `swirl_center`, zero disables it
+    pub(crate) swirl_strength: T,
+    pub(crate) swirl_center: Vector2D<T>,
+    /// Angular velocity of the reference frame around `frame_center`, counter-clockwise if
+    /// positive, zero for an inertial frame
+    pub(crate) frame_rotation: T,
+    pub(crate) frame_center: Vector2D<T>,
+    /// Immovable bodies that attract every particle but are never integrated themselves
+    pub(crate) attractors: &'a [Particle<T>],
+    /// Immovable circles that particles bounce off after moving
+    pub(crate) obstacles: &'a [Obstacle<T>],
+    /// Springs connecting pairs of particles
+    pub(crate) springs: &'a [Spring<T>],
+    /// Precomputed accelerations per particle, e.g. from SPH pressure, empty if there are none
+    pub(crate) extra_accelerations: &'a [Vector2D<T>],
+    /// Forces applied from outside the simulation per particle, empty if there are none
+    pub(crate) external_forces: &'a [Vector2D<T>],
+    /// Elements the integration never moves, they still attract the others. Missing ones are free.
+    pub(crate) pinned: &'a [bool],
+    /// Size of a periodic world, where every distance is measured to the nearest image
+    pub(crate) period: Option<Vector2D<T>>,
+    /// Largest acceleration a particle may receive during one step, larger ones are scaled down
+    pub(crate) max_acceleration: Option<T>,
 }
 
-impl<
-        T: Copy
-            + Default
-            + QuadtreePointValue<T>
-            + PartialOrd
-            + Sub<Output = T>
-            + Add<Output = T>
-            + Mul<Output = T>
-            + Div<Output = T>
-            + std::fmt::Display,
-    > ParticleQuadTree<T>
+/// A tree that approximates the forces between elements (Barnes-Hut). Everything that does not
+/// depend on the tree, like fields, attractors and springs, is shared by all trees.
+pub trait ForceTree<T>: Sync
+where
+    T: Float + Default + Send + Sync,
 {
-    pub(crate) fn new(
-        center: Vector2D<T>,
-        width: T,
-        height: T,
-        max_capacity: usize,
-    ) -> ParticleQuadTree<T> {
-        ParticleQuadTree {
+    /// Rebuilds the tree over all elements, sized to enclose their positions
+    fn rebuild(&mut self, elements: &[Particle<T>]);
+
+    /// Adds the change in velocity caused by all elements of the tree on the element at `index`
+    /// to `delta_v`, counting the work done in `stats`
+    fn accumulate_pull(
+        &self,
+        elements: &[Particle<T>],
+        index: usize,
+        params: &ForceParameters<'_, T>,
+        elapsed_s: T,
+        delta_v: &mut Vector2D<T>,
+        stats: &mut ForceStats,
+    );
+
+    /// Writes the acceleration of every element at its current position to `accelerations`,
+    /// without changing the elements. Returns how many accelerations were clamped.
+    fn accelerations(
+        &self,
+        elements: &[Particle<T>],
+        params: &ForceParameters<'_, T>,
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize {
+        // the change in velocity over one second is the acceleration
+        let one_second = T::from(1).unwrap();
+        delta_velocities(self, elements, None, params, one_second, accelerations);
+        clamp_accelerations(accelerations, params, one_second)
+    }
+
+    /// Like `accelerations`, but only evaluates the elements marked in `active`, still against all
+    /// elements of the tree. The accelerations of the other elements are left zero.
+    fn accelerations_of(
+        &self,
+        elements: &[Particle<T>],
+        active: &[bool],
+        params: &ForceParameters<'_, T>,
+        accelerations: &mut Vec<Vector2D<T>>,
+    ) -> usize {
+        let one_second = T::from(1).unwrap();
+        delta_velocities(
+            self,
+            elements,
+            Some(active),
+            params,
+            one_second,
+            accelerations,
+        );
+        clamp_accelerations(accelerations, params, one_second)
+    }
+}
+
+/// A short-range repulsive force `strength / r^exponent` between particles closer than `cutoff`,
+/// so that clusters reach an equilibrium size instead of collapsing into a point
+#[derive(Copy, Clone)]
+pub struct Repulsion<T> {
+    pub(crate) strength: T,
+    pub(crate) exponent: u32,
+    pub(crate) cutoff: T,
+}
+
+/// The energy of a set of elements at one point in time
+#[derive(Copy, Clone, Debug)]
+pub struct EnergyReport<T> {
+    pub(crate) kinetic: T,
+    /// Gravitational potential energy, approximated like the forces
+    pub(crate) potential: T,
+    pub(crate) total: T,
+}
+
+/// The shape of a tree, for tuning the leaf capacity
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TreeStats {
+    /// Number of levels, 1 for a tree that is a single leaf
+    pub(crate) depth: usize,
+    /// Number of nodes, leaves included
+    pub(crate) nodes: usize,
+    pub(crate) leaves: usize,
+    pub(crate) empty_leaves: usize,
+    pub(crate) elements: usize,
+    /// Number of elements in the fullest leaf
+    pub(crate) max_leaf_occupancy: usize,
+    /// Number of leaves at every depth, below which leaves cannot split
+    pub(crate) leaves_per_depth: [usize; MAX_DEPTH + 1],
+}
+
+impl TreeStats {
+    /// The number of leaves at every depth down to the deepest one
+    pub(crate) fn leaf_depth_histogram(&self) -> &[usize] {
+        &self.leaves_per_depth[..self.depth]
+    }
+
+    /// The average number of elements per leaf, empty leaves included
+    pub(crate) fn mean_leaf_occupancy(&self) -> f32 {
+        self.elements as f32 / self.leaves.max(1) as f32
+    }
+}
+
+/// The amount of work done while evaluating forces
+#[derive(Default)]
+pub struct ForceStats {
+    pub(crate) interactions: usize,
+    pub(crate) approximations: usize,
+}
+
+#[cfg(feature = "parallel")]
+impl ForceStats {
+    /// The work of both evaluations together
+    fn combined(self, other: ForceStats) -> ForceStats {
+        ForceStats {
+            interactions: self.interactions + other.interactions,
+            approximations: self.approximations + other.approximations,
+        }
+    }
+}
+
+/// Iterator over the leaves below a node in depth-first order, children in the order top left,
+/// top right, bottom left, bottom right like in [`QuadTree::visit`]
+pub struct Leaves<'a, T, E> {
+    stack: Vec<&'a QuadTree<T, E>>,
+}
+
+impl<'a, T, E> Iterator for Leaves<'a, T, E> {
+    type Item = (&'a QuadTree<T, E>, &'a [usize]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(tree) = self.stack.pop() {
+            match &tree.node {
+                QuadtreeNode::Node {
+                    top_left,
+                    top_right,
+                    bottom_left,
+                    bottom_right,
+                } => {
+                    // pushed in reverse, so that the top left child comes out first
+                    self.stack
+                        .extend([bottom_right, bottom_left, top_right, top_left].map(|c| &**c));
+                }
+                QuadtreeNode::Leaf { element_indices } => return Some((tree, element_indices)),
+            }
+        }
+        None
+    }
+}
+
+/// A node waiting to be searched, ordered so that a `BinaryHeap` yields the nearest one first
+struct QueuedNode<'a, T, E> {
+    distance_sq: T,
+    node: &'a QuadTree<T, E>,
+}
+
+impl<T: PartialOrd, E> PartialEq for QueuedNode<'_, T, E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: PartialOrd, E> Eq for QueuedNode<'_, T, E> {}
+
+impl<T: PartialOrd, E> PartialOrd for QueuedNode<'_, T, E> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: PartialOrd, E> Ord for QueuedNode<'_, T, E> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance_sq
+            .partial_cmp(&self.distance_sq)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// An element found by a k-nearest search, ordered so that a `BinaryHeap` yields the farthest
+/// one first, and the highest index among equally far ones
+struct Candidate<T> {
+    distance_sq: T,
+    index: usize,
+}
+
+impl<T: PartialOrd> PartialEq for Candidate<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: PartialOrd> Eq for Candidate<T> {}
+
+impl<T: PartialOrd> PartialOrd for Candidate<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: PartialOrd> Ord for Candidate<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_sq
+            .partial_cmp(&other.distance_sq)
+            .unwrap_or(Ordering::Equal)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+/// Visits the nodes of a tree depth-first. Every method can stop the whole traversal by returning
+/// `ControlFlow::Break`, the node methods return `Continue(false)` to skip what lies below.
+///
+/// The traversal calls the `_at_depth` methods, which pass on to the ones without the depth
+/// unless a visitor overrides them. The depth counts from the node the traversal started at.
+pub trait QuadtreeVisitor<T, E = Particle<T>> {
+    /// Called for an inner node, `Continue(true)` descends into its children
+    fn visit_node(&mut self, _tree: &QuadTree<T, E>) -> ControlFlow<(), bool> {
+        ControlFlow::Continue(true)
+    }
+    /// Called for a leaf, `Continue(true)` visits its elements
+    fn visit_leaf_node(
+        &mut self,
+        _tree: &QuadTree<T, E>,
+        _element_indices: &[usize],
+    ) -> ControlFlow<(), bool> {
+        ControlFlow::Continue(true)
+    }
+    /// Called for every element of a leaf, together with the leaf holding it
+    fn visit_element(&mut self, leaf: &QuadTree<T, E>, index: usize) -> ControlFlow<()>;
+    /// Like [`Self::visit_node`], with the depth of the node
+    fn visit_node_at_depth(
+        &mut self,
+        tree: &QuadTree<T, E>,
+        _depth: usize,
+    ) -> ControlFlow<(), bool> {
+        self.visit_node(tree)
+    }
+    /// Like [`Self::visit_leaf_node`], with the depth of the leaf
+    fn visit_leaf_node_at_depth(
+        &mut self,
+        tree: &QuadTree<T, E>,
+        element_indices: &[usize],
+        _depth: usize,
+    ) -> ControlFlow<(), bool> {
+        self.visit_leaf_node(tree, element_indices)
+    }
+}
+
+/// Like [`QuadtreeVisitor`], but every element is handed over mutably together with its index,
+/// so that visitors can change particles while they know where in the tree they are.
+pub trait QuadtreeVisitorMut<T, E = Particle<T>> {
+    /// Called for an inner node, `Continue(true)` descends into its children
+    fn visit_node(&mut self, _tree: &QuadTree<T, E>) -> ControlFlow<(), bool> {
+        ControlFlow::Continue(true)
+    }
+    /// Called for a leaf, `Continue(true)` visits its elements
+    fn visit_leaf_node(
+        &mut self,
+        _tree: &QuadTree<T, E>,
+        _element_indices: &[usize],
+    ) -> ControlFlow<(), bool> {
+        ControlFlow::Continue(true)
+    }
+    /// Like [`Self::visit_node`], with the depth of the node
+    fn visit_node_at_depth(
+        &mut self,
+        tree: &QuadTree<T, E>,
+        _depth: usize,
+    ) -> ControlFlow<(), bool> {
+        self.visit_node(tree)
+    }
+    /// Like [`Self::visit_leaf_node`], with the depth of the leaf
+    fn visit_leaf_node_at_depth(
+        &mut self,
+        tree: &QuadTree<T, E>,
+        element_indices: &[usize],
+        _depth: usize,
+    ) -> ControlFlow<(), bool> {
+        self.visit_leaf_node(tree, element_indices)
+    }
+    /// Called for every element of a leaf, together with the leaf holding it
+    fn visit_element(
+        &mut self,
+        leaf: &QuadTree<T, E>,
+        index: usize,
+        element: &mut E,
+    ) -> ControlFlow<()>;
+}
+
+/// Writes one line per node for [`QuadTree::dump`], stopping the traversal at the first error
+struct TreeDump<W> {
+    writer: W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> TreeDump<W> {
+    /// Writes the line of a node, ending with its element indices if it is a leaf
+    fn write_node<T: std::fmt::Display, E>(
+        &mut self,
+        tree: &QuadTree<T, E>,
+        element_indices: Option<&[usize]>,
+        depth: usize,
+    ) -> std::io::Result<()> {
+        let summary = &tree.summary_particle;
+        write!(
+            self.writer,
+            "{:indent$}{} depth={} center={},{} size={},{} elements={} mass={} charge={} summary={},{}",
+            "",
+            if element_indices.is_some() { "leaf" } else { "node" },
+            depth,
+            tree.center.x,
+            tree.center.y,
+            tree.width,
+            tree.height,
+            tree.num_elements,
+            summary.mass,
+            summary.charge,
+            summary.position.x,
+            summary.position.y,
+            indent = 2 * depth,
+        )?;
+        if let Some(element_indices) = element_indices {
+            write!(self.writer, " indices=")?;
+            for (i, index) in element_indices.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                write!(self.writer, "{}{}", separator, index)?;
+            }
+        }
+        writeln!(self.writer)
+    }
+
+    /// Continues with `descend` after a successful write, otherwise keeps the error and stops
+    fn continue_if_ok(
+        &mut self,
+        result: std::io::Result<()>,
+        descend: bool,
+    ) -> ControlFlow<(), bool> {
+        match result {
+            Ok(()) => ControlFlow::Continue(descend),
+            Err(error) => {
+                self.error = Some(error);
+                ControlFlow::Break(())
+            }
+        }
+    }
+}
+
+impl<T: std::fmt::Display, E, W: Write> QuadtreeVisitor<T, E> for TreeDump<W> {
+    fn visit_element(&mut self, _leaf: &QuadTree<T, E>, _index: usize) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    fn visit_node_at_depth(
+        &mut self,
+        tree: &QuadTree<T, E>,
+        depth: usize,
+    ) -> ControlFlow<(), bool> {
+        let result = self.write_node(tree, None, depth);
+        self.continue_if_ok(result, true)
+    }
+
+    fn visit_leaf_node_at_depth(
+        &mut self,
+        tree: &QuadTree<T, E>,
+        element_indices: &[usize],
+        depth: usize,
+    ) -> ControlFlow<(), bool> {
+        // the line already lists the elements
+        let result = self.write_node(tree, Some(element_indices), depth);
+        self.continue_if_ok(result, false)
+    }
+}
+
+impl<T: Float + Default + std::fmt::Display + Send + Sync, E: Positioned<T> + Sync> QuadTree<T, E> {
+    pub(crate) fn new(center: Vector2D<T>, width: T, height: T, max_capacity: usize) -> Self {
+        QuadTree {
             center,
             summary_particle: Particle {
                 position: center,
                 velocity: Default::default(),
                 radius: Default::default(),
                 mass: Default::default(),
+                charge: Default::default(),
+                species: Default::default(),
             },
             width,
             height,
             max_capacity,
+            loose_factor: None,
+            reach: T::default(),
+            depth: 0,
             num_elements: 0,
             node: QuadtreeNode::Leaf {
                 element_indices: Vec::with_capacity(max_capacity),
             },
+            spare: SpareNodes::default(),
+            elements: PhantomData,
         }
     }
 
-    pub(crate) fn insert(&mut self, elements: &Vec<Particle<T>>, index: usize) {
-        let element = elements.get(index).unwrap();
+    /// Builds a tree over all elements, sized to enclose their positions. See
+    /// [`Self::clear_and_rebuild`].
+    pub fn build(elements: &[E], max_capacity: usize) -> Self {
+        let mut tree = Self::new(
+            Vector2D::default(),
+            T::default(),
+            T::default(),
+            max_capacity,
+        );
+        tree.rebuild(elements);
+        tree
+    }
 
-        // update summary particle
-        if self.num_elements == 0 {
-            self.summary_particle.position = element.position;
-            self.summary_particle.mass = element.mass;
-            self.num_elements = 1;
+    /// Like [`Self::build`], but a loose tree: every element is a disc of its radius times
+    /// `factor` around its position, e.g. a factor of 1 for the particles themselves or 2 for
+    /// their interaction range. Rectangle, radius and neighbor queries return every element whose
+    /// disc they touch, even if its position lies in a node they miss. Nearest neighbor searches
+    /// still measure to the positions.
+    pub fn build_loose(elements: &[E], max_capacity: usize, factor: T) -> Self {
+        let mut tree = Self::new(
+            Vector2D::default(),
+            T::default(),
+            T::default(),
+            max_capacity,
+        );
+        tree.loose_factor = Some(factor);
+        tree.rebuild(elements);
+        tree
+    }
+
+    /// Empties the tree and builds it again over all elements, in the smallest square enclosing
+    /// their positions. See [`Self::clear_and_rebuild`]. A square root keeps every node square,
+    /// while the raw bounds of e.g. a wide spawn box would split into rectangles just as elongated
+    /// at every level, which approximate the forces worse than square nodes of the same longer
+    /// side. Use [`Self::clear_and_rebuild`] for a tree over a given rectangle instead.
+    pub fn rebuild(&mut self, elements: &[E]) {
+        let (min, max) = enclosing_bounds(elements);
+        let two = T::from(2).unwrap();
+        let center = Vector2D {
+            x: (min.x + max.x) / two,
+            y: (min.y + max.y) / two,
+        };
+        let (width, height) = (max.x - min.x, max.y - min.y);
+        let size = if width > height { width } else { height };
+        self.clear_and_rebuild(center, size, size, elements);
+    }
+
+    /// Empties the tree, moves it to a new rectangle and builds it again over all elements. The
+    /// elements are partitioned top-down instead of inserted one by one, which gives the same
+    /// nodes as inserting them, except that a node grown by an element arriving after its split
+    /// is split with its final size. The summaries are computed exactly on the way back up. The
+    /// nodes and leaf storage of the previous build are reused, so rebuilding every frame does
+    /// not allocate once the tree reached its usual size.
+    pub fn clear_and_rebuild(&mut self, center: Vector2D<T>, width: T, height: T, elements: &[E]) {
+        let mut spare = std::mem::take(&mut self.spare);
+        self.recycle_children(&mut spare);
+        self.reset(center, width, height, 0);
+        let mut indices = std::mem::take(&mut spare.indices);
+        indices.clear();
+        indices.extend(0..elements.len());
+        self.build_from(elements, &mut indices, &mut spare);
+        spare.indices = indices;
+        self.spare = spare;
+    }
+
+    /// Fills this empty leaf with the given elements, splitting it the way inserting them would
+    /// while they do not fit, and sets the summaries bottom-up
+    fn build_from(&mut self, elements: &[E], indices: &mut [usize], spare: &mut SpareNodes<T, E>) {
+        let grow_to_contain = |tree: &mut Self, position: Vector2D<T>| {
+            if !tree.contains_point(position) {
+                tree.grow_to(position);
+            }
+        };
+
+        if indices.len() <= self.max_capacity || self.depth >= MAX_DEPTH {
+            for &i in indices.iter() {
+                grow_to_contain(self, elements[i].position());
+            }
+            if let QuadtreeNode::Leaf { element_indices } = &mut self.node {
+                element_indices.extend_from_slice(indices);
+            }
+            self.summarize(indices.iter().map(|&i| (&elements[i], 1)));
+            if self.loose_factor.is_some() {
+                for &i in indices.iter() {
+                    self.widen_reach(&elements[i]);
+                }
+            }
+            return;
+        }
+
+        let parallel = self.depth == 0 && indices.len() >= PARALLEL_BUILD_MIN_ELEMENTS;
+        // elements exactly on a center line belong to the left or top child, like in insert.
+        // Growing keeps the center, so it can happen while the elements are partitioned.
+        let (cx, cy) = (self.center.x, self.center.y);
+        let (left, right) = partition(indices, |i| {
+            grow_to_contain(self, elements[i].position());
+            elements[i].position().x <= cx
+        });
+        let (top_left_indices, bottom_left_indices) =
+            partition(left, |i| elements[i].position().y <= cy);
+        let (top_right_indices, bottom_right_indices) =
+            partition(right, |i| elements[i].position().y <= cy);
+
+        let mut children = Self::split_children(
+            self.center,
+            self.width,
+            self.height,
+            self.depth + 1,
+            self.max_capacity,
+            self.loose_factor,
+            spare,
+        );
+        let parts = [
+            top_left_indices,
+            top_right_indices,
+            bottom_left_indices,
+            bottom_right_indices,
+        ];
+        if parallel {
+            Self::build_children_parallel(&mut children, parts, elements, spare);
         } else {
-            let cx = self.summary_particle.position.x;
-            let cy = self.summary_particle.position.y;
-            let n = <T as QuadtreePointValue<T>>::from(self.num_elements);
-            let n_plus = <T as QuadtreePointValue<T>>::from(self.num_elements + 1);
-            self.summary_particle.position = Vector2D {
-                x: cx.mul(n).add(element.position.x).div(n_plus),
-                y: cy.mul(n).add(element.position.y).div(n_plus),
-            };
-            self.summary_particle.mass = self.summary_particle.mass + element.mass;
-            self.num_elements = self.num_elements + 1;
+            for (child, indices) in children.iter_mut().zip(parts) {
+                child.build_from(elements, indices, spare);
+            }
+        }
+        self.summarize(
+            children
+                .iter()
+                .map(|child| (&child.summary_particle, child.num_elements)),
+        );
+        for child in &children {
+            if child.reach > self.reach {
+                self.reach = child.reach;
+            }
+        }
+
+        let [top_left, top_right, bottom_left, bottom_right] = children;
+        let node = QuadtreeNode::Node {
+            top_left,
+            top_right,
+            bottom_left,
+            bottom_right,
+        };
+        if let QuadtreeNode::Leaf { element_indices } = std::mem::replace(&mut self.node, node) {
+            spare.leaves.push(element_indices);
+        }
+    }
+
+    /// Builds the four children of the root on separate threads, each with a quarter of the spare
+    /// storage, which gets all of it back afterwards. Every child is built exactly like in the
+    /// serial build, so the tree does not depend on the number of threads.
+    #[cfg(feature = "parallel")]
+    fn build_children_parallel(
+        children: &mut [Box<Self>; 4],
+        parts: [&mut [usize]; 4],
+        elements: &[E],
+        spare: &mut SpareNodes<T, E>,
+    ) {
+        let mut shares: [SpareNodes<T, E>; 4] = std::array::from_fn(|i| spare.take_share(4 - i));
+        rayon::scope(|scope| {
+            for ((child, indices), share) in children.iter_mut().zip(parts).zip(&mut shares) {
+                scope.spawn(move |_| child.build_from(elements, indices, share));
+            }
+        });
+        for share in shares {
+            spare.nodes.extend(share.nodes);
+            spare.leaves.extend(share.leaves);
+        }
+    }
+
+    /// Without the `parallel` feature the children are built one after the other
+    #[cfg(not(feature = "parallel"))]
+    fn build_children_parallel(
+        children: &mut [Box<Self>; 4],
+        parts: [&mut [usize]; 4],
+        elements: &[E],
+        spare: &mut SpareNodes<T, E>,
+    ) {
+        for (child, indices) in children.iter_mut().zip(parts) {
+            child.build_from(elements, indices, spare);
+        }
+    }
+
+    /// Sets the summary particle to the combination of the given parts, see [`summarize`]
+    fn summarize<'p, P: Positioned<T> + 'p>(&mut self, parts: impl Iterator<Item = (&'p P, usize)>)
+    where
+        T: 'p,
+    {
+        let (summary_particle, num_elements) = summarize(parts, self.center);
+        self.summary_particle = summary_particle;
+        self.num_elements = num_elements;
+    }
+
+    /// Turns this node into an empty leaf, handing all nodes below it to `spare`
+    fn recycle_children(&mut self, spare: &mut SpareNodes<T, E>) {
+        let leaf = QuadtreeNode::Leaf {
+            element_indices: Vec::new(),
+        };
+        match std::mem::replace(&mut self.node, leaf) {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for mut child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.recycle_children(spare);
+                    spare.nodes.push(child);
+                }
+                self.node = QuadtreeNode::Leaf {
+                    element_indices: spare.leaves.pop().unwrap_or_default(),
+                };
+            }
+            QuadtreeNode::Leaf {
+                mut element_indices,
+            } => {
+                element_indices.clear();
+                self.node = QuadtreeNode::Leaf { element_indices };
+            }
+        }
+    }
+
+    /// Moves an empty leaf to a new rectangle and depth
+    fn reset(&mut self, center: Vector2D<T>, width: T, height: T, depth: usize) {
+        self.center = center;
+        self.width = width;
+        self.height = height;
+        self.depth = depth;
+        self.num_elements = 0;
+        self.reach = T::default();
+        self.summary_particle.position = center;
+        self.summary_particle.mass = T::default();
+        self.summary_particle.charge = T::default();
+    }
+
+    /// Inserts the element with the given index. Elements outside of this node grow it
+    /// symmetrically around its center until it contains them, so every node on the path to an
+    /// element always covers it, which the force and collision traversals rely on.
+    pub fn insert(&mut self, elements: &[E], index: usize) {
+        let mut spare = std::mem::take(&mut self.spare);
+        self.insert_with(elements, index, &mut spare);
+        self.spare = spare;
+    }
+
+    /// Inserts the element, taking the children of split leaves from `spare` while it has some
+    fn insert_with(&mut self, elements: &[E], index: usize, spare: &mut SpareNodes<T, E>) {
+        let element = elements.get(index).unwrap();
+
+        if !self.contains_point(element.position()) {
+            self.grow_to(element.position());
         }
 
-        // recursion: add element to correct child node
+        self.add_to_summary(element);
+        if self.loose_factor.is_some() {
+            self.widen_reach(element);
+        }
+
+        // recursion: add element to correct child node. Elements exactly on a center line belong
+        // to the left or top child, here and when a leaf is split.
         match self.node {
             QuadtreeNode::Node {
                 ref mut top_left,
@@ -106,108 +842,78 @@ impl<
                 ref mut bottom_left,
                 ref mut bottom_right,
             } => {
-                if element.position.x <= self.center.x {
+                if element.position().x <= self.center.x {
                     // left
-                    if element.position.y <= self.center.y {
+                    if element.position().y <= self.center.y {
                         // top
-                        top_left.insert(elements, index);
+                        top_left.insert_with(elements, index, spare);
                     } else {
                         // bottom
-                        bottom_left.insert(elements, index);
+                        bottom_left.insert_with(elements, index, spare);
                     }
                 } else {
                     // right
-                    if element.position.y <= self.center.y {
+                    if element.position().y <= self.center.y {
                         // top
-                        top_right.insert(elements, index);
+                        top_right.insert_with(elements, index, spare);
                     } else {
                         // bottom
-                        bottom_right.insert(elements, index);
+                        bottom_right.insert_with(elements, index, spare);
                     }
                 }
             }
             QuadtreeNode::Leaf {
                 ref mut element_indices,
             } => {
-                if element_indices.len() < self.max_capacity {
+                if element_indices.len() < self.max_capacity || self.depth >= MAX_DEPTH {
                     // recursion end: add element to list of elements
                     element_indices.push(index);
                 } else {
                     // if maximum capacity is reached, we need to split the elements into four quads
-                    let two = <T as QuadtreePointValue<T>>::from(2);
-                    let half_width = (self.width + two) / two; // increase slightly because of fuzzy floating point comparisons
-                    let half_height = (self.height + two) / two;
-                    let quarter_width = half_width / two;
-                    let quarter_height = half_height / two;
                     let cx = self.center.x;
                     let cy = self.center.y;
+                    let [mut top_left, mut top_right, mut bottom_left, mut bottom_right] =
+                        Self::split_children(
+                            self.center,
+                            self.width,
+                            self.height,
+                            self.depth + 1,
+                            self.max_capacity,
+                            self.loose_factor,
+                            spare,
+                        );
 
-                    let mut top_left = Box::new(ParticleQuadTree::new(
-                        Vector2D {
-                            x: cx.sub(quarter_width),
-                            y: cy.sub(quarter_height),
-                        },
-                        half_width,
-                        half_height,
-                        self.max_capacity,
-                    ));
-
-                    let mut top_right = Box::new(ParticleQuadTree::new(
-                        Vector2D {
-                            x: cx.add(quarter_width),
-                            y: cy.sub(quarter_height),
-                        },
-                        half_width,
-                        half_height,
-                        self.max_capacity,
-                    ));
-
-                    let mut bottom_left = Box::new(ParticleQuadTree::new(
-                        Vector2D {
-                            x: cx.sub(quarter_width),
-                            y: cy.add(quarter_height),
-                        },
-                        half_width,
-                        half_height,
-                        self.max_capacity,
-                    ));
-
-                    let mut bottom_right = Box::new(ParticleQuadTree::new(
-                        Vector2D {
-                            x: cx.add(quarter_width),
-                            y: cy.add(quarter_height),
-                        },
-                        half_width,
-                        half_height,
-                        self.max_capacity,
-                    ));
-
+                    // the element that triggered the split is distributed along with the others.
+                    // swap_remove reorders them, but only depending on their order in this leaf,
+                    // so the same insertions always produce the same leaf order
+                    element_indices.push(index);
                     while !element_indices.is_empty() {
                         let element_index = element_indices.swap_remove(0);
                         let element = elements.get(element_index).unwrap();
 
-                        if element.position.x < cx {
+                        if element.position().x <= cx {
                             // left
-                            if element.position.y < cy {
+                            if element.position().y <= cy {
                                 // top
 
-                                top_left.insert(elements, element_index);
+                                top_left.insert_with(elements, element_index, spare);
                             } else {
                                 // bottom
-                                bottom_left.insert(elements, element_index);
+                                bottom_left.insert_with(elements, element_index, spare);
                             }
                         } else {
                             // right
-                            if element.position.y < cy {
+                            if element.position().y <= cy {
                                 // top
-                                top_right.insert(elements, element_index);
+                                top_right.insert_with(elements, element_index, spare);
                             } else {
                                 // bottom
-                                bottom_right.insert(elements, element_index);
+                                bottom_right.insert_with(elements, element_index, spare);
                             }
                         }
                     }
 
+                    spare.leaves.push(std::mem::take(element_indices));
                     self.node = QuadtreeNode::Node {
                         top_left,
                         top_right,
@@ -219,153 +925,3445 @@ impl<
         }
     }
 
-    pub fn visit(&self, visitor: &mut dyn QuadtreeVisitor<T>) {
-        match self.node {
-            QuadtreeNode::Node {
-                ref top_left,
-                ref top_right,
-                ref bottom_left,
-                ref bottom_right,
-            } => {
-                visitor.visit_node(self);
-                top_left.visit(visitor);
-                top_right.visit(visitor);
-                bottom_left.visit(visitor);
-                bottom_right.visit(visitor);
+    /// The four empty children of a node with the given center and size, taken from `spare`
+    /// while it has some. In the order top left, top right, bottom left, bottom right.
+    fn split_children(
+        center: Vector2D<T>,
+        width: T,
+        height: T,
+        depth: usize,
+        max_capacity: usize,
+        loose_factor: Option<T>,
+        spare: &mut SpareNodes<T, E>,
+    ) -> [Box<Self>; 4] {
+        let two = T::from(2).unwrap();
+        let half_width = width / two;
+        let half_height = height / two;
+        let quarter_width = half_width / two;
+        let quarter_height = half_height / two;
+        let (cx, cy) = (center.x, center.y);
+
+        let mut child = |x: T, y: T| {
+            let center = Vector2D { x, y };
+            match spare.nodes.pop() {
+                Some(mut child) => {
+                    child.reset(center, half_width, half_height, depth);
+                    child
+                }
+                None => {
+                    let mut child =
+                        Box::new(Self::new(center, half_width, half_height, max_capacity));
+                    child.depth = depth;
+                    child.loose_factor = loose_factor;
+                    child
+                }
             }
-            QuadtreeNode::Leaf {
-                ref element_indices,
-            } => {
-                visitor.visit_leaf_node(self, element_indices);
-                for element_index in element_indices {
-                    visitor.visit_element(*element_index);
+        };
+        [
+            child(cx.sub(quarter_width), cy.sub(quarter_height)),
+            child(cx.add(quarter_width), cy.sub(quarter_height)),
+            child(cx.sub(quarter_width), cy.add(quarter_height)),
+            child(cx.add(quarter_width), cy.add(quarter_height)),
+        ]
+    }
+
+    /// The radius of the disc of the element in queries, zero in a tight tree
+    fn extent(&self, element: &E) -> T {
+        match self.loose_factor {
+            Some(factor) => element.radius() * factor,
+            None => T::default(),
+        }
+    }
+
+    /// Grows the reach of this node to cover the disc of the element
+    fn widen_reach(&mut self, element: &E) {
+        let extent = self.extent(element);
+        if extent > self.reach {
+            self.reach = extent;
+        }
+    }
+
+    /// Accumulates the element into the summary particle of this node
+    fn add_to_summary(&mut self, element: &impl Positioned<T>) {
+        if self.num_elements == 0 {
+            self.summary_particle.position = element.position();
+            self.summary_particle.mass = element.mass();
+            self.summary_particle.charge = element.charge();
+            self.num_elements = 1;
+        } else {
+            // the summary sits at the center of mass, or at the plain average of massless elements
+            let mass = self.summary_particle.mass;
+            let total_mass = mass + element.mass();
+            let (weight, new_weight, total_weight) = if total_mass != T::default() {
+                (mass, element.mass(), total_mass)
+            } else {
+                (
+                    T::from(self.num_elements).unwrap(),
+                    T::from(1).unwrap(),
+                    T::from(self.num_elements + 1).unwrap(),
+                )
+            };
+            let cx = self.summary_particle.position.x;
+            let cy = self.summary_particle.position.y;
+            self.summary_particle.position = Vector2D {
+                x: cx
+                    .mul(weight)
+                    .add(element.position().x.mul(new_weight))
+                    .div(total_weight),
+                y: cy
+                    .mul(weight)
+                    .add(element.position().y.mul(new_weight))
+                    .div(total_weight),
+            };
+            self.summary_particle.mass = total_mass;
+            self.summary_particle.charge = self.summary_particle.charge + element.charge();
+            self.num_elements += 1;
+        }
+    }
+
+    /// Removes the element with the given index, which must still be at the position it was
+    /// inserted at, and takes it out of the summaries along its path. Nodes left with at most
+    /// half a leaf of elements become leaves again. Returns false and changes nothing if the
+    /// tree does not hold the index there.
+    pub fn remove(&mut self, elements: &[E], index: usize) -> bool {
+        let mut spare = std::mem::take(&mut self.spare);
+        let inserted = point_mass(elements.get(index).unwrap());
+        let removed = self.remove_inserted(elements, index, &inserted, &mut spare);
+        self.spare = spare;
+        removed
+    }
+
+    /// Moves the element with the given index from `old_position`, where it was inserted, to its
+    /// current position. If it stays in the same leaf only the summaries along the path change,
+    /// otherwise it is removed and inserted again. Returns false and changes nothing if the tree
+    /// does not hold the index at the old position.
+    pub fn relocate(&mut self, elements: &[E], index: usize, old_position: Vector2D<T>) -> bool {
+        let Some(element) = elements.get(index) else {
+            return false;
+        };
+        let mut inserted = point_mass(element);
+        inserted.position = old_position;
+        match self.keeps_leaf(index, old_position, element.position()) {
+            None => false,
+            Some(true) => {
+                self.move_in_summaries(&inserted, element.position());
+                true
+            }
+            Some(false) => {
+                let mut spare = std::mem::take(&mut self.spare);
+                self.remove_inserted(elements, index, &inserted, &mut spare);
+                self.insert_with(elements, index, &mut spare);
+                self.spare = spare;
+                true
+            }
+        }
+    }
+
+    /// Removes the element with the given index, as it was when it was inserted, handing the
+    /// children of collapsed nodes to `spare`
+    fn remove_inserted(
+        &mut self,
+        elements: &[E],
+        index: usize,
+        inserted: &Particle<T>,
+        spare: &mut SpareNodes<T, E>,
+    ) -> bool {
+        let removed = match &mut self.node {
+            QuadtreeNode::Node { .. } => self
+                .child_mut(inserted.position)
+                .unwrap()
+                .remove_inserted(elements, index, inserted, spare),
+            QuadtreeNode::Leaf { element_indices } => {
+                match element_indices.iter().position(|&i| i == index) {
+                    // keep the order of the others, which decides the order of force evaluation
+                    Some(position) => {
+                        element_indices.remove(position);
+                        true
+                    }
+                    None => false,
                 }
             }
+        };
+        if removed {
+            self.remove_from_summary(elements, inserted);
+            self.collapse_if_underfull(spare);
         }
+        removed
     }
 
-    pub fn tick(&self, elements: &mut Vec<Particle<T>>, grav_const: T, elapsed_s: T) {
-        self.tick_with_summaries(elements, grav_const, elapsed_s, &Vec::new());
+    /// Turns this node back into a leaf holding all of its elements once they fit into half a
+    /// leaf, so removals do not leave a tree of nearly empty nodes behind. The gap to the
+    /// capacity at which leaves split keeps a node whose count hovers around one threshold from
+    /// splitting and merging over and over. The summary stays as it is, the elements are the same.
+    fn collapse_if_underfull(&mut self, spare: &mut SpareNodes<T, E>) {
+        if self.num_elements > self.max_capacity / 2 {
+            return;
+        }
+        if let QuadtreeNode::Leaf { .. } = self.node {
+            return;
+        }
+        // in visiting order, which keeps the order of force evaluation
+        let mut element_indices = spare.leaves.pop().unwrap_or_default();
+        element_indices.extend(self.iter_indices());
+        let leaf = QuadtreeNode::Leaf { element_indices };
+        if let QuadtreeNode::Node {
+            top_left,
+            top_right,
+            bottom_left,
+            bottom_right,
+        } = std::mem::replace(&mut self.node, leaf)
+        {
+            for mut child in [top_left, top_right, bottom_left, bottom_right] {
+                child.recycle_children(spare);
+                spare.nodes.push(child);
+            }
+        }
     }
 
-    fn tick_with_summaries(
+    /// Whether the element with the given index, held in the leaf reached from `old_position`,
+    /// would be held by the same leaf at `new_position`. `None` if that leaf does not hold it.
+    fn keeps_leaf(
         &self,
-        elements: &mut Vec<Particle<T>>,
-        grav_const: T,
-        elapsed_s: T,
-        summaries: &Vec<Particle<T>>,
-    ) {
+        index: usize,
+        old_position: Vector2D<T>,
+        new_position: Vector2D<T>,
+    ) -> Option<bool> {
+        let stays = self.contains_point(new_position);
+        match &self.node {
+            QuadtreeNode::Node { .. } => {
+                let child = self.child(old_position).unwrap();
+                let same_child = std::ptr::eq(child, self.child(new_position).unwrap());
+                let keeps = child.keeps_leaf(index, old_position, new_position)?;
+                Some(stays && same_child && keeps)
+            }
+            QuadtreeNode::Leaf { element_indices } => {
+                element_indices.contains(&index).then_some(stays)
+            }
+        }
+    }
+
+    /// Moves the element from its inserted position to `new_position` in the summaries along its
+    /// path, which must not change
+    fn move_in_summaries(&mut self, inserted: &Particle<T>, new_position: Vector2D<T>) {
+        if self.num_elements == 1 {
+            self.summary_particle.position = new_position;
+        } else {
+            // the same weights as in add_to_summary
+            let mass = self.summary_particle.mass;
+            let weight = if mass != T::default() {
+                inserted.mass / mass
+            } else {
+                T::from(1).unwrap() / T::from(self.num_elements).unwrap()
+            };
+            let offset = new_position - inserted.position;
+            self.summary_particle.position = &self.summary_particle.position + offset * weight;
+        }
+        if let Some(child) = self.child_mut(inserted.position) {
+            child.move_in_summaries(inserted, new_position);
+        }
+    }
+
+    /// The child holding elements at the position, `None` for a leaf. Elements exactly on a
+    /// center line belong to the left or top child, like in [`Self::insert`].
+    fn child(&self, position: Vector2D<T>) -> Option<&Self> {
         match &self.node {
             QuadtreeNode::Node {
                 top_left,
                 top_right,
                 bottom_left,
                 bottom_right,
-            } => {
-                top_left.tick_with_summaries(
-                    elements,
-                    grav_const,
-                    elapsed_s,
-                    &ParticleQuadTree::create_summaries(
-                        summaries,
-                        top_right,
-                        bottom_left,
-                        bottom_right,
-                    ),
-                );
-                top_right.tick_with_summaries(
-                    elements,
-                    grav_const,
-                    elapsed_s,
-                    &ParticleQuadTree::create_summaries(
-                        summaries,
-                        top_left,
-                        bottom_left,
-                        bottom_right,
-                    ),
-                );
-                bottom_left.tick_with_summaries(
-                    elements,
-                    grav_const,
-                    elapsed_s,
-                    &ParticleQuadTree::create_summaries(
-                        summaries,
-                        top_right,
-                        top_left,
-                        bottom_right,
-                    ),
-                );
-                bottom_right.tick_with_summaries(
-                    elements,
-                    grav_const,
-                    elapsed_s,
-                    &ParticleQuadTree::create_summaries(
-                        summaries,
-                        top_right,
-                        bottom_left,
-                        top_left,
-                    ),
-                );
+            } => Some(
+                match (position.x <= self.center.x, position.y <= self.center.y) {
+                    (true, true) => top_left,
+                    (false, true) => top_right,
+                    (true, false) => bottom_left,
+                    (false, false) => bottom_right,
+                },
+            ),
+            QuadtreeNode::Leaf { .. } => None,
+        }
+    }
+
+    /// Like [`Self::child`], but mutable
+    fn child_mut(&mut self, position: Vector2D<T>) -> Option<&mut Self> {
+        match &mut self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => Some(
+                match (position.x <= self.center.x, position.y <= self.center.y) {
+                    (true, true) => top_left,
+                    (false, true) => top_right,
+                    (true, false) => bottom_left,
+                    (false, false) => bottom_right,
+                },
+            ),
+            QuadtreeNode::Leaf { .. } => None,
+        }
+    }
+
+    /// Takes the element out of the summary particle of this node, reversing
+    /// [`Self::add_to_summary`]
+    fn remove_from_summary(&mut self, elements: &[E], element: &Particle<T>) {
+        self.num_elements -= 1;
+        if self.num_elements == 0 {
+            self.summary_particle.position = self.center;
+            self.summary_particle.mass = T::default();
+            self.summary_particle.charge = T::default();
+            return;
+        }
+
+        let mass = self.summary_particle.mass;
+        let remaining_mass = mass - element.mass();
+        let (weight, old_weight, remaining_weight) = if remaining_mass != T::default() {
+            (mass, element.mass(), remaining_mass)
+        } else if mass == T::default() {
+            (
+                T::from(self.num_elements + 1).unwrap(),
+                T::from(1).unwrap(),
+                T::from(self.num_elements).unwrap(),
+            )
+        } else {
+            // only massless elements remain, whose positions the summary never took into account
+            let indices: Vec<usize> = self.iter_indices().collect();
+            self.num_elements = 0;
+            for i in indices {
+                self.add_to_summary(&elements[i]);
+            }
+            return;
+        };
+        let cx = self.summary_particle.position.x;
+        let cy = self.summary_particle.position.y;
+        self.summary_particle.position = Vector2D {
+            x: cx
+                .mul(weight)
+                .sub(element.position().x.mul(old_weight))
+                .div(remaining_weight),
+            y: cy
+                .mul(weight)
+                .sub(element.position().y.mul(old_weight))
+                .div(remaining_weight),
+        };
+        self.summary_particle.mass = remaining_mass;
+        self.summary_particle.charge = self.summary_particle.charge - element.charge();
+    }
+
+    /// The leaves below this node together with the indices they hold, in the same order as
+    /// [`Self::visit`] visits them.
+    pub fn iter_leaves(&self) -> Leaves<'_, T, E> {
+        Leaves { stack: vec![self] }
+    }
+
+    /// The indices of all elements below this node, each exactly once.
+    pub fn iter_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.iter_leaves()
+            .flat_map(|(_, element_indices)| element_indices.iter().copied())
+    }
+
+    /// Walks the tree depth-first, children in the order top left, top right, bottom left,
+    /// bottom right. This node has depth 0 for the visitor. Returns `Break` if the visitor stopped
+    /// the traversal.
+    pub fn visit(&self, visitor: &mut dyn QuadtreeVisitor<T, E>) -> ControlFlow<()> {
+        self.visit_at(visitor, 0)
+    }
+
+    fn visit_at(&self, visitor: &mut dyn QuadtreeVisitor<T, E>, depth: usize) -> ControlFlow<()> {
+        match self.node {
+            QuadtreeNode::Node {
+                ref top_left,
+                ref top_right,
+                ref bottom_left,
+                ref bottom_right,
+            } => {
+                if visitor.visit_node_at_depth(self, depth)? {
+                    top_left.visit_at(visitor, depth + 1)?;
+                    top_right.visit_at(visitor, depth + 1)?;
+                    bottom_left.visit_at(visitor, depth + 1)?;
+                    bottom_right.visit_at(visitor, depth + 1)?;
+                }
+            }
+            QuadtreeNode::Leaf {
+                ref element_indices,
+            } => {
+                if visitor.visit_leaf_node_at_depth(self, element_indices, depth)? {
+                    for element_index in element_indices {
+                        visitor.visit_element(self, *element_index)?;
+                    }
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Walks the tree like [`Self::visit`], handing the visitor the elements it holds to modify.
+    /// The summaries are not updated, so a visitor that moves elements or changes their masses
+    /// leaves the tree out of date.
+    pub fn visit_mut(
+        &self,
+        elements: &mut [E],
+        visitor: &mut dyn QuadtreeVisitorMut<T, E>,
+    ) -> ControlFlow<()> {
+        self.visit_mut_at(elements, visitor, 0)
+    }
+
+    fn visit_mut_at(
+        &self,
+        elements: &mut [E],
+        visitor: &mut dyn QuadtreeVisitorMut<T, E>,
+        depth: usize,
+    ) -> ControlFlow<()> {
+        match self.node {
+            QuadtreeNode::Node {
+                ref top_left,
+                ref top_right,
+                ref bottom_left,
+                ref bottom_right,
+            } => {
+                if visitor.visit_node_at_depth(self, depth)? {
+                    top_left.visit_mut_at(elements, visitor, depth + 1)?;
+                    top_right.visit_mut_at(elements, visitor, depth + 1)?;
+                    bottom_left.visit_mut_at(elements, visitor, depth + 1)?;
+                    bottom_right.visit_mut_at(elements, visitor, depth + 1)?;
+                }
+            }
+            QuadtreeNode::Leaf {
+                ref element_indices,
+            } => {
+                if visitor.visit_leaf_node_at_depth(self, element_indices, depth)? {
+                    for &element_index in element_indices {
+                        visitor.visit_element(self, element_index, &mut elements[element_index])?;
+                    }
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Calls `f(i, j)` with `i < j` once for every pair of elements held by the same leaf or by
+    /// two leaves whose rectangles are at most `max_dist` apart along each axis. Elements lie within
+    /// their leaves, so this includes every pair closer than `max_dist`, together with candidates
+    /// farther apart. One rectangle query per leaf finds the elements of its neighbors. In a loose
+    /// tree the rectangles are grown by their reach, so the distance is the one between the discs.
+    pub fn for_each_leaf_pair_near(&self, max_dist: T, mut f: impl FnMut(usize, usize)) {
+        let mut candidates = Vec::new();
+        for (leaf, element_indices) in self.iter_leaves() {
+            if element_indices.is_empty() {
+                continue;
+            }
+            let (min, max) = (leaf.min(), leaf.max());
+            let margin = max_dist + leaf.reach;
+            let min = Vector2D {
+                x: min.x - margin,
+                y: min.y - margin,
+            };
+            let max = Vector2D {
+                x: max.x + margin,
+                y: max.y + margin,
+            };
+            candidates.clear();
+            self.collect_in_rect(min, max, &mut candidates);
+            for &i in element_indices {
+                for &j in &candidates {
+                    // the pair is also found from the leaf of j, but only passed on from here
+                    if i < j {
+                        f(i, j);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collects the indices of all elements in leaves whose rectangles, grown by their reach,
+    /// overlap the one from `min` to `max`, wherever in their leaves the elements are.
+    fn collect_in_rect(&self, min: Vector2D<T>, max: Vector2D<T>, indices: &mut Vec<usize>) {
+        if self.num_elements == 0 || !self.reaches_rect(min, max) {
+            return;
+        }
+
+        match &self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.collect_in_rect(min, max, indices);
+                }
+            }
+            QuadtreeNode::Leaf { element_indices } => indices.extend(element_indices),
+        }
+    }
+
+    /// Collects the indices of all elements in leaves that lie within `reach` of the position,
+    /// plus their own reach in a loose tree.
+    pub(crate) fn collect_near(&self, position: Vector2D<T>, reach: T, indices: &mut Vec<usize>) {
+        if self.num_elements == 0 || !self.bounds_contain(position, reach + self.reach) {
+            return;
+        }
+
+        match &self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.collect_near(position, reach, indices);
+                }
+            }
+            QuadtreeNode::Leaf { element_indices } => indices.extend(element_indices),
+        }
+    }
+
+    /// Collects the indices of all elements whose positions lie within the rectangle from `min` to
+    /// `max`, edges included, or in a loose tree whose discs touch it. Subtrees whose rectangle,
+    /// grown by their reach, misses it are skipped.
+    pub fn query_rect(
+        &self,
+        elements: &[E],
+        min: Vector2D<T>,
+        max: Vector2D<T>,
+        indices: &mut Vec<usize>,
+    ) {
+        if self.num_elements == 0 || !self.reaches_rect(min, max) {
+            return;
+        }
+
+        match &self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.query_rect(elements, min, max, indices);
+                }
             }
             QuadtreeNode::Leaf { element_indices } => {
-                // simple gravitational pull
-                let mut delta_velocities = Vec::new();
-                let num_particles = element_indices.len();
-                (0..num_particles).for_each(|i| {
-                    let mut delta_v: Vector2D<T> = Vector2D {
-                        x: Default::default(),
-                        y: Default::default(),
-                    };
+                indices.extend(element_indices.iter().filter(|&&i| {
+                    let position = elements[i].position();
+                    if self.loose_factor.is_some() {
+                        let extent = self.extent(&elements[i]);
+                        distance_sq_to_rect(position, min, max) <= extent * extent
+                    } else {
+                        position.x >= min.x
+                            && position.x <= max.x
+                            && position.y >= min.y
+                            && position.y <= max.y
+                    }
+                }));
+            }
+        }
+    }
+
+    /// Collects the indices of all elements whose positions lie within `radius` of the center,
+    /// the boundary included, or in a loose tree whose discs touch that circle. Subtrees whose
+    /// rectangle is farther away than the radius plus their reach are skipped.
+    pub fn query_radius(
+        &self,
+        elements: &[E],
+        center: Vector2D<T>,
+        radius: T,
+        indices: &mut Vec<usize>,
+    ) {
+        let radius_sq = radius * radius;
+        let node_radius = radius + self.reach;
+        if self.num_elements == 0 || self.distance_sq_to(center) > node_radius * node_radius {
+            return;
+        }
+
+        match &self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.query_radius(elements, center, radius, indices);
+                }
+            }
+            QuadtreeNode::Leaf { element_indices } => {
+                indices.extend(element_indices.iter().filter(|&&i| {
+                    let distance_sq = elements[i].position().distance_sq(&center);
+                    if self.loose_factor.is_some() {
+                        let element_radius = radius + self.extent(&elements[i]);
+                        distance_sq <= element_radius * element_radius
+                    } else {
+                        distance_sq <= radius_sq
+                    }
+                }));
+            }
+        }
+    }
+
+    /// The index of the element nearest to the point, the lowest one among equally near elements,
+    /// or `None` if the tree is empty.
+    pub fn nearest(&self, elements: &[E], point: Vector2D<T>) -> Option<usize> {
+        self.search_nearest(elements, point, None)
+    }
+
+    /// Like [`Self::nearest`], but ignores elements farther than `max_distance` from the point.
+    pub fn nearest_within(
+        &self,
+        elements: &[E],
+        point: Vector2D<T>,
+        max_distance: T,
+    ) -> Option<usize> {
+        self.search_nearest(elements, point, Some(max_distance * max_distance))
+    }
+
+    /// Best-first search: nodes are searched in the order of their distance to the point, until
+    /// the next one is farther away than the nearest element found so far.
+    fn search_nearest(
+        &self,
+        elements: &[E],
+        point: Vector2D<T>,
+        max_distance_sq: Option<T>,
+    ) -> Option<usize> {
+        let mut best: Option<(T, usize)> = None;
+        let mut queue = BinaryHeap::new();
+        queue.push(QueuedNode {
+            distance_sq: self.distance_sq_to(point),
+            node: self,
+        });
+        while let Some(QueuedNode { distance_sq, node }) = queue.pop() {
+            let limit = best.map(|(best_sq, _)| best_sq).or(max_distance_sq);
+            if limit.is_some_and(|limit| distance_sq > limit) {
+                break;
+            }
+            if node.num_elements == 0 {
+                continue;
+            }
+            match &node.node {
+                QuadtreeNode::Node {
+                    top_left,
+                    top_right,
+                    bottom_left,
+                    bottom_right,
+                } => {
+                    for child in [top_left, top_right, bottom_left, bottom_right] {
+                        queue.push(QueuedNode {
+                            distance_sq: child.distance_sq_to(point),
+                            node: child,
+                        });
+                    }
+                }
+                QuadtreeNode::Leaf { element_indices } => {
+                    for &i in element_indices {
+                        let element_sq = elements[i].position().distance_sq(&point);
+                        let closer = match best {
+                            Some((best_sq, best_i)) => {
+                                element_sq < best_sq || (element_sq == best_sq && i < best_i)
+                            }
+                            None => max_distance_sq.is_none_or(|limit| element_sq <= limit),
+                        };
+                        if closer {
+                            best = Some((element_sq, i));
+                        }
+                    }
+                }
+            }
+        }
+        best.map(|(_, i)| i)
+    }
+
+    /// Appends the indices of the `k` elements nearest to the point, nearest first and the lower
+    /// index first among equally near ones. Fewer if the tree holds fewer elements.
+    pub fn knn(&self, elements: &[E], point: Vector2D<T>, k: usize, indices: &mut Vec<usize>) {
+        if k == 0 {
+            return;
+        }
+        // the k nearest candidates so far, the farthest of them on top
+        let mut found: BinaryHeap<Candidate<T>> = BinaryHeap::with_capacity(k + 1);
+        let mut queue = BinaryHeap::new();
+        queue.push(QueuedNode {
+            distance_sq: self.distance_sq_to(point),
+            node: self,
+        });
+        while let Some(QueuedNode { distance_sq, node }) = queue.pop() {
+            if found.len() == k
+                && found
+                    .peek()
+                    .is_some_and(|kth| distance_sq > kth.distance_sq)
+            {
+                break;
+            }
+            if node.num_elements == 0 {
+                continue;
+            }
+            match &node.node {
+                QuadtreeNode::Node {
+                    top_left,
+                    top_right,
+                    bottom_left,
+                    bottom_right,
+                } => {
+                    for child in [top_left, top_right, bottom_left, bottom_right] {
+                        queue.push(QueuedNode {
+                            distance_sq: child.distance_sq_to(point),
+                            node: child,
+                        });
+                    }
+                }
+                QuadtreeNode::Leaf { element_indices } => {
+                    for &index in element_indices {
+                        found.push(Candidate {
+                            distance_sq: elements[index].position().distance_sq(&point),
+                            index,
+                        });
+                        if found.len() > k {
+                            found.pop();
+                        }
+                    }
+                }
+            }
+        }
+        indices.extend(
+            found
+                .into_sorted_vec()
+                .into_iter()
+                .map(|candidate| candidate.index),
+        );
+    }
+
+    /// Writes the nodes below this one for inspection, one line each in the order of
+    /// [`Self::visit`] and indented by their depth below this node. A line holds the kind of node,
+    /// its depth, center, size, number of elements and summary particle, and for a leaf the
+    /// indices of its elements:
+    ///
+    /// ```text
+    /// node depth=0 center=0,0 size=4,4 elements=3 mass=4 charge=0.5 summary=0.5,-0.5
+    ///   leaf depth=1 center=-1,-1 size=2,2 elements=1 mass=1 charge=0 summary=-1,-1 indices=0
+    /// ```
+    pub fn dump<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        let mut dump = TreeDump {
+            writer,
+            error: None,
+        };
+        let _ = self.visit(&mut dump);
+        match dump.error {
+            Some(error) => Err(error),
+            None => dump.writer.flush(),
+        }
+    }
+
+    /// The shorter side of the smallest leaf below this node that holds elements, `None` if there
+    /// are none
+    pub fn smallest_leaf(&self) -> Option<T> {
+        self.iter_leaves()
+            .filter(|(_, element_indices)| !element_indices.is_empty())
+            .map(|(leaf, _)| {
+                if leaf.width < leaf.height {
+                    leaf.width
+                } else {
+                    leaf.height
+                }
+            })
+            .reduce(|smallest, side| if side < smallest { side } else { smallest })
+    }
+
+    /// Describes the shape of the tree below this node.
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        self.add_stats(self.depth, &mut stats);
+        stats
+    }
+
+    fn add_stats(&self, root_depth: usize, stats: &mut TreeStats) {
+        stats.nodes += 1;
+        stats.depth = stats.depth.max(self.depth - root_depth + 1);
+        match &self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.add_stats(root_depth, stats);
+                }
+            }
+            QuadtreeNode::Leaf { element_indices } => {
+                stats.leaves += 1;
+                stats.leaves_per_depth[self.depth - root_depth] += 1;
+                if element_indices.is_empty() {
+                    stats.empty_leaves += 1;
+                }
+                stats.elements += element_indices.len();
+                stats.max_leaf_occupancy = stats.max_leaf_occupancy.max(element_indices.len());
+            }
+        }
+    }
+
+    /// The center of the rectangle covered by this node, where it splits into its children
+    pub fn center(&self) -> Vector2D<T> {
+        self.center
+    }
+
+    pub fn width(&self) -> T {
+        self.width
+    }
+
+    pub fn height(&self) -> T {
+        self.height
+    }
+
+    /// The particle standing in for all elements below this node in the force approximation:
+    /// their total mass and charge at their center of mass, or at their plain average if they are
+    /// massless, and at the center of an empty node
+    pub fn summary(&self) -> &Particle<T> {
+        &self.summary_particle
+    }
+
+    /// The number of elements below this node
+    pub fn len(&self) -> usize {
+        self.num_elements
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_elements == 0
+    }
+
+    /// How far the discs of the elements below this node reach over its rectangle, zero in a
+    /// tight tree
+    pub fn reach(&self) -> T {
+        self.reach
+    }
+
+    /// The longer side of the rectangle covered by this node, which decides whether the node is
+    /// far enough away to be approximated
+    fn longest_side(&self) -> T {
+        if self.width > self.height {
+            self.width
+        } else {
+            self.height
+        }
+    }
+
+    /// The corner of the rectangle covered by this node with the smallest coordinates
+    pub fn min(&self) -> Vector2D<T> {
+        let two = T::from(2).unwrap();
+        Vector2D {
+            x: self.center.x - self.width / two,
+            y: self.center.y - self.height / two,
+        }
+    }
+
+    /// The corner of the rectangle covered by this node with the largest coordinates
+    pub fn max(&self) -> Vector2D<T> {
+        let two = T::from(2).unwrap();
+        Vector2D {
+            x: self.center.x + self.width / two,
+            y: self.center.y + self.height / two,
+        }
+    }
+
+    /// Checks whether the point lies within the rectangle covered by this node, edges included,
+    /// so exactly when inserting an element there does not grow the node. The children of a node
+    /// share their edges, an element on the center line is routed to the left or top child.
+    pub fn contains_point(&self, point: Vector2D<T>) -> bool {
+        self.bounds_contain(point, T::default())
+    }
+
+    /// Grows the rectangle of this node around its center so that it contains the point
+    fn grow_to(&mut self, point: Vector2D<T>) {
+        let two = T::from(2).unwrap();
+        let distance = |a: T, b: T| if a < b { b - a } else { a - b };
+        // a little more than twice the distance, so that rounding cannot leave the point outside
+        let margin = T::from(1.0 + 1.0 / 1024.0).unwrap();
+        let width = distance(point.x, self.center.x) * two * margin;
+        let height = distance(point.y, self.center.y) * two * margin;
+        if self.width < width {
+            self.width = width;
+        }
+        if self.height < height {
+            self.height = height;
+        }
+    }
+
+    /// The squared distance from the point to the nearest point of the rectangle covered by this
+    /// node, zero inside of it.
+    fn distance_sq_to(&self, point: Vector2D<T>) -> T {
+        distance_sq_to_rect(point, self.min(), self.max())
+    }
+
+    /// Checks whether the rectangle from `min` to `max` overlaps the one covered by this node,
+    /// touching edges included.
+    pub fn intersects_rect(&self, min: Vector2D<T>, max: Vector2D<T>) -> bool {
+        let two = T::from(2).unwrap();
+        let half_width = self.width / two;
+        let half_height = self.height / two;
+        self.center.x - half_width <= max.x
+            && self.center.x + half_width >= min.x
+            && self.center.y - half_height <= max.y
+            && self.center.y + half_height >= min.y
+    }
+
+    /// Like [`Self::intersects_rect`], but with the rectangle of this node grown by its reach
+    fn reaches_rect(&self, min: Vector2D<T>, max: Vector2D<T>) -> bool {
+        let (node_min, node_max) = (self.min(), self.max());
+        node_min.x - self.reach <= max.x
+            && node_max.x + self.reach >= min.x
+            && node_min.y - self.reach <= max.y
+            && node_max.y + self.reach >= min.y
+    }
+
+    /// Checks whether the position lies within the rectangle covered by this node, grown by
+    /// `margin` on every side.
+    fn bounds_contain(&self, position: Vector2D<T>, margin: T) -> bool {
+        let two = T::from(2).unwrap();
+        let half_width = self.width / two + margin;
+        let half_height = self.height / two + margin;
+        position.x >= self.center.x - half_width
+            && position.x <= self.center.x + half_width
+            && position.y >= self.center.y - half_height
+            && position.y <= self.center.y + half_height
+    }
+}
+
+impl<T: Float + Default + std::fmt::Display + Send + Sync> ParticleQuadTree<T> {
+    /// Computes the kinetic energy of the elements exactly and their gravitational potential energy
+    /// with the same approximation as the forces, so the tree must have been built from the
+    /// current positions. A pair at distance `r` has the potential `G m1 m2 ln(r^2 + s^2) / 2` of
+    /// the softened 1/r force, or `-G m1 m2 / ((n - 1) (r^2 + s^2)^((n - 1) / 2))` for another
+    /// exponent `n`.
+    pub fn energy(
+        &self,
+        elements: &[Particle<T>],
+        params: &ForceParameters<'_, T>,
+    ) -> EnergyReport<T> {
+        let _span = info_span!("energy", particles = elements.len()).entered();
+        let kinetic = elements
+            .iter()
+            .fold(T::default(), |sum, element| sum + kinetic_energy(element));
+        let mut potential = T::default();
+        for index in 0..elements.len() {
+            self.accumulate_potential(elements, index, params, &mut potential);
+        }
+        // every pair was counted once from each side
+        let potential = potential / T::from(2).unwrap();
+        EnergyReport {
+            kinetic,
+            potential,
+            total: kinetic + potential,
+        }
+    }
+
+    /// Adds the potential energy between the element at `index` and all elements of this subtree
+    /// to `potential`, opening nodes like `accumulate_pull`.
+    fn accumulate_potential(
+        &self,
+        elements: &[Particle<T>],
+        index: usize,
+        params: &ForceParameters<'_, T>,
+        potential: &mut T,
+    ) {
+        if self.num_elements == 0 {
+            return;
+        }
+
+        let element = &elements[index];
+        let position = element.position;
+        let distance_sq =
+            displacement(position, self.summary_particle.position, params.period).length_sq();
+        let size = self.longest_side();
+        let is_far = size * size < params.theta * params.theta * distance_sq;
+        if is_far && !self.bounds_contain(position, T::default()) {
+            *potential =
+                *potential + pair_potential(element, &self.summary_particle, params, T::default());
+            return;
+        }
+
+        match &self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.accumulate_potential(elements, index, params, potential);
+                }
+            }
+            QuadtreeNode::Leaf { element_indices } => {
+                for &other in element_indices {
+                    if other != index {
+                        let other = &elements[other];
+                        let interior = interior_radius(element, other, params);
+                        *potential = *potential + pair_potential(element, other, params, interior);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolves overlapping elements as collisions after a step, conserving momentum, and pushes
+    /// them apart so they do not stay stuck inside each other. The relative normal velocity after
+    /// a collision is `restitution` times the one before, so 1 is elastic and 0 perfectly inelastic.
+    /// `friction` is the fraction of the relative tangential velocity removed by a collision.
+    /// The tree must have been built from the positions before the step; candidates are found
+    /// by widening the search by the largest distance any element moved since then.
+    /// Unless `temperatures` is empty, each element of a colliding pair is heated by half of the
+    /// kinetic energy the collision dissipated.
+    pub fn resolve_collisions(
+        &self,
+        elements: &mut [Particle<T>],
+        elapsed_s: T,
+        restitution: T,
+        friction: T,
+        temperatures: &mut [T],
+    ) {
+        let span = info_span!("collisions", collisions = field::Empty).entered();
+        // both elements of a pair may have left their leaves by the distance they moved
+        let margin = search_margin(elements, elapsed_s);
+        let max_dist = margin + margin;
+
+        let mut collisions = 0;
+        self.for_each_leaf_pair_near(max_dist, |i, j| {
+            if let Some(dissipated) = collide(elements, i, j, restitution, friction) {
+                collisions += 1;
+                if !temperatures.is_empty() {
+                    let half = dissipated / T::from(2).unwrap();
+                    temperatures[i] = temperatures[i] + half;
+                    temperatures[j] = temperatures[j] + half;
+                }
+            }
+        });
+        span.record("collisions", collisions);
+    }
+
+    /// Merges overlapping elements into one, conserving mass and momentum, and returns the
+    /// ascending indices of the elements that were absorbed and should be removed.
+    /// An element that absorbed another keeps absorbing with its merged state, so overlaps of
+    /// three or more elements never count the same mass twice.
+    /// With a density, the radius of a merged element is derived from its new mass.
+    pub fn merge_collisions(
+        &self,
+        elements: &mut [Particle<T>],
+        elapsed_s: T,
+        density: Option<T>,
+        merging: &[bool],
+    ) -> Vec<usize> {
+        let span = info_span!("merges", merges = field::Empty).entered();
+        let margin = search_margin(elements, elapsed_s);
+
+        let mut absorbed = vec![false; elements.len()];
+        let mut candidates = Vec::new();
+        for i in 0..elements.len() {
+            if absorbed[i] {
+                continue;
+            }
+            let reach = elements[i].radius + margin;
+            candidates.clear();
+            self.collect_near(elements[i].position, reach, &mut candidates);
+            for &j in &candidates {
+                if j > i && !absorbed[j] && merge(elements, i, j, density, merging) {
+                    absorbed[j] = true;
+                }
+            }
+        }
+
+        let removed: Vec<usize> = (0..elements.len()).filter(|&i| absorbed[i]).collect();
+        span.record("merges", removed.len());
+        removed
+    }
+}
+
+impl<T: Float + Default + std::fmt::Display + Send + Sync> ForceTree<T> for ParticleQuadTree<T> {
+    fn rebuild(&mut self, elements: &[Particle<T>]) {
+        QuadTree::rebuild(self, elements);
+    }
+
+    /// Adds the change in velocity caused by all elements of this subtree on the element at
+    /// `index` to `delta_v` (Barnes-Hut). A node that appears small from the element's position,
+    /// i.e. `longest side / distance < theta`, is approximated by its summary particle, otherwise its
+    /// children or elements are evaluated individually.
+    ///
+    /// Floating point addition is not associative, so reproducible runs rely on this order: the
+    /// children are visited top left, top right, bottom left, bottom right, and the elements of a
+    /// leaf in the order they are stored there (see `insert`).
+    fn accumulate_pull(
+        &self,
+        elements: &[Particle<T>],
+        index: usize,
+        params: &ForceParameters<'_, T>,
+        elapsed_s: T,
+        delta_v: &mut Vector2D<T>,
+        stats: &mut ForceStats,
+    ) {
+        if self.num_elements == 0 {
+            return;
+        }
+
+        let element = &elements[index];
+        let position = element.position;
+        let distance_sq =
+            displacement(position, self.summary_particle.position, params.period).length_sq();
+        let size = self.longest_side();
+        let is_far = size * size < params.theta * params.theta * distance_sq;
+        if is_far && !self.bounds_contain(position, T::default()) {
+            let pull = pull(element, &self.summary_particle, params, T::default());
+            *delta_v = &*delta_v + (pull * elapsed_s);
+            stats.approximations += 1;
+            return;
+        }
+
+        match &self.node {
+            QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } => {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    child.accumulate_pull(elements, index, params, elapsed_s, delta_v, stats);
+                }
+            }
+            QuadtreeNode::Leaf { element_indices } => {
+                for &other in element_indices {
+                    if other == index {
+                        continue;
+                    }
+                    let pull = pull(
+                        element,
+                        &elements[other],
+                        params,
+                        interior_radius(element, &elements[other], params),
+                    );
+                    *delta_v = &*delta_v + (pull * elapsed_s);
+                    if let (Some(repulsion), None) = (&params.repulsion, params.neighbors) {
+                        let push = repulsion.push(element, &elements[other], params.period);
+                        *delta_v = &*delta_v + (push * elapsed_s);
+                    }
+                    stats.interactions += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Calculates the change in velocity of every element during one step from the pulls between the
+/// elements from `tree`, without modifying them. The result replaces the contents of
+/// `delta_velocities`. With `active`, only the marked elements are evaluated and the others get
+/// zero.
+fn delta_velocities<T>(
+    tree: &(impl ForceTree<T> + ?Sized),
+    elements: &[Particle<T>],
+    active: Option<&[bool]>,
+    params: &ForceParameters<'_, T>,
+    elapsed_s: T,
+    delta_velocities: &mut Vec<Vector2D<T>>,
+) where
+    T: Float + Default + Send + Sync,
+{
+    let span = info_span!(
+        "forces",
+        interactions = field::Empty,
+        approximations = field::Empty
+    )
+    .entered();
+    let is_active = |index: usize| active.is_none_or(|active| active[index]);
+    let delta_v_of = |index: usize, stats: &mut ForceStats| {
+        if !is_active(index) {
+            return Vector2D::default();
+        }
+        let mut delta_v = params.uniform_field * elapsed_s;
+        let swirl_offset = elements[index].position - params.swirl_center;
+        let swirl_distance = swirl_offset.length();
+        if params.swirl_strength != T::default() && swirl_distance > T::default() {
+            let swirl = swirl_offset.perpendicular() * (params.swirl_strength / swirl_distance);
+            delta_v = &delta_v + (swirl * elapsed_s);
+        }
+        if params.frame_rotation != T::default() {
+            let acceleration = centrifugal_acceleration(&elements[index], params);
+            delta_v = &delta_v + (acceleration * elapsed_s);
+        }
+        if let Some(acceleration) = params.extra_accelerations.get(index) {
+            delta_v = &delta_v + (*acceleration * elapsed_s);
+        }
+        if let Some(force) = params.external_forces.get(index) {
+            delta_v = &delta_v + (*force * (elapsed_s / elements[index].mass));
+        }
+        for attractor in params.attractors {
+            let pull = pull(&elements[index], attractor, params, T::default());
+            delta_v = &delta_v + (pull * elapsed_s);
+        }
+        tree.accumulate_pull(elements, index, params, elapsed_s, &mut delta_v, stats);
+        if let (Some(repulsion), Some(neighbors)) = (&params.repulsion, params.neighbors) {
+            for &other in neighbors.neighbors(index) {
+                let push = repulsion.push(&elements[index], &elements[other], params.period);
+                delta_v = &delta_v + (push * elapsed_s);
+            }
+        }
+        delta_v
+    };
+    delta_velocities.clear();
+    #[cfg(not(feature = "parallel"))]
+    let stats = {
+        let mut stats = ForceStats::default();
+        delta_velocities.extend((0..elements.len()).map(|index| delta_v_of(index, &mut stats)));
+        stats
+    };
+    // each element is evaluated by one thread, in the same order as serially, so the results do
+    // not depend on the number of threads
+    #[cfg(feature = "parallel")]
+    let stats = {
+        delta_velocities.resize(elements.len(), Vector2D::default());
+        delta_velocities
+            .par_iter_mut()
+            .enumerate()
+            .fold(ForceStats::default, |mut stats, (index, delta_v)| {
+                *delta_v = delta_v_of(index, &mut stats);
+                stats
+            })
+            .reduce(ForceStats::default, ForceStats::combined)
+    };
+    for spring in params.springs {
+        spring.apply(elements, delta_velocities, elapsed_s);
+    }
+    if active.is_some() {
+        // springs also pull on the inactive ends
+        for (index, delta_v) in delta_velocities.iter_mut().enumerate() {
+            if !is_active(index) {
+                *delta_v = Vector2D::default();
+            }
+        }
+    }
+    span.record("interactions", stats.interactions);
+    span.record("approximations", stats.approximations);
+}
+
+/// The smallest rectangle containing the positions of all elements as its minimum and maximum
+/// corner, both zero if there are none
+pub(crate) fn enclosing_bounds<T, E>(elements: &[E]) -> (Vector2D<T>, Vector2D<T>)
+where
+    T: Copy + Default + PartialOrd,
+    E: Positioned<T>,
+{
+    let lower = |a: T, b: T| if b < a { b } else { a };
+    let upper = |a: T, b: T| if b > a { b } else { a };
+    let mut positions = elements.iter().map(|element| element.position());
+    match positions.next() {
+        Some(first) => positions.fold((first, first), |(min, max), position| {
+            (
+                Vector2D {
+                    x: lower(min.x, position.x),
+                    y: lower(min.y, position.y),
+                },
+                Vector2D {
+                    x: upper(max.x, position.x),
+                    y: upper(max.y, position.y),
+                },
+            )
+        }),
+        None => (Vector2D::default(), Vector2D::default()),
+    }
+}
+
+/// Combines the given parts of a node, each of them an element or summary particle standing for
+/// a number of elements, into its summary particle and number of elements. The summary sits at
+/// the center of mass, or like in [`QuadTree::add_to_summary`] at the plain average of massless
+/// elements, and at `empty_position` if there are no elements.
+pub(crate) fn summarize<'p, T, P>(
+    parts: impl Iterator<Item = (&'p P, usize)>,
+    empty_position: Vector2D<T>,
+) -> (Particle<T>, usize)
+where
+    P: Positioned<T> + 'p,
+    T: 'p + Float + Default,
+{
+    let mut num_elements = 0;
+    let mut mass = T::default();
+    let mut charge = T::default();
+    let mut weighted = Vector2D::default();
+    let mut sum = Vector2D::default();
+    for (part, count) in parts {
+        num_elements += count;
+        mass = mass + part.mass();
+        charge = charge + part.charge();
+        weighted = &weighted + part.position() * part.mass();
+        sum = &sum + part.position() * T::from(count).unwrap();
+    }
+
+    let position = if num_elements == 0 {
+        empty_position
+    } else {
+        let (total, weight) = if mass != T::default() {
+            (weighted, mass)
+        } else {
+            (sum, T::from(num_elements).unwrap())
+        };
+        Vector2D {
+            x: total.x / weight,
+            y: total.y / weight,
+        }
+    };
+    let summary_particle = Particle {
+        position,
+        velocity: Vector2D::default(),
+        radius: T::default(),
+        mass,
+        charge,
+        species: Default::default(),
+    };
+    (summary_particle, num_elements)
+}
+
+/// A particle standing in for the element in the summaries, with its position, mass and charge
+fn point_mass<T: Copy + Default>(element: &impl Positioned<T>) -> Particle<T> {
+    Particle {
+        position: element.position(),
+        velocity: Vector2D::default(),
+        radius: T::default(),
+        mass: element.mass(),
+        charge: element.charge(),
+        species: Default::default(),
+    }
+}
+
+/// The squared distance from the point to the nearest point of the rectangle from `min` to `max`,
+/// zero inside of it
+fn distance_sq_to_rect<T>(point: Vector2D<T>, min: Vector2D<T>, max: Vector2D<T>) -> T
+where
+    T: Copy + PartialOrd + Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+{
+    let clamp = |value: T, min: T, max: T| {
+        if value < min {
+            min
+        } else if value > max {
+            max
+        } else {
+            value
+        }
+    };
+    let nearest = Vector2D {
+        x: clamp(point.x, min.x, max.x),
+        y: clamp(point.y, min.y, max.y),
+    };
+    nearest.distance_sq(&point)
+}
+
+/// Reorders the indices so that those satisfying the predicate come first, and splits them there
+fn partition(
+    indices: &mut [usize],
+    mut predicate: impl FnMut(usize) -> bool,
+) -> (&mut [usize], &mut [usize]) {
+    let mut split = 0;
+    for i in 0..indices.len() {
+        if predicate(indices[i]) {
+            indices.swap(split, i);
+            split += 1;
+        }
+    }
+    indices.split_at_mut(split)
+}
+
+/// The vector from `from` to `to`, or to the nearest periodic image of `to` in a periodic world.
+pub(crate) fn displacement<T>(
+    from: Vector2D<T>,
+    to: Vector2D<T>,
+    period: Option<Vector2D<T>>,
+) -> Vector2D<T>
+where
+    T: Float + Default,
+{
+    let v_dir = to - from;
+    let Some(period) = period else {
+        return v_dir;
+    };
+    let nearest = |d: T, length: T| {
+        let half = length / T::from(2).unwrap();
+        if d > half {
+            d - length
+        } else if d < T::default() - half {
+            d + length
+        } else {
+            d
+        }
+    };
+    Vector2D {
+        x: nearest(v_dir.x, period.x),
+        y: nearest(v_dir.y, period.y),
+    }
+}
+
+/// Limits every change in velocity to `max_acceleration * elapsed_s`, returning how many were
+/// scaled down.
+fn clamp_accelerations<T>(
+    delta_velocities: &mut [Vector2D<T>],
+    params: &ForceParameters<'_, T>,
+    elapsed_s: T,
+) -> usize
+where
+    T: Float,
+{
+    let Some(max_acceleration) = params.max_acceleration else {
+        return 0;
+    };
+    let max_delta_v = max_acceleration * elapsed_s;
+    let mut clamped = 0;
+    for delta_v in delta_velocities {
+        if delta_v.length_sq() > max_delta_v * max_delta_v {
+            *delta_v = delta_v.clamp_length(max_delta_v);
+            clamped += 1;
+        }
+    }
+    clamped
+}
+
+/// The centrifugal acceleration `omega^2 * r` in a rotating frame.
+fn centrifugal_acceleration<T>(
+    element: &Particle<T>,
+    params: &ForceParameters<'_, T>,
+) -> Vector2D<T>
+where
+    T: Copy + Sub<Output = T> + Mul<Output = T>,
+{
+    let omega = params.frame_rotation;
+    (element.position - params.frame_center) * (omega * omega)
+}
+
+/// The distance below which two individual elements attract like uniform discs: the sum of their
+/// radii with solid interiors, otherwise zero.
+pub(crate) fn interior_radius<T>(
+    element: &Particle<T>,
+    other: &Particle<T>,
+    params: &ForceParameters<'_, T>,
+) -> T
+where
+    T: Copy + Default + Add<Output = T>,
+{
+    if params.solid_interior {
+        element.radius + other.radius
+    } else {
+        T::default()
+    }
+}
+
+/// The softened acceleration that `other` exerts on `element` through gravity and, if enabled,
+/// the Coulomb force, which is repulsive for like charges.
+/// Closer than `interior_radius`, gravity grows linearly with the distance from zero at the
+/// center to the point-mass value at `interior_radius`.
+pub(crate) fn pull<T>(
+    element: &Particle<T>,
+    other: &Particle<T>,
+    params: &ForceParameters<'_, T>,
+    interior_radius: T,
+) -> Vector2D<T>
+where
+    T: Float + Default,
+{
+    let v_dir = displacement(element.position, other.position, params.period);
+    let softening_sq = params.softening * params.softening;
+    let r_sq = v_dir.length_sq() + softening_sq;
+    // inside, the strength of the field at the surface is applied to the actual direction
+    let interior_sq = interior_radius * interior_radius + softening_sq;
+    let gravity_r_sq = if r_sq < interior_sq {
+        interior_sq
+    } else {
+        r_sq
+    };
+    let mut a1 =
+        params.grav_const * other.mass / gravity_denominator(gravity_r_sq, params.force_exponent);
+    let zero = T::default();
+    if params.coulomb_const != zero && element.charge != zero {
+        a1 = a1 - params.coulomb_const * element.charge * other.charge / element.mass / r_sq;
+    }
+    v_dir * a1
+}
+
+/// The gravitational potential energy of two elements, the counterpart of `pull`.
+fn pair_potential<T>(
+    element: &Particle<T>,
+    other: &Particle<T>,
+    params: &ForceParameters<'_, T>,
+    interior_radius: T,
+) -> T
+where
+    T: Float + Default,
+{
+    let v_dir = displacement(element.position, other.position, params.period);
+    let softening_sq = params.softening * params.softening;
+    let r_sq = v_dir.length_sq() + softening_sq;
+    let interior_sq = interior_radius * interior_radius + softening_sq;
+    let strength = params.grav_const * element.mass * other.mass;
+    if r_sq < interior_sq {
+        // the harmonic potential of the linear interior field, matching the outside at the surface
+        let surface = point_potential(strength, interior_sq, params.force_exponent);
+        let stiffness = strength / gravity_denominator(interior_sq, params.force_exponent);
+        return surface - stiffness * (interior_sq - r_sq) / T::from(2).unwrap();
+    }
+    point_potential(strength, r_sq, params.force_exponent)
+}
+
+/// The potential energy `strength * ln(r^2) / 2` of two point masses for the 1/r force, or
+/// `-strength / ((n - 1) * r^(n - 1))` for another exponent `n`, given the softened `r^2`.
+fn point_potential<T>(strength: T, r_sq: T, n: T) -> T
+where
+    T: Float + Default,
+{
+    let one = T::from(1).unwrap();
+    if n == one {
+        strength * r_sq.ln() / T::from(2).unwrap()
+    } else {
+        // -1 / ((n - 1) * r^(n - 1)), which vanishes far away for n > 1
+        let half_power = (n - one) / T::from(2).unwrap();
+        T::default() - strength / ((n - one) * r_sq.powf(half_power))
+    }
+}
+
+/// Divides `grav_const * m * d` to get the softened force `grav_const * m / r^n` along the
+/// unnormalized direction `d`, i.e. `(r^2 + s^2)^((n + 1) / 2)` for the softened squared distance.
+/// For the default 1/r force this is just the squared distance.
+fn gravity_denominator<T>(r_sq: T, exponent: T) -> T
+where
+    T: Float,
+{
+    let one = T::from(1).unwrap();
+    if exponent == one {
+        r_sq
+    } else {
+        r_sq.powf((exponent + one) / T::from(2).unwrap())
+    }
+}
+
+impl<T: Float + Default> Repulsion<T> {
+    /// The acceleration that `other` exerts on `element` through the repulsion, which is zero
+    /// beyond the cutoff. It is only evaluated for pairs that are not approximated by summaries.
+    pub(crate) fn push(
+        &self,
+        element: &Particle<T>,
+        other: &Particle<T>,
+        period: Option<Vector2D<T>>,
+    ) -> Vector2D<T> {
+        let zero = T::default();
+        let v_dir = displacement(other.position, element.position, period);
+        let r_sq = v_dir.length_sq();
+        if r_sq >= self.cutoff * self.cutoff || r_sq <= zero || element.mass <= zero {
+            return Vector2D::default();
+        }
+
+        // scale the direction by strength / r^(exponent + 1) to get a force of strength / r^exponent
+        let r = r_sq.sqrt();
+        let mut r_pow = r;
+        for _ in 0..self.exponent {
+            r_pow = r_pow * r;
+        }
+        v_dir * (self.strength / element.mass / r_pow)
+    }
+}
+
+/// The distance by which the collision search around an element is widened beyond its own radius:
+/// the largest radius plus the largest distance any element moved during the step.
+fn search_margin<T>(elements: &[Particle<T>], elapsed_s: T) -> T
+where
+    T: Float + Default,
+{
+    let zero = T::default();
+    let mut max_radius = zero;
+    let mut max_displacement_sq = zero;
+    for element in elements {
+        if element.radius > max_radius {
+            max_radius = element.radius;
+        }
+        let displacement_sq = (element.velocity * elapsed_s).length_sq();
+        if displacement_sq > max_displacement_sq {
+            max_displacement_sq = displacement_sq;
+        }
+    }
+    max_radius + max_displacement_sq.sqrt()
+}
+
+/// Resolves a collision between the elements `i` and `j` if they overlap. Returns the kinetic
+/// energy dissipated by the collision, or `None` if they did not overlap.
+fn collide<T>(
+    elements: &mut [Particle<T>],
+    i: usize,
+    j: usize,
+    restitution: T,
+    friction: T,
+) -> Option<T>
+where
+    T: Float + Default,
+{
+    let p1 = elements[i];
+    let p2 = elements[j];
+    let zero = T::default();
+    let v_dir = p2.position - p1.position;
+    let distance_sq = v_dir.length_sq();
+    let min_distance = p1.radius + p2.radius;
+    if distance_sq >= min_distance * min_distance {
+        return None;
+    }
+
+    let total_mass = p1.mass + p2.mass;
+    if total_mass <= zero {
+        return None;
+    }
+
+    // push the elements apart along the line between them, the lighter one moving further
+    let distance = distance_sq.sqrt();
+    let normal = if distance > zero {
+        v_dir * (T::from(1).unwrap() / distance)
+    } else {
+        // coincident elements have no defined normal, so separate them horizontally
+        Vector2D {
+            x: T::from(1).unwrap(),
+            y: zero,
+        }
+    };
+    let overlap = min_distance - distance;
+    elements[i].position = p1.position - normal * (overlap * p2.mass / total_mass);
+    elements[j].position = &p2.position + normal * (overlap * p1.mass / total_mass);
+
+    // exchange momentum only if the elements are approaching each other: the normal part of the
+    // relative velocity bounces back, friction removes some of the tangential part
+    let v_rel = p1.velocity - p2.velocity;
+    let approach = v_rel.dot(&normal);
+    if approach > zero {
+        let one = T::from(1).unwrap();
+        let tangential = v_rel - v_rel.project_onto(&normal);
+        let change = &(normal * ((one + restitution) * approach)) + tangential * friction;
+        elements[i].velocity = p1.velocity - change * (p2.mass / total_mass);
+        elements[j].velocity = &p2.velocity + change * (p1.mass / total_mass);
+        let dissipated = kinetic_energy(&p1) + kinetic_energy(&p2)
+            - kinetic_energy(&elements[i])
+            - kinetic_energy(&elements[j]);
+        // an elastic collision may come out very slightly negative due to rounding
+        if dissipated > zero {
+            return Some(dissipated);
+        }
+    }
+    Some(zero)
+}
+
+/// The kinetic energy `m * v^2 / 2` of an element.
+fn kinetic_energy<T>(element: &Particle<T>) -> T
+where
+    T: Float + Default,
+{
+    element.mass * element.velocity.length_sq() / T::from(2).unwrap()
+}
+
+/// Merges element `j` into element `i` if they overlap, returning whether they did.
+/// Without a density, the merged element keeps the total area, so its radius is
+/// `sqrt(r1^2 + r2^2)`.
+fn merge<T>(
+    elements: &mut [Particle<T>],
+    i: usize,
+    j: usize,
+    density: Option<T>,
+    merging: &[bool],
+) -> bool
+where
+    T: Float + Default,
+{
+    let p1 = elements[i];
+    let p2 = elements[j];
+    let merges = |particle: &Particle<T>| merging.get(particle.species as usize) != Some(&false);
+    if !merges(&p1) || !merges(&p2) {
+        return false;
+    }
+    let min_distance = p1.radius + p2.radius;
+    if (p2.position - p1.position).length_sq() >= min_distance * min_distance {
+        return false;
+    }
+
+    let total_mass = p1.mass + p2.mass;
+    if total_mass <= T::default() {
+        return false;
+    }
 
-                    // calculate gravitational pull for every particle in the same node
-                    (0..num_particles).for_each(|j| {
-                        if i == j {
-                            return;
-                        }
+    let merged = &mut elements[i];
+    merged.position =
+        &(p1.position * (p1.mass / total_mass)) + p2.position * (p2.mass / total_mass);
+    merged.velocity =
+        &(p1.velocity * (p1.mass / total_mass)) + p2.velocity * (p2.mass / total_mass);
+    merged.radius = match density {
+        Some(density) => Particle::radius_for_mass(total_mass, density),
+        None => (p1.radius * p1.radius + p2.radius * p2.radius).sqrt(),
+    };
+    merged.mass = total_mass;
+    merged.charge = p1.charge + p2.charge;
+    // the heavier particle decides what the result is
+    if p2.mass > p1.mass {
+        merged.species = p2.species;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particle(x: f64, y: f64, mass: f64) -> Particle<f64> {
+        Particle {
+            position: Vector2D { x, y },
+            velocity: Vector2D::default(),
+            radius: 1.0,
+            mass,
+            charge: 0.0,
+            species: 0,
+        }
+    }
+
+    /// Plain 1/r gravity with the given softening and opening angle, and nothing else
+    fn params(softening: f64, theta: f64) -> ForceParameters<'static, f64> {
+        ForceParameters {
+            grav_const: 10.0,
+            force_exponent: 1.0,
+            solid_interior: false,
+            softening,
+            theta,
+            coulomb_const: 0.0,
+            repulsion: None,
+            neighbors: None,
+            damping: 0.0,
+            species_drag: &[],
+            uniform_field: Vector2D::default(),
+            swirl_strength: 0.0,
+            swirl_center: Vector2D::default(),
+            frame_rotation: 0.0,
+            frame_center: Vector2D::default(),
+            attractors: &[],
+            obstacles: &[],
+            springs: &[],
+            extra_accelerations: &[],
+            external_forces: &[],
+            pinned: &[],
+            period: None,
+            max_acceleration: None,
+        }
+    }
+
+    fn accelerations(
+        elements: &[Particle<f64>],
+        params: &ForceParameters<'_, f64>,
+    ) -> Vec<Vector2D<f64>> {
+        let tree = ParticleQuadTree::build(elements, 4);
+        let mut accelerations = Vec::new();
+        tree.accelerations(elements, params, &mut accelerations);
+        accelerations
+    }
+
+    #[test]
+    fn softening_keeps_coincident_particles_finite() {
+        let elements = [
+            particle(3.0, -2.0, 1.0),
+            particle(3.0, -2.0, 5.0),
+            particle(4.0, -2.0, 1.0),
+        ];
+        for acceleration in accelerations(&elements, &params(0.5, 0.7)) {
+            assert!(acceleration.x.is_finite() && acceleration.y.is_finite());
+        }
+    }
 
-                        let p1 = elements.get(*element_indices.get(i).unwrap()).unwrap();
-                        let p2 = elements.get(*element_indices.get(j).unwrap()).unwrap();
-                        let m2 = p2.mass;
-                        let v_dir = p2.position - p1.position;
-                        let r_sq = v_dir.length_sq();
-                        let a1 = grav_const * m2 / r_sq;
-                        delta_v = &delta_v + (v_dir * a1 * elapsed_s);
+    #[test]
+    fn softening_leaves_far_forces_unchanged() {
+        let elements = [particle(0.0, 0.0, 1.0), particle(600.0, 800.0, 2.0)];
+        let softened = accelerations(&elements, &params(0.5, 0.7));
+        let exact = accelerations(&elements, &params(0.0, 0.7));
+        for (softened, exact) in softened.iter().zip(&exact) {
+            let error = (*softened - *exact).length() / exact.length();
+            assert!(error < 1e-6, "relative error {}", error);
+        }
+        // G m / r towards the other particle
+        assert!((exact[0].length() - 10.0 * 2.0 / 1000.0).abs() < 1e-12);
+    }
+
+    /// The accelerations from every pair of elements, without a tree
+    fn brute_force(
+        elements: &[Particle<f64>],
+        params: &ForceParameters<'_, f64>,
+    ) -> Vec<Vector2D<f64>> {
+        (0..elements.len())
+            .map(|i| {
+                (0..elements.len())
+                    .filter(|&j| j != i)
+                    .fold(Vector2D::default(), |sum, j| {
+                        &sum + pull(&elements[i], &elements[j], params, 0.0)
+                    })
+            })
+            .collect()
+    }
+
+    /// The root mean square of the differences relative to the one of the exact values
+    fn relative_error(approximated: &[Vector2D<f64>], exact: &[Vector2D<f64>]) -> f64 {
+        let (error_sq, norm_sq) =
+            approximated
+                .iter()
+                .zip(exact)
+                .fold((0.0, 0.0), |(error_sq, norm_sq), (a, b)| {
+                    (error_sq + (*a - *b).length_sq(), norm_sq + b.length_sq())
+                });
+        (error_sq / norm_sq).sqrt()
+    }
+
+    #[test]
+    fn barnes_hut_converges_to_brute_force() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(252);
+        let elements: Vec<_> = (0..300)
+            .map(|_| {
+                particle(
+                    rng.gen_range(-100.0..100.0),
+                    rng.gen_range(-100.0..100.0),
+                    rng.gen_range(0.5..2.0),
+                )
+            })
+            .collect();
+        let exact = brute_force(&elements, &params(0.5, 0.0));
+        let relative_error = |theta| {
+            let approximated = accelerations(&elements, &params(0.5, theta));
+            let (error_sq, norm_sq) =
+                approximated
+                    .iter()
+                    .zip(&exact)
+                    .fold((0.0, 0.0), |(error_sq, norm_sq), (a, b)| {
+                        (error_sq + (*a - *b).length_sq(), norm_sq + b.length_sq())
                     });
+            (error_sq / norm_sq).sqrt()
+        };
+        let errors: Vec<f64> = [1.0, 0.7, 0.4, 0.2, 0.0]
+            .into_iter()
+            .map(relative_error)
+            .collect();
+        assert!(
+            errors.windows(2).all(|pair| pair[1] < pair[0]),
+            "errors {:?}",
+            errors
+        );
+        assert!(errors[1] < 0.05, "errors {:?}", errors);
+        assert!(errors[4] < 1e-12, "errors {:?}", errors);
+    }
+
+    /// Two overlapping elements of radius 1 approaching each other along the x axis
+    fn head_on(mass_1: f64, mass_2: f64) -> [Particle<f64>; 2] {
+        let mut elements = [particle(0.0, 0.0, mass_1), particle(1.5, 0.0, mass_2)];
+        elements[0].velocity = Vector2D { x: 2.0, y: 0.0 };
+        elements[1].velocity = Vector2D { x: -1.0, y: 0.0 };
+        elements
+    }
+
+    fn collide_all(elements: &mut [Particle<f64>]) {
+        collide_with(elements, 1.0, 0.0);
+    }
+
+    fn collide_with(elements: &mut [Particle<f64>], restitution: f64, friction: f64) {
+        let tree = ParticleQuadTree::build(elements, 4);
+        tree.resolve_collisions(elements, 0.01, restitution, friction, &mut []);
+    }
+
+    #[test]
+    fn elastic_collisions_conserve_momentum_and_energy() {
+        let mut elements = head_on(1.0, 3.0);
+        let momentum = |elements: &[Particle<f64>]| {
+            elements
+                .iter()
+                .fold(Vector2D::default(), |sum, e| &sum + e.velocity * e.mass)
+        };
+        let energy = |elements: &[Particle<f64>]| elements.iter().map(kinetic_energy).sum::<f64>();
+        let (momentum_before, energy_before) = (momentum(&elements), energy(&elements));
+        collide_all(&mut elements);
+        assert!((momentum(&elements) - momentum_before).length() < 1e-12);
+        assert!((energy(&elements) - energy_before).abs() < 1e-12);
+        // pushed apart until they touch
+        let distance = (elements[1].position - elements[0].position).length();
+        assert!((distance - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn equal_masses_exchange_velocities() {
+        let mut elements = head_on(2.0, 2.0);
+        collide_all(&mut elements);
+        assert_eq!(elements[0].velocity, Vector2D { x: -1.0, y: 0.0 });
+        assert_eq!(elements[1].velocity, Vector2D { x: 2.0, y: 0.0 });
+    }
+
+    #[test]
+    fn three_way_merges_count_every_mass_once() {
+        let mut elements = [
+            particle(0.0, 0.0, 1.0),
+            particle(0.5, 0.0, 2.0),
+            particle(1.0, 0.0, 3.0),
+        ];
+        elements[0].velocity = Vector2D { x: 6.0, y: 0.0 };
+        elements[2].velocity = Vector2D { x: 0.0, y: 2.0 };
+        let tree = ParticleQuadTree::build(&elements, 4);
+        let removed = tree.merge_collisions(&mut elements, 0.01, None, &[]);
+        assert_eq!(removed, [1, 2]);
+        let merged = elements[0];
+        assert_eq!(merged.mass, 6.0);
+        // the center of mass and the total momentum
+        assert!(
+            (merged.position
+                - Vector2D {
+                    x: 4.0 / 6.0,
+                    y: 0.0
+                })
+            .length()
+                < 1e-12
+        );
+        assert!((merged.velocity * merged.mass - Vector2D { x: 6.0, y: 6.0 }).length() < 1e-12);
+        // the total area of three discs of radius 1
+        assert!((merged.radius - 3f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn opposite_charges_attract_and_like_charges_repel() {
+        let params = ForceParameters {
+            grav_const: 0.0,
+            coulomb_const: 1.0,
+            ..params(0.0, 0.7)
+        };
+        let charged = |x, charge| Particle {
+            charge,
+            ..particle(x, 0.0, 1.0)
+        };
+        let opposite = accelerations(&[charged(0.0, 1.0), charged(2.0, -1.0)], &params);
+        assert!(opposite[0].x > 0.0 && opposite[1].x < 0.0);
+        let like = accelerations(&[charged(0.0, -1.0), charged(2.0, -1.0)], &params);
+        assert!(like[0].x < 0.0 && like[1].x > 0.0);
+        // k q1 q2 / r, like 1/r gravity
+        assert!((like[0].x + 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn merged_radius_follows_the_density() {
+        // radius 1 at mass 1
+        let density = 1.0 / std::f64::consts::PI;
+        let mut elements = [particle(0.0, 0.0, 1.0), particle(0.5, 0.0, 1.0)];
+        let tree = ParticleQuadTree::build(&elements, 4);
+        tree.merge_collisions(&mut elements, 0.01, Some(density), &[]);
+        assert!((elements[0].radius - 2f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn restitution_scales_the_relative_speed() {
+        for restitution in [0.0, 0.3, 0.5, 1.0] {
+            let mut elements = head_on(1.0, 3.0);
+            collide_with(&mut elements, restitution, 0.0);
+            // they approached at 3
+            let separating = elements[1].velocity.x - elements[0].velocity.x;
+            assert!((separating - 3.0 * restitution).abs() < 1e-12);
+            let momentum = elements[0].velocity.x + 3.0 * elements[1].velocity.x;
+            assert!((momentum - (2.0 - 3.0)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn friction_removes_part_of_the_tangential_velocity() {
+        let oblique = || {
+            let mut elements = head_on(1.0, 1.0);
+            elements[0].velocity.y = 1.0;
+            elements[1].velocity.y = -1.0;
+            elements
+        };
+        let mut frictionless = oblique();
+        collide_with(&mut frictionless, 1.0, 0.0);
+        let mut rough = oblique();
+        collide_with(&mut rough, 1.0, 0.25);
+        let relative = |elements: &[Particle<f64>; 2]| elements[0].velocity - elements[1].velocity;
+        // the tangential part of 2 keeps 3/4, the normal part bounces back unchanged
+        assert!((relative(&rough).y - 1.5).abs() < 1e-12);
+        assert!((relative(&frictionless).y - 2.0).abs() < 1e-12);
+        assert!((relative(&rough).x - relative(&frictionless).x).abs() < 1e-12);
+    }
+
+    #[test]
+    fn collisions_heat_by_the_dissipated_energy() {
+        let energy = |elements: &[Particle<f64>]| elements.iter().map(kinetic_energy).sum::<f64>();
+        for restitution in [1.0, 0.5, 0.0] {
+            let mut elements = head_on(1.0, 3.0);
+            let before = energy(&elements);
+            let mut temperatures = [0.0; 2];
+            let tree = ParticleQuadTree::build(&elements, 4);
+            tree.resolve_collisions(&mut elements, 0.01, restitution, 0.0, &mut temperatures);
+            let dissipated = before - energy(&elements);
+            // split evenly between the two
+            assert!((temperatures[0] - dissipated / 2.0).abs() < 1e-12);
+            assert_eq!(temperatures[0], temperatures[1]);
+            if restitution == 1.0 {
+                assert_eq!(temperatures, [0.0; 2]);
+            } else {
+                assert!(dissipated > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn two_body_energy_matches_the_analytic_value() {
+        let mut elements = [particle(0.0, 0.0, 2.0), particle(30.0, 40.0, 3.0)];
+        elements[0].velocity = Vector2D { x: 1.0, y: 2.0 };
+        elements[1].velocity = Vector2D { x: -3.0, y: 0.0 };
+        let params = params(0.5, 0.7);
+        let tree = ParticleQuadTree::build(&elements, 4);
+        let report = tree.energy(&elements, &params);
+        // m v^2 / 2 and G m1 m2 ln(r^2 + s^2) / 2
+        let kinetic = 2.0 * 5.0 / 2.0 + 3.0 * 9.0 / 2.0;
+        let potential = 10.0 * 2.0 * 3.0 * (2500.0f64 + 0.25).ln() / 2.0;
+        assert!((report.kinetic - kinetic).abs() < 1e-12);
+        assert!((report.potential - potential).abs() < 1e-9);
+        assert_eq!(report.total, report.kinetic + report.potential);
+
+        // other exponents: -G m1 m2 / ((n - 1) (r^2 + s^2)^((n - 1) / 2))
+        let inverse_square = ForceParameters {
+            force_exponent: 2.0,
+            ..params
+        };
+        let report = tree.energy(&elements, &inverse_square);
+        let potential = -10.0 * 2.0 * 3.0 / (2500.0f64 + 0.25).sqrt();
+        assert!((report.potential - potential).abs() < 1e-9);
+    }
+
+    #[test]
+    fn force_exponent_sets_the_falloff() {
+        let strength = |exponent: f64, distance: f64| {
+            let params = ForceParameters {
+                force_exponent: exponent,
+                ..params(0.0, 0.7)
+            };
+            let elements = [particle(0.0, 0.0, 1.0), particle(distance, 0.0, 2.0)];
+            accelerations(&elements, &params)[0].x
+        };
+        for distance in [0.5, 3.0, 40.0] {
+            // G m / r^n along the unit direction
+            let inverse_square = 10.0 * 2.0 / (distance * distance);
+            assert!((strength(2.0, distance) / inverse_square - 1.0).abs() < 1e-12);
+            assert!((strength(1.0, distance) / (10.0 * 2.0 / distance) - 1.0).abs() < 1e-12);
+        }
+        // twice the distance halves 1/r, but quarters 1/r^2 and leaves an eighth of 1/r^3
+        assert!((strength(1.0, 10.0) / strength(1.0, 20.0) - 2.0).abs() < 1e-12);
+        assert!((strength(2.0, 10.0) / strength(2.0, 20.0) - 4.0).abs() < 1e-12);
+        assert!((strength(3.0, 10.0) / strength(3.0, 20.0) - 8.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn solid_interiors_attract_linearly_and_continuously() {
+        let params = ForceParameters {
+            solid_interior: true,
+            ..params(0.0, 0.7)
+        };
+        let strength = |distance: f64| {
+            let element = particle(0.0, 0.0, 1.0);
+            let other = particle(distance, 0.0, 2.0);
+            pull(
+                &element,
+                &other,
+                &params,
+                interior_radius(&element, &other, &params),
+            )
+            .x
+        };
+        // the radii add up to 2
+        assert_eq!(strength(0.0), 0.0);
+        assert!((strength(2.0 - 1e-9) - strength(2.0 + 1e-9)).abs() < 1e-6);
+        assert!((strength(2.0) - 10.0 * 2.0 / 2.0).abs() < 1e-12);
+        // linear inside
+        assert!((strength(0.5) - strength(2.0) / 4.0).abs() < 1e-12);
+        assert!((strength(1.0) - strength(2.0) / 2.0).abs() < 1e-12);
+    }
+
+    /// A tree over the square of the given size around the origin, filled by inserting the
+    /// elements one by one
+    fn inserted(elements: &[Particle<f64>], size: f64, capacity: usize) -> ParticleQuadTree<f64> {
+        let mut tree = ParticleQuadTree::new(Vector2D::default(), size, size, capacity);
+        for i in 0..elements.len() {
+            tree.insert(elements, i);
+        }
+        tree
+    }
+
+    #[test]
+    fn the_summary_of_a_heavy_and_a_light_element_sits_near_the_heavy_one() {
+        let elements = [particle(0.0, 0.0, 100.0), particle(10.0, 10.0, 1.0)];
+        for tree in [
+            inserted(&elements, 40.0, 4),
+            ParticleQuadTree::build(&elements, 4),
+        ] {
+            let summary = tree.summary();
+            assert_eq!(summary.mass, 101.0);
+            // 10 / 101 of the way towards the light one
+            assert!((summary.position.x - 10.0 / 101.0).abs() < 1e-12);
+            assert!((summary.position.y - 10.0 / 101.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn equal_masses_keep_the_plain_running_average() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(304);
+        let elements: Vec<_> = (0..1000)
+            .map(|_| particle(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 1.0))
+            .collect();
+        let tree = inserted(&elements, 100.0, 4);
+        // the summary before weighting by mass
+        let mut average = elements[0].position;
+        for (n, element) in elements.iter().enumerate().skip(1) {
+            let n = n as f64;
+            average = Vector2D {
+                x: (average.x * n + element.position.x) / (n + 1.0),
+                y: (average.y * n + element.position.y) / (n + 1.0),
+            };
+        }
+        assert_eq!(tree.summary().position, average);
+        assert_eq!(tree.summary().mass, 1000.0);
+    }
+
+    /// A point without mass, like a probe or a label
+    struct Marker(Vector2D<f64>);
+
+    impl Positioned<f64> for Marker {
+        fn position(&self) -> Vector2D<f64> {
+            self.0
+        }
+        fn mass(&self) -> f64 {
+            0.0
+        }
+    }
+
+    #[test]
+    fn massless_elements_are_summarized_at_their_plain_average() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(325);
+        let markers: Vec<_> = (0..200)
+            .map(|_| {
+                Marker(Vector2D {
+                    x: rng.gen_range(-50.0..50.0),
+                    y: rng.gen_range(-50.0..50.0),
+                })
+            })
+            .collect();
+        let average = |markers: &[Marker]| {
+            let sum = markers
+                .iter()
+                .fold(Vector2D::default(), |sum, marker| &sum + marker.0);
+            sum * (1.0 / markers.len() as f64)
+        };
+        let mut inserted = QuadTree::new(Vector2D::default(), 100.0, 100.0, 4);
+        for i in 0..markers.len() {
+            inserted.insert(&markers, i);
+        }
+        for tree in [&inserted, &QuadTree::build(&markers, 4)] {
+            assert_eq!(tree.len(), markers.len());
+            assert_eq!(tree.summary().mass, 0.0);
+            assert!(tree.summary().position.distance_sq(&average(&markers)) < 1e-18);
+        }
+        // and the average of the rest once some are gone
+        for i in 0..100 {
+            assert!(inserted.remove(&markers, i));
+        }
+        assert!(
+            inserted
+                .summary()
+                .position
+                .distance_sq(&average(&markers[100..]))
+                < 1e-18
+        );
+    }
+
+    #[test]
+    fn elements_on_center_lines_and_outside_the_root_are_found() {
+        let elements = [
+            // on the center lines of the root and of its children
+            particle(0.0, 0.0, 1.0),
+            particle(0.0, 2.0, 1.0),
+            particle(2.0, 0.0, 1.0),
+            particle(-2.0, 0.0, 1.0),
+            particle(0.0, -2.0, 1.0),
+            particle(2.0, 2.0, 1.0),
+            particle(-2.0, -2.0, 1.0),
+            particle(-2.0, 3.0, 1.0),
+            // just outside the square from -4 to 4
+            particle(4.001, 0.0, 2.0),
+            particle(-4.5, 4.5, 1.0),
+            particle(0.0, -4.000001, 1.0),
+            particle(1e-9, 4.0 + 1e-9, 1.0),
+        ];
+        let mut built = ParticleQuadTree::new(Vector2D::default(), 8.0, 8.0, 1);
+        built.clear_and_rebuild(Vector2D::default(), 8.0, 8.0, &elements);
+        for tree in [inserted(&elements, 8.0, 1), built] {
+            assert_eq!(tree.len(), elements.len());
+            assert_eq!(tree.stats().elements, elements.len());
+            assert_eq!(tree.summary().mass, elements.len() as f64 + 1.0);
+            let mut held: Vec<_> = tree.iter_indices().collect();
+            held.sort_unstable();
+            assert_eq!(held, (0..elements.len()).collect::<Vec<_>>());
+            for (i, element) in elements.iter().enumerate() {
+                assert!(tree.contains_point(element.position));
+                let mut found = Vec::new();
+                tree.query_radius(&elements, element.position, 0.0, &mut found);
+                assert_eq!(found, [i]);
+                found.clear();
+                tree.query_rect(&elements, element.position, element.position, &mut found);
+                assert_eq!(found, [i]);
+                assert_eq!(tree.nearest(&elements, element.position), Some(i));
+            }
+        }
+    }
+
+    #[test]
+    fn elements_on_center_and_axis_lines_are_found_before_and_after_the_split() {
+        // the root is centered at (1, 1), so its center lines are not the axes
+        let elements = [
+            // fill the leaf before it splits
+            particle(1.0, 1.0, 1.0),
+            particle(0.0, 0.0, 1.0),
+            particle(1.0, -2.0, 1.0),
+            particle(0.0, 3.0, 1.0),
+            // splits the root
+            particle(-2.0, 1.0, 1.0),
+            // inserted into the children
+            particle(1.0, 4.0, 1.0),
+            particle(0.0, 1.0, 1.0),
+            particle(4.0, 0.0, 1.0),
+            particle(-1.0, -1.0, 1.0),
+            particle(3.0, 3.0, 1.0),
+            particle(1.0, 0.0, 1.0),
+            particle(-3.0, 0.0, 1.0),
+        ];
+        let found_at = |tree: &ParticleQuadTree<f64>, i: usize| {
+            let mut found = Vec::new();
+            let position = elements[i].position;
+            tree.query_rect(&elements, position, position, &mut found);
+            found
+        };
+        let center = Vector2D { x: 1.0, y: 1.0 };
+        let mut tree = ParticleQuadTree::new(center, 8.0, 8.0, 4);
+        for i in 0..elements.len() {
+            tree.insert(&elements, i);
+            assert_eq!(matches!(tree.node, QuadtreeNode::Leaf { .. }), i < 4);
+            for j in 0..=i {
+                assert_eq!(found_at(&tree, j), [j], "{} after inserting {}", j, i);
+            }
+        }
+        tree.clear_and_rebuild(center, 8.0, 8.0, &elements);
+        for i in 0..elements.len() {
+            assert_eq!(found_at(&tree, i), [i]);
+        }
+    }
+
+    #[test]
+    fn dumps_list_every_node_indented_by_depth() {
+        let mut elements = [
+            particle(-1.0, -1.0, 1.0),
+            particle(1.0, -1.0, 2.0),
+            particle(1.0, 1.0, 1.0),
+        ];
+        elements[1].charge = 0.5;
+        let tree = inserted(&elements, 4.0, 2);
+        let mut dump = Vec::new();
+        tree.dump(&mut dump).unwrap();
+        let expected = "\
+node depth=0 center=0,0 size=4,4 elements=3 mass=4 charge=0.5 summary=0.5,-0.5
+  leaf depth=1 center=-1,-1 size=2,2 elements=1 mass=1 charge=0 summary=-1,-1 indices=0
+  leaf depth=1 center=1,-1 size=2,2 elements=1 mass=2 charge=0.5 summary=1,-1 indices=1
+  leaf depth=1 center=-1,1 size=2,2 elements=0 mass=0 charge=0 summary=-1,1 indices=
+  leaf depth=1 center=1,1 size=2,2 elements=1 mass=1 charge=0 summary=1,1 indices=2
+";
+        assert_eq!(String::from_utf8(dump).unwrap(), expected);
+    }
+
+    #[test]
+    fn points_and_rectangles_on_the_edges_belong_to_the_node() {
+        // covers x from -1 to 3 and y from 1 to 3
+        let tree = ParticleQuadTree::<f64>::new(Vector2D { x: 1.0, y: 2.0 }, 4.0, 2.0, 4);
+        let point = |x, y| Vector2D { x, y };
+        for inside in [
+            point(1.0, 2.0),
+            point(0.0, 1.5),
+            // edges and corners
+            point(-1.0, 2.0),
+            point(3.0, 2.0),
+            point(1.0, 1.0),
+            point(1.0, 3.0),
+            point(-1.0, 1.0),
+            point(3.0, 3.0),
+        ] {
+            assert!(tree.contains_point(inside), "{:?}", inside);
+        }
+        for outside in [
+            point(3.001, 2.0),
+            point(1.0, 0.999),
+            point(-1.0, 3.5),
+            point(-2.0, 5.0),
+        ] {
+            assert!(!tree.contains_point(outside), "{:?}", outside);
+        }
+
+        for (min, max) in [
+            // straddling an edge, inside, around the node
+            (point(2.0, 2.5), point(5.0, 5.0)),
+            (point(0.0, 1.5), point(2.0, 2.5)),
+            (point(-10.0, -10.0), point(10.0, 10.0)),
+            // touching an edge or a corner, and a point on an edge
+            (point(3.0, 1.5), point(4.0, 2.5)),
+            (point(3.0, 0.0), point(4.0, 1.0)),
+            (point(-1.0, 2.0), point(-1.0, 2.0)),
+        ] {
+            assert!(tree.intersects_rect(min, max), "{:?} to {:?}", min, max);
+        }
+        for (min, max) in [
+            (point(3.5, 0.0), point(5.0, 5.0)),
+            (point(-1.0, -3.0), point(3.0, 0.5)),
+            (point(-3.0, 3.001), point(0.0, 4.0)),
+        ] {
+            assert!(!tree.intersects_rect(min, max), "{:?} to {:?}", min, max);
+        }
+    }
+
+    #[test]
+    fn identical_elements_stop_splitting_at_the_maximum_depth() {
+        let elements = vec![particle(1.5, -2.5, 1.0); 200];
+        for tree in [
+            inserted(&elements, 8.0, 100),
+            ParticleQuadTree::build(&elements, 100),
+        ] {
+            let stats = tree.stats();
+            // the stats count levels, the root is one of them
+            assert!(stats.depth <= MAX_DEPTH + 1, "{} levels", stats.depth);
+            assert_eq!(stats.elements, 200);
+            assert_eq!(stats.max_leaf_occupancy, 200);
+            assert_eq!(tree.summary().position, Vector2D { x: 1.5, y: -2.5 });
+        }
+    }
+
+    /// Random elements in the square from -50 to 50, half of them on a coarse grid so that some
+    /// share a position or lie on the center lines of nodes
+    fn cloud(seed: u64, count: usize) -> Vec<Particle<f64>> {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|i| {
+                if i % 2 == 0 {
+                    particle(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 1.0)
+                } else {
+                    let x = rng.gen_range(-10..=10) as f64 * 5.0;
+                    let y = rng.gen_range(-10..=10) as f64 * 5.0;
+                    particle(x, y, 1.0)
+                }
+            })
+            .collect()
+    }
 
-                    // calculate pull for the summaries of other nodes
-                    for summary_particle in summaries {
-                        let p1 = elements.get(*element_indices.get(i).unwrap()).unwrap();
-                        let p2 = summary_particle;
-                        let m2 = p2.mass;
-                        let v_dir = p2.position - p1.position;
-                        let r_sq = v_dir.length_sq();
-                        let a1 = grav_const * m2 / r_sq;
-                        delta_v = &delta_v + (v_dir * a1 * elapsed_s);
+    #[test]
+    fn rect_queries_match_brute_force() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(310);
+        let elements = cloud(3100, 2000);
+        for tree in [
+            inserted(&elements, 100.0, 8),
+            ParticleQuadTree::build(&elements, 8),
+        ] {
+            for _ in 0..500 {
+                // from tiny rectangles to ones holding the whole root
+                let size = 10f64.powf(rng.gen_range(-1.0..2.5));
+                let min = Vector2D {
+                    x: rng.gen_range(-80.0..60.0),
+                    y: rng.gen_range(-80.0..60.0),
+                };
+                let max = Vector2D {
+                    x: min.x + size * rng.gen_range(0.0..1.0),
+                    y: min.y + size * rng.gen_range(0.0..1.0),
+                };
+                let mut found = Vec::new();
+                tree.query_rect(&elements, min, max, &mut found);
+                found.sort_unstable();
+                let expected: Vec<_> = (0..elements.len())
+                    .filter(|&i| {
+                        let position = elements[i].position;
+                        (min.x..=max.x).contains(&position.x)
+                            && (min.y..=max.y).contains(&position.y)
+                    })
+                    .collect();
+                assert_eq!(
+                    found, expected,
+                    "{},{} to {},{}",
+                    min.x, min.y, max.x, max.y
+                );
+            }
+            let mut all = Vec::new();
+            let min = Vector2D {
+                x: -1000.0,
+                y: -1000.0,
+            };
+            let max = Vector2D {
+                x: 1000.0,
+                y: 1000.0,
+            };
+            tree.query_rect(&elements, min, max, &mut all);
+            assert_eq!(all.len(), elements.len());
+        }
+    }
+
+    #[test]
+    fn radius_queries_match_brute_force() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(311);
+        for seed in 0..5 {
+            let elements = cloud(3110 + seed, 1000);
+            for tree in [
+                inserted(&elements, 100.0, 8),
+                ParticleQuadTree::build(&elements, 8),
+            ] {
+                for query in 0..200 {
+                    // a third of the queries sit on an element or a grid point with radius 0
+                    let (center, radius) = match query % 3 {
+                        0 => (elements[rng.gen_range(0..elements.len())].position, 0.0),
+                        1 => (
+                            Vector2D {
+                                x: rng.gen_range(-10..=10) as f64 * 5.0,
+                                y: rng.gen_range(-10..=10) as f64 * 5.0,
+                            },
+                            rng.gen_range(0..4) as f64 * 5.0,
+                        ),
+                        _ => (
+                            Vector2D {
+                                x: rng.gen_range(-70.0..70.0),
+                                y: rng.gen_range(-70.0..70.0),
+                            },
+                            rng.gen_range(0.0..40.0),
+                        ),
+                    };
+                    let mut found = Vec::new();
+                    tree.query_radius(&elements, center, radius, &mut found);
+                    found.sort_unstable();
+                    let expected: Vec<_> = (0..elements.len())
+                        .filter(|&i| elements[i].position.distance_sq(&center) <= radius * radius)
+                        .collect();
+                    assert_eq!(
+                        found, expected,
+                        "{},{} radius {}",
+                        center.x, center.y, radius
+                    );
+                    if radius == 0.0 && query % 3 == 0 {
+                        assert!(!found.is_empty());
                     }
+                }
+            }
+        }
+    }
+
+    /// A random point in and around the square of the cloud, or on a grid finer than the one of
+    /// the cloud
+    fn query_point(rng: &mut impl rand::Rng, on_grid: bool) -> Vector2D<f64> {
+        if !on_grid {
+            Vector2D {
+                x: rng.gen_range(-70.0..70.0),
+                y: rng.gen_range(-70.0..70.0),
+            }
+        } else {
+            Vector2D {
+                x: rng.gen_range(-12..=12) as f64 * 2.5,
+                y: rng.gen_range(-12..=12) as f64 * 2.5,
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(312);
+        let elements = cloud(3120, 1000);
+        for tree in [
+            inserted(&elements, 100.0, 8),
+            ParticleQuadTree::build(&elements, 8),
+        ] {
+            for i in 0..1000 {
+                let point = query_point(&mut rng, i % 2 == 1);
+                // the lowest index among the nearest ones
+                let distance_sq = |j: usize| elements[j].position.distance_sq(&point);
+                let expected = (0..elements.len())
+                    .min_by(|&a, &b| distance_sq(a).total_cmp(&distance_sq(b)))
+                    .unwrap();
+                assert_eq!(tree.nearest(&elements, point), Some(expected));
+                let distance = distance_sq(expected).sqrt();
+                assert_eq!(
+                    tree.nearest_within(&elements, point, distance * 1.01),
+                    Some(expected)
+                );
+                if distance > 0.0 {
+                    assert_eq!(tree.nearest_within(&elements, point, distance * 0.99), None);
+                }
+            }
+        }
+        let empty = ParticleQuadTree::new(Vector2D::default(), 10.0, 10.0, 8);
+        assert_eq!(empty.nearest(&elements, Vector2D::default()), None);
+    }
 
-                    delta_velocities.push(delta_v);
+    #[test]
+    fn knn_matches_a_sort_by_distance() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(313);
+        let elements = cloud(3130, 500);
+        for tree in [
+            inserted(&elements, 100.0, 8),
+            ParticleQuadTree::build(&elements, 8),
+        ] {
+            for i in 0..300 {
+                // grid points are equally far from many elements of the cloud
+                let point = query_point(&mut rng, i % 2 == 1);
+                let k = match i % 5 {
+                    0 => elements.len() + rng.gen_range(0..10),
+                    _ => rng.gen_range(1..40),
+                };
+                let mut sorted: Vec<_> = (0..elements.len()).collect();
+                sorted.sort_by(|&a, &b| {
+                    let a_sq = elements[a].position.distance_sq(&point);
+                    let b_sq = elements[b].position.distance_sq(&point);
+                    a_sq.total_cmp(&b_sq).then(a.cmp(&b))
                 });
+                sorted.truncate(k);
+                let mut found = vec![usize::MAX];
+                tree.knn(&elements, point, k, &mut found);
+                assert_eq!(found[0], usize::MAX);
+                assert_eq!(
+                    found[1..],
+                    sorted,
+                    "{} nearest to {},{}",
+                    k,
+                    point.x,
+                    point.y
+                );
+            }
+            let mut found = Vec::new();
+            tree.knn(&elements, Vector2D::default(), 0, &mut found);
+            assert!(found.is_empty());
+        }
+    }
 
-                // add delta velocities to total values and update position
-                (0..num_particles).for_each(|i| {
-                    let particle = elements.get_mut(*element_indices.get(i).unwrap()).unwrap();
-                    particle.velocity = &particle.velocity + delta_velocities.get(i).unwrap();
-                    particle.position = &particle.position + (&particle.velocity * elapsed_s);
+    /// Checks that every node counts and weighs exactly the elements below it, with its summary at
+    /// their center of mass, and remembers the leaves at the greatest depth
+    #[derive(Default)]
+    struct CountCheck<'a> {
+        elements: &'a [Particle<f64>],
+        deepest: (usize, Vec<usize>),
+        nodes: usize,
+    }
+
+    impl CountCheck<'_> {
+        fn check(&mut self, tree: &ParticleQuadTree<f64>) {
+            self.nodes += 1;
+            let indices: Vec<_> = tree.iter_indices().collect();
+            assert_eq!(tree.len(), indices.len());
+            let mass: f64 = indices.iter().map(|&i| self.elements[i].mass).sum();
+            assert!((tree.summary().mass - mass).abs() < 1e-9);
+            if mass > 0.0 {
+                let moment = indices.iter().fold(Vector2D::default(), |sum, &i| {
+                    &sum + self.elements[i].position * self.elements[i].mass
                 });
+                let center_of_mass = moment * (1.0 / mass);
+                assert!(tree.summary().position.distance_sq(&center_of_mass) < 1e-12);
+            }
+        }
+    }
+
+    impl QuadtreeVisitor<f64> for CountCheck<'_> {
+        fn visit_node(&mut self, tree: &ParticleQuadTree<f64>) -> ControlFlow<(), bool> {
+            self.check(tree);
+            ControlFlow::Continue(true)
+        }
+        fn visit_leaf_node_at_depth(
+            &mut self,
+            tree: &ParticleQuadTree<f64>,
+            element_indices: &[usize],
+            depth: usize,
+        ) -> ControlFlow<(), bool> {
+            self.check(tree);
+            if depth > self.deepest.0 && !element_indices.is_empty() {
+                self.deepest = (depth, element_indices.to_vec());
+            }
+            ControlFlow::Continue(false)
+        }
+        fn visit_element(
+            &mut self,
+            _leaf: &ParticleQuadTree<f64>,
+            _index: usize,
+        ) -> ControlFlow<()> {
+            ControlFlow::Continue(())
+        }
+    }
+
+    /// Collects the indices of the elements in the order they are visited
+    #[derive(Default)]
+    struct Collect(Vec<usize>);
+
+    impl QuadtreeVisitor<f64> for Collect {
+        fn visit_element(
+            &mut self,
+            _leaf: &ParticleQuadTree<f64>,
+            index: usize,
+        ) -> ControlFlow<()> {
+            self.0.push(index);
+            ControlFlow::Continue(())
+        }
+    }
+
+    #[test]
+    fn iterators_yield_every_index_once_in_visiting_order() {
+        let elements = cloud(3170, 2000);
+        for tree in [
+            inserted(&elements, 100.0, 8),
+            ParticleQuadTree::build(&elements, 8),
+        ] {
+            let indices: Vec<_> = tree.iter_indices().collect();
+            let mut sorted = indices.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..elements.len()).collect::<Vec<_>>());
+            let concatenated: Vec<_> = tree
+                .iter_leaves()
+                .flat_map(|(_, element_indices)| element_indices.to_vec())
+                .collect();
+            assert_eq!(concatenated, indices);
+            let mut visited = Collect::default();
+            assert!(tree.visit(&mut visited).is_continue());
+            assert_eq!(visited.0, indices);
+            assert_eq!(tree.iter_leaves().count(), tree.stats().leaves);
+        }
+    }
+
+    /// Stops the traversal at the first leaf, or with `in_leaf` at the first element
+    #[derive(Default)]
+    struct StopEarly {
+        in_leaf: bool,
+        leaves: usize,
+        elements: usize,
+    }
+
+    impl QuadtreeVisitor<f64> for StopEarly {
+        fn visit_leaf_node(
+            &mut self,
+            _tree: &ParticleQuadTree<f64>,
+            _element_indices: &[usize],
+        ) -> ControlFlow<(), bool> {
+            self.leaves += 1;
+            if self.in_leaf {
+                ControlFlow::Continue(true)
+            } else {
+                ControlFlow::Break(())
+            }
+        }
+        fn visit_element(
+            &mut self,
+            _leaf: &ParticleQuadTree<f64>,
+            _index: usize,
+        ) -> ControlFlow<()> {
+            self.elements += 1;
+            ControlFlow::Break(())
+        }
+    }
+
+    /// Skips every node left of `center_x`
+    struct SkipLeft {
+        center_x: f64,
+        seen: Vec<usize>,
+    }
+
+    impl QuadtreeVisitor<f64> for SkipLeft {
+        fn visit_node(&mut self, tree: &ParticleQuadTree<f64>) -> ControlFlow<(), bool> {
+            ControlFlow::Continue(tree.center().x >= self.center_x)
+        }
+        fn visit_leaf_node(
+            &mut self,
+            tree: &ParticleQuadTree<f64>,
+            _element_indices: &[usize],
+        ) -> ControlFlow<(), bool> {
+            ControlFlow::Continue(tree.center().x >= self.center_x)
+        }
+        fn visit_element(
+            &mut self,
+            _leaf: &ParticleQuadTree<f64>,
+            index: usize,
+        ) -> ControlFlow<()> {
+            self.seen.push(index);
+            ControlFlow::Continue(())
+        }
+    }
+
+    #[test]
+    fn visitors_stop_the_traversal_and_skip_nodes() {
+        let elements = cloud(3180, 1000);
+        let tree = ParticleQuadTree::build(&elements, 8);
+
+        let mut first_leaf = StopEarly::default();
+        assert!(tree.visit(&mut first_leaf).is_break());
+        assert_eq!((first_leaf.leaves, first_leaf.elements), (1, 0));
+        // the first leaf holding elements, with only its first element
+        let mut first_element = StopEarly {
+            in_leaf: true,
+            ..Default::default()
+        };
+        assert!(tree.visit(&mut first_element).is_break());
+        let empty_leaves = tree
+            .iter_leaves()
+            .take_while(|(_, element_indices)| element_indices.is_empty())
+            .count();
+        assert_eq!(first_element.leaves, empty_leaves + 1);
+        assert_eq!(first_element.elements, 1);
+
+        let center_x = tree.center().x;
+        let mut right = SkipLeft {
+            center_x,
+            seen: Vec::new(),
+        };
+        assert!(tree.visit(&mut right).is_continue());
+        assert!(right
+            .seen
+            .iter()
+            .all(|&i| elements[i].position.x > center_x));
+        let on_the_right = elements.iter().filter(|e| e.position.x > center_x).count();
+        assert_eq!(right.seen.len(), on_the_right);
+    }
+
+    #[test]
+    fn every_node_counts_and_weighs_the_elements_below_it() {
+        // distinct masses from 1 to 500, so a node holding the wrong elements weighs wrong too
+        let mut elements = cloud(3300, 500);
+        for (i, element) in elements.iter_mut().enumerate() {
+            element.mass = (i + 1) as f64;
+        }
+        for tree in [
+            inserted(&elements, 100.0, 4),
+            ParticleQuadTree::build(&elements, 4),
+        ] {
+            assert_eq!(tree.len(), 500);
+            assert_eq!(tree.summary().mass, 500.0 * 501.0 / 2.0);
+            let mut check = CountCheck {
+                elements: &elements,
+                ..Default::default()
+            };
+            assert!(tree.visit(&mut check).is_continue());
+            assert_eq!(check.nodes, tree.stats().nodes);
+        }
+    }
+
+    /// Records the kind and depth of every node it visits, and passes the elements on untouched
+    #[derive(Default)]
+    struct Depths(Vec<(&'static str, usize)>);
+
+    impl QuadtreeVisitor<f64> for Depths {
+        fn visit_node_at_depth(
+            &mut self,
+            _tree: &ParticleQuadTree<f64>,
+            depth: usize,
+        ) -> ControlFlow<(), bool> {
+            self.0.push(("node", depth));
+            ControlFlow::Continue(true)
+        }
+        fn visit_leaf_node_at_depth(
+            &mut self,
+            _tree: &ParticleQuadTree<f64>,
+            _element_indices: &[usize],
+            depth: usize,
+        ) -> ControlFlow<(), bool> {
+            self.0.push(("leaf", depth));
+            ControlFlow::Continue(false)
+        }
+        fn visit_element(
+            &mut self,
+            _leaf: &ParticleQuadTree<f64>,
+            _index: usize,
+        ) -> ControlFlow<()> {
+            ControlFlow::Continue(())
+        }
+    }
+
+    impl QuadtreeVisitorMut<f64> for Depths {
+        fn visit_node_at_depth(
+            &mut self,
+            tree: &ParticleQuadTree<f64>,
+            depth: usize,
+        ) -> ControlFlow<(), bool> {
+            QuadtreeVisitor::visit_node_at_depth(self, tree, depth)
+        }
+        fn visit_leaf_node_at_depth(
+            &mut self,
+            tree: &ParticleQuadTree<f64>,
+            element_indices: &[usize],
+            depth: usize,
+        ) -> ControlFlow<(), bool> {
+            QuadtreeVisitor::visit_leaf_node_at_depth(self, tree, element_indices, depth)
+        }
+        fn visit_element(
+            &mut self,
+            _leaf: &ParticleQuadTree<f64>,
+            _index: usize,
+            _element: &mut Particle<f64>,
+        ) -> ControlFlow<()> {
+            ControlFlow::Continue(())
+        }
+    }
+
+    #[test]
+    fn visitors_get_the_depth_below_the_node_they_started_at() {
+        // five elements in the top left quadrant of the square from -4 to 4, one in each of its
+        // quadrants and one more in the first
+        let mut elements = vec![
+            particle(-3.0, -3.0, 1.0),
+            particle(-1.0, -3.0, 1.0),
+            particle(-3.0, -1.0, 1.0),
+            particle(-1.0, -1.0, 1.0),
+            particle(-2.5, -2.5, 1.0),
+        ];
+        let tree = inserted(&elements, 8.0, 4);
+        let expected = [
+            ("node", 0),
+            ("node", 1),
+            ("leaf", 2),
+            ("leaf", 2),
+            ("leaf", 2),
+            ("leaf", 2),
+            ("leaf", 1),
+            ("leaf", 1),
+            ("leaf", 1),
+        ];
+        let mut depths = Depths::default();
+        let _ = tree.visit(&mut depths);
+        assert_eq!(depths.0, expected);
+        let mut depths = Depths::default();
+        let _ = tree.visit_mut(&mut elements, &mut depths);
+        assert_eq!(depths.0, expected);
+        assert_eq!(tree.stats().leaf_depth_histogram(), [0, 3, 4]);
+
+        // from the top left child, one level less
+        let QuadtreeNode::Node { top_left, .. } = &tree.node else {
+            panic!("the root did not split");
+        };
+        let mut depths = Depths::default();
+        let _ = top_left.visit(&mut depths);
+        assert_eq!(
+            depths.0,
+            expected[1..6]
+                .iter()
+                .map(|&(kind, depth)| (kind, depth - 1))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(top_left.stats().leaf_depth_histogram(), [0, 4]);
+    }
+
+    #[test]
+    fn inserting_and_removing_an_element_restores_the_summaries() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(314);
+        let mut elements = cloud(3140, 300);
+        for element in &mut elements {
+            element.mass = rng.gen_range(0.1..10.0);
+            element.charge = rng.gen_range(-1.0..1.0);
+        }
+        elements.push(particle(12.3, -45.6, 50.0));
+        elements[300].charge = 3.0;
+        let mut tree = inserted(&elements[..300], 100.0, 4);
+        let summaries = |tree: &ParticleQuadTree<f64>| {
+            let mut summaries = vec![(tree.len(), *tree.summary())];
+            if let QuadtreeNode::Node {
+                top_left,
+                top_right,
+                bottom_left,
+                bottom_right,
+            } = &tree.node
+            {
+                for child in [top_left, top_right, bottom_left, bottom_right] {
+                    summaries.push((child.len(), *child.summary()));
+                }
+            }
+            summaries
+        };
+        let before = summaries(&tree);
+        assert_eq!(before.len(), 5);
+        tree.insert(&elements, 300);
+        assert_eq!(tree.len(), 301);
+        assert!(tree.remove(&elements, 300));
+        for ((len, summary), (len_before, summary_before)) in summaries(&tree).iter().zip(&before) {
+            assert_eq!(len, len_before);
+            assert!((summary.mass - summary_before.mass).abs() < 1e-9);
+            assert!((summary.charge - summary_before.charge).abs() < 1e-9);
+            assert!(summary.position.distance_sq(&summary_before.position) < 1e-18);
+        }
+        // no longer held, nothing changes
+        assert!(!tree.remove(&elements, 300));
+        assert_eq!(summaries(&tree).len(), 5);
+        assert_eq!(tree.len(), 300);
+    }
+
+    #[test]
+    fn removing_from_a_deep_leaf_updates_the_ancestor_counts() {
+        let mut elements = cloud(3141, 200);
+        // a tight cluster that needs many levels to split
+        for i in 0..20 {
+            elements.push(particle(10.0 + i as f64 * 1e-5, 10.0, 1.0 + i as f64));
+        }
+        let mut tree = inserted(&elements, 100.0, 4);
+        let mut check = CountCheck {
+            elements: &elements,
+            ..Default::default()
+        };
+        let _ = tree.visit(&mut check);
+        let (depth, deepest) = std::mem::take(&mut check.deepest);
+        assert!(depth > 10, "deepest leaf at {}", depth);
+        let mass_before = tree.summary().mass;
+        for (removed, index) in deepest.into_iter().enumerate() {
+            assert!(tree.remove(&elements, index));
+            assert_eq!(tree.len(), elements.len() - removed - 1);
+            let _ = tree.visit(&mut check);
+            assert!(!tree.iter_indices().any(|i| i == index));
+            assert!(!tree.remove(&elements, index));
+            assert!(tree.summary().mass < mass_before);
+        }
+    }
+
+    #[test]
+    fn relocated_elements_are_found_at_their_new_position() {
+        let mut elements = cloud(3150, 400);
+        let mut tree = inserted(&elements, 100.0, 8);
+        let index = 10;
+        let leaf_of = |tree: &ParticleQuadTree<f64>| {
+            let (leaf, indices) = tree
+                .iter_leaves()
+                .find(|(_, indices)| indices.contains(&index))
+                .unwrap();
+            (leaf.center(), indices.to_vec())
+        };
+        let check = |tree: &ParticleQuadTree<f64>, elements: &[Particle<f64>]| {
+            let position = elements[index].position;
+            let mut found = Vec::new();
+            tree.query_rect(elements, position, position, &mut found);
+            assert!(found.contains(&index));
+            assert_eq!(tree.iter_indices().filter(|&i| i == index).count(), 1);
+            let _ = tree.visit(&mut CountCheck {
+                elements,
+                ..Default::default()
+            });
+            let fresh = inserted(elements, 100.0, 8);
+            assert_eq!(tree.len(), fresh.len());
+            assert!(
+                tree.summary()
+                    .position
+                    .distance_sq(&fresh.summary().position)
+                    < 1e-18
+            );
+        };
+
+        // halfway towards the center of its leaf, only the summaries change
+        let (leaf_center, leaf_indices) = leaf_of(&tree);
+        let old_position = elements[index].position;
+        elements[index].position = (&old_position + leaf_center) * 0.5;
+        assert!(tree.relocate(&elements, index, old_position));
+        assert_eq!(leaf_of(&tree), (leaf_center, leaf_indices));
+        check(&tree, &elements);
+
+        // into the opposite quadrant of the root, removed and inserted again
+        let old_position = elements[index].position;
+        elements[index].position = old_position * -1.0;
+        assert!(tree.relocate(&elements, index, old_position));
+        assert_ne!(leaf_of(&tree).0, leaf_center);
+        check(&tree, &elements);
+
+        // the tree does not hold the element at the given position, or no such element
+        let mut before = Vec::new();
+        tree.dump(&mut before).unwrap();
+        assert!(!tree.relocate(&elements, index, old_position));
+        assert!(!tree.relocate(&elements, elements.len(), old_position));
+        let mut after = Vec::new();
+        tree.dump(&mut after).unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn removals_collapse_a_node_into_one_leaf_without_flipping_at_the_threshold() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(322);
+        let elements: Vec<_> = (0..150)
+            .map(|_| particle(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 1.0))
+            .collect();
+        let is_leaf = |tree: &ParticleQuadTree<f64>| matches!(tree.node, QuadtreeNode::Leaf { .. });
+        let mut tree = inserted(&elements, 100.0, 100);
+        assert!(!is_leaf(&tree));
+        for i in 0..100 {
+            assert!(tree.remove(&elements, i));
+            // half a leaf is 50 elements
+            assert_eq!(is_leaf(&tree), i == 99, "after removing {}", i);
+        }
+        let mut held: Vec<_> = tree.iter_indices().collect();
+        held.sort_unstable();
+        assert_eq!(held, (100..150).collect::<Vec<_>>());
+        assert_eq!(tree.stats().nodes, 1);
+        let remaining = inserted(&elements[100..], 100.0, 100);
+        assert_eq!(tree.len(), 50);
+        assert!((tree.summary().mass - remaining.summary().mass).abs() < 1e-9);
+        assert!(
+            tree.summary()
+                .position
+                .distance_sq(&remaining.summary().position)
+                < 1e-18
+        );
+
+        // one element in and out at the lower threshold keeps the leaf
+        for _ in 0..3 {
+            tree.insert(&elements, 0);
+            assert!(is_leaf(&tree));
+            assert!(tree.remove(&elements, 0));
+            assert!(is_leaf(&tree));
+        }
+        // and at the upper one the node, once it split
+        for i in 0..51 {
+            tree.insert(&elements, i);
+        }
+        assert_eq!(tree.len(), 101);
+        assert!(!is_leaf(&tree));
+        for _ in 0..3 {
+            assert!(tree.remove(&elements, 50));
+            assert!(!is_leaf(&tree));
+            tree.insert(&elements, 50);
+            assert!(!is_leaf(&tree));
+        }
+        let _ = tree.visit(&mut CountCheck {
+            elements: &elements,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn bulk_built_trees_answer_queries_like_inserted_ones() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(323);
+        let elements = cloud(3230, 3000);
+        let inserted = inserted(&elements, 100.0, 8);
+        let built = ParticleQuadTree::build(&elements, 8);
+        assert_eq!(inserted.len(), built.len());
+        assert_eq!(inserted.summary().mass, built.summary().mass);
+        assert!(
+            inserted
+                .summary()
+                .position
+                .distance_sq(&built.summary().position)
+                < 1e-18
+        );
+        let answers = |tree: &ParticleQuadTree<f64>, point: Vector2D<f64>, size: f64| {
+            let mut in_rect = Vec::new();
+            let max = Vector2D {
+                x: point.x + size,
+                y: point.y + size,
+            };
+            tree.query_rect(&elements, point, max, &mut in_rect);
+            in_rect.sort_unstable();
+            let mut in_radius = Vec::new();
+            tree.query_radius(&elements, point, size, &mut in_radius);
+            in_radius.sort_unstable();
+            let mut nearest = Vec::new();
+            tree.knn(&elements, point, 10, &mut nearest);
+            (in_rect, in_radius, tree.nearest(&elements, point), nearest)
+        };
+        for i in 0..500 {
+            let point = query_point(&mut rng, i % 2 == 1);
+            let size = rng.gen_range(0.0..30.0);
+            assert_eq!(
+                answers(&inserted, point, size),
+                answers(&built, point, size)
+            );
+        }
+    }
+
+    #[test]
+    fn stats_count_the_levels_leaves_and_elements_of_a_small_cluster() {
+        // five elements in the top left eighth of the square from -4 to 4, three of them in the
+        // top left sixteenth and two in the bottom right one
+        let elements = [
+            particle(-3.6, -3.6, 1.0),
+            particle(-3.4, -3.7, 1.0),
+            particle(-3.7, -3.3, 1.0),
+            particle(-2.4, -2.6, 1.0),
+            particle(-2.7, -2.2, 1.0),
+        ];
+        let mut leaves_per_depth = [0; MAX_DEPTH + 1];
+        leaves_per_depth[..4].copy_from_slice(&[0, 3, 3, 4]);
+        let expected = TreeStats {
+            depth: 4,
+            nodes: 13,
+            leaves: 10,
+            empty_leaves: 8,
+            elements: 5,
+            max_leaf_occupancy: 3,
+            leaves_per_depth,
+        };
+        let mut built = ParticleQuadTree::new(Vector2D::default(), 8.0, 8.0, 4);
+        built.clear_and_rebuild(Vector2D::default(), 8.0, 8.0, &elements);
+        for tree in [inserted(&elements, 8.0, 4), built] {
+            let stats = tree.stats();
+            assert_eq!(stats, expected);
+            assert_eq!(stats.leaf_depth_histogram(), [0, 3, 3, 4]);
+            assert_eq!(stats.mean_leaf_occupancy(), 0.5);
+        }
+    }
+
+    /// Prints the shape of the tree next to the timings of a benchmark
+    fn print_stats(tree: &ParticleQuadTree<f64>) {
+        let stats = tree.stats();
+        println!(
+            "  {} nodes, {} levels, {} leaves ({} empty), {:.1} elements per leaf, at most {}, \
+             leaves per level: {:?}",
+            stats.nodes,
+            stats.depth,
+            stats.leaves,
+            stats.empty_leaves,
+            stats.mean_leaf_occupancy(),
+            stats.max_leaf_occupancy,
+            stats.leaf_depth_histogram()
+        );
+    }
+
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn bulk_building_is_faster_than_inserting() {
+        use std::time::Instant;
+        let elements = cloud(3231, 100_000);
+        let start = Instant::now();
+        let inserted = inserted(&elements, 100.0, 8);
+        let insert_time = start.elapsed();
+        let start = Instant::now();
+        let built = ParticleQuadTree::build(&elements, 8);
+        let build_time = start.elapsed();
+        assert_eq!(inserted.len(), built.len());
+        println!(
+            "100k elements: inserted in {:.2?}, built in {:.2?}",
+            insert_time, build_time
+        );
+        print_stats(&built);
+        assert!(build_time < insert_time);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_builds_match_serial_ones() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(327);
+        let mut elements: Vec<_> = (0..30_000)
+            .map(|_| particle(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 1.0))
+            .collect();
+        elements.extend(cloud(3270, 2000));
+        let dump = |tree: &ParticleQuadTree<f64>| {
+            let mut dump = Vec::new();
+            tree.dump(&mut dump).unwrap();
+            String::from_utf8(dump).unwrap()
+        };
+        // only a root at depth 0 builds its children in parallel, the depth of the others is
+        // relative to it in the dump
+        let mut serial = ParticleQuadTree::new(Vector2D::default(), 100.0, 100.0, 8);
+        serial.depth = 1;
+        let mut indices: Vec<_> = (0..elements.len()).collect();
+        serial.build_from(&elements, &mut indices, &mut SpareNodes::default());
+
+        let mut parallel = ParticleQuadTree::new(Vector2D::default(), 100.0, 100.0, 8);
+        parallel.clear_and_rebuild(Vector2D::default(), 100.0, 100.0, &elements);
+        assert_eq!(dump(&parallel), dump(&serial));
+        // again with the spare nodes of the first build shared among the threads
+        parallel.clear_and_rebuild(Vector2D::default(), 100.0, 100.0, &elements);
+        assert_eq!(dump(&parallel), dump(&serial));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn bulk_building_scales_with_threads() {
+        use rand::{Rng, SeedableRng};
+        use std::time::Instant;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3271);
+        let elements: Vec<_> = (0..200_000)
+            .map(|_| particle(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 1.0))
+            .collect();
+        let build_time = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let mut tree = ParticleQuadTree::build(&elements, 8);
+                let start = Instant::now();
+                for _ in 0..5 {
+                    tree.rebuild(&elements);
+                }
+                (start.elapsed() / 5, tree)
+            })
+        };
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let (one, _) = build_time(1);
+        let (all, tree) = build_time(cores);
+        println!(
+            "200k elements: built in {:.2?} on 1 thread, {:.2?} on {} threads, {:.2}x",
+            one,
+            all,
+            cores,
+            one.as_secs_f64() / all.as_secs_f64()
+        );
+        print_stats(&tree);
+        if cores > 1 {
+            assert!(all < one, "{} threads are not faster than 1", cores);
+        }
+    }
+
+    /// The addresses of this node and all nodes below it
+    fn node_addresses(tree: &ParticleQuadTree<f64>, addresses: &mut Vec<usize>) {
+        addresses.push(tree as *const ParticleQuadTree<f64> as usize);
+        if let QuadtreeNode::Node {
+            top_left,
+            top_right,
+            bottom_left,
+            bottom_right,
+        } = &tree.node
+        {
+            for child in [top_left, top_right, bottom_left, bottom_right] {
+                node_addresses(child, addresses);
+            }
+        }
+    }
+
+    #[test]
+    fn rebuilding_the_same_elements_reuses_every_node() {
+        let elements = cloud(3080, 5000);
+        let mut tree = ParticleQuadTree::build(&elements, 8);
+        let nodes = |tree: &ParticleQuadTree<f64>| {
+            let mut addresses = Vec::new();
+            node_addresses(tree, &mut addresses);
+            addresses.sort_unstable();
+            addresses
+        };
+        let first = nodes(&tree);
+        let spare_leaves = tree.spare.leaves.len();
+        for _ in 0..2 {
+            tree.rebuild(&elements);
+            assert_eq!(nodes(&tree), first);
+            // every recycled node went back into the tree, none was allocated
+            assert!(tree.spare.nodes.is_empty());
+            assert_eq!(tree.spare.leaves.len(), spare_leaves);
+        }
+    }
+
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn rebuilding_is_faster_than_building_a_new_tree() {
+        use rand::{Rng, SeedableRng};
+        use std::time::Instant;
+        // without the stacks of elements on the grid of the cloud, which split down to the
+        // maximum depth and dominate the time of both
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3081);
+        let elements: Vec<_> = (0..50_000)
+            .map(|_| particle(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 1.0))
+            .collect();
+        let runs = 20;
+        let start = Instant::now();
+        for _ in 0..runs {
+            let tree = ParticleQuadTree::build(&elements, 8);
+            assert_eq!(tree.len(), elements.len());
+        }
+        let build_time = start.elapsed() / runs;
+        let mut tree = ParticleQuadTree::build(&elements, 8);
+        let start = Instant::now();
+        for _ in 0..runs {
+            tree.rebuild(&elements);
+            assert_eq!(tree.len(), elements.len());
+        }
+        let rebuild_time = start.elapsed() / runs;
+        println!(
+            "50k elements: new tree in {:.2?}, rebuilt in {:.2?}",
+            build_time, rebuild_time
+        );
+        print_stats(&tree);
+        assert!(rebuild_time < build_time);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    #[ignore = "measures time, run with --release --ignored"]
+    fn force_pass_scales_with_threads() {
+        use std::time::Instant;
+        let elements = cloud(328, 50_000);
+        let tree = ParticleQuadTree::build(&elements, 8);
+        let params = params(0.5, 0.7);
+        let force_time = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut accelerations = Vec::new();
+            pool.install(|| {
+                let start = Instant::now();
+                for _ in 0..5 {
+                    tree.accelerations(&elements, &params, &mut accelerations);
+                }
+                (start.elapsed().as_secs_f64() / 5.0, accelerations)
+            })
+        };
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let (one, serial) = force_time(1);
+        let (all, parallel) = force_time(cores);
+        println!(
+            "50k elements: {:.2?} ms per force pass on 1 thread, {:.2?} ms on {} threads, {:.2}x",
+            one * 1e3,
+            all * 1e3,
+            cores,
+            one / all
+        );
+        print_stats(&tree);
+        assert_eq!(serial, parallel);
+        if cores > 1 {
+            assert!(all < one, "{} threads are not faster than 1", cores);
+        }
+    }
+
+    #[test]
+    fn forces_in_a_100_to_1_box_stay_close_to_brute_force() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(331);
+        let mut box_of = |width: f64, height: f64| -> Vec<_> {
+            (0..2000)
+                .map(|_| {
+                    particle(
+                        rng.gen_range(0.0..width),
+                        rng.gen_range(0.0..height),
+                        rng.gen_range(0.5..2.0),
+                    )
+                })
+                .collect()
+        };
+        let error_of = |elements: &[Particle<f64>], tree: &ParticleQuadTree<f64>| {
+            let params = params(0.5, 0.5);
+            let mut approximated = Vec::new();
+            tree.accelerations(elements, &params, &mut approximated);
+            relative_error(&approximated, &brute_force(elements, &params))
+        };
+        for (width, height) in [(100.0, 10_000.0), (10_000.0, 100.0)] {
+            let elements = box_of(width, height);
+            // a square root, and one over the raw bounds whose nodes are all 100:1
+            let mut raw = ParticleQuadTree::build(&[], 8);
+            let center = Vector2D {
+                x: width / 2.0,
+                y: height / 2.0,
+            };
+            raw.clear_and_rebuild(center, width, height, &elements);
+            for tree in [ParticleQuadTree::build(&elements, 8), raw] {
+                // opening the nodes of the raw tall box by their width alone gave an error near 2
+                let error = error_of(&elements, &tree);
+                assert!(error < 0.02, "{} by {}: error {}", width, height, error);
+            }
+        }
+    }
+
+    #[test]
+    fn a_slightly_stale_tree_stays_within_the_approximation_error() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(333);
+        let mut elements: Vec<_> = (0..2000)
+            .map(|_| {
+                particle(
+                    rng.gen_range(-5.0..5.0),
+                    rng.gen_range(-5.0..5.0),
+                    rng.gen_range(0.5..2.0),
+                )
+            })
+            .collect();
+        let params = params(0.5, 0.5);
+        let stale = ParticleQuadTree::build(&elements, 8);
+        // the leaves have their real size, not one padded to at least 2
+        let smallest_leaf = stale.smallest_leaf().unwrap();
+        assert!(smallest_leaf < 1.0, "smallest leaf {}", smallest_leaf);
+        // as far as a rebuild threshold of 0.1 lets the particles move before rebuilding
+        let max_displacement = 0.1 * smallest_leaf;
+        for element in &mut elements {
+            let angle = rng.gen_range(0.0..std::f64::consts::TAU);
+            let distance = rng.gen_range(0.0..max_displacement);
+            element.position.x += distance * angle.cos();
+            element.position.y += distance * angle.sin();
+        }
+        let mut from_stale = Vec::new();
+        stale.accelerations(&elements, &params, &mut from_stale);
+        let mut fresh = Vec::new();
+        ParticleQuadTree::build(&elements, 8).accelerations(&elements, &params, &mut fresh);
+        let exact = brute_force(&elements, &params);
+        // the fresh tree splits elsewhere, so both differ by about the approximation error, but
+        // the stale one is just as close to the exact forces
+        let approximation = relative_error(&fresh, &exact);
+        let staleness = relative_error(&from_stale, &fresh);
+        let stale_approximation = relative_error(&from_stale, &exact);
+        assert!(
+            staleness < approximation && stale_approximation < 1.1 * approximation,
+            "stale tree off by {} from the fresh one and {} from the exact forces, the fresh one by {}",
+            staleness,
+            stale_approximation,
+            approximation
+        );
+    }
+
+    #[test]
+    fn touching_elements_in_neighboring_quadrants_collide() {
+        // overlapping by 0.02 across the vertical center line, and diagonally across the center
+        for (a, b) in [
+            (Vector2D { x: -0.99, y: 0.5 }, Vector2D { x: 0.99, y: 0.5 }),
+            (Vector2D { x: -0.7, y: -0.7 }, Vector2D { x: 0.7, y: 0.7 }),
+        ] {
+            let mut elements = vec![
+                particle(a.x, a.y, 1.0),
+                particle(b.x, b.y, 1.0),
+                // corners that center the root on the origin
+                particle(-40.0, -40.0, 1.0),
+                particle(40.0, 40.0, 1.0),
+            ];
+            elements[0].velocity = b - a;
+            elements[1].velocity = a - b;
+            for tree in [
+                inserted(&elements, 80.0, 1),
+                ParticleQuadTree::build(&elements, 1),
+            ] {
+                let leaf_of = |index| {
+                    let (leaf, _) = tree
+                        .iter_leaves()
+                        .find(|(_, indices)| indices.contains(&index))
+                        .unwrap();
+                    leaf.center
+                };
+                assert_ne!(leaf_of(0), leaf_of(1));
+                let mut collided = elements.clone();
+                tree.resolve_collisions(&mut collided, 0.0, 1.0, 0.0, &mut []);
+                // equal masses exchange their velocities
+                for (i, j) in [(0, 1), (1, 0)] {
+                    let exchanged = collided[i].velocity.distance_sq(&elements[j].velocity);
+                    assert!(exchanged < 1e-24, "{:?}", collided[i].velocity);
+                }
             }
         }
     }
 
-    fn create_summaries(
-        original: &Vec<Particle<T>>,
-        tree1: &Box<ParticleQuadTree<T>>,
-        tree2: &Box<ParticleQuadTree<T>>,
-        tree3: &Box<ParticleQuadTree<T>>,
-    ) -> Vec<Particle<T>> {
-        let mut summaries = original.clone();
-        summaries.push(tree1.summary_particle.clone());
-        summaries.push(tree2.summary_particle.clone());
-        summaries.push(tree3.summary_particle.clone());
-        summaries
+    #[test]
+    fn loose_trees_find_large_discs_across_a_boundary() {
+        let mut elements = cloud(3350, 200);
+        // centered just left of the vertical center line of the root from -50 to 50
+        elements[0] = particle(-0.5, 10.0, 1.0);
+        elements[0].radius = 10.0;
+        elements.push(particle(-50.0, -50.0, 1.0));
+        elements.push(particle(50.0, 50.0, 1.0));
+        // right of the center line, overlapping the disc but not its center
+        let (min, max) = (Vector2D { x: 5.0, y: 5.0 }, Vector2D { x: 20.0, y: 15.0 });
+        let loose = ParticleQuadTree::build_loose(&elements, 4, 1.0);
+        let (leaf, _) = loose
+            .iter_leaves()
+            .find(|(_, indices)| indices.contains(&0))
+            .unwrap();
+        assert!(leaf.max().x <= 0.0);
+        let mut found = Vec::new();
+        loose.query_rect(&elements, min, max, &mut found);
+        assert!(found.contains(&0));
+        // only the position counts in a tight tree
+        found.clear();
+        ParticleQuadTree::build(&elements, 4).query_rect(&elements, min, max, &mut found);
+        assert!(!found.contains(&0));
+        // a rectangle beyond the disc still misses it
+        found.clear();
+        let beyond = Vector2D { x: 10.0, y: 5.0 };
+        loose.query_rect(&elements, beyond, max, &mut found);
+        assert!(!found.contains(&0));
     }
 }
@@ -0,0 +1,307 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::span;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::util::vector2d::Vector2D;
+
+/// Names of the spans that make up a frame, in the order they are displayed
+pub const PHASES: [&str; 5] = ["tree_build", "forces", "integration", "render_prep", "draw"];
+
+/// Number of frames kept in the profiler history
+const HISTORY_LEN: usize = 120;
+/// Width of the bars in pixels, corresponding to one frame budget
+const BAR_WIDTH: f32 = 300.0;
+const BAR_HEIGHT: f32 = 12.0;
+const CHART_HEIGHT: f32 = 60.0;
+const SPACING: f32 = 6.0;
+
+/// Time spent in each phase of a single frame
+#[derive(Copy, Clone, Default)]
+pub struct StepReport {
+    pub(crate) phases: [Duration; PHASES.len()],
+}
+
+/// Collects the durations of the phase spans and publishes them as a `StepReport`
+/// whenever the enclosing "frame" span closes.
+pub struct StepReportLayer {
+    current: Mutex<StepReport>,
+    latest: Arc<Mutex<StepReport>>,
+}
+
+/// Time at which a span was created
+struct SpanStart(Instant);
+
+impl StepReportLayer {
+    /// Creates the layer together with a handle to the report of the last completed frame.
+    pub fn new() -> (StepReportLayer, Arc<Mutex<StepReport>>) {
+        let latest = Arc::new(Mutex::new(StepReport::default()));
+        let layer = StepReportLayer {
+            current: Mutex::new(StepReport::default()),
+            latest: Arc::clone(&latest),
+        };
+        (layer, latest)
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for StepReportLayer {
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanStart(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(elapsed) = span.extensions().get::<SpanStart>().map(|s| s.0.elapsed()) else {
+            return;
+        };
+
+        let mut current = self.current.lock().unwrap();
+        if span.name() == "frame" {
+            *self.latest.lock().unwrap() = *current;
+            *current = StepReport::default();
+        } else if let Some(phase) = PHASES.iter().position(|name| *name == span.name()) {
+            current.phases[phase] += elapsed;
+        }
+    }
+}
+
+/// An axis-aligned rectangle of the profiler HUD in screen coordinates
+pub struct HudRect {
+    pub(crate) min: Vector2D<f32>,
+    pub(crate) max: Vector2D<f32>,
+    pub(crate) phase: Option<usize>, // None for the background
+}
+
+/// Keeps the recent frame timings and lays them out as a stacked bar of the last frame
+/// followed by a strip chart of the whole history.
+pub struct ProfilerHud {
+    pub(crate) visible: bool,
+    history: VecDeque<StepReport>,
+}
+
+impl Default for ProfilerHud {
+    fn default() -> Self {
+        ProfilerHud::new()
+    }
+}
+
+impl ProfilerHud {
+    pub fn new() -> ProfilerHud {
+        ProfilerHud {
+            visible: false,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    /// Adds the report of a frame, dropping the oldest one once the history is full.
+    pub fn push(&mut self, report: StepReport) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(report);
+    }
+
+    /// Computes the rectangles to draw, scaled so that `budget` fills the full bar width
+    /// and chart height. Phases exceeding the budget are cut off.
+    pub fn layout(&self, origin: Vector2D<f32>, budget: Duration) -> Vec<HudRect> {
+        let budget_s = budget.as_secs_f32();
+        let bar_max = Vector2D {
+            x: origin.x + BAR_WIDTH,
+            y: origin.y + BAR_HEIGHT,
+        };
+        let chart_min = Vector2D {
+            x: origin.x,
+            y: bar_max.y + SPACING,
+        };
+        let chart_max = Vector2D {
+            x: bar_max.x,
+            y: chart_min.y + CHART_HEIGHT,
+        };
+        let mut rects = vec![
+            HudRect {
+                min: origin,
+                max: bar_max,
+                phase: None,
+            },
+            HudRect {
+                min: chart_min,
+                max: chart_max,
+                phase: None,
+            },
+        ];
+
+        // stacked horizontal bar of the last frame
+        if let Some(report) = self.history.back() {
+            let mut x = origin.x;
+            for (phase, duration) in report.phases.iter().enumerate() {
+                let end = (x + duration.as_secs_f32() / budget_s * BAR_WIDTH).min(bar_max.x);
+                if end > x {
+                    rects.push(HudRect {
+                        min: Vector2D { x, y: origin.y },
+                        max: Vector2D {
+                            x: end,
+                            y: bar_max.y,
+                        },
+                        phase: Some(phase),
+                    });
+                }
+                x = end;
+            }
+        }
+
+        // one stacked column per frame, growing upwards, newest frame on the right
+        let column_width = BAR_WIDTH / HISTORY_LEN as f32;
+        let first_column = HISTORY_LEN - self.history.len();
+        for (i, report) in self.history.iter().enumerate() {
+            let x = chart_min.x + (first_column + i) as f32 * column_width;
+            let mut y = chart_max.y;
+            for (phase, duration) in report.phases.iter().enumerate() {
+                let top = (y - duration.as_secs_f32() / budget_s * CHART_HEIGHT).max(chart_min.y);
+                if top < y {
+                    rects.push(HudRect {
+                        min: Vector2D { x, y: top },
+                        max: Vector2D {
+                            x: x + column_width,
+                            y,
+                        },
+                        phase: Some(phase),
+                    });
+                }
+                y = top;
+            }
+        }
+
+        rects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::info_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn report_sums_the_phases_of_a_frame() {
+        let (layer, latest) = StepReportLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let frame = info_span!("frame").entered();
+            for _ in 0..2 {
+                let _forces = info_span!("forces").entered();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            // not a phase, so it is not reported
+            info_span!("bounds").in_scope(|| std::thread::sleep(Duration::from_millis(5)));
+            // published only once the frame closes
+            assert_eq!(
+                latest.lock().unwrap().phases,
+                [Duration::ZERO; PHASES.len()]
+            );
+            frame.exit();
+        });
+
+        let phases = latest.lock().unwrap().phases;
+        assert!(phases[1] >= Duration::from_millis(10), "{:?}", phases);
+        for (phase, duration) in phases.iter().enumerate() {
+            if phase != 1 {
+                assert_eq!(*duration, Duration::ZERO, "{}", PHASES[phase]);
+            }
+        }
+    }
+
+    fn report(millis: [u64; PHASES.len()]) -> StepReport {
+        StepReport {
+            phases: millis.map(Duration::from_millis),
+        }
+    }
+
+    #[test]
+    fn history_keeps_the_latest_frames() {
+        let mut hud = ProfilerHud::new();
+        for frame in 0..HISTORY_LEN as u64 + 10 {
+            hud.push(report([frame, 0, 0, 0, 0]));
+        }
+        assert_eq!(hud.history.len(), HISTORY_LEN);
+        assert_eq!(hud.history[0].phases[0], Duration::from_millis(10));
+        assert_eq!(
+            hud.history.back().unwrap().phases[0],
+            Duration::from_millis(HISTORY_LEN as u64 + 9)
+        );
+    }
+
+    #[test]
+    fn bar_stacks_the_phases_of_the_last_frame() {
+        let mut hud = ProfilerHud::new();
+        hud.push(report([4, 0, 2, 2, 0]));
+        let origin = Vector2D { x: 10.0, y: 20.0 };
+        let rects = hud.layout(origin, Duration::from_millis(16));
+        let bar: Vec<_> = rects
+            .iter()
+            .filter(|rect| rect.phase.is_some() && rect.min.y == origin.y)
+            .map(|rect| (rect.phase.unwrap(), rect.min.x, rect.max.x))
+            .collect();
+        // a quarter, then an eighth and another eighth of the width, empty phases are left out
+        assert_eq!(
+            bar,
+            [
+                (0, 10.0, 10.0 + BAR_WIDTH / 4.0),
+                (2, 10.0 + BAR_WIDTH / 4.0, 10.0 + BAR_WIDTH * 3.0 / 8.0),
+                (3, 10.0 + BAR_WIDTH * 3.0 / 8.0, 10.0 + BAR_WIDTH / 2.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn phases_over_the_budget_are_cut_off() {
+        let mut hud = ProfilerHud::new();
+        hud.push(report([10, 10, 10, 0, 0]));
+        let origin = Vector2D { x: 0.0, y: 0.0 };
+        let rects = hud.layout(origin, Duration::from_millis(16));
+        let bar: Vec<_> = rects
+            .iter()
+            .filter(|rect| rect.phase.is_some() && rect.min.y == origin.y)
+            .collect();
+        assert_eq!(bar.len(), 2);
+        assert_eq!(bar[1].max.x, BAR_WIDTH);
+        assert!(rects.iter().all(|rect| rect.max.x <= BAR_WIDTH));
+        let chart_top = BAR_HEIGHT + SPACING;
+        assert!(rects
+            .iter()
+            .all(|rect| rect.max.y <= chart_top + CHART_HEIGHT));
+        // the chart column of the frame reaches up to the top of the chart, not beyond
+        assert!(rects
+            .iter()
+            .filter(|rect| rect.max.y > BAR_HEIGHT)
+            .all(|rect| rect.min.y >= chart_top));
+    }
+
+    #[test]
+    fn chart_puts_the_newest_frame_on_the_right() {
+        let mut hud = ProfilerHud::new();
+        hud.push(report([8, 0, 0, 0, 0]));
+        hud.push(report([0, 4, 0, 0, 0]));
+        let rects = hud.layout(Vector2D { x: 0.0, y: 0.0 }, Duration::from_millis(16));
+        let chart_bottom = BAR_HEIGHT + SPACING + CHART_HEIGHT;
+        let columns: Vec<_> = rects
+            .iter()
+            .filter(|rect| rect.phase.is_some() && rect.max.y == chart_bottom)
+            .map(|rect| (rect.phase.unwrap(), rect.max.x, chart_bottom - rect.min.y))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                (0, BAR_WIDTH * 119.0 / 120.0, CHART_HEIGHT / 2.0),
+                (1, BAR_WIDTH, CHART_HEIGHT / 4.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use rand::Rng;
+
+use crate::util::particle::{create_particle, Particle};
+use crate::util::vector2d::Vector2D;
+use crate::util::velocity_distribution::VelocityDistribution;
+use crate::Scalar;
+
+/// The force law the initial velocities are computed for
+#[derive(Copy, Clone)]
+pub struct Gravity {
+    pub(crate) grav_const: Scalar,
+    pub(crate) softening: Scalar,
+    /// Exponent `n` of the force `grav_const * m / r^n`
+    pub(crate) exponent: Scalar,
+}
+
+impl Gravity {
+    /// The speed of a circular orbit at distance `r` around the given enclosed mass.
+    /// For the default two-dimensional force, which falls off with 1/r, mass inside a
+    /// rotationally symmetric distribution acts as if it were concentrated at the center;
+    /// for other exponents this is only an approximation.
+    pub fn circular_speed(&self, enclosed_mass: Scalar, r: Scalar) -> Scalar {
+        let r_sq = r * r;
+        let softened_sq = r_sq + self.softening * self.softening;
+        let denominator = if self.exponent == 1.0 {
+            softened_sq
+        } else {
+            softened_sq.powf((self.exponent + 1.0) / 2.0)
+        };
+        (self.grav_const * enclosed_mass * r_sq / denominator).sqrt()
+    }
+}
+
+/// A rotating disc of unit mass particles in an annulus around a heavy central particle
+#[derive(Copy, Clone)]
+pub struct Disc {
+    pub(crate) center: Vector2D<Scalar>,
+    /// Bulk velocity of the whole disc
+    pub(crate) velocity: Vector2D<Scalar>,
+    pub(crate) central_mass: Scalar,
+    pub(crate) inner_radius: Scalar,
+    pub(crate) outer_radius: Scalar,
+    /// Number of particles besides the central one
+    pub(crate) count: usize,
+}
+
+impl Disc {
+    /// Adds the central particle and the disc, with every particle on a roughly circular
+    /// counter-clockwise orbit around the mass enclosed by its radius. Returns the index of the
+    /// central particle.
+    pub fn generate<R: Rng>(
+        &self,
+        gravity: &Gravity,
+        rng: &mut R,
+        particles: &mut Vec<Particle<Scalar>>,
+    ) -> usize {
+        let central_index = particles.len();
+        let mut central = create_particle(self.center.x, self.center.y, self.central_mass, 0.0);
+        central.velocity = self.velocity;
+        central.radius = 3.0;
+        particles.push(central);
+
+        // uniform in area, sorted so the enclosed mass is just the number of inner particles
+        let inner_sq = self.inner_radius * self.inner_radius;
+        let outer_sq = self.outer_radius * self.outer_radius;
+        let mut radii: Vec<Scalar> = (0..self.count)
+            .map(|_| (inner_sq + rng.gen::<Scalar>() * (outer_sq - inner_sq)).sqrt())
+            .collect();
+        radii.sort_by(Scalar::total_cmp);
+
+        for (i, r) in radii.into_iter().enumerate() {
+            let angle = rng.gen_range(0.0..crate::consts::TAU);
+            let speed = gravity.circular_speed(self.central_mass + i as Scalar, r);
+            let offset = Vector2D::from_polar(r, angle);
+            let orbital_velocity = Vector2D::from_polar(speed, angle + crate::consts::FRAC_PI_2);
+            let position = &self.center + offset;
+            let mut particle = create_particle(position.x, position.y, 1.0, 0.0);
+            particle.velocity = &self.velocity + orbital_velocity;
+            particles.push(particle);
+        }
+        central_index
+    }
+}
+
+/// Two rotating discs that approach each other slightly off-center, so they pass through each
+/// other and eventually merge. `split` is the fraction of the particles in the first galaxy.
+pub fn two_galaxies<R: Rng>(
+    gravity: &Gravity,
+    separation: Scalar,
+    central_mass: Scalar,
+    count: usize,
+    split: Scalar,
+    rng: &mut R,
+    particles: &mut Vec<Particle<Scalar>>,
+) {
+    let first_count = (count as Scalar * split.clamp(0.0, 1.0)).round() as usize;
+    let speed = 0.3 * gravity.circular_speed(2.0 * central_mass, separation / 2.0);
+    let disc = |center: Vector2D<Scalar>, velocity: Vector2D<Scalar>, count: usize| Disc {
+        center,
+        velocity,
+        central_mass,
+        inner_radius: separation / 40.0,
+        outer_radius: separation / 4.0,
+        count,
+    };
+
+    disc(
+        Vector2D {
+            x: -separation / 2.0,
+            y: -separation / 8.0,
+        },
+        Vector2D { x: speed, y: 0.0 },
+        first_count,
+    )
+    .generate(gravity, rng, particles);
+    disc(
+        Vector2D {
+            x: separation / 2.0,
+            y: separation / 8.0,
+        },
+        Vector2D { x: -speed, y: 0.0 },
+        count - first_count,
+    )
+    .generate(gravity, rng, particles);
+}
+
+/// Particles spaced evenly on a circle, each moved by up to `jitter` in a random direction
+#[derive(Copy, Clone)]
+pub struct Ring {
+    pub(crate) center: Vector2D<Scalar>,
+    pub(crate) radius: Scalar,
+    pub(crate) jitter: Scalar,
+    /// Counter-clockwise speed along the circle, 0 leaves the ring to collapse
+    pub(crate) speed: Scalar,
+    pub(crate) count: usize,
+}
+
+impl Ring {
+    pub fn generate<R: Rng>(&self, rng: &mut R, particles: &mut Vec<Particle<Scalar>>) {
+        for i in 0..self.count {
+            let angle = i as Scalar / self.count as Scalar * crate::consts::TAU;
+            let mut position = &self.center + Vector2D::from_polar(self.radius, angle);
+            if self.jitter > 0.0 {
+                let offset = Vector2D::from_polar(
+                    self.jitter * rng.gen::<Scalar>().sqrt(),
+                    rng.gen_range(0.0..crate::consts::TAU),
+                );
+                position = &position + offset;
+            }
+            let mut particle = create_particle(position.x, position.y, 1.0, 0.0);
+            particle.velocity = Vector2D::from_polar(self.speed, angle + crate::consts::FRAC_PI_2);
+            particles.push(particle);
+        }
+    }
+}
+
+/// Fraction of the Plummer profile's mass that is sampled, the rest lies in a very sparse halo
+const PLUMMER_MASS_FRACTION: Scalar = 0.99;
+
+/// A cluster following the two-dimensional analogue of the Plummer model, with surface
+/// density proportional to (1 + r²/a²)⁻² and equal mass particles. Half of the mass lies
+/// within the scale radius `a`.
+#[derive(Copy, Clone)]
+pub struct Plummer {
+    pub(crate) center: Vector2D<Scalar>,
+    pub(crate) scale_radius: Scalar,
+    pub(crate) total_mass: Scalar,
+    pub(crate) count: usize,
+}
+
+impl Plummer {
+    /// Adds the cluster with velocities close to its equilibrium, ignoring softening.
+    pub fn generate<R: Rng>(
+        &self,
+        gravity: &Gravity,
+        rng: &mut R,
+        particles: &mut Vec<Particle<Scalar>>,
+    ) {
+        if self.count == 0 {
+            return;
+        }
+        let first = particles.len();
+        let mass = self.total_mass / self.count as Scalar;
+        for _ in 0..self.count {
+            let radius = self.sample_radius(rng.gen_range(0.0..PLUMMER_MASS_FRACTION));
+            let angle = rng.gen_range(0.0..crate::consts::TAU);
+            let position = &self.center + Vector2D::from_polar(radius, angle);
+            particles.push(create_particle(position.x, position.y, mass, 0.0));
+        }
+
+        // with a force falling off like 1/r the Jeans equation gives the same isotropic
+        // dispersion G*M/4 per velocity component at every radius
+        let dispersion_sq = gravity.grav_const.max(0.0) * self.total_mass / 4.0;
+        VelocityDistribution::Maxwell {
+            temperature: dispersion_sq * mass,
+            remove_drift: true,
+        }
+        .assign(rng, &mut particles[first..]);
+    }
+
+    /// Inverts the enclosed mass fraction M(r)/M = r²/(r² + a²).
+    fn sample_radius(&self, enclosed_fraction: Scalar) -> Scalar {
+        self.scale_radius * (enclosed_fraction / (1.0 - enclosed_fraction)).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn ring_particles_start_within_the_jitter_of_the_radius() {
+        let center = Vector2D { x: 10.0, y: -5.0 };
+        let ring = Ring {
+            center,
+            radius: 40.0,
+            jitter: 2.0,
+            speed: 3.0,
+            count: 500,
+        };
+        let mut particles = Vec::new();
+        ring.generate(&mut StdRng::seed_from_u64(271), &mut particles);
+        assert_eq!(particles.len(), 500);
+        for particle in &particles {
+            let distance = (particle.position - center).length();
+            assert!(
+                (distance - 40.0).abs() <= 2.0 + 1e-3,
+                "distance {}",
+                distance
+            );
+            assert!((particle.velocity.length() - 3.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn plummer_median_radius_is_the_half_mass_radius() {
+        let center = Vector2D { x: 100.0, y: 100.0 };
+        let plummer = Plummer {
+            center,
+            scale_radius: 20.0,
+            total_mass: 1000.0,
+            count: 10_000,
+        };
+        let gravity = Gravity {
+            grav_const: 10.0,
+            softening: 0.0,
+            exponent: 1.0,
+        };
+        let mut particles = Vec::new();
+        plummer.generate(&gravity, &mut StdRng::seed_from_u64(273), &mut particles);
+        let mut radii: Vec<Scalar> = particles
+            .iter()
+            .map(|p| (p.position - center).length())
+            .collect();
+        radii.sort_by(Scalar::total_cmp);
+        // half of the mass lies within the scale radius
+        let median = radii[radii.len() / 2];
+        assert!((median / 20.0 - 1.0).abs() < 0.03, "median {}", median);
+        let total: Scalar = particles.iter().map(|p| p.mass).sum();
+        assert!((total / 1000.0 - 1.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,386 @@
+use clap::ValueEnum;
+use std::fmt;
+use std::path::Path;
+
+use crate::util::particle::Particle;
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+/// Size of the Gadget-2 header block in bytes
+const GADGET_HEADER_SIZE: u32 = 256;
+/// Number of particle types in a Gadget-2 snapshot
+const GADGET_NUM_TYPES: usize = 6;
+
+/// The plane onto which three-dimensional snapshots are projected
+#[derive(ValueEnum, Copy, Clone, Debug)]
+pub enum Projection {
+    Xy,
+    Xz,
+    Yz,
+}
+
+impl Projection {
+    fn project(self, v: [f64; 3]) -> Vector2D<Scalar> {
+        let (x, y) = match self {
+            Projection::Xy => (v[0], v[1]),
+            Projection::Xz => (v[0], v[2]),
+            Projection::Yz => (v[1], v[2]),
+        };
+        Vector2D {
+            x: x as Scalar,
+            y: y as Scalar,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    /// A line of a text snapshot could not be parsed
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// A binary snapshot is truncated or uses a block layout that is not supported
+    UnsupportedLayout(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            SnapshotError::UnsupportedLayout(message) => {
+                write!(f, "unsupported snapshot layout: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// Loads the particles of a snapshot file, which is either a Gadget-2 binary snapshot in
+/// format 1, or a text file with one "x y z vx vy vz m" line per particle.
+/// The format is detected from the content.
+pub fn load_snapshot(
+    path: &Path,
+    projection: Projection,
+) -> Result<Vec<Particle<Scalar>>, SnapshotError> {
+    let bytes = std::fs::read(path)?;
+    if detect_gadget_endianness(&bytes).is_some() {
+        parse_gadget(&bytes, projection)
+    } else {
+        let text = String::from_utf8(bytes).map_err(|_| {
+            SnapshotError::UnsupportedLayout(String::from(
+                "neither a Gadget-2 snapshot nor a text file",
+            ))
+        })?;
+        parse_text(&text, projection)
+    }
+}
+
+/// Parses a text snapshot. Empty lines and lines starting with '#' are ignored.
+pub fn parse_text(
+    text: &str,
+    projection: Projection,
+) -> Result<Vec<Particle<Scalar>>, SnapshotError> {
+    let mut particles = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let values = line
+            .split_whitespace()
+            .map(|value| value.parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| SnapshotError::Parse {
+                line: i + 1,
+                message: e.to_string(),
+            })?;
+        if values.len() != 7 {
+            return Err(SnapshotError::Parse {
+                line: i + 1,
+                message: format!(
+                    "expected 7 values (x y z vx vy vz m), found {}",
+                    values.len()
+                ),
+            });
+        }
+
+        particles.push(Particle {
+            position: projection.project([values[0], values[1], values[2]]),
+            velocity: projection.project([values[3], values[4], values[5]]),
+            radius: 1.0,
+            mass: values[6] as Scalar,
+            charge: 0.0,
+            species: 0,
+        });
+    }
+    Ok(particles)
+}
+
+#[derive(Copy, Clone)]
+enum Endianness {
+    Little,
+    Big,
+}
+
+/// Gadget-2 files start with the size marker of the 256 byte header block,
+/// which tells us the byte order the file was written in.
+fn detect_gadget_endianness(bytes: &[u8]) -> Option<Endianness> {
+    let marker: [u8; 4] = bytes.get(0..4)?.try_into().ok()?;
+    if u32::from_le_bytes(marker) == GADGET_HEADER_SIZE {
+        Some(Endianness::Little)
+    } else if u32::from_be_bytes(marker) == GADGET_HEADER_SIZE {
+        Some(Endianness::Big)
+    } else {
+        None
+    }
+}
+
+/// Reads the Fortran-style blocks of a Gadget-2 file, each framed by its size in bytes.
+struct GadgetReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    endianness: Endianness,
+}
+
+impl<'a> GadgetReader<'a> {
+    /// Returns the content of the next block, checking that both size markers agree.
+    fn next_block(&mut self, name: &str) -> Result<&'a [u8], SnapshotError> {
+        let size = self.read_u32(self.offset).ok_or_else(|| truncated(name))? as usize;
+        let start = self.offset + 4;
+        let end = start + size;
+        if end + 4 > self.bytes.len() {
+            return Err(truncated(name));
+        }
+        if self.read_u32(end) != Some(size as u32) {
+            return Err(SnapshotError::UnsupportedLayout(format!(
+                "size markers of the {} block do not match",
+                name
+            )));
+        }
+        self.offset = end + 4;
+        Ok(&self.bytes[start..end])
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let raw: [u8; 4] = self.bytes.get(offset..offset + 4)?.try_into().ok()?;
+        Some(self.u32_from(raw))
+    }
+
+    fn u32_from(&self, raw: [u8; 4]) -> u32 {
+        match self.endianness {
+            Endianness::Little => u32::from_le_bytes(raw),
+            Endianness::Big => u32::from_be_bytes(raw),
+        }
+    }
+
+    fn u32_at(&self, block: &[u8], offset: usize) -> u32 {
+        self.u32_from(block[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn f32_at(&self, block: &[u8], offset: usize) -> f32 {
+        f32::from_bits(self.u32_at(block, offset))
+    }
+
+    fn f64_at(&self, block: &[u8], offset: usize) -> f64 {
+        let raw: [u8; 8] = block[offset..offset + 8].try_into().unwrap();
+        match self.endianness {
+            Endianness::Little => f64::from_le_bytes(raw),
+            Endianness::Big => f64::from_be_bytes(raw),
+        }
+    }
+
+    /// Reads `count` floating point values from a block which stores either f32 or f64 values.
+    fn read_reals(
+        &self,
+        block: &[u8],
+        count: usize,
+        name: &str,
+    ) -> Result<Vec<f64>, SnapshotError> {
+        if block.len() == count * 4 {
+            Ok((0..count)
+                .map(|i| self.f32_at(block, i * 4) as f64)
+                .collect())
+        } else if block.len() == count * 8 {
+            Ok((0..count).map(|i| self.f64_at(block, i * 8)).collect())
+        } else {
+            Err(SnapshotError::UnsupportedLayout(format!(
+                "{} block has {} bytes, expected {} single or double precision values",
+                name,
+                block.len(),
+                count
+            )))
+        }
+    }
+}
+
+fn truncated(name: &str) -> SnapshotError {
+    SnapshotError::UnsupportedLayout(format!("file ends before the {} block", name))
+}
+
+/// Parses a Gadget-2 snapshot in format 1 (blocks without name tags).
+/// Only the header, positions, velocities, ids and masses are read.
+pub fn parse_gadget(
+    bytes: &[u8],
+    projection: Projection,
+) -> Result<Vec<Particle<Scalar>>, SnapshotError> {
+    let endianness = detect_gadget_endianness(bytes).ok_or_else(|| {
+        SnapshotError::UnsupportedLayout(String::from("missing Gadget-2 header block"))
+    })?;
+    let mut reader = GadgetReader {
+        bytes,
+        offset: 0,
+        endianness,
+    };
+
+    // header: particle counts per type, followed by the mass table per type
+    let header = reader.next_block("header")?;
+    let mut num_per_type = [0usize; GADGET_NUM_TYPES];
+    let mut mass_table = [0f64; GADGET_NUM_TYPES];
+    for t in 0..GADGET_NUM_TYPES {
+        num_per_type[t] = reader.u32_at(header, t * 4) as usize;
+        mass_table[t] = reader.f64_at(header, 24 + t * 8);
+    }
+    let count: usize = num_per_type.iter().sum();
+
+    let block = reader.next_block("position")?;
+    let positions = reader.read_reals(block, 3 * count, "position")?;
+    let block = reader.next_block("velocity")?;
+    let velocities = reader.read_reals(block, 3 * count, "velocity")?;
+    let ids = reader.next_block("id")?;
+    if ids.len() != count * 4 && ids.len() != count * 8 {
+        return Err(SnapshotError::UnsupportedLayout(format!(
+            "id block has {} bytes for {} particles",
+            ids.len(),
+            count
+        )));
+    }
+
+    // individual masses are only stored for types without an entry in the mass table
+    let num_individual_masses: usize = (0..GADGET_NUM_TYPES)
+        .filter(|t| mass_table[*t] == 0.0)
+        .map(|t| num_per_type[t])
+        .sum();
+    let individual_masses = if num_individual_masses > 0 {
+        let block = reader.next_block("mass")?;
+        reader.read_reals(block, num_individual_masses, "mass")?
+    } else {
+        Vec::new()
+    };
+
+    let mut particles = Vec::with_capacity(count);
+    let mut next_individual_mass = individual_masses.iter();
+    let mut i = 0;
+    for t in 0..GADGET_NUM_TYPES {
+        for _ in 0..num_per_type[t] {
+            let mass = if mass_table[t] == 0.0 {
+                *next_individual_mass.next().unwrap()
+            } else {
+                mass_table[t]
+            };
+            particles.push(Particle {
+                position: projection.project([
+                    positions[3 * i],
+                    positions[3 * i + 1],
+                    positions[3 * i + 2],
+                ]),
+                velocity: projection.project([
+                    velocities[3 * i],
+                    velocities[3 * i + 1],
+                    velocities[3 * i + 2],
+                ]),
+                radius: 1.0,
+                mass: mass as Scalar,
+                charge: 0.0,
+                species: 0,
+            });
+            i += 1;
+        }
+    }
+    Ok(particles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two particles of type 1 with the mass 0.5 from the mass table, then one of type 4 with an
+    /// individual mass of 2
+    const GADGET_LE_F32: &[u8] = include_bytes!("../../tests/fixtures/gadget_le_f32.bin");
+    /// The same particles, written big endian in double precision
+    const GADGET_BE_F64: &[u8] = include_bytes!("../../tests/fixtures/gadget_be_f64.bin");
+
+    #[test]
+    fn parses_gadget_fixtures() {
+        for bytes in [GADGET_LE_F32, GADGET_BE_F64] {
+            let particles = parse_gadget(bytes, Projection::Xy).unwrap();
+            assert_eq!(particles.len(), 3);
+            let masses: Vec<Scalar> = particles.iter().map(|p| p.mass).collect();
+            assert_eq!(masses, [0.5, 0.5, 2.0]);
+            assert_eq!(particles[0].position, Vector2D { x: 1.0, y: 2.0 });
+            assert_eq!(particles[2].position, Vector2D { x: 7.0, y: -8.0 });
+            assert_eq!(particles[0].velocity, Vector2D { x: 0.5, y: 0.0 });
+        }
+    }
+
+    #[test]
+    fn projects_onto_the_chosen_plane() {
+        let particles = parse_gadget(GADGET_LE_F32, Projection::Yz).unwrap();
+        assert_eq!(particles[1].position, Vector2D { x: 5.5, y: 6.0 });
+        let particles = parse_gadget(GADGET_LE_F32, Projection::Xz).unwrap();
+        assert_eq!(particles[2].position, Vector2D { x: 7.0, y: 9.25 });
+        assert_eq!(particles[0].velocity, Vector2D { x: 0.5, y: -1.0 });
+    }
+
+    #[test]
+    fn rejects_truncated_gadget_files() {
+        let truncated = &GADGET_LE_F32[..GADGET_LE_F32.len() - 10];
+        assert!(matches!(
+            parse_gadget(truncated, Projection::Xy),
+            Err(SnapshotError::UnsupportedLayout(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_mismatched_block_markers() {
+        let mut bytes = GADGET_LE_F32.to_vec();
+        // the closing marker of the header block
+        bytes[4 + 256] ^= 1;
+        let error = parse_gadget(&bytes, Projection::Xy).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unsupported snapshot layout: size markers of the header block do not match"
+        );
+    }
+
+    #[test]
+    fn parses_text_snapshots() {
+        let text = "# x y z vx vy vz m\n\n1 2 3 0.5 0 -1 4\n  -1 0 0 0 0 0 0.25\n";
+        let particles = parse_text(text, Projection::Xy).unwrap();
+        assert_eq!(particles.len(), 2);
+        assert_eq!(particles[0].position, Vector2D { x: 1.0, y: 2.0 });
+        assert_eq!(particles[0].velocity, Vector2D { x: 0.5, y: 0.0 });
+        assert_eq!(particles[1].mass, 0.25);
+    }
+
+    #[test]
+    fn reports_the_line_of_text_errors() {
+        let error = parse_text("1 2 3 4 5 6 7\n1 2 3\n", Projection::Xy).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2: expected 7 values (x y z vx vy vz m), found 3"
+        );
+        let error = parse_text("# header\n1 2 3 4 5 six 7\n", Projection::Xy).unwrap_err();
+        assert!(matches!(error, SnapshotError::Parse { line: 2, .. }));
+    }
+}
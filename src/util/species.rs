@@ -0,0 +1,82 @@
+use std::str::FromStr;
+
+use crate::Scalar;
+
+/// Defaults shared by all particles of one kind
+#[derive(Clone, Debug)]
+pub struct SpeciesConfig {
+    pub(crate) name: String,
+    /// Share of newly spawned particles, relative to the other species
+    pub(crate) fraction: Scalar,
+    pub(crate) mass: Scalar,
+    pub(crate) radius: Scalar,
+    /// Red, green and blue components used to draw the particles
+    pub(crate) color: (f32, f32, f32),
+    /// Added to the global damping for particles of this species
+    pub(crate) drag: Scalar,
+    /// Whether particles of this species merge on contact when merging is enabled
+    pub(crate) merges: bool,
+}
+
+/// Names that can be used in a species list
+pub const KNOWN_SPECIES: [&str; 3] = ["gas", "stars", "dust"];
+
+impl SpeciesConfig {
+    /// The preset for a known species name.
+    fn preset(name: &str, fraction: Scalar) -> Option<SpeciesConfig> {
+        let (mass, radius, color, drag, merges) = match name {
+            // dissipative, and it never clumps into stars
+            "gas" => (1.0, 1.0, (0.55, 0.75, 1.0), 0.5, false),
+            "stars" => (10.0, 2.0, (1.0, 0.9, 0.55), 0.0, true),
+            "dust" => (0.2, 0.5, (0.7, 0.5, 0.3), 0.1, true),
+            _ => return None,
+        };
+        Some(SpeciesConfig {
+            name: String::from(name),
+            fraction,
+            mass,
+            radius,
+            color,
+            drag,
+            merges,
+        })
+    }
+
+    /// Picks a species index with probability proportional to the fractions, given a uniform
+    /// sample from `0..1`.
+    pub fn choose(species: &[SpeciesConfig], sample: Scalar) -> u8 {
+        let total: Scalar = species.iter().map(|s| s.fraction).sum();
+        let mut remaining = sample * total;
+        for (i, s) in species.iter().enumerate() {
+            if remaining < s.fraction {
+                return i as u8;
+            }
+            remaining -= s.fraction;
+        }
+        species.len().saturating_sub(1) as u8
+    }
+}
+
+/// Parses a species given as `name:fraction`, e.g. `gas:0.8`.
+impl FromStr for SpeciesConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, fraction) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected name:fraction for a species, got: {}", s))?;
+        let fraction = fraction
+            .trim()
+            .parse::<Scalar>()
+            .ok()
+            .filter(|fraction| *fraction >= 0.0)
+            .ok_or_else(|| format!("invalid species fraction: {}", s))?;
+        SpeciesConfig::preset(name.trim(), fraction).ok_or_else(|| {
+            format!(
+                "unknown species {}, expected one of {}",
+                name,
+                KNOWN_SPECIES.join(", ")
+            )
+        })
+    }
+}
@@ -0,0 +1,176 @@
+use crate::consts::PI;
+
+use crate::util::neighbor_list::NeighborList;
+use crate::util::particle::Particle;
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+/// Fraction of the normal velocity kept when a particle bounces off a tank wall
+const WALL_RESTITUTION: Scalar = 0.5;
+
+/// Settings of the smoothed particle hydrodynamics mode, where particles behave like a
+/// compressible fluid inside a rectangular tank
+#[derive(Copy, Clone, Debug)]
+pub struct SphParameters {
+    /// Radius within which particles interact
+    pub(crate) smoothing_length: Scalar,
+    /// Converts the deviation from the rest density into pressure
+    pub(crate) stiffness: Scalar,
+    pub(crate) rest_density: Scalar,
+    pub(crate) tank_min: Vector2D<Scalar>,
+    pub(crate) tank_max: Vector2D<Scalar>,
+}
+
+impl SphParameters {
+    /// Calculates the acceleration of every particle caused by the pressure gradient.
+    /// Densities use the poly6 kernel, the pressure force uses the gradient of the spiky kernel.
+    /// `neighbors` must list every pair closer than the smoothing length.
+    pub fn pressure_accelerations(
+        &self,
+        neighbors: &NeighborList<Scalar>,
+        particles: &[Particle<Scalar>],
+    ) -> Vec<Vector2D<Scalar>> {
+        let h = self.smoothing_length;
+        let h_sq = h * h;
+        let poly6 = 4.0 / (PI * h_sq.powi(4));
+        let spiky_gradient = -30.0 / (PI * h.powi(5));
+
+        let densities: Vec<Scalar> = particles
+            .iter()
+            .enumerate()
+            .map(|(i, particle)| {
+                let own = particle.mass * poly6 * h_sq.powi(3);
+                own + neighbors
+                    .neighbors(i)
+                    .iter()
+                    .map(|&j| {
+                        let r_sq = (particles[j].position - particle.position).length_sq();
+                        if r_sq < h_sq {
+                            particles[j].mass * poly6 * (h_sq - r_sq).powi(3)
+                        } else {
+                            0.0
+                        }
+                    })
+                    .sum::<Scalar>()
+            })
+            .collect();
+        let pressures: Vec<Scalar> = densities
+            .iter()
+            .map(|density| self.stiffness * (density - self.rest_density))
+            .collect();
+
+        let mut accelerations = Vec::with_capacity(particles.len());
+        for (i, particle) in particles.iter().enumerate() {
+            let mut acceleration = Vector2D::default();
+            for &j in neighbors.neighbors(i) {
+                let v_dir = particle.position - particles[j].position;
+                let r = v_dir.length();
+                if r >= h || r <= 0.0 {
+                    continue;
+                }
+                let gradient = v_dir * (spiky_gradient * (h - r) * (h - r) / r);
+                let scale = -particles[j].mass * (pressures[i] + pressures[j])
+                    / (2.0 * densities[j] * densities[i]);
+                acceleration = &acceleration + gradient * scale;
+            }
+            accelerations.push(acceleration);
+        }
+        accelerations
+    }
+
+    /// Keeps the particles inside the tank by reflecting them off its walls.
+    pub fn confine(&self, particles: &mut [Particle<Scalar>]) {
+        for particle in particles {
+            let (position, velocity) = (&mut particle.position, &mut particle.velocity);
+            confine_axis(
+                &mut position.x,
+                &mut velocity.x,
+                self.tank_min.x,
+                self.tank_max.x,
+            );
+            confine_axis(
+                &mut position.y,
+                &mut velocity.y,
+                self.tank_min.y,
+                self.tank_max.y,
+            );
+        }
+    }
+}
+
+fn confine_axis(position: &mut Scalar, velocity: &mut Scalar, min: Scalar, max: Scalar) {
+    if *position < min {
+        *position = min;
+        *velocity = velocity.abs() * WALL_RESTITUTION;
+    } else if *position > max {
+        *position = max;
+        *velocity = -velocity.abs() * WALL_RESTITUTION;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::particle::create_particle;
+
+    fn water() -> SphParameters {
+        SphParameters {
+            smoothing_length: 2.0,
+            stiffness: 50.0,
+            rest_density: 0.5,
+            tank_min: Vector2D {
+                x: -100.0,
+                y: -100.0,
+            },
+            tank_max: Vector2D { x: 100.0, y: 100.0 },
+        }
+    }
+
+    fn pressure(sph: &SphParameters, particles: &[Particle<Scalar>]) -> Vec<Vector2D<Scalar>> {
+        let mut neighbors = NeighborList::new(0.0);
+        neighbors.update(particles, sph.smoothing_length);
+        sph.pressure_accelerations(&neighbors, particles)
+    }
+
+    #[test]
+    fn a_symmetric_pair_pushes_equally_and_oppositely() {
+        let sph = water();
+        let particles = [
+            create_particle(-0.5, 0.0, 1.0, 0.0),
+            create_particle(0.5, 0.0, 1.0, 0.0),
+        ];
+        let accelerations = pressure(&sph, &particles);
+        assert!(accelerations[0].x != 0.0);
+        assert_eq!(accelerations[0].y, 0.0);
+        assert_eq!(accelerations[0].x, -accelerations[1].x);
+        assert_eq!(accelerations[0].y, -accelerations[1].y);
+    }
+
+    #[test]
+    fn a_column_at_rest_gains_no_momentum() {
+        let sph = water();
+        let mut particles = Vec::new();
+        for row in 0..20 {
+            for column in 0..5 {
+                let mass = if row % 2 == 0 { 1.0 } else { 1.5 };
+                let x = column as Scalar * 0.8 + (row % 2) as Scalar * 0.3;
+                particles.push(create_particle(x, row as Scalar * 0.7, mass, 0.0));
+            }
+        }
+        let accelerations = pressure(&sph, &particles);
+        let mut momentum = Vector2D::default();
+        let mut magnitude = 0.0;
+        for (particle, acceleration) in particles.iter().zip(&accelerations) {
+            momentum = &momentum + *acceleration * particle.mass;
+            magnitude += acceleration.length() * particle.mass;
+        }
+        // the pressure forces between every pair cancel, only rounding is left
+        assert!(magnitude > 1.0, "no pressure at all");
+        assert!(
+            momentum.length() < magnitude * 1e-5,
+            "momentum {:?} of {}",
+            momentum,
+            magnitude
+        );
+    }
+}
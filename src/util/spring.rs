@@ -0,0 +1,123 @@
+use num_traits::Float;
+
+use crate::util::particle::Particle;
+use crate::util::vector2d::Vector2D;
+
+/// A damped spring connecting two particles, given by their indices
+#[derive(Copy, Clone, Debug)]
+pub struct Spring<T> {
+    pub(crate) a: usize,
+    pub(crate) b: usize,
+    pub(crate) rest_length: T,
+    pub(crate) stiffness: T,
+    /// Damping of the relative velocity along the spring
+    pub(crate) damping: T,
+}
+
+impl<T: Float + Default> Spring<T> {
+    /// Adds the change in velocity caused by the spring during one step to both ends
+    /// (Hooke's law plus damping). Springs referring to missing particles are ignored.
+    pub fn apply(
+        &self,
+        elements: &[Particle<T>],
+        delta_velocities: &mut [Vector2D<T>],
+        elapsed_s: T,
+    ) {
+        let zero = T::default();
+        let (Some(p1), Some(p2)) = (elements.get(self.a), elements.get(self.b)) else {
+            return;
+        };
+        let v_dir = p2.position - p1.position;
+        let distance = v_dir.length();
+        if distance <= zero || p1.mass <= zero || p2.mass <= zero {
+            return;
+        }
+
+        let normal = v_dir * (T::from(1).unwrap() / distance);
+        let v_rel = p2.velocity - p1.velocity;
+        let closing_speed = v_rel.x * normal.x + v_rel.y * normal.y;
+        let force = self.stiffness * (distance - self.rest_length) + self.damping * closing_speed;
+        delta_velocities[self.a] =
+            &delta_velocities[self.a] + normal * (force / p1.mass * elapsed_s);
+        delta_velocities[self.b] =
+            delta_velocities[self.b] - normal * (force / p2.mass * elapsed_s);
+    }
+}
+
+/// Updates the particle indices of the springs after the particles at the given ascending
+/// indices were removed, dropping springs attached to a removed particle.
+pub fn remap_springs<T>(springs: &mut Vec<Spring<T>>, removed: &[usize]) {
+    springs.retain_mut(|spring| {
+        if removed.binary_search(&spring.a).is_ok() || removed.binary_search(&spring.b).is_ok() {
+            return false;
+        }
+        spring.a -= removed.partition_point(|&i| i < spring.a);
+        spring.b -= removed.partition_point(|&i| i < spring.b);
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spring(a: usize, b: usize) -> Spring<f64> {
+        Spring {
+            a,
+            b,
+            rest_length: 2.0,
+            stiffness: 8.0,
+            damping: 0.0,
+        }
+    }
+
+    #[test]
+    fn oscillates_about_its_rest_length() {
+        let particle = |x| Particle {
+            position: Vector2D { x, y: 0.0 },
+            velocity: Vector2D::default(),
+            radius: 1.0,
+            mass: 1.0,
+            charge: 0.0,
+            species: 0,
+        };
+        let mut elements = [particle(0.0), particle(3.0)];
+        let spring = spring(0, 1);
+        let dt = 0.001;
+        let mut delta_velocities = [Vector2D::default(); 2];
+        let mut lengths = Vec::new();
+        for _ in 0..2000 {
+            delta_velocities.fill(Vector2D::default());
+            spring.apply(&elements, &mut delta_velocities, dt);
+            for (element, delta_v) in elements.iter_mut().zip(delta_velocities) {
+                element.velocity = &element.velocity + delta_v;
+                element.position = &element.position + element.velocity * dt;
+            }
+            lengths.push(elements[1].position.x - elements[0].position.x);
+        }
+        // the reduced mass is 1/2, so the length oscillates with omega = sqrt(2 k) = 4 between
+        // 3 and 1, with a period of about 1.57 it passes the rest length 3 times in 2 seconds
+        let (min, max) = lengths.iter().fold((f64::MAX, f64::MIN), |(min, max), &l| {
+            (min.min(l), max.max(l))
+        });
+        assert!(
+            (min - 1.0).abs() < 0.01 && (max - 3.0).abs() < 0.01,
+            "between {} and {}",
+            min,
+            max
+        );
+        let crossings = lengths
+            .windows(2)
+            .filter(|w| (w[0] - 2.0) * (w[1] - 2.0) < 0.0)
+            .count();
+        assert_eq!(crossings, 3);
+    }
+
+    #[test]
+    fn remapping_drops_springs_of_removed_particles() {
+        let mut springs = vec![spring(0, 1), spring(1, 4), spring(2, 5), spring(4, 5)];
+        remap_springs(&mut springs, &[1, 3]);
+        let ends: Vec<_> = springs.iter().map(|s| (s.a, s.b)).collect();
+        assert_eq!(ends, [(1, 3), (2, 3)]);
+    }
+}
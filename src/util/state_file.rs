@@ -0,0 +1,441 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::util::particle::Particle;
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+/// Identifies a binary state file
+pub const MAGIC: [u8; 4] = *b"GRVS";
+/// The format version written by this build. Files of older versions can still be read.
+pub const VERSION: u8 = 3;
+
+const CONFIG_TAG: [u8; 4] = *b"CONF";
+const PARTICLES_TAG: [u8; 4] = *b"PART";
+const SPECIES_TAG: [u8; 4] = *b"SPEC";
+
+/// Simulation settings and progress stored alongside the particles
+#[derive(Copy, Clone, Debug)]
+pub struct StateConfig {
+    pub(crate) grav_const: Scalar,
+    pub(crate) num_steps: u64,
+    pub(crate) sim_time: Scalar,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    Corrupt(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "{}", e),
+            StateError::BadMagic => write!(f, "not a state file"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "state file version {} is newer than the supported version {}",
+                version, VERSION
+            ),
+            StateError::Corrupt(message) => write!(f, "corrupt state file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<std::io::Error> for StateError {
+    fn from(e: std::io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+/// Writes the state as the magic number and version, followed by a sequence of sections.
+/// Each section consists of a four byte tag, its length in bytes and its content, so that
+/// readers can skip sections they do not know. All numbers are little endian. Floating point
+/// numbers are stored at the precision of the simulation, whose width in bytes is the first byte
+/// of the config section. Up to version 2 they were always `f32` and the width was not stored.
+pub fn write_state<W: Write>(
+    w: &mut W,
+    config: &StateConfig,
+    particles: &[Particle<Scalar>],
+) -> std::io::Result<()> {
+    let width = std::mem::size_of::<Scalar>() as u64;
+    w.write_all(&MAGIC)?;
+    w.write_all(&[VERSION])?;
+
+    w.write_all(&CONFIG_TAG)?;
+    w.write_all(&(1 + 8 + 2 * width).to_le_bytes())?;
+    w.write_all(&[width as u8])?;
+    w.write_all(&config.grav_const.to_le_bytes())?;
+    w.write_all(&config.num_steps.to_le_bytes())?;
+    w.write_all(&config.sim_time.to_le_bytes())?;
+
+    // particles are stored as one array per attribute
+    let n = particles.len() as u64;
+    w.write_all(&PARTICLES_TAG)?;
+    w.write_all(&(8 + n * 7 * width).to_le_bytes())?;
+    w.write_all(&n.to_le_bytes())?;
+    let attributes: [fn(&Particle<Scalar>) -> Scalar; 7] = [
+        |p| p.position.x,
+        |p| p.position.y,
+        |p| p.velocity.x,
+        |p| p.velocity.y,
+        |p| p.radius,
+        |p| p.mass,
+        |p| p.charge,
+    ];
+    for attribute in attributes {
+        for particle in particles {
+            w.write_all(&attribute(particle).to_le_bytes())?;
+        }
+    }
+
+    // one byte per particle, in its own section so older readers skip it
+    w.write_all(&SPECIES_TAG)?;
+    w.write_all(&n.to_le_bytes())?;
+    for particle in particles {
+        w.write_all(&[particle.species])?;
+    }
+    Ok(())
+}
+
+/// Reads a state written by `write_state` of this or an older version.
+pub fn read_state(bytes: &[u8]) -> Result<(StateConfig, Vec<Particle<Scalar>>), StateError> {
+    if bytes.len() < 5 || bytes[0..4] != MAGIC {
+        return Err(StateError::BadMagic);
+    }
+    let version = bytes[4];
+    if version > VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    // bytes per floating point number, stored in the config section since version 3
+    let mut width = (version < 3).then_some(4);
+    let mut config = None;
+    let mut particles: Option<Vec<Particle<Scalar>>> = None;
+    let mut species = None;
+    let mut reader = StateReader { bytes, offset: 5 };
+    while reader.offset < bytes.len() {
+        let tag = reader.take(4, "section tag")?;
+        let length = reader.u64("section length")? as usize;
+        let content = reader.take(length, "section")?;
+        let mut section = StateReader {
+            bytes: content,
+            offset: 0,
+        };
+        match <[u8; 4]>::try_from(tag).unwrap() {
+            CONFIG_TAG => {
+                let config_width = if version < 3 {
+                    4
+                } else {
+                    match section.take(1, "number width")?[0] {
+                        width @ (4 | 8) => width as usize,
+                        other => {
+                            return Err(StateError::Corrupt(format!(
+                                "unsupported number width {}",
+                                other
+                            )))
+                        }
+                    }
+                };
+                width = Some(config_width);
+                config = Some(StateConfig {
+                    grav_const: section.scalar(config_width, "gravitational constant")?,
+                    num_steps: section.u64("step count")?,
+                    sim_time: section.scalar(config_width, "simulation time")?,
+                })
+            }
+            PARTICLES_TAG => {
+                let width = width.ok_or_else(|| {
+                    StateError::Corrupt(String::from("particle section before config section"))
+                })?;
+                let n = section.u64("particle count")? as usize;
+                // version 1 did not store charges
+                let num_attributes = if version < 2 { 6 } else { 7 };
+                let mut attributes = Vec::with_capacity(num_attributes);
+                for _ in 0..num_attributes {
+                    let values = (0..n)
+                        .map(|_| section.scalar(width, "particle attribute"))
+                        .collect::<Result<Vec<Scalar>, StateError>>()?;
+                    attributes.push(values);
+                }
+                particles = Some(
+                    (0..n)
+                        .map(|i| Particle {
+                            position: Vector2D {
+                                x: attributes[0][i],
+                                y: attributes[1][i],
+                            },
+                            velocity: Vector2D {
+                                x: attributes[2][i],
+                                y: attributes[3][i],
+                            },
+                            radius: attributes[4][i],
+                            mass: attributes[5][i],
+                            charge: attributes.get(6).map_or(0.0, |charges| charges[i]),
+                            species: 0,
+                        })
+                        .collect(),
+                );
+            }
+            SPECIES_TAG => species = Some(content),
+            // written by a newer version, safe to ignore
+            _ => {}
+        }
+    }
+
+    if let (Some(particles), Some(species)) = (&mut particles, species) {
+        if species.len() != particles.len() {
+            return Err(StateError::Corrupt(String::from(
+                "species do not match the particles",
+            )));
+        }
+        for (particle, species) in particles.iter_mut().zip(species) {
+            particle.species = *species;
+        }
+    }
+
+    match (config, particles) {
+        (Some(config), Some(particles)) => Ok((config, particles)),
+        (None, _) => Err(StateError::Corrupt(String::from("missing config section"))),
+        (_, None) => Err(StateError::Corrupt(String::from(
+            "missing particle section",
+        ))),
+    }
+}
+
+pub fn save_state(
+    path: &Path,
+    config: &StateConfig,
+    particles: &[Particle<Scalar>],
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_state(&mut writer, config, particles)?;
+    writer.flush()
+}
+
+pub fn load_state(path: &Path) -> Result<(StateConfig, Vec<Particle<Scalar>>), StateError> {
+    read_state(&std::fs::read(path)?)
+}
+
+struct StateReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize, what: &str) -> Result<&'a [u8], StateError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| StateError::Corrupt(format!("file ends inside {}", what)))?;
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self, what: &str) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8, what)?.try_into().unwrap()))
+    }
+
+    /// Reads a stored `f32` or `f64`, depending on the width in bytes, as the floating point type
+    /// of the simulation
+    fn scalar(&mut self, width: usize, what: &str) -> Result<Scalar, StateError> {
+        let bytes = self.take(width, what)?;
+        Ok(match width {
+            4 => f32::from_le_bytes(bytes.try_into().unwrap()) as Scalar,
+            _ => f64::from_le_bytes(bytes.try_into().unwrap()) as Scalar,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particle(x: Scalar, y: Scalar, mass: Scalar, species: u8) -> Particle<Scalar> {
+        Particle {
+            position: Vector2D { x, y },
+            velocity: Vector2D { x: y, y: -x },
+            radius: 1.0,
+            mass,
+            charge: -mass,
+            species,
+        }
+    }
+
+    fn write(config: &StateConfig, particles: &[Particle<Scalar>]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_state(&mut bytes, config, particles).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip_keeps_the_full_precision() {
+        // none of these is exact in binary, so any rounding on the way changes them
+        let config = StateConfig {
+            grav_const: 1.0 / 3.0,
+            num_steps: 12,
+            sim_time: 0.1,
+        };
+        let particles = [
+            particle(0.1, 0.2, 0.7, 0),
+            particle(1.0 / 7.0, -2.0 / 3.0, 0.3, 1),
+        ];
+        let (read_config, read_particles) = read_state(&write(&config, &particles)).unwrap();
+        assert_eq!(
+            read_config.grav_const.to_bits(),
+            config.grav_const.to_bits()
+        );
+        assert_eq!(read_config.sim_time.to_bits(), config.sim_time.to_bits());
+        for (read, written) in read_particles.iter().zip(&particles) {
+            assert_eq!(read.position, written.position);
+            assert_eq!(read.velocity, written.velocity);
+            assert_eq!(read.mass.to_bits(), written.mass.to_bits());
+            assert_eq!(read.charge.to_bits(), written.charge.to_bits());
+        }
+    }
+
+    #[test]
+    fn stores_the_number_width_in_the_config() {
+        let config = StateConfig {
+            grav_const: 1.0,
+            num_steps: 0,
+            sim_time: 0.0,
+        };
+        let bytes = write(&config, &[]);
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(&bytes[5..9], &CONFIG_TAG);
+        assert_eq!(bytes[17] as usize, std::mem::size_of::<Scalar>());
+    }
+
+    #[test]
+    fn reads_version_2_files_of_f32() {
+        let (config, particles) =
+            read_state(include_bytes!("../../tests/fixtures/state_v2.bin")).unwrap();
+        assert_eq!(config.grav_const, 0.5);
+        assert_eq!(config.num_steps, 7);
+        assert_eq!(config.sim_time, 1.25);
+        assert_eq!(particles.len(), 2);
+        assert_eq!(particles[1].position, Vector2D { x: -2.5, y: 0.25 });
+        assert_eq!(particles[1].velocity, Vector2D { x: -0.125, y: 4.0 });
+        assert_eq!(particles[1].radius, 2.0);
+        assert_eq!(particles[1].mass, 20.0);
+        assert_eq!(particles[1].charge, 1.0);
+        assert_eq!(particles[1].species, 1);
+    }
+
+    #[test]
+    fn rejects_unknown_number_widths() {
+        let config = StateConfig {
+            grav_const: 1.0,
+            num_steps: 0,
+            sim_time: 0.0,
+        };
+        let mut bytes = write(&config, &[]);
+        bytes[17] = 2;
+        assert!(matches!(read_state(&bytes), Err(StateError::Corrupt(_))));
+    }
+
+    #[test]
+    fn round_trips_a_large_state() {
+        let config = StateConfig {
+            grav_const: 10.0,
+            num_steps: 123_456,
+            sim_time: 2057.5,
+        };
+        let particles: Vec<_> = (0..100_000)
+            .map(|i| {
+                particle(
+                    i as Scalar * 0.5,
+                    -(i as Scalar),
+                    1.0 + i as Scalar,
+                    (i % 3) as u8,
+                )
+            })
+            .collect();
+        let (read_config, read_particles) = read_state(&write(&config, &particles)).unwrap();
+        assert_eq!(read_config.num_steps, config.num_steps);
+        assert_eq!(read_config.sim_time, config.sim_time);
+        assert_eq!(read_particles.len(), particles.len());
+        for (read, written) in read_particles.iter().zip(&particles) {
+            assert_eq!(read.position, written.position);
+            assert_eq!(read.velocity, written.velocity);
+            assert_eq!(read.radius, written.radius);
+            assert_eq!(read.mass, written.mass);
+            assert_eq!(read.charge, written.charge);
+            assert_eq!(read.species, written.species);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_headers() {
+        let config = StateConfig {
+            grav_const: 1.0,
+            num_steps: 0,
+            sim_time: 0.0,
+        };
+        let bytes = write(&config, &[particle(1.0, 2.0, 3.0, 0)]);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'J';
+        let error = read_state(&bad_magic).unwrap_err();
+        assert_eq!(error.to_string(), "not a state file");
+        assert!(matches!(read_state(b"GRV"), Err(StateError::BadMagic)));
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        let error = read_state(&newer).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "state file version {} is newer than the supported version {}",
+                VERSION + 1,
+                VERSION
+            )
+        );
+
+        let error = read_state(&bytes[..bytes.len() - 3]).unwrap_err();
+        assert!(matches!(error, StateError::Corrupt(_)));
+    }
+
+    #[test]
+    fn skips_unknown_sections() {
+        let config = StateConfig {
+            grav_const: 1.0,
+            num_steps: 3,
+            sim_time: 0.5,
+        };
+        let mut bytes = write(&config, &[particle(1.0, 2.0, 3.0, 2)]);
+        bytes.extend_from_slice(b"NEWS");
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let (read_config, particles) = read_state(&bytes).unwrap();
+        assert_eq!(read_config.num_steps, 3);
+        assert_eq!(particles[0].species, 2);
+    }
+
+    #[test]
+    fn reads_version_1_files_without_charges() {
+        let (config, particles) =
+            read_state(include_bytes!("../../tests/fixtures/state_v1.bin")).unwrap();
+        assert_eq!(config.grav_const, 0.5);
+        assert_eq!(config.num_steps, 7);
+        assert_eq!(config.sim_time, 1.25);
+        assert_eq!(particles.len(), 2);
+        assert_eq!(particles[0].position, Vector2D { x: 1.0, y: 3.0 });
+        assert_eq!(particles[1].position, Vector2D { x: -2.5, y: 0.25 });
+        assert_eq!(particles[1].velocity, Vector2D { x: -0.125, y: 4.0 });
+        assert_eq!(particles[1].radius, 2.0);
+        assert_eq!(particles[1].mass, 20.0);
+        assert_eq!(particles[1].charge, 0.0);
+        assert_eq!(particles[1].species, 0);
+    }
+}
@@ -0,0 +1,327 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::util::particle::Particle;
+use crate::util::particle_quad_tree::EnergyReport;
+use crate::Scalar;
+
+/// Minimum wall-clock time between two published snapshots
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a client may take to send its request before the connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of the simulation as seen by the status server
+struct StatusSnapshot {
+    step: u64,
+    sim_time: Scalar,
+    steps_per_sec: Scalar,
+    grav_const: Scalar,
+    /// The latest measured energy and the step it was measured at
+    energy: Option<(u64, EnergyReport<Scalar>)>,
+    diagnostics: Diagnostics,
+    particles: Vec<Particle<Scalar>>,
+}
+
+/// Counts from the last step that hint at an unstable simulation
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Diagnostics {
+    /// Particles removed by the kill boundary so far
+    pub(crate) culled: usize,
+    /// Particles whose acceleration was clamped
+    pub(crate) clamped: usize,
+    /// Particles slowed down to the maximum speed
+    pub(crate) speed_limited: usize,
+    /// Rebuilds of the neighbor lists so far, compared with the step count this is how often
+    /// they are rebuilt
+    pub(crate) neighbor_rebuilds: u64,
+}
+
+/// Serves the latest published simulation snapshot over HTTP on a background thread.
+///
+/// `GET /status` returns a small JSON summary, `GET /state` the full list of particles.
+pub struct StatusServer {
+    snapshot: Arc<Mutex<Arc<StatusSnapshot>>>,
+    port: u16,
+    last_publish: Option<Instant>,
+    last_publish_step: u64,
+}
+
+impl StatusServer {
+    /// Binds to the given address and port and starts serving requests. Port 0 picks a free
+    /// one, see [`StatusServer::port`].
+    pub fn start(address: IpAddr, port: u16) -> std::io::Result<StatusServer> {
+        let listener = TcpListener::bind((address, port))?;
+        let port = listener.local_addr()?.port();
+        let snapshot = Arc::new(Mutex::new(Arc::new(StatusSnapshot {
+            step: 0,
+            sim_time: 0.0,
+            steps_per_sec: 0.0,
+            grav_const: 0.0,
+            energy: None,
+            diagnostics: Diagnostics::default(),
+            particles: Vec::new(),
+        })));
+
+        let shared = Arc::clone(&snapshot);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // only hold the lock long enough to grab the current snapshot
+                let snapshot = Arc::clone(&shared.lock().unwrap());
+                // every connection gets its own thread, so a slow client does not hold up the
+                // others. A client hanging up early is not our problem
+                thread::spawn(move || {
+                    let _ = handle_request(stream, &snapshot);
+                });
+            }
+        });
+
+        Ok(StatusServer {
+            snapshot,
+            port,
+            last_publish: None,
+            last_publish_step: 0,
+        })
+    }
+
+    /// The port the server listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Replaces the served snapshot, unless the previous one was published only recently.
+    pub(crate) fn publish(
+        &mut self,
+        step: u64,
+        sim_time: Scalar,
+        grav_const: Scalar,
+        energy: Option<(u64, EnergyReport<Scalar>)>,
+        diagnostics: Diagnostics,
+        particles: &[Particle<Scalar>],
+    ) {
+        let now = Instant::now();
+        let steps_per_sec = match self.last_publish {
+            Some(last) if now - last < PUBLISH_INTERVAL => return,
+            Some(last) => {
+                step.saturating_sub(self.last_publish_step) as Scalar
+                    / (now - last).as_secs_f64() as Scalar
+            }
+            None => 0.0,
+        };
+        self.last_publish = Some(now);
+        self.last_publish_step = step;
+
+        let snapshot = Arc::new(StatusSnapshot {
+            step,
+            sim_time,
+            steps_per_sec,
+            grav_const,
+            energy,
+            diagnostics,
+            particles: particles.to_vec(),
+        });
+        *self.snapshot.lock().unwrap() = snapshot;
+    }
+}
+
+fn handle_request(stream: TcpStream, snapshot: &StatusSnapshot) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // skip the headers, we do not need any of them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/status")) => ("200 OK", status_json(snapshot)),
+        (Some("GET"), Some("/state")) => ("200 OK", state_json(snapshot)),
+        _ => ("404 Not Found", String::from("{\"error\":\"not found\"}")),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn status_json(snapshot: &StatusSnapshot) -> String {
+    // null until the first energy report, see `--energy-every`
+    let energy = match &snapshot.energy {
+        Some((step, energy)) => format!(
+            "{{\"step\":{},\"kinetic\":{},\"potential\":{},\"total\":{}}}",
+            step,
+            json_number(energy.kinetic),
+            json_number(energy.potential),
+            json_number(energy.total)
+        ),
+        None => String::from("null"),
+    };
+    let diagnostics = &snapshot.diagnostics;
+    format!(
+        "{{\"step\":{},\"sim_time\":{},\"particle_count\":{},\"steps_per_sec\":{},\
+         \"energy\":{},\"diagnostics\":{{\"culled\":{},\"clamped\":{},\"speed_limited\":{},\
+         \"neighbor_rebuilds\":{}}}}}",
+        snapshot.step,
+        json_number(snapshot.sim_time),
+        snapshot.particles.len(),
+        json_number(snapshot.steps_per_sec),
+        energy,
+        diagnostics.culled,
+        diagnostics.clamped,
+        diagnostics.speed_limited,
+        diagnostics.neighbor_rebuilds
+    )
+}
+
+fn state_json(snapshot: &StatusSnapshot) -> String {
+    let mut json = format!(
+        "{{\"step\":{},\"sim_time\":{},\"grav_const\":{},\"particles\":[",
+        snapshot.step,
+        json_number(snapshot.sim_time),
+        json_number(snapshot.grav_const)
+    );
+    for (i, particle) in snapshot.particles.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "{{\"position\":[{},{}],\"velocity\":[{},{}],\"radius\":{},\"mass\":{}}}",
+            json_number(particle.position.x),
+            json_number(particle.position.y),
+            json_number(particle.velocity.x),
+            json_number(particle.velocity.y),
+            json_number(particle.radius),
+            json_number(particle.mass)
+        );
+    }
+    json.push_str("]}");
+    json
+}
+
+/// Formats a number as JSON, which has no representation for NaN or infinities.
+fn json_number(value: Scalar) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::util::particle::create_particle;
+
+    /// Sends a GET request to the server and returns the status line and the body.
+    fn get(server: &StatusServer, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status_line = head.lines().next().unwrap().to_string();
+        (status_line, body.to_string())
+    }
+
+    fn published_server() -> StatusServer {
+        let mut server = StatusServer::start(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).unwrap();
+        let energy = EnergyReport {
+            kinetic: 1.5,
+            potential: -4.0,
+            total: -2.5,
+        };
+        let diagnostics = Diagnostics {
+            culled: 1,
+            clamped: 2,
+            speed_limited: 3,
+            neighbor_rebuilds: 4,
+        };
+        let particles = [
+            create_particle(1.0, 2.0, 3.0, 0.0),
+            create_particle(-1.0, 0.5, 1.0, 0.0),
+        ];
+        server.publish(42, 2.5, 0.25, Some((40, energy)), diagnostics, &particles);
+        server
+    }
+
+    #[test]
+    fn status_reports_progress_energy_and_diagnostics() {
+        let server = published_server();
+        let (status, body) = get(&server, "/status");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            body,
+            "{\"step\":42,\"sim_time\":2.5,\"particle_count\":2,\"steps_per_sec\":0,\
+             \"energy\":{\"step\":40,\"kinetic\":1.5,\"potential\":-4,\"total\":-2.5},\
+             \"diagnostics\":{\"culled\":1,\"clamped\":2,\"speed_limited\":3,\
+             \"neighbor_rebuilds\":4}}"
+        );
+    }
+
+    #[test]
+    fn status_has_null_energy_before_the_first_report() {
+        let mut server = StatusServer::start(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).unwrap();
+        server.publish(1, 0.5, 1.0, None, Diagnostics::default(), &[]);
+        let (_, body) = get(&server, "/status");
+        assert!(body.contains("\"energy\":null"), "{}", body);
+    }
+
+    #[test]
+    fn steps_going_back_report_no_progress() {
+        let mut server = published_server();
+        // e.g. a saved state with fewer steps was loaded since the last snapshot
+        server.last_publish = Some(Instant::now() - 2 * PUBLISH_INTERVAL);
+        server.publish(5, 0.1, 1.0, None, Diagnostics::default(), &[]);
+        let (_, body) = get(&server, "/status");
+        assert!(body.contains("\"step\":5,"), "{}", body);
+        assert!(body.contains("\"steps_per_sec\":0,"), "{}", body);
+    }
+
+    #[test]
+    fn state_lists_every_particle() {
+        let server = published_server();
+        let (status, body) = get(&server, "/state");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            body,
+            "{\"step\":42,\"sim_time\":2.5,\"grav_const\":0.25,\"particles\":[\
+             {\"position\":[1,2],\"velocity\":[0,0],\"radius\":1,\"mass\":3},\
+             {\"position\":[-1,0.5],\"velocity\":[0,0],\"radius\":1,\"mass\":1}]}"
+        );
+    }
+
+    #[test]
+    fn silent_clients_do_not_block_others() {
+        let server = published_server();
+        let _silent = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        let start = Instant::now();
+        let (status, _) = get(&server, "/status");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(start.elapsed() < REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let server = published_server();
+        let (status, _) = get(&server, "/nothing");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+}
@@ -0,0 +1,126 @@
+/// Gravitational constant in m³/(kg·s²)
+pub const GRAV_CONST_SI: f64 = 6.674_30e-11;
+
+/// The size of one internal simulation unit of length, mass and time, expressed in SI units.
+/// Quantities entering or leaving the simulation are converted with it, while the simulation
+/// itself only ever sees internal units.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Units {
+    pub(crate) length_m: f64,
+    pub(crate) mass_kg: f64,
+    pub(crate) time_s: f64,
+}
+
+impl Units {
+    /// Meters, kilograms and seconds
+    pub const SI: Units = Units {
+        length_m: 1.0,
+        mass_kg: 1.0,
+        time_s: 1.0,
+    };
+
+    /// Astronomical units, solar masses and Julian years
+    pub const SOLAR: Units = Units {
+        length_m: 1.495_978_707e11,
+        mass_kg: 1.988_47e30,
+        time_s: 365.25 * 86_400.0,
+    };
+
+    /// The gravitational constant in internal units
+    pub fn grav_const(&self) -> f64 {
+        GRAV_CONST_SI * self.mass_kg * self.time_s * self.time_s / self.length_m.powi(3)
+    }
+
+    pub fn length_to_internal(&self, meters: f64) -> f64 {
+        meters / self.length_m
+    }
+
+    pub fn length_from_internal(&self, length: f64) -> f64 {
+        length * self.length_m
+    }
+
+    pub fn mass_to_internal(&self, kilograms: f64) -> f64 {
+        kilograms / self.mass_kg
+    }
+
+    pub fn mass_from_internal(&self, mass: f64) -> f64 {
+        mass * self.mass_kg
+    }
+
+    pub fn time_to_internal(&self, seconds: f64) -> f64 {
+        seconds / self.time_s
+    }
+
+    pub fn time_from_internal(&self, time: f64) -> f64 {
+        time * self.time_s
+    }
+
+    pub fn velocity_to_internal(&self, meters_per_second: f64) -> f64 {
+        meters_per_second * self.time_s / self.length_m
+    }
+
+    pub fn velocity_from_internal(&self, velocity: f64) -> f64 {
+        velocity * self.length_m / self.time_s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= 1e-12 * expected.abs(),
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        for units in [Units::SI, Units::SOLAR] {
+            for value in [0.0, 1.0, -3.5, 2.5e17] {
+                assert_close(
+                    units.length_from_internal(units.length_to_internal(value)),
+                    value,
+                );
+                assert_close(
+                    units.mass_from_internal(units.mass_to_internal(value)),
+                    value,
+                );
+                assert_close(
+                    units.time_from_internal(units.time_to_internal(value)),
+                    value,
+                );
+                assert_close(
+                    units.velocity_from_internal(units.velocity_to_internal(value)),
+                    value,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn solar_units_convert_to_si() {
+        let solar = Units::SOLAR;
+        assert_close(solar.length_from_internal(1.0), 1.495_978_707e11);
+        assert_close(solar.mass_to_internal(1.988_47e30), 1.0);
+        assert_close(solar.time_from_internal(2.0), 2.0 * 365.25 * 86_400.0);
+        // the earth moves at about 29.8 km/s, 2 pi AU per year
+        let earth_speed = solar.velocity_to_internal(29_780.0);
+        assert!(
+            (earth_speed - std::f64::consts::TAU).abs() < 1e-2,
+            "{}",
+            earth_speed
+        );
+    }
+
+    #[test]
+    fn grav_const_matches_the_units() {
+        assert_eq!(Units::SI.grav_const(), GRAV_CONST_SI);
+        // Kepler's third law: G M = 4 pi^2 AU^3 / year^2 for the sun
+        let four_pi_sq = 4.0 * std::f64::consts::PI * std::f64::consts::PI;
+        assert!((Units::SOLAR.grav_const() / four_pi_sq - 1.0).abs() < 1e-3);
+    }
+}
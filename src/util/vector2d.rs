@@ -1,7 +1,9 @@
 use std::ops::{Add, Mul, Sub};
 
+use num_traits::Float;
+
 /// A two-dimensional vector of type <T>
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Vector2D<T> {
     pub(crate) x: T,
     pub(crate) y: T,
@@ -86,4 +88,57 @@ impl<T: Copy + Add<Output = T> + Mul<Output = T>> Vector2D<T> {
     pub(crate) fn length_sq(&self) -> T {
         self.x * self.x + self.y * self.y
     }
+
+    /// The squared distance between the two points, cheaper than the distance itself.
+    pub(crate) fn distance_sq(&self, other: &Vector2D<T>) -> T
+    where
+        T: Sub<Output = T>,
+    {
+        (self - other).length_sq()
+    }
+
+    pub(crate) fn dot(&self, other: &Vector2D<T>) -> T {
+        self.x * other.x + self.y * other.y
+    }
+
+    /// The vector rotated by 90 degrees counter-clockwise.
+    pub(crate) fn perpendicular(&self) -> Vector2D<T>
+    where
+        T: Default + Sub<Output = T>,
+    {
+        Vector2D {
+            x: T::default() - self.y,
+            y: self.x,
+        }
+    }
+
+    /// The component of this vector along the given unit vector.
+    pub(crate) fn project_onto(&self, unit: &Vector2D<T>) -> Vector2D<T> {
+        unit * self.dot(unit)
+    }
+}
+
+impl<T: Float> Vector2D<T> {
+    /// Creates a vector from its length and its angle to the x axis in radians.
+    pub fn from_polar(radius: T, angle: T) -> Vector2D<T> {
+        let (sin, cos) = angle.sin_cos();
+        Vector2D {
+            x: radius * cos,
+            y: radius * sin,
+        }
+    }
+
+    pub fn length(&self) -> T {
+        self.length_sq().sqrt()
+    }
+
+    /// Scales the vector down to the given length if it is longer, keeping its direction.
+    pub(crate) fn clamp_length(&self, max: T) -> Vector2D<T> {
+        let length = self.length();
+        if length > max {
+            self * (max / length)
+        } else {
+            *self
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use rand::Rng;
+use rand_distr::StandardNormal;
+
+use crate::util::particle::Particle;
+use crate::util::vector2d::Vector2D;
+use crate::Scalar;
+
+/// How the initial velocities of newly created particles are chosen
+#[derive(Copy, Clone, Debug)]
+pub enum VelocityDistribution {
+    /// All particles start at rest
+    Zero,
+    /// Two-dimensional Maxwell-Boltzmann distribution at the given temperature (with k_B = 1).
+    /// If `remove_drift` is set, the net momentum of the particles is subtracted afterwards.
+    Maxwell {
+        temperature: Scalar,
+        remove_drift: bool,
+    },
+}
+
+impl VelocityDistribution {
+    /// Draws a velocity for a single particle of the given mass.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R, mass: Scalar) -> Vector2D<Scalar> {
+        match *self {
+            VelocityDistribution::Zero => Vector2D::default(),
+            VelocityDistribution::Maxwell { temperature, .. } => {
+                // every velocity component is normally distributed with variance kT/m
+                let sigma = (temperature / mass).sqrt();
+                Vector2D {
+                    x: sigma * rng.sample::<Scalar, _>(StandardNormal),
+                    y: sigma * rng.sample::<Scalar, _>(StandardNormal),
+                }
+            }
+        }
+    }
+
+    /// Assigns sampled velocities to all given particles.
+    pub fn assign<R: Rng + ?Sized>(&self, rng: &mut R, particles: &mut [Particle<Scalar>]) {
+        for particle in particles.iter_mut() {
+            particle.velocity = self.sample(rng, particle.mass);
+        }
+        if let VelocityDistribution::Maxwell {
+            remove_drift: true, ..
+        } = self
+        {
+            remove_net_momentum(particles);
+        }
+    }
+}
+
+/// Shifts all velocities by the same amount so that the total momentum becomes zero.
+pub fn remove_net_momentum(particles: &mut [Particle<Scalar>]) {
+    let mut momentum: Vector2D<Scalar> = Vector2D::default();
+    let mut total_mass = 0.0;
+    for particle in particles.iter() {
+        momentum = &momentum + (particle.velocity * particle.mass);
+        total_mass += particle.mass;
+    }
+    if total_mass <= 0.0 {
+        return;
+    }
+
+    let drift = momentum * (1.0 / total_mass);
+    for particle in particles.iter_mut() {
+        particle.velocity = particle.velocity - drift;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::util::particle::create_particle;
+
+    #[test]
+    fn maxwell_speeds_match_the_analytic_moments() {
+        let (temperature, mass) = (2.0, 0.5);
+        let distribution = VelocityDistribution::Maxwell {
+            temperature,
+            remove_drift: false,
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 100_000;
+        let speeds: Vec<f64> = (0..n)
+            .map(|_| distribution.sample(&mut rng, mass).length() as f64)
+            .collect();
+        let mean = speeds.iter().sum::<f64>() / n as f64;
+        let variance = speeds.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / n as f64;
+
+        // the speed follows a Rayleigh distribution with sigma^2 = kT/m
+        let sigma_sq = (temperature / mass) as f64;
+        let expected_mean = (std::f64::consts::FRAC_PI_2 * sigma_sq).sqrt();
+        let expected_variance = (2.0 - std::f64::consts::FRAC_PI_2) * sigma_sq;
+        assert!((mean / expected_mean - 1.0).abs() < 0.01, "{}", mean);
+        assert!(
+            (variance / expected_variance - 1.0).abs() < 0.02,
+            "{}",
+            variance
+        );
+    }
+
+    #[test]
+    fn removing_the_drift_zeroes_the_momentum() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut particles: Vec<_> = (0..1000)
+            .map(|i| create_particle(0.0, 0.0, 1.0 + (i % 7) as Scalar, 0.0))
+            .collect();
+        let distribution = VelocityDistribution::Maxwell {
+            temperature: 1.0,
+            remove_drift: true,
+        };
+        distribution.assign(&mut rng, &mut particles);
+
+        let momentum = particles
+            .iter()
+            .fold(Vector2D::default(), |sum, p| &sum + p.velocity * p.mass);
+        let scale: Scalar = particles.iter().map(|p| p.velocity.length() * p.mass).sum();
+        assert!(momentum.length() < 1e-5 * scale, "{:?}", momentum);
+        assert!(particles.iter().any(|p| p.velocity.length() > 0.0));
+    }
+
+    #[test]
+    fn zero_distribution_keeps_particles_at_rest() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut particles = vec![create_particle(1.0, 2.0, 1.0, 0.0); 10];
+        VelocityDistribution::Zero.assign(&mut rng, &mut particles);
+        assert!(particles.iter().all(|p| p.velocity == Vector2D::default()));
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::util::particle::Particle;
+use crate::Scalar;
+
+/// Writes snapshots of the particles as legacy VTK files which ParaView can load as a series.
+pub struct VtkExporter {
+    directory: PathBuf,
+    every: u64,
+}
+
+impl VtkExporter {
+    /// Creates the output directory if necessary. A snapshot is written every `every` steps.
+    pub fn new(directory: PathBuf, every: u64) -> std::io::Result<VtkExporter> {
+        std::fs::create_dir_all(&directory)?;
+        Ok(VtkExporter {
+            directory,
+            every: every.max(1),
+        })
+    }
+
+    /// Writes a snapshot if one is due at the given step.
+    pub fn export(&self, step: u64, particles: &[Particle<Scalar>]) -> std::io::Result<()> {
+        if !step.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        // zero padded so that ParaView detects the files as one series
+        let path = self.directory.join(format!("snapshot_{:08}.vtk", step));
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_vtk(&mut writer, step, particles)?;
+        writer.flush()
+    }
+}
+
+/// Writes the particles as VTK polydata with one vertex per particle, and their speed,
+/// mass, charge and index as point data.
+pub fn write_vtk<W: Write>(
+    w: &mut W,
+    step: u64,
+    particles: &[Particle<Scalar>],
+) -> std::io::Result<()> {
+    let n = particles.len();
+    writeln!(w, "# vtk DataFile Version 3.0")?;
+    writeln!(w, "particle snapshot at step {}", step)?;
+    writeln!(w, "ASCII")?;
+    writeln!(w, "DATASET POLYDATA")?;
+
+    writeln!(w, "POINTS {} float", n)?;
+    for particle in particles {
+        writeln!(w, "{} {} 0", particle.position.x, particle.position.y)?;
+    }
+    writeln!(w, "VERTICES {} {}", n, 2 * n)?;
+    for i in 0..n {
+        writeln!(w, "1 {}", i)?;
+    }
+
+    writeln!(w, "POINT_DATA {}", n)?;
+    writeln!(w, "SCALARS speed float 1")?;
+    writeln!(w, "LOOKUP_TABLE default")?;
+    for particle in particles {
+        writeln!(w, "{}", particle.velocity.length())?;
+    }
+    writeln!(w, "SCALARS mass float 1")?;
+    writeln!(w, "LOOKUP_TABLE default")?;
+    for particle in particles {
+        writeln!(w, "{}", particle.mass)?;
+    }
+    writeln!(w, "SCALARS charge float 1")?;
+    writeln!(w, "LOOKUP_TABLE default")?;
+    for particle in particles {
+        writeln!(w, "{}", particle.charge)?;
+    }
+    writeln!(w, "SCALARS id int 1")?;
+    writeln!(w, "LOOKUP_TABLE default")?;
+    for i in 0..n {
+        writeln!(w, "{}", i)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::particle::create_particle;
+    use crate::util::vector2d::Vector2D;
+
+    fn three_particles() -> Vec<Particle<Scalar>> {
+        let mut particles = vec![
+            create_particle(1.0, 2.0, 4.0, 0.0),
+            create_particle(-3.5, 0.0, 1.0, -1.0),
+            create_particle(0.0, 8.0, 0.5, 2.0),
+        ];
+        particles[0].velocity = Vector2D { x: 3.0, y: 4.0 };
+        particles[2].velocity = Vector2D { x: 0.0, y: -2.0 };
+        particles
+    }
+
+    #[test]
+    fn writes_points_and_point_data() {
+        let mut output = Vec::new();
+        write_vtk(&mut output, 42, &three_particles()).unwrap();
+        let expected = "\
+# vtk DataFile Version 3.0
+particle snapshot at step 42
+ASCII
+DATASET POLYDATA
+POINTS 3 float
+1 2 0
+-3.5 0 0
+0 8 0
+VERTICES 3 6
+1 0
+1 1
+1 2
+POINT_DATA 3
+SCALARS speed float 1
+LOOKUP_TABLE default
+5
+0
+2
+SCALARS mass float 1
+LOOKUP_TABLE default
+4
+1
+0.5
+SCALARS charge float 1
+LOOKUP_TABLE default
+0
+-1
+2
+SCALARS id int 1
+LOOKUP_TABLE default
+0
+1
+2
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn exports_a_zero_padded_series() {
+        let directory = std::env::temp_dir().join(format!("vtk_series_{}", std::process::id()));
+        let exporter = VtkExporter::new(directory.clone(), 10).unwrap();
+        for step in 0..25 {
+            exporter.export(step, &three_particles()).unwrap();
+        }
+        let mut names: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(
+            names,
+            [
+                "snapshot_00000000.vtk",
+                "snapshot_00000010.vtk",
+                "snapshot_00000020.vtk"
+            ]
+        );
+    }
+}